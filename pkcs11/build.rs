@@ -0,0 +1,37 @@
+use std::{env, fs, path::Path};
+
+// The version of the crate is reported as the libraryVersion of CK_INFO,
+// CK_VERSION only has a byte for the major and the minor version.
+fn version_part(name: &str, value: &str) -> u8 {
+    let value: u64 = value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid {name} version: {value}"));
+    match u8::try_from(value) {
+        Ok(value) => value,
+        Err(_) => {
+            println!(
+                "cargo:warning=The {name} version {value} doesn't fit in CK_VERSION, using 255"
+            );
+            u8::MAX
+        }
+    }
+}
+
+fn main() {
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let mut parts = version.split(['.', '-', '+']);
+    let major = version_part("major", parts.next().unwrap_or_default());
+    let minor = version_part("minor", parts.next().unwrap_or_default());
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("version.rs");
+    fs::write(
+        out,
+        format!(
+            "pub const LIBRARY_VERSION_MAJOR: u8 = {major};\n\
+             pub const LIBRARY_VERSION_MINOR: u8 = {minor};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
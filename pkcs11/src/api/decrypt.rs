@@ -24,7 +24,7 @@ pub extern "C" fn C_DecryptInit(
         Ok(mech) => mech,
         Err(e) => {
             error!("C_DecryptInit() failed to convert mechanism: {}", e);
-            return cryptoki_sys::CKR_MECHANISM_INVALID;
+            return e.into();
         }
     };
 
@@ -54,7 +54,14 @@ pub extern "C" fn C_Decrypt(
 
     let buffer_size = unsafe { *pulDataLen } as usize;
 
-    let theoretical_size = session.decrypt_theoretical_size(ulEncryptedDataLen as usize);
+    // the size is estimated from the mechanism, the NetHSM is not called
+    let theoretical_size = match session.decrypt_theoretical_size(ulEncryptedDataLen as usize) {
+        Ok(size) => size,
+        Err(e) => {
+            session.decrypt_clear();
+            return e.into();
+        }
+    };
 
     unsafe {
         std::ptr::write(pulDataLen, theoretical_size as CK_ULONG);
@@ -114,17 +121,51 @@ pub extern "C" fn C_DecryptUpdate(
 
     let data = unsafe { std::slice::from_raw_parts(pEncryptedPart, ulEncryptedPartLen as usize) };
 
-    // we only add to the buffer, so we don't need to check the size
+    // the blocks decrypted are known in advance, the last one is kept for C_DecryptFinal
+    let size = match session.decrypt_update_len(data.len()) {
+        Ok(size) => size,
+        Err(e) => {
+            session.decrypt_clear();
+            return e.into();
+        }
+    };
+
+    let buffer_size = unsafe { std::ptr::read(pulPartLen) } as usize;
     unsafe {
-        std::ptr::write(pulPartLen, 0 as CK_ULONG);
+        std::ptr::write(pulPartLen, size as CK_ULONG);
+    }
+
+    // only a query of the length, the ciphertext isn't consumed
+    if pPart.is_null() {
+        return cryptoki_sys::CKR_OK;
+    }
+
+    if buffer_size < size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
-    match session.decrypt_update(data) {
-        Ok(()) => cryptoki_sys::CKR_OK,
+
+    let decrypted_data = match session.decrypt_update(data) {
+        Ok(data) => data,
         Err(e) => {
             session.decrypt_clear();
-            e.into()
+            return e.into();
         }
+    };
+
+    unsafe {
+        std::ptr::write(pulPartLen, decrypted_data.len() as CK_ULONG);
     }
+
+    // shouldn't happen
+    if decrypted_data.len() > buffer_size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(decrypted_data.as_ptr(), pPart, decrypted_data.len());
+    }
+
+    cryptoki_sys::CKR_OK
 }
 
 pub extern "C" fn C_DecryptFinal(
@@ -189,6 +230,11 @@ pub extern "C" fn C_DecryptFinal(
     cryptoki_sys::CKR_OK
 }
 
+// Decrypts and verifies at the same time, with AES-CBC for the decryption and an HMAC for the
+// verification. The expected calls are:
+// C_DecryptInit, C_VerifyInit, C_DecryptVerifyUpdate for each part, then C_DecryptFinal
+// and C_VerifyFinal. Each part returns the plaintext of the complete blocks received so far,
+// the plaintext is fed to the verification as it is decrypted.
 pub extern "C" fn C_DecryptVerifyUpdate(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
     pEncryptedPart: cryptoki_sys::CK_BYTE_PTR,
@@ -198,14 +244,69 @@ pub extern "C" fn C_DecryptVerifyUpdate(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_DecryptVerifyUpdate() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    if pulPartLen.is_null() || pEncryptedPart.is_null() {
+        session.decrypt_clear();
+        session.verify_clear();
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let size = match session.decrypt_verify_update_len(ulEncryptedPartLen as usize) {
+        Ok(size) => size,
+        Err(e) => return e.into(),
+    };
+
+    let buffer_size = unsafe { std::ptr::read(pulPartLen) } as usize;
+    unsafe {
+        std::ptr::write(pulPartLen, size as CK_ULONG);
+    }
+
+    if pPart.is_null() {
+        return cryptoki_sys::CKR_OK;
+    }
+
+    if size > buffer_size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    let data = unsafe { std::slice::from_raw_parts(pEncryptedPart, ulEncryptedPartLen as usize) };
+
+    let plaintext = match session.decrypt_verify_update(data) {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            session.decrypt_clear();
+            session.verify_clear();
+            return e.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::write(pulPartLen, plaintext.len() as CK_ULONG);
+    }
+
+    // the length was computed before decrypting, it can't be bigger
+    if plaintext.len() > buffer_size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(plaintext.as_ptr(), pPart, plaintext.len());
+    }
+
+    cryptoki_sys::CKR_OK
 }
 
 #[cfg(test)]
 mod tests {
 
+    use nethsm_sdk_rs::models::KeyMechanism;
+
     use super::*;
-    use crate::{backend::slot::init_for_tests, data::SESSION_MANAGER};
+    use crate::{
+        backend::{db::Object, slot::init_for_tests},
+        data::SESSION_MANAGER,
+    };
 
     fn setup_session() -> cryptoki_sys::CK_SESSION_HANDLE {
         SESSION_MANAGER.lock().unwrap().setup_dummy_session()
@@ -231,6 +332,212 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
     }
 
+    #[test]
+    fn test_decrypt_init_invalid_iv() {
+        init_for_tests();
+        let mut iv = [0u8; 16];
+        for len in [0, 8, 12, 32] {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_AES_CBC,
+                pParameter: iv.as_mut_ptr() as _,
+                ulParameterLen: len,
+            };
+            let rv = C_DecryptInit(0, &mut mech, 0);
+            assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+        }
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 16,
+        };
+        let rv = C_DecryptInit(0, &mut mech, 0);
+        assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+
+        // the NetHSM has no padded CBC nor GCM
+        for mechanism in [cryptoki_sys::CKM_AES_CBC_PAD, cryptoki_sys::CKM_AES_GCM] {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism,
+                pParameter: iv.as_mut_ptr() as _,
+                ulParameterLen: 16,
+            };
+            let rv = C_DecryptInit(0, &mut mech, 0);
+            assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_init_operation_active() {
+        init_for_tests();
+
+        // the mock rejects the decryptions with the key "invalid"
+        let (session, slot, _) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "invalid".to_string();
+        key.mechanisms = vec![KeyMechanism::AesDecryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        assert_eq!(
+            C_DecryptInit(session, &mut mech, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        assert_eq!(
+            C_DecryptInit(session, &mut mech, key_handle),
+            cryptoki_sys::CKR_OPERATION_ACTIVE
+        );
+
+        // the only block is kept for C_DecryptFinal
+        let mut data = [0u8; 16];
+        let mut plaintext = [0u8; 16];
+        let rv = C_DecryptUpdate(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            plaintext.as_mut_ptr(),
+            &mut 0,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut plaintext_len = plaintext.len() as CK_ULONG;
+        let rv = C_DecryptFinal(session, plaintext.as_mut_ptr(), &mut plaintext_len);
+        assert_ne!(rv, cryptoki_sys::CKR_OK);
+
+        // the failed C_DecryptFinal terminated the operation
+        assert_eq!(
+            C_DecryptInit(session, &mut mech, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_decrypt_update_streaming() {
+        init_for_tests();
+
+        let (session, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesDecryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        assert_eq!(
+            C_DecryptInit(session, &mut mech, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        // a block is decrypted once it can't be the last one
+        let mut lens = Vec::new();
+        for mut byte in [0u8; 32] {
+            // the length is announced without consuming the byte
+            let mut plaintext_len = 0;
+            let rv = C_DecryptUpdate(
+                session,
+                &mut byte,
+                1,
+                std::ptr::null_mut(),
+                &mut plaintext_len,
+            );
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+            let mut plaintext = [0u8; 16];
+            let rv = C_DecryptUpdate(
+                session,
+                &mut byte,
+                1,
+                plaintext.as_mut_ptr(),
+                &mut plaintext_len,
+            );
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            lens.push(plaintext_len);
+        }
+        let mut expected = vec![0; 32];
+        expected[16] = 16;
+        assert_eq!(lens, expected);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/decrypt"),
+            1
+        );
+
+        // the last block is returned at the end
+        let mut plaintext = [0u8; 16];
+        let mut plaintext_len = plaintext.len() as CK_ULONG;
+        let rv = C_DecryptFinal(session, plaintext.as_mut_ptr(), &mut plaintext_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(plaintext_len, 16);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/decrypt"),
+            2
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_decrypt_final_buffer_too_small_retry() {
+        init_for_tests();
+
+        let (session, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesDecryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        assert_eq!(
+            C_DecryptInit(session, &mut mech, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        // the only block is kept for C_DecryptFinal
+        let mut data = [0u8; 16];
+        let mut plaintext = [0u8; 16];
+        let rv = C_DecryptUpdate(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            plaintext.as_mut_ptr(),
+            &mut 0,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut plaintext_len = 4;
+        let rv = C_DecryptFinal(session, plaintext.as_mut_ptr(), &mut plaintext_len);
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(plaintext_len, 16);
+
+        // the operation is still active, the call is repeated with a big enough buffer
+        let rv = C_DecryptFinal(session, plaintext.as_mut_ptr(), &mut plaintext_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(plaintext_len, 16);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/decrypt"),
+            1
+        );
+
+        let rv = C_DecryptFinal(session, plaintext.as_mut_ptr(), &mut plaintext_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
     #[test]
     fn test_decrypt_init_invalid_session() {
         init_for_tests();
@@ -311,7 +618,8 @@ mod tests {
             std::ptr::null_mut(),
             &mut pulDataLen,
         );
-        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        // the size depends on the mechanism of the operation
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
     }
 
     #[test]
@@ -431,16 +739,29 @@ mod tests {
 
     // unsupported function
     #[test]
-    fn test_decrypt_verify_update() {
+    fn test_decrypt_verify_update_not_initialized() {
         init_for_tests();
+        let session = setup_session();
+
+        let mut encrypted = [0u8; 16];
+        let mut part = [0u8; 16];
+        let mut part_len = part.len() as CK_ULONG;
         let rv = C_DecryptVerifyUpdate(
-            0,
+            session,
+            encrypted.as_mut_ptr(),
+            encrypted.len() as CK_ULONG,
+            part.as_mut_ptr(),
+            &mut part_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let rv = C_DecryptVerifyUpdate(
+            session,
             std::ptr::null_mut(),
             0,
             std::ptr::null_mut(),
             std::ptr::null_mut(),
         );
-
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
     }
 }
@@ -1,8 +1,14 @@
 /*
-    We won't implement these function as it is not a feature of NetHSM.
+    The NetHSM has no digest feature, digests are computed in software by the module.
 */
 
-use log::trace;
+use cryptoki_sys::CK_ULONG;
+use log::{error, trace};
+
+use crate::{
+    backend::mechanism::{CkRawMechanism, MechDigest},
+    lock_session,
+};
 
 pub extern "C" fn C_DigestInit(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
@@ -10,11 +16,30 @@ pub extern "C" fn C_DigestInit(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_DigestInit() called");
 
-    if pMechanism.is_null() {
-        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    let raw_mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+        Some(mech) => mech,
+        None => {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+    };
+
+    let digest = match MechDigest::from_ck_mech(raw_mech.type_()) {
+        Some(digest) => digest,
+        None => {
+            error!(
+                "C_DigestInit() called with unsupported mechanism {}",
+                raw_mech.type_()
+            );
+            return cryptoki_sys::CKR_MECHANISM_INVALID;
+        }
+    };
+
+    lock_session!(hSession, session);
+
+    match session.digest_init(digest) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(e) => e.into(),
     }
-
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
 }
 
 pub extern "C" fn C_Digest(
@@ -26,11 +51,53 @@ pub extern "C" fn C_Digest(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_Digest() called");
 
-    if pData.is_null() || pDigest.is_null() || pulDigestLen.is_null() {
+    lock_session!(hSession, session);
+
+    if pData.is_null() || pulDigestLen.is_null() {
+        session.digest_clear();
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
+
+    let buffer_size = unsafe { *pulDigestLen } as usize;
+
+    let theoretical_size = match session.digest_theoretical_size() {
+        Ok(size) => size,
+        Err(err) => {
+            session.digest_clear();
+            return err.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::write(pulDigestLen, theoretical_size as CK_ULONG);
+    }
+
+    if pDigest.is_null() {
+        // only the size was requested
+        return cryptoki_sys::CKR_OK;
+    }
+
+    if buffer_size < theoretical_size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    let digest = match session.digest(data) {
+        Ok(digest) => digest,
+        Err(err) => {
+            session.digest_clear();
+            return err.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(digest.as_ptr(), pDigest, digest.len());
+    }
+
+    session.digest_clear();
+
+    cryptoki_sys::CKR_OK
 }
 
 pub extern "C" fn C_DigestUpdate(
@@ -40,11 +107,22 @@ pub extern "C" fn C_DigestUpdate(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_DigestUpdate() called");
 
+    lock_session!(hSession, session);
+
     if pPart.is_null() {
+        session.digest_clear();
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    let part = unsafe { std::slice::from_raw_parts(pPart, ulPartLen as usize) };
+
+    match session.digest_update(part) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(err) => {
+            session.digest_clear();
+            err.into()
+        }
+    }
 }
 
 pub extern "C" fn C_DigestFinal(
@@ -54,20 +132,68 @@ pub extern "C" fn C_DigestFinal(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_DigestFinal() called");
 
-    if pDigest.is_null() || pulDigestLen.is_null() {
+    lock_session!(hSession, session);
+
+    if pulDigestLen.is_null() {
+        session.digest_clear();
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    let buffer_size = unsafe { *pulDigestLen } as usize;
+
+    let theoretical_size = match session.digest_theoretical_size() {
+        Ok(size) => size,
+        Err(err) => {
+            session.digest_clear();
+            return err.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::write(pulDigestLen, theoretical_size as CK_ULONG);
+    }
+
+    if pDigest.is_null() {
+        // only the size was requested
+        return cryptoki_sys::CKR_OK;
+    }
+
+    if buffer_size < theoretical_size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    let digest = match session.digest_final() {
+        Ok(digest) => digest,
+        Err(err) => {
+            session.digest_clear();
+            return err.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(digest.as_ptr(), pDigest, digest.len());
+    }
+
+    session.digest_clear();
+
+    cryptoki_sys::CKR_OK
 }
 
 pub extern "C" fn C_DigestKey(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
     hKey: cryptoki_sys::CK_OBJECT_HANDLE,
 ) -> cryptoki_sys::CK_RV {
-    trace!("C_DigestKey() called");
+    trace!("C_DigestKey() called with hKey {}", hKey);
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    match session.digest_key(hKey) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(err) => {
+            session.digest_clear();
+            err.into()
+        }
+    }
 }
 
 pub extern "C" fn C_DigestEncryptUpdate(
@@ -96,32 +222,150 @@ pub extern "C" fn C_DecryptDigestUpdate(
 
 #[cfg(test)]
 mod tests {
-    use cryptoki_sys::CK_ULONG;
-
-    use crate::backend::slot::init_for_tests;
+    use std::sync::Arc;
+
+    use cryptoki_sys::{CKA_SENSITIVE, CKA_VALUE, CK_ULONG};
+    use sha2::Digest;
+
+    use crate::{
+        api::session::C_SessionCancel,
+        backend::{
+            db::{
+                object::{Attribute, ObjectKind},
+                Object,
+            },
+            slot::init_for_tests,
+        },
+        config::device::SlotBuilder,
+        data::SESSION_MANAGER,
+    };
 
     use super::*;
+
+    fn sha256_mechanism() -> cryptoki_sys::CK_MECHANISM {
+        cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_SHA256,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        }
+    }
+
+    fn add_secret_key(
+        session: cryptoki_sys::CK_SESSION_HANDLE,
+        value: Attribute,
+        sensitive: bool,
+    ) -> cryptoki_sys::CK_OBJECT_HANDLE {
+        let mut object = Object::default();
+        object.kind = ObjectKind::SecretKey;
+        object.id = "secret".to_string();
+        object.set_attr(CKA_VALUE, value);
+        object.set_attr(CKA_SENSITIVE, Attribute::Bool(sensitive));
+
+        let session = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap();
+        let session = session.lock().unwrap();
+        let mut db = session.db.lock().unwrap();
+        db.add_object(object).0
+    }
+
     #[test]
     fn test_digest_init() {
         init_for_tests();
         let rv = C_DigestInit(0, std::ptr::null_mut());
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
 
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
         let mut mech = cryptoki_sys::CK_MECHANISM {
             mechanism: 0,
             pParameter: std::ptr::null_mut(),
             ulParameterLen: 0,
         };
 
+        let rv = C_DigestInit(session, &mut mech);
+        assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
+
+        let mut mech = sha256_mechanism();
+
+        let rv = C_DigestInit(session, &mut mech);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let rv = C_DigestInit(session, &mut mech);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_ACTIVE);
+    }
+
+    #[test]
+    fn test_digest_sha1() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_SHA_1,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let mut data = b"abc".to_vec();
+        let mut digest = [0u8; 20];
+        let mut digest_len = digest.len() as CK_ULONG;
+        let rv = C_Digest(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            digest.as_mut_ptr(),
+            &mut digest_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(digest.to_vec(), sha1::Sha1::digest(&data).to_vec());
+    }
+
+    #[test]
+    fn test_digest_sha1_disabled() {
+        init_for_tests();
+        let slot = SlotBuilder::new().disable_sha1(true).build().unwrap();
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_SHA_1,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            C_DigestInit(session, &mut mech),
+            cryptoki_sys::CKR_MECHANISM_INVALID
+        );
+
+        // the other digests are still available
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+    }
+
+    #[test]
+    fn test_digest_invalid_session() {
+        init_for_tests();
+        SESSION_MANAGER.lock().unwrap().delete_session(0);
+
+        let mut mech = sha256_mechanism();
+
         let rv = C_DigestInit(0, &mut mech);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
     }
 
     #[test]
     fn test_digest() {
         init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
         let rv = C_Digest(
-            0,
+            session,
             std::ptr::null_mut(),
             0 as CK_ULONG,
             std::ptr::null_mut(),
@@ -130,49 +374,246 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
 
         let mut digest_len: CK_ULONG = 0;
-        let mut digest: Vec<u8> = Vec::new();
-        let mut data: Vec<u8> = Vec::new();
+        let mut digest = [0u8; 32];
+        let mut data = b"hello world".to_vec();
 
         let rv = C_Digest(
-            0,
+            session,
             data.as_mut_ptr(),
             data.len() as CK_ULONG,
             digest.as_mut_ptr(),
             &mut digest_len,
         );
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        // size query
+        let rv = C_Digest(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut digest_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(digest_len, 32);
+
+        let rv = C_Digest(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            digest.as_mut_ptr(),
+            &mut digest_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(digest.to_vec(), sha2::Sha256::digest(&data).to_vec());
     }
 
     #[test]
     fn test_digest_update() {
         init_for_tests();
-        let rv = C_DigestUpdate(0, std::ptr::null_mut(), 0 as CK_ULONG);
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let rv = C_DigestUpdate(session, std::ptr::null_mut(), 0 as CK_ULONG);
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
 
         let mut data: Vec<u8> = Vec::new();
 
-        let rv = C_DigestUpdate(0, data.as_mut_ptr(), data.len() as CK_ULONG);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let rv = C_DigestUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
     }
 
     #[test]
     fn test_digest_final() {
         init_for_tests();
-        let rv = C_DigestFinal(0, std::ptr::null_mut(), std::ptr::null_mut());
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let rv = C_DigestFinal(session, std::ptr::null_mut(), std::ptr::null_mut());
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
 
-        let mut digest_len: CK_ULONG = 0;
-        let mut digest: Vec<u8> = Vec::new();
+        let mut digest_len: CK_ULONG = 32;
+        let mut digest = [0u8; 32];
 
-        let rv = C_DigestFinal(0, digest.as_mut_ptr(), &mut digest_len);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+    }
+
+    #[test]
+    fn test_digest_init_after_final() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let mut digest_len: CK_ULONG = 32;
+        let mut digest = [0u8; 32];
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+    }
+
+    #[test]
+    fn test_digest_cancel_then_init() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let mut data = b"dropped".to_vec();
+        let rv = C_DigestUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let rv = C_SessionCancel(session, cryptoki_sys::CKF_DIGEST);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut digest_len: CK_ULONG = 32;
+        let mut digest = [0u8; 32];
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        // the cancelled data isn't part of the new digest
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+        let mut data = b"abc".to_vec();
+        let rv = C_DigestUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(digest.to_vec(), sha2::Sha256::digest(b"abc").to_vec());
+    }
+
+    #[test]
+    fn test_session_cancel_invalid_session() {
+        init_for_tests();
+        let rv = C_SessionCancel(0xdead, cryptoki_sys::CKF_DIGEST);
+        assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
+    }
+
+    #[test]
+    fn test_digest_multipart() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let mut part1 = b"hello ".to_vec();
+        let mut part2 = b"world".to_vec();
+
+        let rv = C_DigestUpdate(session, part1.as_mut_ptr(), part1.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        let rv = C_DigestUpdate(session, part2.as_mut_ptr(), part2.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut digest_len: CK_ULONG = 16;
+        let mut digest = [0u8; 32];
+
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(digest_len, 32);
+
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(
+            digest.to_vec(),
+            sha2::Sha256::digest(b"hello world").to_vec()
+        );
+    }
+
+    #[test]
+    fn test_digest_final_size_query() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        for (mechanism, len) in [
+            (cryptoki_sys::CKM_SHA_1, 20),
+            (cryptoki_sys::CKM_SHA256, 32),
+            (cryptoki_sys::CKM_SHA384, 48),
+            (cryptoki_sys::CKM_SHA512, 64),
+        ] {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            };
+            assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+            let mut data = b"hello world".to_vec();
+            let rv = C_DigestUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+            let mut digest_len: CK_ULONG = 0;
+            let rv = C_DigestFinal(session, std::ptr::null_mut(), &mut digest_len);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            assert_eq!(digest_len, len);
+
+            // the query doesn't end the operation
+            let mut digest = vec![0u8; len as usize];
+            let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            assert_eq!(digest_len, len);
+            if mechanism == cryptoki_sys::CKM_SHA256 {
+                assert_eq!(digest, sha2::Sha256::digest(&data).to_vec());
+            }
+        }
     }
 
     #[test]
     fn test_digest_key() {
         init_for_tests();
-        let rv = C_DigestKey(0, 0);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let key_value = b"0123456789abcdef".to_vec();
+        let key = add_secret_key(session, Attribute::Bytes(key_value.clone()), false);
+
+        let rv = C_DigestKey(session, key);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let mut data = b"prefix".to_vec();
+        let rv = C_DigestUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let rv = C_DigestKey(session, key);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut digest_len: CK_ULONG = 32;
+        let mut digest = [0u8; 32];
+        let rv = C_DigestFinal(session, digest.as_mut_ptr(), &mut digest_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut expected = sha2::Sha256::new();
+        expected.update(&data);
+        expected.update(&key_value);
+        assert_eq!(digest.to_vec(), expected.finalize().to_vec());
+    }
+
+    #[test]
+    fn test_digest_key_indigestible() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let sensitive_key = add_secret_key(session, Attribute::Sensitive, true);
+
+        let mut mech = sha256_mechanism();
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let rv = C_DigestKey(session, sensitive_key);
+        assert_eq!(rv, cryptoki_sys::CKR_KEY_INDIGESTIBLE);
+
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+
+        let rv = C_DigestKey(session, 9999);
+        assert_eq!(rv, cryptoki_sys::CKR_KEY_HANDLE_INVALID);
     }
 
     #[test]
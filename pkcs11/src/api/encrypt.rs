@@ -2,10 +2,7 @@ use cryptoki_sys::CK_ULONG;
 use log::{error, trace};
 
 use crate::{
-    backend::{
-        encrypt::ENCRYPT_BLOCK_SIZE,
-        mechanism::{CkRawMechanism, Mechanism},
-    },
+    backend::mechanism::{CkRawMechanism, Mechanism},
     lock_session,
 };
 
@@ -27,7 +24,7 @@ pub extern "C" fn C_EncryptInit(
         Ok(mech) => mech,
         Err(e) => {
             error!("C_EncryptInit() failed to convert mechanism: {}", e);
-            return cryptoki_sys::CKR_MECHANISM_INVALID;
+            return e.into();
         }
     };
 
@@ -57,11 +54,18 @@ pub extern "C" fn C_Encrypt(
 
     let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
 
-    // We only support AES-CBC for now the size of the encrypted data is the same as the size of the input
+    // the size is computed from the mechanism, the NetHSM is not called
+    let theoretical_size = match session.encrypt_theoretical_size(data.len()) {
+        Ok(size) => size,
+        Err(e) => {
+            session.encrypt_clear();
+            return e.into();
+        }
+    };
 
     if pEncryptedData.is_null() {
         unsafe {
-            std::ptr::write(pulEncryptedDataLen, data.len() as CK_ULONG);
+            std::ptr::write(pulEncryptedDataLen, theoretical_size as CK_ULONG);
         }
         return cryptoki_sys::CKR_OK;
     }
@@ -69,10 +73,10 @@ pub extern "C" fn C_Encrypt(
     let buffer_len = unsafe { *pulEncryptedDataLen } as usize;
 
     unsafe {
-        std::ptr::write(pulEncryptedDataLen, data.len() as CK_ULONG);
+        std::ptr::write(pulEncryptedDataLen, theoretical_size as CK_ULONG);
     }
 
-    if data.len() > buffer_len {
+    if theoretical_size > buffer_len {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
 
@@ -88,7 +92,7 @@ pub extern "C" fn C_Encrypt(
         std::ptr::write(pulEncryptedDataLen, encrypted_data.len() as CK_ULONG);
     }
 
-    // this shouldn't happen as it's checked above, but it's safe to keep it if encrypted_data.len() != data.len()
+    // this shouldn't happen as it's checked above, but it's safe to keep it if encrypted_data.len() != theoretical_size
 
     if encrypted_data.len() > buffer_len {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
@@ -129,9 +133,14 @@ pub extern "C" fn C_EncryptUpdate(
 
     let buffer_len = unsafe { std::ptr::read(pulEncryptedPartLen) as usize };
 
-    // We only support AES-CBC for now the size of the encrypted data is the same as the size of the input
-
-    let theoretical_size = ENCRYPT_BLOCK_SIZE * (data.len() / ENCRYPT_BLOCK_SIZE + 1);
+    // the complete blocks are encrypted, the partial one is kept for C_EncryptFinal
+    let theoretical_size = match session.encrypt_update_len(data.len()) {
+        Ok(size) => size,
+        Err(e) => {
+            session.encrypt_clear();
+            return e.into();
+        }
+    };
 
     unsafe {
         std::ptr::write(pulEncryptedPartLen, theoretical_size as CK_ULONG);
@@ -185,25 +194,26 @@ pub extern "C" fn C_EncryptFinal(
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    // enverything should be encrypted at this point, so we just need to return the last block
+    // an error terminates the operation, like a failed C_EncryptFinal
+    let size = match session.encrypt_final_output_len() {
+        Ok(size) => size,
+        Err(e) => {
+            session.encrypt_clear();
+            return e.into();
+        }
+    };
 
-    let buffer_len = unsafe { std::ptr::read(pulLastEncryptedPartLen) as usize };
+    // the complete blocks were encrypted by C_EncryptUpdate, only what is left is returned
+    let buffer_len = unsafe { std::ptr::read(pulLastEncryptedPartLen) };
     unsafe {
-        std::ptr::write(pulLastEncryptedPartLen, ENCRYPT_BLOCK_SIZE as CK_ULONG);
+        std::ptr::write(pulLastEncryptedPartLen, size);
     }
 
+    // only a query of the length, the operation stays active
     if pLastEncryptedPart.is_null() {
         return cryptoki_sys::CKR_OK;
     }
 
-    let size = match session.encrypt_get_theoretical_final_size() {
-        Ok(size) => size,
-        Err(e) => {
-            session.encrypt_clear();
-            return e.into();
-        }
-    };
-
     if buffer_len < size {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
@@ -222,7 +232,7 @@ pub extern "C" fn C_EncryptFinal(
 
     // shouldn't happen
 
-    if encrypted_data.len() > buffer_len {
+    if encrypted_data.len() as CK_ULONG > buffer_len {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
 
@@ -241,10 +251,47 @@ pub extern "C" fn C_EncryptFinal(
 
 #[cfg(test)]
 mod tests {
-    use crate::{backend::slot::init_for_tests, data::SESSION_MANAGER};
+    use std::sync::Arc;
+
+    use nethsm_sdk_rs::models::KeyMechanism;
+
+    use crate::{
+        backend::{db::Object, slot::init_for_tests},
+        config::device::SlotBuilder,
+        data::SESSION_MANAGER,
+    };
 
     use super::*;
 
+    // a session with an AES-CBC encryption initialized, the NetHSM is unreachable
+    fn setup_encrypt_session() -> cryptoki_sys::CK_SESSION_HANDLE {
+        let slot = SlotBuilder::new()
+            .url("http://127.0.0.1:1/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let session_handle = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        session_handle
+    }
+
     #[test]
     fn test_encrypt_init_null_mechanism() {
         init_for_tests();
@@ -265,6 +312,40 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
     }
 
+    #[test]
+    fn test_encrypt_init_invalid_iv() {
+        init_for_tests();
+        let mut iv = [0u8; 16];
+        for len in [0, 8, 12, 32] {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_AES_CBC,
+                pParameter: iv.as_mut_ptr() as _,
+                ulParameterLen: len,
+            };
+            let rv = C_EncryptInit(0, &mut mech, 0);
+            assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+        }
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 16,
+        };
+        let rv = C_EncryptInit(0, &mut mech, 0);
+        assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+
+        // the NetHSM has no padded CBC nor GCM
+        for mechanism in [cryptoki_sys::CKM_AES_CBC_PAD, cryptoki_sys::CKM_AES_GCM] {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism,
+                pParameter: iv.as_mut_ptr() as _,
+                ulParameterLen: 16,
+            };
+            let rv = C_EncryptInit(0, &mut mech, 0);
+            assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
+        }
+    }
+
     #[test]
     fn test_encrypt_init_invalid_session() {
         init_for_tests();
@@ -374,6 +455,110 @@ mod tests {
         let mut data: Vec<u8> = Vec::new();
         let mut pEncryptedDataLen: CK_ULONG = 0;
 
+        let rv = C_Encrypt(
+            session_handle,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut pEncryptedDataLen,
+        );
+        // the size depends on the mechanism of the operation
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+    }
+
+    #[test]
+    fn test_encrypt_init_operation_active() {
+        init_for_tests();
+
+        // the mock rejects the encryptions with the key "invalid"
+        let (session_handle, slot, _) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "invalid".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_ACTIVE);
+
+        // an incomplete block stays buffered until C_EncryptFinal
+        let mut data = [0u8; 3];
+        let mut encrypted = [0u8; 16];
+        let mut encrypted_len = 0;
+        let rv = C_EncryptUpdate(
+            session_handle,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut encrypted_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(encrypted_len, 0);
+
+        let mut encrypted_len = encrypted.len() as CK_ULONG;
+        let rv = C_EncryptUpdate(
+            session_handle,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            encrypted.as_mut_ptr(),
+            &mut encrypted_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(encrypted_len, 0);
+
+        let mut encrypted_len = encrypted.len() as CK_ULONG;
+        let rv = C_EncryptFinal(session_handle, encrypted.as_mut_ptr(), &mut encrypted_len);
+        assert_ne!(rv, cryptoki_sys::CKR_OK);
+
+        // the failed C_EncryptFinal terminated the operation
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .delete_session(session_handle);
+    }
+
+    #[test]
+    fn test_encrypt_size_query() {
+        init_for_tests();
+
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let session_handle = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut data = vec![0u8; 48];
+        let mut pEncryptedDataLen: CK_ULONG = 0;
         let rv = C_Encrypt(
             session_handle,
             data.as_mut_ptr(),
@@ -382,6 +567,25 @@ mod tests {
             &mut pEncryptedDataLen,
         );
         assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(pEncryptedDataLen, 48);
+
+        // the operation is still active after the size query
+        let mut encrypted = vec![0u8; 16];
+        let mut pEncryptedDataLen = encrypted.len() as CK_ULONG;
+        let rv = C_Encrypt(
+            session_handle,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            encrypted.as_mut_ptr(),
+            &mut pEncryptedDataLen,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(pEncryptedDataLen, 48);
+
+        SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .delete_session(session_handle);
     }
 
     #[test]
@@ -442,7 +646,7 @@ mod tests {
     #[test]
     fn test_encrypt_update_null_encrypted_part() {
         init_for_tests();
-        let session_handle = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+        let session_handle = setup_encrypt_session();
 
         let mut data: Vec<u8> = Vec::new();
         let mut pEncryptedPartLen: CK_ULONG = 0;
@@ -460,7 +664,7 @@ mod tests {
     #[test]
     fn test_encrypt_update_buffer_too_small() {
         init_for_tests();
-        let session_handle = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+        let session_handle = setup_encrypt_session();
 
         let mut data: Vec<u8> = vec![0; 100];
         let mut pEncryptedPart: Vec<u8> = Vec::new();
@@ -502,8 +706,203 @@ mod tests {
 
         let mut pEncryptedPartLen: CK_ULONG = 0;
 
+        // a length query needs an active operation
+        let rv = C_EncryptFinal(session_handle, std::ptr::null_mut(), &mut pEncryptedPartLen);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+    }
+
+    #[test]
+    fn test_encrypt_final_size_query() {
+        init_for_tests();
+
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let session_handle = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // nothing is left to encrypt, the NetHSM isn't called
+        let mut pEncryptedPartLen: CK_ULONG = 12;
+        let rv = C_EncryptFinal(session_handle, std::ptr::null_mut(), &mut pEncryptedPartLen);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(pEncryptedPartLen, 0);
+
+        // an incomplete block can't be encrypted without padding
+        let mut data = vec![0u8; 5];
+        let mut encrypted = vec![0u8; 16];
+        let mut pEncryptedPartLen = encrypted.len() as CK_ULONG;
+        let rv = C_EncryptUpdate(
+            session_handle,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            encrypted.as_mut_ptr(),
+            &mut pEncryptedPartLen,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(pEncryptedPartLen, 0);
+
+        let rv = C_EncryptFinal(session_handle, std::ptr::null_mut(), &mut pEncryptedPartLen);
+        assert_eq!(rv, cryptoki_sys::CKR_DATA_LEN_RANGE);
+
+        SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .delete_session(session_handle);
+    }
+
+    #[test]
+    fn test_encrypt_update_streaming() {
+        init_for_tests();
+
+        let (session_handle, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // a block is sent each time one is complete
+        let mut lens = Vec::new();
+        for mut byte in [0u8; 32] {
+            let mut encrypted = [0u8; 32];
+            let mut encrypted_len = encrypted.len() as CK_ULONG;
+            let rv = C_EncryptUpdate(
+                session_handle,
+                &mut byte,
+                1,
+                encrypted.as_mut_ptr(),
+                &mut encrypted_len,
+            );
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            lens.push(encrypted_len);
+        }
+        let mut expected = vec![0; 32];
+        expected[15] = 16;
+        expected[31] = 16;
+        assert_eq!(lens, expected);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/encrypt"),
+            2
+        );
+
+        let mut encrypted = [0u8; 16];
+        let mut encrypted_len = encrypted.len() as CK_ULONG;
+        let rv = C_EncryptFinal(session_handle, encrypted.as_mut_ptr(), &mut encrypted_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(encrypted_len, 0);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/encrypt"),
+            2
+        );
+
+        SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .delete_session(session_handle);
+    }
+
+    #[test]
+    fn test_encrypt_final_len() {
+        init_for_tests();
+
+        let (session_handle, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "aes".to_string();
+        key.mechanisms = vec![KeyMechanism::AesEncryptionCbc];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut iv = [0u8; 16];
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: iv.as_mut_ptr() as *mut _,
+            ulParameterLen: iv.len() as CK_ULONG,
+        };
+        let mut encrypted = vec![0u8; 16];
+        let update = |data: &mut [u8], encrypted: &mut [u8]| {
+            let mut len = encrypted.len() as CK_ULONG;
+            let rv = C_EncryptUpdate(
+                session_handle,
+                data.as_mut_ptr(),
+                data.len() as CK_ULONG,
+                encrypted.as_mut_ptr(),
+                &mut len,
+            );
+            (rv, len)
+        };
+
+        // the whole block is returned by C_EncryptUpdate, the final has nothing left
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(
+            update(&mut [0; 16], &mut encrypted),
+            (cryptoki_sys::CKR_OK, 16)
+        );
+        let mut pEncryptedPartLen: CK_ULONG = 16;
         let rv = C_EncryptFinal(session_handle, std::ptr::null_mut(), &mut pEncryptedPartLen);
         assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(pEncryptedPartLen, 0);
+        let rv = C_EncryptFinal(
+            session_handle,
+            encrypted.as_mut_ptr(),
+            &mut pEncryptedPartLen,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(pEncryptedPartLen, 0);
+
+        // the partial block can't be encrypted, the length query ends the operation
+        let rv = C_EncryptInit(session_handle, &mut mechanism, key_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(
+            update(&mut [0; 5], &mut encrypted),
+            (cryptoki_sys::CKR_OK, 0)
+        );
+        let rv = C_EncryptFinal(session_handle, std::ptr::null_mut(), &mut pEncryptedPartLen);
+        assert_eq!(rv, cryptoki_sys::CKR_DATA_LEN_RANGE);
+        let rv = C_EncryptFinal(
+            session_handle,
+            encrypted.as_mut_ptr(),
+            &mut pEncryptedPartLen,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        // only the whole block was sent to the NetHSM
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/aes/encrypt"),
+            1
+        );
+
+        SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .delete_session(session_handle);
     }
 
     #[test]
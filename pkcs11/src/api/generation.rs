@@ -39,7 +39,7 @@ pub extern "C" fn C_GenerateKey(
         Ok(mech) => mech,
         Err(e) => {
             error!("C_GenerateKey() failed to convert mechanism: {}", e);
-            return cryptoki_sys::CKR_MECHANISM_INVALID;
+            return e.into();
         }
     };
 
@@ -56,7 +56,7 @@ pub extern "C" fn C_GenerateKey(
         Ok(key) => key,
         Err(e) => {
             error!("C_GenerateKey() failed to generate key: {:?}", e);
-            return cryptoki_sys::CKR_FUNCTION_FAILED;
+            return e.into();
         }
     };
 
@@ -110,7 +110,7 @@ pub extern "C" fn C_GenerateKeyPair(
         Ok(mech) => mech,
         Err(e) => {
             error!("C_GenerateKeyPair() failed to convert mechanism: {}", e);
-            return cryptoki_sys::CKR_MECHANISM_INVALID;
+            return e.into();
         }
     };
     let public_template = match unsafe {
@@ -137,7 +137,7 @@ pub extern "C" fn C_GenerateKeyPair(
         Ok(keys) => keys,
         Err(e) => {
             error!("C_GenerateKeyPair() failed to generate key: {:?}", e);
-            return cryptoki_sys::CKR_FUNCTION_FAILED;
+            return e.into();
         }
     };
 
@@ -167,6 +167,36 @@ pub extern "C" fn C_WrapKey(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_WrapKey() called");
 
+    read_session!(hSession, session);
+
+    if pulWrappedKeyLen.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    if let Err(err) = session.check_wrap(hWrappingKey, hKey) {
+        return err.into();
+    }
+
+    // the applications ask for the length first, it is known without wrapping even if the wrap
+    // itself is refused below
+    if pWrappedKey.is_null() {
+        let mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+            Some(mech) => mech,
+            None => return cryptoki_sys::CKR_ARGUMENTS_BAD,
+        };
+        return match session.wrap_output_len(mech.type_(), hWrappingKey, hKey) {
+            Ok(Some(len)) => {
+                unsafe {
+                    std::ptr::write(pulWrappedKeyLen, len);
+                }
+                cryptoki_sys::CKR_OK
+            }
+            Ok(None) => cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED,
+            Err(err) => err.into(),
+        };
+    }
+
+    // the keys never leave the NetHSM
     cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
 }
 
@@ -182,7 +212,50 @@ pub extern "C" fn C_UnwrapKey(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_UnwrapKey() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    if pWrappedKey.is_null() || pTemplate.is_null() || phKey.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+        Some(mech) => mech,
+        None => {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+    };
+
+    let mech = match Mechanism::from_ckraw_mech(&mech) {
+        Ok(mech) => mech,
+        Err(e) => {
+            error!("C_UnwrapKey() failed to convert mechanism: {}", e);
+            return e.into();
+        }
+    };
+
+    let template =
+        match unsafe { CkRawAttrTemplate::from_raw_ptr(pTemplate, ulAttributeCount as usize) } {
+            Some(template) => template,
+            None => {
+                return cryptoki_sys::CKR_ARGUMENTS_BAD;
+            }
+        };
+
+    let wrapped_key = unsafe { std::slice::from_raw_parts(pWrappedKey, ulWrappedKeyLen as usize) };
+
+    lock_session!(hSession, session);
+
+    let handle = match session.unwrap_key(&mech, hUnwrappingKey, wrapped_key, template) {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("C_UnwrapKey() failed to unwrap the key: {:?}", e);
+            return e.into();
+        }
+    };
+
+    unsafe {
+        std::ptr::write(phKey, handle);
+    }
+
+    cryptoki_sys::CKR_OK
 }
 
 pub extern "C" fn C_DeriveKey(
@@ -195,7 +268,19 @@ pub extern "C" fn C_DeriveKey(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_DeriveKey() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    if pMechanism.is_null() || phKey.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    read_session!(hSession, session);
+
+    if let Err(e) = session.check_derive_key(unsafe { (*pMechanism).mechanism }, hBaseKey) {
+        error!("C_DeriveKey() failed to use the base key: {:?}", e);
+        return e.into();
+    }
+
+    // the NetHSM can't derive keys, there is no mechanism to use
+    cryptoki_sys::CKR_MECHANISM_INVALID
 }
 
 // we silently ignore this function as NetHSM handles the random number generation
@@ -257,6 +342,10 @@ pub extern "C" fn C_GenerateRandom(
         crate::backend::login::UserMode::Operator,
     ) {
         Ok(data) => data,
+        Err(crate::backend::ApiError::InstanceRemoved) => {
+            error!("C_GenerateRandom() failed, the NetHSM can't be reached");
+            return cryptoki_sys::CKR_DEVICE_REMOVED;
+        }
         Err(e) => {
             error!("C_GenerateRandom() failed to generate random data: {:?}", e);
             return cryptoki_sys::CKR_FUNCTION_FAILED;
@@ -282,7 +371,19 @@ pub extern "C" fn C_GenerateRandom(
 
 #[cfg(test)]
 mod tests {
-    use crate::backend::slot::init_for_tests;
+    use cryptoki_sys::{CK_OBJECT_HANDLE, CK_ULONG};
+
+    use crate::{
+        backend::{
+            db::{
+                object::{Attribute, ObjectKind},
+                Object,
+            },
+            session::tests::count_requests,
+            slot::init_for_tests,
+        },
+        data::SESSION_MANAGER,
+    };
 
     use super::*;
 
@@ -577,6 +678,82 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_OK);
     }
 
+    #[test]
+    fn test_generate_aes_key_wrap() {
+        init_for_tests();
+        for wrap in [
+            None,
+            Some(cryptoki_sys::CK_FALSE),
+            Some(cryptoki_sys::CK_TRUE),
+        ] {
+            let (session, _, _) = crate::backend::session::tests::mock_session(0);
+
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_AES_KEY_GEN,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            };
+            let mut value_len: CK_ULONG = 32;
+            let mut wrap_value = wrap.unwrap_or_default();
+            let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_VALUE_LEN,
+                pValue: &mut value_len as *mut _ as _,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+            }];
+            if wrap.is_some() {
+                template.push(cryptoki_sys::CK_ATTRIBUTE {
+                    type_: cryptoki_sys::CKA_WRAP,
+                    pValue: &mut wrap_value as *mut _ as _,
+                    ulValueLen: 1,
+                });
+            }
+            let mut key: CK_OBJECT_HANDLE = 0;
+            let rv = C_GenerateKey(
+                session,
+                &mut mech,
+                template.as_mut_ptr(),
+                template.len() as _,
+                &mut key,
+            );
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+            // CKA_WRAP defaults to false, CKA_UNWRAP wasn't given
+            let mut values = [0xaa_u8; 2];
+            let mut template = [
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: cryptoki_sys::CKA_WRAP,
+                    pValue: values[..1].as_mut_ptr() as _,
+                    ulValueLen: 1,
+                },
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: cryptoki_sys::CKA_UNWRAP,
+                    pValue: values[1..].as_mut_ptr() as _,
+                    ulValueLen: 1,
+                },
+            ];
+            let rv =
+                crate::api::object::C_GetAttributeValue(session, key, template.as_mut_ptr(), 2);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            assert_eq!(values, [wrap_value, cryptoki_sys::CK_FALSE]);
+
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_AES_KEY_WRAP,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            };
+            let mut len = 0;
+            let rv = C_WrapKey(session, &mut mech, key, key, std::ptr::null_mut(), &mut len);
+            if wrap == Some(cryptoki_sys::CK_TRUE) {
+                assert_eq!(rv, cryptoki_sys::CKR_OK);
+                assert_eq!(len, 40);
+            } else {
+                assert_eq!(rv, cryptoki_sys::CKR_KEY_FUNCTION_NOT_PERMITTED);
+            }
+
+            SESSION_MANAGER.lock().unwrap().delete_session(session);
+        }
+    }
+
     #[test]
     fn test_wrap_key() {
         init_for_tests();
@@ -588,11 +765,305 @@ mod tests {
             std::ptr::null_mut(),
             std::ptr::null_mut(),
         );
+        assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
+    }
+
+    #[test]
+    fn test_generate_ed25519_key_pair() {
+        init_for_tests();
+        let (session, _, _) = crate::backend::session::tests::mock_session(0);
+
+        let generate = |ec_params: &mut [u8],
+                        public: &mut CK_OBJECT_HANDLE,
+                        private: &mut CK_OBJECT_HANDLE| {
+            let mut mech = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_EC_KEY_PAIR_GEN,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            };
+            let mut public_template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_EC_PARAMS,
+                pValue: ec_params.as_mut_ptr() as _,
+                ulValueLen: ec_params.len() as _,
+            }];
+            let mut sign = cryptoki_sys::CK_TRUE;
+            let mut private_template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_SIGN,
+                pValue: &mut sign as *mut _ as _,
+                ulValueLen: 1,
+            }];
+            C_GenerateKeyPair(
+                session,
+                &mut mech,
+                public_template.as_mut_ptr(),
+                1,
+                private_template.as_mut_ptr(),
+                1,
+                public,
+                private,
+            )
+        };
+
+        // id-EdDSA, 1.3.101.112
+        let mut ed25519 = [0x06, 0x03, 0x2b, 0x65, 0x70];
+        let (mut public, mut private) = (0, 0);
+        let rv = generate(&mut ed25519, &mut public, &mut private);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let (public_key, private_key) = {
+            let manager = SESSION_MANAGER.lock().unwrap();
+            let session = manager.get_session(session).unwrap();
+            let session = session.lock().unwrap();
+            (
+                session.get_object(public).unwrap(),
+                session.get_object(private).unwrap(),
+            )
+        };
+        assert_eq!(
+            private_key.get_attribute(cryptoki_sys::CKA_KEY_TYPE),
+            Some(&Attribute::Ulong(cryptoki_sys::CKK_EC_EDWARDS))
+        );
+        for key in [&public_key, &private_key] {
+            assert_eq!(
+                key.get_attribute(cryptoki_sys::CKA_EC_PARAMS),
+                Some(&Attribute::Bytes(ed25519.to_vec()))
+            );
+        }
+        assert!(public_key
+            .get_attribute(cryptoki_sys::CKA_EC_POINT)
+            .is_some());
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_EDDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let rv = crate::api::sign::C_SignInit(session, &mut mech, private);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        let mut data = *b"message";
+        let mut signature = [0u8; 64];
+        let mut signature_len = signature.len() as CK_ULONG;
+        let rv = crate::api::sign::C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(signature_len, 64);
+
+        // 1.3.101.113 is Ed448
+        let mut ed448 = [0x06, 0x03, 0x2b, 0x65, 0x71];
+        let rv = generate(&mut ed448, &mut public, &mut private);
+        assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+    }
+
+    #[test]
+    fn test_generate_aes_key_allowed_mechanisms() {
+        init_for_tests();
+        let (session, _, _) = crate::backend::session::tests::mock_session(0);
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_KEY_GEN,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut value_len: CK_ULONG = 32;
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_VALUE_LEN,
+            pValue: &mut value_len as *mut _ as _,
+            ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+        }];
+        let mut key: CK_OBJECT_HANDLE = 0;
+        let rv = C_GenerateKey(session, &mut mech, template.as_mut_ptr(), 1, &mut key);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut mechanisms = [0 as CK_ULONG; 4];
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_ALLOWED_MECHANISMS,
+            pValue: mechanisms.as_mut_ptr() as _,
+            ulValueLen: std::mem::size_of_val(&mechanisms) as _,
+        }];
+        let rv = crate::api::object::C_GetAttributeValue(session, key, template.as_mut_ptr(), 1);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        // the NetHSM has AES-CBC, for encryption and decryption, and the module AES-CMAC with it
+        assert_eq!(
+            template[0].ulValueLen as usize,
+            2 * std::mem::size_of::<CK_ULONG>()
+        );
+        assert_eq!(
+            mechanisms[..2],
+            [cryptoki_sys::CKM_AES_CBC, cryptoki_sys::CKM_AES_CMAC]
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_generate_aes_key_value_len() {
+        init_for_tests();
+        let (session, slot, _) = crate::backend::session::tests::mock_session(0);
+
+        let get_value_len = |key| {
+            let mut value_len: CK_ULONG = 0;
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_VALUE_LEN,
+                pValue: &mut value_len as *mut _ as _,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+            }];
+            let rv =
+                crate::api::object::C_GetAttributeValue(session, key, template.as_mut_ptr(), 1);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            assert_eq!(
+                template[0].ulValueLen as usize,
+                std::mem::size_of::<CK_ULONG>()
+            );
+            value_len
+        };
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_KEY_GEN,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        for len in [16, 24, 32] {
+            let mut value_len: CK_ULONG = len;
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_VALUE_LEN,
+                pValue: &mut value_len as *mut _ as _,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+            }];
+            let mut key: CK_OBJECT_HANDLE = 0;
+            let rv = C_GenerateKey(session, &mut mech, template.as_mut_ptr(), 1, &mut key);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+            assert_eq!(get_value_len(key), len);
+
+            // the length is kept when the key is fetched again from the NetHSM
+            let login_ctx = SESSION_MANAGER
+                .lock()
+                .unwrap()
+                .get_session(session)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .login_ctx
+                .clone();
+            crate::backend::key::fetch_key("generated", None, login_ctx, slot.db.clone()).unwrap();
+            assert_eq!(get_value_len(key), len);
+        }
+
+        // the NetHSM doesn't give the length of the keys the module didn't create
+        let login_ctx = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .login_ctx
+            .clone();
+        let (other, _) =
+            crate::backend::key::fetch_key("other", None, login_ctx, slot.db.clone()).unwrap()[0];
+        let mut value_len: CK_ULONG = 0;
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_VALUE_LEN,
+            pValue: &mut value_len as *mut _ as _,
+            ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+        }];
+        let rv = crate::api::object::C_GetAttributeValue(session, other, template.as_mut_ptr(), 1);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID);
+        assert_eq!(
+            template[0].ulValueLen,
+            cryptoki_sys::CK_UNAVAILABLE_INFORMATION
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_wrap_key_length() {
+        init_for_tests();
+        let (session, slot, _) = crate::backend::session::tests::mock_session(0);
+        let db = slot.db.clone();
+        let login_ctx = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .login_ctx
+            .clone();
+        // the public key of an RSA-OAEP key can wrap
+        let wrapping = crate::backend::key::fetch_key("oaep", None, login_ctx, db.clone())
+            .unwrap()
+            .into_iter()
+            .find(|(_, object)| object.kind == ObjectKind::PublicKey)
+            .unwrap()
+            .0;
+        let key = {
+            let mut key = Object::default();
+            key.id = "aes".to_string();
+            key.size = Some(32);
+            db.lock().unwrap().add_object(key).0
+        };
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_RSA_PKCS_OAEP,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut len = 0;
+        let rv = C_WrapKey(
+            session,
+            &mut mech,
+            wrapping,
+            key,
+            std::ptr::null_mut(),
+            &mut len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(len, 256);
+
+        mech.mechanism = cryptoki_sys::CKM_AES_KEY_WRAP;
+        let rv = C_WrapKey(
+            session,
+            &mut mech,
+            wrapping,
+            key,
+            std::ptr::null_mut(),
+            &mut len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(len, 40);
+
+        // the key is still not wrapped
+        let mut wrapped = [0u8; 40];
+        let rv = C_WrapKey(
+            session,
+            &mut mech,
+            wrapping,
+            key,
+            wrapped.as_mut_ptr(),
+            &mut len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+
+        mech.mechanism = cryptoki_sys::CKM_AES_CBC;
+        let rv = C_WrapKey(
+            session,
+            &mut mech,
+            wrapping,
+            key,
+            std::ptr::null_mut(),
+            &mut len,
+        );
         assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
     }
 
     #[test]
-    fn test_unwrap_key() {
+    fn test_unwrap_key_null_arguments() {
         init_for_tests();
         let rv = C_UnwrapKey(
             0,
@@ -604,7 +1075,157 @@ mod tests {
             0,
             std::ptr::null_mut(),
         );
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+    }
+
+    #[test]
+    fn test_unwrap_key_null_template() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_CBC,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut wrapped = [0u8; 32];
+        let mut key = 0;
+        for count in [0, 1] {
+            let rv = C_UnwrapKey(
+                session,
+                &mut mech,
+                0,
+                wrapped.as_mut_ptr(),
+                wrapped.len() as CK_ULONG,
+                std::ptr::null_mut(),
+                count,
+                &mut key,
+            );
+            assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+        }
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_unwrap_key() {
+        init_for_tests();
+        let (session, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let login_ctx = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .login_ctx
+            .clone();
+        let rsa = crate::backend::key::fetch_key("oaep", None, login_ctx, slot.db.clone())
+            .unwrap()
+            .into_iter()
+            .find(|(_, object)| object.kind == ObjectKind::PrivateKey)
+            .unwrap()
+            .0;
+        let aes = {
+            let mut key = Object::default();
+            key.id = "aes".to_string();
+            key.kind = ObjectKind::SecretKey;
+            slot.db.lock().unwrap().add_object(key).0
+        };
+
+        let mut class = cryptoki_sys::CKO_SECRET_KEY;
+        let mut key_type = cryptoki_sys::CKK_AES;
+        let mut id = *b"unwrapped";
+        let mut template = [
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_CLASS,
+                pValue: &mut class as *mut _ as _,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_KEY_TYPE,
+                pValue: &mut key_type as *mut _ as _,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as _,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_ID,
+                pValue: id.as_mut_ptr() as _,
+                ulValueLen: id.len() as _,
+            },
+        ];
+        let mut params = cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS {
+            hashAlg: cryptoki_sys::CKM_SHA256,
+            mgf: cryptoki_sys::CKG_MGF1_SHA256,
+            source: cryptoki_sys::CKZ_DATA_SPECIFIED,
+            pSourceData: std::ptr::null_mut(),
+            ulSourceDataLen: 0,
+        };
+        let mut unwrap = |mech: &mut cryptoki_sys::CK_MECHANISM, key, handle: &mut _| {
+            let mut wrapped = [0u8; 256];
+            C_UnwrapKey(
+                session,
+                mech,
+                key,
+                wrapped.as_mut_ptr(),
+                wrapped.len() as _,
+                template.as_mut_ptr(),
+                template.len() as _,
+                handle,
+            )
+        };
+
+        // the AES key is decrypted with the RSA key by the NetHSM, then imported
+        let mut oaep = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_RSA_PKCS_OAEP,
+            pParameter: &mut params as *mut _ as _,
+            ulParameterLen: std::mem::size_of::<cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS>() as _,
+        };
+        let mut handle = 0;
+        assert_eq!(unwrap(&mut oaep, rsa, &mut handle), cryptoki_sys::CKR_OK);
+        let key = slot.db.lock().unwrap().object(handle).cloned().unwrap();
+        assert_eq!(key.kind, ObjectKind::SecretKey);
+        assert_eq!(key.id, "unwrapped");
+        assert_eq!(count_requests(&requests, "/api/v1/keys/oaep/decrypt"), 1);
+        // the import and the fetch of the key
+        assert_eq!(count_requests(&requests, "/api/v1/keys/unwrapped"), 2);
+
+        // the AES key has no CKA_UNWRAP
+        assert_eq!(
+            unwrap(&mut oaep, aes, &mut handle),
+            cryptoki_sys::CKR_KEY_FUNCTION_NOT_PERMITTED
+        );
+
+        // no AES key wrap: the AES keys are on the NetHSM, which has no key wrap and never gives
+        // out their value, and the secrets of the module are HMAC keys
+        let mut aes_key_wrap = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_AES_KEY_WRAP,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            unwrap(&mut aes_key_wrap, aes, &mut handle),
+            cryptoki_sys::CKR_MECHANISM_INVALID
+        );
+
+        // after C_Login as the SO, the session can't decrypt with the operator anymore
+        let mut pin = *b"password";
+        assert_eq!(
+            crate::api::token::C_Login(
+                session,
+                cryptoki_sys::CKU_SO,
+                pin.as_mut_ptr(),
+                pin.len() as _
+            ),
+            cryptoki_sys::CKR_OK
+        );
+        assert_eq!(
+            unwrap(&mut oaep, rsa, &mut handle),
+            cryptoki_sys::CKR_USER_NOT_LOGGED_IN
+        );
+        assert_eq!(count_requests(&requests, "/api/v1/keys/oaep/decrypt"), 1);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/unwrapped"), 2);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 
     #[test]
@@ -618,7 +1239,73 @@ mod tests {
             0,
             std::ptr::null_mut(),
         );
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+
+        let (session, slot, _) = crate::backend::session::tests::mock_session(0);
+
+        let login_ctx = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .login_ctx
+            .clone();
+        let ed = crate::backend::key::fetch_key("ed", None, login_ctx, slot.db.clone())
+            .unwrap()
+            .into_iter()
+            .find(|(_, object)| object.kind == ObjectKind::PrivateKey)
+            .unwrap()
+            .0;
+        let derive = {
+            let mut key = Object::default();
+            key.id = "derive".to_string();
+            key.kind = ObjectKind::SecretKey;
+            key.set_attr(cryptoki_sys::CKA_DERIVE, Attribute::Bool(true));
+            slot.db.lock().unwrap().add_object(key).0
+        };
+
+        let rsa = {
+            let mut key = Object::default();
+            key.id = "rsa".to_string();
+            key.kind = ObjectKind::PrivateKey;
+            key.set_attr(cryptoki_sys::CKA_DERIVE, Attribute::Bool(true));
+            key.set_attr(
+                cryptoki_sys::CKA_KEY_TYPE,
+                Attribute::Ulong(cryptoki_sys::CKK_RSA),
+            );
+            slot.db.lock().unwrap().add_object(key).0
+        };
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_ECDH1_DERIVE,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut derive_with = |key| {
+            let mut handle = 0;
+            C_DeriveKey(
+                session,
+                &mut mech,
+                key,
+                std::ptr::null_mut(),
+                0,
+                &mut handle,
+            )
+        };
+
+        // the EC keys of the NetHSM can't be used for ECDH
+        assert_eq!(
+            derive_with(ed),
+            cryptoki_sys::CKR_KEY_FUNCTION_NOT_PERMITTED
+        );
+        assert_eq!(derive_with(derive), cryptoki_sys::CKR_MECHANISM_INVALID);
+        assert_eq!(derive_with(rsa), cryptoki_sys::CKR_KEY_TYPE_INCONSISTENT);
+        assert_eq!(
+            derive_with(CK_OBJECT_HANDLE::MAX),
+            cryptoki_sys::CKR_KEY_HANDLE_INVALID
+        );
     }
 
     #[test]
@@ -11,13 +11,21 @@ pub mod pin;
 pub mod session;
 pub mod sign;
 pub mod token;
+pub mod vendor;
 pub mod verify;
 
-use std::sync::atomic::Ordering;
+use std::sync::{atomic::Ordering, Arc};
 
 use crate::{
-    backend::events::{fetch_slots_state, EventsManager},
-    data::{self, DEVICE, DEVICE_INIT, EVENTS_MANAGER, THREADS_ALLOWED, TOKENS_STATE},
+    backend::{
+        events::{fetch_slots_state, EventsManager},
+        locking::AppMutex,
+        session::{restore_sessions_state, save_sessions_state},
+    },
+    data::{
+        self, APP_MUTEX, DEVICE, DEVICE_INIT, EVENTS_MANAGER, INITIALIZED, SESSION_MANAGER,
+        THREADS_ALLOWED, TOKENS_STATE,
+    },
     defs,
     utils::padded_str,
 };
@@ -35,7 +43,7 @@ pub extern "C" fn C_GetFunctionList(
     }
 
     unsafe {
-        std::ptr::write(pp_fn_list, &mut data::FN_LIST);
+        std::ptr::write(pp_fn_list, std::ptr::addr_of_mut!(data::FN_LIST));
     }
     cryptoki_sys::CKR_OK
 }
@@ -57,6 +65,10 @@ pub extern "C" fn C_Initialize(pInitArgs: CK_VOID_PTR) -> CK_RV {
 
     match result {
         Ok(()) => {}
+        Err(crate::config::initialization::InitializationError::Config(err)) => {
+            error!("NetHSM PKCS#11: Invalid configuration: {err}");
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
         Err(err) => {
             error!("NetHSM PKCS#11: Failed to initialize configuration: {err:?}");
             return cryptoki_sys::CKR_FUNCTION_FAILED;
@@ -88,11 +100,12 @@ pub extern "C" fn C_Initialize(pInitArgs: CK_VOID_PTR) -> CK_RV {
         trace!("C_Initialize() called with flags: {:?}", flags);
         trace!("C_Initialize() called with CreateMutex: {:?}", CreateMutex);
 
-        // currently we don't support custom locking
-        // if the flag is not set and the mutex functions are not null, the program asks us to use only the mutex functions, we can't do that
-        if flags & cryptoki_sys::CKF_OS_LOCKING_OK == 0 && CreateMutex.is_some() {
-            return cryptoki_sys::CKR_CANT_LOCK;
-        }
+        // if the flag is not set and the mutex functions are not null, the program asks us to use only the mutex functions
+        let app_mutex = match AppMutex::from_init_args(&args) {
+            Ok(mutex) => mutex,
+            Err(rv) => return rv,
+        };
+        *APP_MUTEX.write().unwrap() = app_mutex.map(Arc::new);
 
         if flags & cryptoki_sys::CKF_LIBRARY_CANT_CREATE_OS_THREADS != 0 {
             THREADS_ALLOWED.store(false, Ordering::Relaxed);
@@ -101,10 +114,18 @@ pub extern "C" fn C_Initialize(pInitArgs: CK_VOID_PTR) -> CK_RV {
         }
     }
 
+    if device.check_connections().is_err() {
+        return cryptoki_sys::CKR_DEVICE_ERROR;
+    }
+
+    INITIALIZED.store(true, Ordering::SeqCst);
+
     // Initialize the events manager
     *EVENTS_MANAGER.write().unwrap() = EventsManager::new();
     *TOKENS_STATE.lock().unwrap() = std::collections::HashMap::new();
 
+    restore_sessions_state(device);
+
     match fetch_slots_state() {
         Ok(()) => cryptoki_sys::CKR_OK,
         Err(err) => err,
@@ -117,14 +138,34 @@ pub extern "C" fn C_Finalize(pReserved: CK_VOID_PTR) -> CK_RV {
     if !pReserved.is_null() {
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
+
+    if !INITIALIZED.swap(false, Ordering::SeqCst) {
+        return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
+    }
+
     EVENTS_MANAGER.write().unwrap().finalized = true;
 
+    if let Some(device) = DEVICE.get() {
+        // the state is saved before the sessions are closed
+        save_sessions_state(device);
+        SESSION_MANAGER.lock().unwrap().finalize_all(device);
+    }
+
+    // the mutex is destroyed once the last call using it is done
+    *APP_MUTEX.write().unwrap() = None;
+
     cryptoki_sys::CKR_OK
 }
 
 pub extern "C" fn C_GetInfo(pInfo: CK_INFO_PTR) -> CK_RV {
     trace!("C_GetInfo() called");
 
+    // Some modules return CKR_OK here, we follow the specification. Only C_GetFunctionList can be
+    // called before C_Initialize.
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
+    }
+
     if pInfo.is_null() {
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
@@ -146,6 +187,7 @@ pub extern "C" fn C_GetInfo(pInfo: CK_INFO_PTR) -> CK_RV {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::backend::slot::init_for_tests;
 
     #[test]
     fn test_get_function_list() {
@@ -161,8 +203,30 @@ mod test {
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
     }
 
+    #[test]
+    fn test_get_info_version() {
+        init_for_tests();
+        let mut info = std::mem::MaybeUninit::<CK_INFO>::uninit();
+        assert_eq!(C_GetInfo(info.as_mut_ptr()), cryptoki_sys::CKR_OK);
+        let info = unsafe { info.assume_init() };
+
+        assert_eq!(
+            (info.cryptokiVersion.major, info.cryptokiVersion.minor),
+            (2, 40)
+        );
+
+        let mut version = env!("CARGO_PKG_VERSION").split('.');
+        let major: u8 = version.next().unwrap().parse().unwrap();
+        let minor: u8 = version.next().unwrap().parse().unwrap();
+        assert_eq!(
+            (info.libraryVersion.major, info.libraryVersion.minor),
+            (major, minor)
+        );
+    }
+
     #[test]
     fn test_get_info_null_ptr() {
+        init_for_tests();
         let rv = C_GetInfo(std::ptr::null_mut());
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
     }
@@ -2,8 +2,14 @@ use cryptoki_sys::CK_ULONG;
 use log::{error, trace};
 
 use crate::{
-    backend::{db::attr::CkRawAttrTemplate, key},
-    data::{DEVICE, KEY_ALIASES},
+    backend::{
+        db::{
+            attr::CkRawAttrTemplate,
+            object::{Attribute, ObjectKind, CKA_NETHSM_MAX_USAGE_COUNT, CKA_NETHSM_USAGE_COUNT},
+        },
+        key, Error,
+    },
+    data::{initialized_device, KEY_ALIASES},
     lock_session, read_session,
 };
 
@@ -67,9 +73,10 @@ pub extern "C" fn C_FindObjectsFinal(
 
     lock_session!(hSession, session);
 
-    session.enum_final();
-
-    cryptoki_sys::CKR_OK
+    match session.enum_final() {
+        Ok(()) => cryptoki_sys::CKR_OK,
+        Err(err) => err.into(),
+    }
 }
 pub extern "C" fn C_GetAttributeValue(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
@@ -85,15 +92,24 @@ pub extern "C" fn C_GetAttributeValue(
 
     read_session!(hSession, session);
 
-    let object = match session.get_object(hObject) {
-        Some(object) => object,
-        None => {
+    // the object is built with all its attributes from a single GET /keys/{id} when it is
+    // fetched, the template is filled from the Db without calling the NetHSM again
+    let mut object = match session.get_object(hObject) {
+        Ok(object) => object,
+        Err(Error::InvalidObjectHandle(_)) => {
             error!(
                 "C_GetAttributeValue() called with invalid object handle {}.",
                 hObject
             );
             return cryptoki_sys::CKR_OBJECT_HANDLE_INVALID;
         }
+        Err(err) => {
+            error!(
+                "C_GetAttributeValue() failed to get the object {}: {:?}",
+                hObject, err
+            );
+            return err.into();
+        }
     };
 
     trace!(
@@ -102,6 +118,25 @@ pub extern "C" fn C_GetAttributeValue(
         object.kind
     );
 
+    if let Err(err) = session.check_object_access(&object) {
+        return err.into();
+    }
+
+    // the usage of the keys is counted by the session
+    if matches!(object.kind, ObjectKind::PrivateKey | ObjectKind::SecretKey) {
+        let usage = session.key_usage(hObject);
+        object.set_attr(
+            CKA_NETHSM_USAGE_COUNT,
+            Attribute::Ulong(usage.count as CK_ULONG),
+        );
+        if let Some(max) = usage.max {
+            object.set_attr(
+                CKA_NETHSM_MAX_USAGE_COUNT,
+                Attribute::Ulong(max as CK_ULONG),
+            );
+        }
+    }
+
     let mut template = match unsafe { CkRawAttrTemplate::from_raw_ptr(pTemplate, ulCount as usize) }
     {
         Some(template) => template,
@@ -110,6 +145,18 @@ pub extern "C" fn C_GetAttributeValue(
         }
     };
 
+    // the subject of a private key comes from its certificate
+    if object.kind == ObjectKind::PrivateKey
+        && object.get_attribute(cryptoki_sys::CKA_SUBJECT).is_none()
+        && template
+            .iter()
+            .any(|attr| attr.type_() == cryptoki_sys::CKA_SUBJECT)
+    {
+        if let Some(subject) = session.certificate_subject(hObject) {
+            object.set_attr(cryptoki_sys::CKA_SUBJECT, subject);
+        }
+    }
+
     object.fill_attr_template(&mut template)
 }
 pub extern "C" fn C_GetObjectSize(
@@ -126,11 +173,15 @@ pub extern "C" fn C_GetObjectSize(
     read_session!(hSession, session);
 
     let object = match session.get_object(hObject) {
-        Some(object) => object,
-        None => {
+        Ok(object) => object,
+        Err(Error::InvalidObjectHandle(_)) => {
             error!("function called with invalid object handle {}.", hObject);
             return cryptoki_sys::CKR_OBJECT_HANDLE_INVALID;
         }
+        Err(err) => {
+            error!("failed to get the object {}: {:?}", hObject, err);
+            return err.into();
+        }
     };
 
     unsafe {
@@ -148,9 +199,7 @@ pub extern "C" fn C_CreateObject(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_CreateObject() called ");
 
-    // pTemplate checked with from_raw_ptr
-
-    if phObject.is_null() {
+    if pTemplate.is_null() || phObject.is_null() {
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
@@ -191,7 +240,27 @@ pub extern "C" fn C_CopyObject(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_CopyObject() called");
 
-    cryptoki_sys::CKR_ACTION_PROHIBITED
+    if phNewObject.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    // the template can be empty, the copy then has the attributes of the original
+    let template = unsafe { CkRawAttrTemplate::from_raw_ptr(pTemplate, ulCount as usize) };
+    if template.is_none() && ulCount != 0 {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    lock_session!(hSession, session);
+
+    match session.copy_object(hObject, template.as_ref()) {
+        Ok(handle) => {
+            unsafe {
+                std::ptr::write(phNewObject, handle);
+            }
+            cryptoki_sys::CKR_OK
+        }
+        Err(err) => err.into(),
+    }
 }
 
 pub extern "C" fn C_DestroyObject(
@@ -216,12 +285,36 @@ pub extern "C" fn C_SetAttributeValue(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_SetAttributeValue() called");
 
+    if pTemplate.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
     let template = match unsafe { CkRawAttrTemplate::from_raw_ptr(pTemplate, ulCount as usize) } {
         Some(template) => template,
         None => {
             return cryptoki_sys::CKR_ARGUMENTS_BAD;
         }
     };
+
+    // the maximum usage count is kept by the module, the other attributes are set first
+    let (mut max_usage_count, mut rest): (Vec<_>, Vec<_>) =
+        unsafe { std::slice::from_raw_parts(pTemplate, ulCount as usize) }
+            .iter()
+            .copied()
+            .partition(|attr| attr.type_ == CKA_NETHSM_MAX_USAGE_COUNT);
+    if !max_usage_count.is_empty() && !rest.is_empty() {
+        let rv = C_SetAttributeValue(hSession, hObject, rest.as_mut_ptr(), rest.len() as _);
+        if rv != cryptoki_sys::CKR_OK {
+            return rv;
+        }
+        return C_SetAttributeValue(
+            hSession,
+            hObject,
+            max_usage_count.as_mut_ptr(),
+            max_usage_count.len() as _,
+        );
+    }
+
     let parsed = match key::parse_attributes(&template) {
         Ok(parsed) => parsed,
         Err(err) => {
@@ -229,26 +322,53 @@ pub extern "C" fn C_SetAttributeValue(
         }
     };
 
-    let Some(device) = DEVICE.get() else {
+    let Some(device) = initialized_device() else {
         error!("Initialization was not performed or failed");
         return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
     };
 
-    // if the hack is enabled, we update the key alias map
-    if device.enable_set_attribute_value {
-        read_session!(hSession, session);
-
-        let object = match session.get_object(hObject) {
-            Some(object) => object,
-            None => {
-                error!(
-                    "C_SetAttributeValue() called with invalid object handle {}.",
-                    hObject
-                );
-                return cryptoki_sys::CKR_OBJECT_HANDLE_INVALID;
-            }
+    lock_session!(hSession, session);
+
+    let object = match session.get_object(hObject) {
+        Ok(object) => object,
+        Err(Error::InvalidObjectHandle(_)) => {
+            error!(
+                "C_SetAttributeValue() called with invalid object handle {}.",
+                hObject
+            );
+            return cryptoki_sys::CKR_OBJECT_HANDLE_INVALID;
+        }
+        Err(err) => {
+            error!(
+                "C_SetAttributeValue() failed to get the object {}: {:?}",
+                hObject, err
+            );
+            return err.into();
+        }
+    };
+
+    if !max_usage_count.is_empty() {
+        return match session.set_max_usage_count(hObject, &template) {
+            Ok(()) => cryptoki_sys::CKR_OK,
+            Err(err) => err.into(),
         };
+    }
+
+    // the attributes of the session objects and the object ID of the certificates are kept by
+    // the module
+    let object_id_only = object.kind == ObjectKind::Certificate
+        && template
+            .iter()
+            .all(|attr| attr.type_() == cryptoki_sys::CKA_OBJECT_ID);
+    if !object.is_token() || object_id_only {
+        return match session.set_attribute_value(hObject, &template) {
+            Ok(()) => cryptoki_sys::CKR_OK,
+            Err(err) => err.into(),
+        };
+    }
 
+    // if the hack is enabled, we update the key alias map
+    if device.enable_set_attribute_value {
         if let Some(new_name) = parsed.id {
             KEY_ALIASES.lock().unwrap().insert(new_name, object.id);
             cryptoki_sys::CKR_OK
@@ -269,16 +389,25 @@ pub extern "C" fn C_SetAttributeValue(
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{Arc, Mutex};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use cryptoki_sys::{CK_ATTRIBUTE_TYPE, CK_OBJECT_HANDLE, CK_SESSION_HANDLE, CK_ULONG};
 
     use crate::{
+        api::session::C_CloseSession,
         backend::{
-            db::{Db, Object},
+            db::{
+                object::{Attribute, ObjectKind},
+                Db, Object,
+            },
             login::LoginCtx,
             session::Session,
             slot::init_for_tests,
         },
-        config::config_file::RetryConfig,
+        config::{config_file::RetryConfig, device::SlotBuilder},
         data::SESSION_MANAGER,
     };
 
@@ -303,6 +432,27 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
     }
 
+    #[test]
+    fn test_find_objects_closed_session() {
+        init_for_tests();
+        let slot = Arc::new(SlotBuilder::new().build().unwrap());
+        slot.db.lock().unwrap().set_fetched_all_keys(true);
+        let session = SESSION_MANAGER.lock().unwrap().create_session(0, slot, 0);
+
+        let rv = C_FindObjectsInit(session, std::ptr::null_mut(), 0);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(C_CloseSession(session), cryptoki_sys::CKR_OK);
+
+        let mut phObject: cryptoki_sys::CK_OBJECT_HANDLE = 0;
+        let mut pulObjectCount: cryptoki_sys::CK_ULONG = 0;
+        let rv = C_FindObjects(session, &mut phObject, 1, &mut pulObjectCount);
+        assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
+        assert_eq!(
+            C_FindObjectsFinal(session),
+            cryptoki_sys::CKR_SESSION_HANDLE_INVALID
+        );
+    }
+
     #[test]
     fn test_find_objects_null_object() {
         init_for_tests();
@@ -334,6 +484,15 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
     }
 
+    #[test]
+    fn test_find_objects_final_not_initialized() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let rv = C_FindObjectsFinal(session);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+    }
+
     #[test]
     fn test_get_attribute_value_invalid_session() {
         init_for_tests();
@@ -365,6 +524,194 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_OBJECT_HANDLE_INVALID);
     }
 
+    // adds the object to the database of the slot of the session
+    fn add_object(session: CK_SESSION_HANDLE, object: Object) -> CK_OBJECT_HANDLE {
+        let session = SESSION_MANAGER
+            .lock()
+            .unwrap()
+            .get_session(session)
+            .unwrap();
+        let db = session.lock().unwrap().db.clone();
+        let (handle, _) = db.lock().unwrap().add_object(object);
+        handle
+    }
+
+    #[test]
+    fn test_get_attribute_value_private_object() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut object = Object::default();
+        object.set_attr(cryptoki_sys::CKA_PRIVATE, Attribute::Bool(true));
+        let object_handle = add_object(session, object);
+
+        let mut template = vec![];
+
+        // the dummy session is not logged in
+        let rv = C_GetAttributeValue(session, object_handle, template.as_mut_ptr(), 0);
+        assert_eq!(rv, cryptoki_sys::CKR_USER_NOT_LOGGED_IN);
+    }
+
+    #[test]
+    fn test_get_attribute_value_bool_encoding() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let expected = [
+            (cryptoki_sys::CKA_SENSITIVE, cryptoki_sys::CK_TRUE),
+            (cryptoki_sys::CKA_ENCRYPT, cryptoki_sys::CK_FALSE),
+            (cryptoki_sys::CKA_SIGN, cryptoki_sys::CK_TRUE),
+            (cryptoki_sys::CKA_TOKEN, cryptoki_sys::CK_FALSE),
+        ];
+        let mut object = Object::default();
+        for (attr_type, value) in expected {
+            object.set_attr(attr_type, Attribute::Bool(value == cryptoki_sys::CK_TRUE));
+        }
+        let object_handle = add_object(session, object);
+
+        // the length is asked first, then the values are read into larger buffers
+        let mut template: Vec<_> = expected
+            .iter()
+            .map(|(attr_type, _)| cryptoki_sys::CK_ATTRIBUTE {
+                type_: *attr_type,
+                pValue: std::ptr::null_mut(),
+                ulValueLen: 0,
+            })
+            .collect();
+        let rv = C_GetAttributeValue(
+            session,
+            object_handle,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert!(template.iter().all(|attr| attr.ulValueLen == 1));
+
+        let mut buffers = [[0xaa_u8; 4]; 4];
+        for (attr, buffer) in template.iter_mut().zip(buffers.iter_mut()) {
+            attr.pValue = buffer.as_mut_ptr() as _;
+            attr.ulValueLen = buffer.len() as CK_ULONG;
+        }
+        let rv = C_GetAttributeValue(
+            session,
+            object_handle,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        for ((attr, buffer), (_, value)) in template.iter().zip(buffers).zip(expected) {
+            assert_eq!(attr.ulValueLen, 1);
+            assert_eq!(buffer, [value, 0xaa, 0xaa, 0xaa]);
+        }
+    }
+
+    #[test]
+    fn test_get_attribute_value_label_buffer_sizes() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let label = b"a-label-of-20-chars!";
+        let mut object = Object::default();
+        object.set_attr(cryptoki_sys::CKA_LABEL, Attribute::Bytes(label.to_vec()));
+        let object_handle = add_object(session, object);
+
+        // returns the result, the length and the buffer, None for a null pointer
+        let get = |buffer: Option<Vec<u8>>, len: CK_ULONG| {
+            let mut buffer = buffer;
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_LABEL,
+                pValue: buffer
+                    .as_mut()
+                    .map(|buffer| buffer.as_mut_ptr() as _)
+                    .unwrap_or(std::ptr::null_mut()),
+                ulValueLen: len,
+            }];
+            let rv = C_GetAttributeValue(session, object_handle, template.as_mut_ptr(), 1);
+            (rv, template[0].ulValueLen, buffer)
+        };
+
+        // a null pointer asks for the length, whatever the given one
+        for len in [0, 5, 100] {
+            assert_eq!(get(None, len), (cryptoki_sys::CKR_OK, 20, None));
+        }
+
+        // the buffer isn't written when it is too small
+        for len in [0, 1, 19] {
+            assert_eq!(
+                get(Some(vec![0xaa; 32]), len),
+                (
+                    cryptoki_sys::CKR_BUFFER_TOO_SMALL,
+                    cryptoki_sys::CK_UNAVAILABLE_INFORMATION,
+                    Some(vec![0xaa; 32])
+                )
+            );
+        }
+
+        for len in [20, 32] {
+            let (rv, value_len, buffer) = get(Some(vec![0xaa; 32]), len);
+            assert_eq!((rv, value_len), (cryptoki_sys::CKR_OK, 20));
+            let buffer = buffer.unwrap();
+            assert_eq!(&buffer[..20], label);
+            assert!(buffer[20..].iter().all(|b| *b == 0xaa));
+        }
+    }
+
+    // reads the CK_ULONG attributes of an object, returns their lengths and values
+    fn get_ulong_attributes(
+        expected: &[(CK_ATTRIBUTE_TYPE, CK_ULONG)],
+    ) -> Vec<(CK_ULONG, Vec<u8>)> {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut object = Object::default();
+        for (attr_type, value) in expected {
+            object.set_attr(*attr_type, Attribute::Ulong(*value));
+        }
+        let object_handle = add_object(session, object);
+
+        let mut buffers = vec![[0u8; 16]; expected.len()];
+        let mut template: Vec<_> = expected
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|((attr_type, _), buffer)| cryptoki_sys::CK_ATTRIBUTE {
+                type_: *attr_type,
+                pValue: buffer.as_mut_ptr() as _,
+                ulValueLen: buffer.len() as CK_ULONG,
+            })
+            .collect();
+        let rv = C_GetAttributeValue(
+            session,
+            object_handle,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        template
+            .iter()
+            .zip(buffers)
+            .map(|(attr, buffer)| (attr.ulValueLen, buffer[..attr.ulValueLen as usize].to_vec()))
+            .collect()
+    }
+
+    const ULONG_ATTRIBUTES: [(CK_ATTRIBUTE_TYPE, CK_ULONG); 4] = [
+        (cryptoki_sys::CKA_CLASS, cryptoki_sys::CKO_PRIVATE_KEY),
+        (cryptoki_sys::CKA_KEY_TYPE, cryptoki_sys::CKK_RSA),
+        (cryptoki_sys::CKA_MODULUS_BITS, 2048),
+        (cryptoki_sys::CKA_VALUE_LEN, 32),
+    ];
+
+    #[test]
+    fn test_get_attribute_value_ulong() {
+        // CK_ULONG is the C unsigned long: 4 bytes on 32 bits and on 64 bits Windows
+        for ((len, bytes), (_, value)) in get_ulong_attributes(&ULONG_ATTRIBUTES)
+            .into_iter()
+            .zip(ULONG_ATTRIBUTES)
+        {
+            assert_eq!(len as usize, std::mem::size_of::<CK_ULONG>());
+            assert_eq!(bytes, value.to_ne_bytes());
+        }
+    }
+
     #[test]
     fn test_get_object_size_invalid_session() {
         init_for_tests();
@@ -407,12 +754,28 @@ mod tests {
 
         let session_handle = 1;
         let session = Session {
+            handle: 0,
             db: Arc::new(Mutex::new(db)),
             decrypt_ctx: None,
+            digest_ctx: None,
             encrypt_ctx: None,
             sign_ctx: None,
+            message_sign_ctx: None,
+            verify_ctx: None,
+            verify_recover_ctx: None,
             device_error: 0,
             enum_ctx: None,
+            notify: None,
+            key_usage: HashMap::new(),
+            sign_key: None,
+            decrypt_key: None,
+            strict_digestinfo_validation: false,
+            min_pin_len: 0,
+            max_pin_len: 255,
+            disable_sha1: false,
+            disable_rsa_pkcs_decrypt: false,
+            prefetch_on_login: false,
+            prefetch: Default::default(),
             flags: 0,
             login_ctx: LoginCtx::new(
                 None,
@@ -490,9 +853,285 @@ mod tests {
     }
 
     #[test]
-    fn test_copy_object() {
+    fn test_copy_object_null_object() {
         init_for_tests();
         let rv = C_CopyObject(0, 0, std::ptr::null_mut(), 0, std::ptr::null_mut());
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+    }
+
+    // a session with a key stored on the NetHSM
+    fn copy_session(copyable: Option<bool>) -> (CK_SESSION_HANDLE, CK_OBJECT_HANDLE) {
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut object = Object::default();
+        object.id = "key".to_string();
+        object.kind = ObjectKind::PrivateKey;
+        object.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(true));
+        object.set_attr(cryptoki_sys::CKA_LABEL, Attribute::Bytes(b"key".to_vec()));
+        if let Some(copyable) = copyable {
+            object.set_attr(cryptoki_sys::CKA_COPYABLE, Attribute::Bool(copyable));
+        }
+
+        (session, add_object(session, object))
+    }
+
+    fn session_object(session: CK_SESSION_HANDLE, handle: CK_OBJECT_HANDLE) -> Option<Object> {
+        let manager = SESSION_MANAGER.lock().unwrap();
+        let session = manager.get_session(session).unwrap();
+        let session = session.lock().unwrap();
+        session.get_object(handle).ok()
+    }
+
+    #[test]
+    fn test_copy_object_token_to_session() {
+        init_for_tests();
+        let (session, original) = copy_session(None);
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut label = b"copy".to_vec();
+        let mut template = vec![
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_TOKEN,
+                pValue: &mut token as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: 1,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_LABEL,
+                pValue: label.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: label.len() as CK_ULONG,
+            },
+        ];
+
+        let mut copy = 0;
+        let rv = C_CopyObject(
+            session,
+            original,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+            &mut copy,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_ne!(copy, original);
+
+        let object = session_object(session, copy).unwrap();
+        assert_eq!(object.id, "key");
+        assert_eq!(object.copied_from, Some(original));
+        assert!(!object.is_token());
+        assert_eq!(
+            object.get_attribute(cryptoki_sys::CKA_LABEL),
+            Some(&Attribute::Bytes(b"copy".to_vec()))
+        );
+
+        // the copy is destroyed without touching the key on the NetHSM
+        assert_eq!(C_DestroyObject(session, copy), cryptoki_sys::CKR_OK);
+        assert!(session_object(session, copy).is_none());
+        assert!(session_object(session, original).is_some());
+
+        // a copy that stays a token object would need a new key on the NetHSM
+        let rv = C_CopyObject(session, original, std::ptr::null_mut(), 0, &mut copy);
+        assert_eq!(rv, cryptoki_sys::CKR_ACTION_PROHIBITED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_set_attribute_value_session_object() {
+        init_for_tests();
+        let (session, original) = copy_session(None);
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_TOKEN,
+            pValue: &mut token as *mut _ as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: 1,
+        }];
+        let mut copy = 0;
+        let rv = C_CopyObject(session, original, template.as_mut_ptr(), 1, &mut copy);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let mut label = b"renamed".to_vec();
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_LABEL,
+            pValue: label.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: label.len() as CK_ULONG,
+        }];
+        let rv = C_SetAttributeValue(session, copy, template.as_mut_ptr(), 1);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(
+            session_object(session, copy)
+                .unwrap()
+                .get_attribute(cryptoki_sys::CKA_LABEL),
+            Some(&Attribute::Bytes(b"renamed".to_vec()))
+        );
+
+        // the key on the NetHSM keeps its attributes
+        let rv = C_SetAttributeValue(session, original, template.as_mut_ptr(), 1);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_READ_ONLY);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_set_attribute_value_max_usage_count() {
+        init_for_tests();
+        let (session, original) = copy_session(None);
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_TOKEN,
+            pValue: &mut token as *mut _ as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: 1,
+        }];
+        let mut copy = 0;
+        let rv = C_CopyObject(session, original, template.as_mut_ptr(), 1, &mut copy);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // the limit and the label are both set
+        let mut label = b"limited".to_vec();
+        let mut max: CK_ULONG = 5;
+        let mut template = vec![
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_NETHSM_MAX_USAGE_COUNT,
+                pValue: &mut max as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as CK_ULONG,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_LABEL,
+                pValue: label.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: label.len() as CK_ULONG,
+            },
+        ];
+        let rv = C_SetAttributeValue(session, copy, template.as_mut_ptr(), 2);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(
+            session_object(session, copy)
+                .unwrap()
+                .get_attribute(cryptoki_sys::CKA_LABEL),
+            Some(&Attribute::Bytes(b"limited".to_vec()))
+        );
+
+        let mut value: CK_ULONG = 0;
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_NETHSM_MAX_USAGE_COUNT,
+            pValue: &mut value as *mut _ as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: std::mem::size_of::<CK_ULONG>() as CK_ULONG,
+        }];
+        let rv = C_GetAttributeValue(session, copy, template.as_mut_ptr(), 1);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(value, 5);
+
+        // the label of the key on the NetHSM can't be changed, the limit isn't set either
+        let mut template = vec![
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_NETHSM_MAX_USAGE_COUNT,
+                pValue: &mut max as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: std::mem::size_of::<CK_ULONG>() as CK_ULONG,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: cryptoki_sys::CKA_LABEL,
+                pValue: label.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: label.len() as CK_ULONG,
+            },
+        ];
+        let rv = C_SetAttributeValue(session, original, template.as_mut_ptr(), 2);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_READ_ONLY);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_set_attribute_value_certificate_object_id() {
+        init_for_tests();
+        let slot = Arc::new(SlotBuilder::new().build().unwrap());
+        let mut cert = Object::default();
+        cert.id = "cert".to_string();
+        cert.kind = ObjectKind::Certificate;
+        cert.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(true));
+        let (cert, _) = slot.db.lock().unwrap().add_object(cert);
+
+        let mut object_id = [0x06, 0x03, 0x2a, 0x03, 0x04];
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_OBJECT_ID,
+            pValue: object_id.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: object_id.len() as CK_ULONG,
+        }];
+        for (flags, expected) in [
+            (
+                cryptoki_sys::CKF_SERIAL_SESSION,
+                cryptoki_sys::CKR_SESSION_READ_ONLY,
+            ),
+            (
+                cryptoki_sys::CKF_SERIAL_SESSION | cryptoki_sys::CKF_RW_SESSION,
+                cryptoki_sys::CKR_OK,
+            ),
+        ] {
+            let session = SESSION_MANAGER
+                .lock()
+                .unwrap()
+                .create_session(0, slot.clone(), flags);
+            let rv = C_SetAttributeValue(session, cert, template.as_mut_ptr(), 1);
+            assert_eq!(rv, expected);
+            SESSION_MANAGER.lock().unwrap().delete_session(session);
+        }
+        assert_eq!(
+            slot.db
+                .lock()
+                .unwrap()
+                .object(cert)
+                .unwrap()
+                .get_attribute(cryptoki_sys::CKA_OBJECT_ID),
+            Some(&Attribute::Bytes(object_id.to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_copy_object_not_copyable() {
+        init_for_tests();
+        let (session, original) = copy_session(Some(false));
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut template = vec![cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_TOKEN,
+            pValue: &mut token as *mut _ as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: 1,
+        }];
+
+        let mut copy = 0;
+        let rv = C_CopyObject(session, original, template.as_mut_ptr(), 1, &mut copy);
         assert_eq!(rv, cryptoki_sys::CKR_ACTION_PROHIBITED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_copy_object_protection() {
+        init_for_tests();
+        let (session, original) = copy_session(None);
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut value = cryptoki_sys::CK_TRUE;
+        let mut copy = 0;
+        for attr in [
+            cryptoki_sys::CKA_PRIVATE,
+            cryptoki_sys::CKA_TRUSTED,
+            cryptoki_sys::CKA_TOKEN,
+        ] {
+            let mut template = vec![
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: cryptoki_sys::CKA_TOKEN,
+                    pValue: &mut token as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                    ulValueLen: 1,
+                },
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: attr,
+                    pValue: &mut value as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                    ulValueLen: 1,
+                },
+            ];
+            let rv = C_CopyObject(session, original, template.as_mut_ptr(), 2, &mut copy);
+            assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_READ_ONLY);
+        }
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 }
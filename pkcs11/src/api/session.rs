@@ -1,14 +1,15 @@
 use log::{error, trace};
 
+use crate::backend::session::SessionNotify;
 use crate::backend::slot::get_slot;
 use crate::data::SESSION_MANAGER;
-use crate::read_session;
+use crate::{lock_session, read_session};
 
 pub extern "C" fn C_OpenSession(
     slotID: cryptoki_sys::CK_SLOT_ID,
     flags: cryptoki_sys::CK_FLAGS,
-    _pApplication: cryptoki_sys::CK_VOID_PTR,
-    _Notify: cryptoki_sys::CK_NOTIFY,
+    pApplication: cryptoki_sys::CK_VOID_PTR,
+    Notify: cryptoki_sys::CK_NOTIFY,
     phSession: cryptoki_sys::CK_SESSION_HANDLE_PTR,
 ) -> cryptoki_sys::CK_RV {
     trace!(
@@ -27,15 +28,21 @@ pub extern "C" fn C_OpenSession(
 
     let slot = match get_slot(slotID as usize) {
         Ok(slot) => slot,
-        Err(_) => {
-            error!("C_OpenSession() called with invalid slotID {}.", slotID);
-            return cryptoki_sys::CKR_SLOT_ID_INVALID;
+        Err(rv) => {
+            // get_slot logs when the module isn't initialized
+            if rv == cryptoki_sys::CKR_SLOT_ID_INVALID {
+                error!("C_OpenSession() called with invalid slotID {}.", slotID);
+            }
+            return rv;
         }
     };
 
     // create the session in memory
     let mut manager = SESSION_MANAGER.lock().unwrap();
     let session = manager.create_session(slotID, slot, flags);
+    if let Some(created) = manager.get_session(session) {
+        created.lock().unwrap().notify = SessionNotify::new(session, Notify, pApplication);
+    }
 
     trace!("C_OpenSession() created session: {:?}", session);
 
@@ -66,12 +73,14 @@ pub extern "C" fn C_CloseSession(hSession: cryptoki_sys::CK_SESSION_HANDLE) -> c
 pub extern "C" fn C_CloseAllSessions(slotID: cryptoki_sys::CK_SLOT_ID) -> cryptoki_sys::CK_RV {
     trace!("C_CloseAllSessions() called");
 
-    if get_slot(slotID as usize).is_err() {
-        error!(
-            "C_CloseAllSessions() called with invalid slotID {}.",
-            slotID
-        );
-        return cryptoki_sys::CKR_SLOT_ID_INVALID;
+    if let Err(rv) = get_slot(slotID as usize) {
+        if rv == cryptoki_sys::CKR_SLOT_ID_INVALID {
+            error!(
+                "C_CloseAllSessions() called with invalid slotID {}.",
+                slotID
+            );
+        }
+        return rv;
     }
 
     let mut manager = SESSION_MANAGER.lock().unwrap();
@@ -141,9 +150,46 @@ pub extern "C" fn C_CancelFunction(
     cryptoki_sys::CKR_FUNCTION_NOT_PARALLEL
 }
 
+// PKCS#11 v3.0, cryptoki_sys only has the v2.40 function list so it is exported by name.
+// Ends the operations selected by the flags without finishing them.
+#[no_mangle]
+pub extern "C" fn C_SessionCancel(
+    hSession: cryptoki_sys::CK_SESSION_HANDLE,
+    flags: cryptoki_sys::CK_FLAGS,
+) -> cryptoki_sys::CK_RV {
+    trace!("C_SessionCancel() called with flags {}", flags);
+
+    lock_session!(hSession, session);
+
+    if flags & cryptoki_sys::CKF_DIGEST != 0 {
+        session.digest_clear();
+    }
+    if flags & cryptoki_sys::CKF_SIGN != 0 {
+        session.sign_clear();
+    }
+    if flags & cryptoki_sys::CKF_VERIFY != 0 {
+        session.verify_clear();
+    }
+    if flags & cryptoki_sys::CKF_ENCRYPT != 0 {
+        session.encrypt_clear();
+    }
+    if flags & cryptoki_sys::CKF_DECRYPT != 0 {
+        session.decrypt_clear();
+    }
+
+    cryptoki_sys::CKR_OK
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+
+    use cryptoki_sys::{CK_NOTIFICATION, CK_RV, CK_SESSION_HANDLE};
+
+    use crate::api::{generation::C_GenerateRandom, token::C_Logout};
+    use crate::backend::session::CKN_DEVICE_REMOVED;
     use crate::backend::slot::init_for_tests;
+    use crate::config::device::SlotBuilder;
 
     use super::*;
 
@@ -160,6 +206,20 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
     }
 
+    #[test]
+    fn test_open_session_invalid_slot() {
+        init_for_tests();
+        let mut session = 0;
+        let rv = C_OpenSession(
+            999,
+            cryptoki_sys::CKF_SERIAL_SESSION,
+            std::ptr::null_mut(),
+            None,
+            &mut session,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_SLOT_ID_INVALID);
+    }
+
     #[test]
     fn test_open_session_parallel() {
         init_for_tests();
@@ -168,6 +228,175 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_SESSION_PARALLEL_NOT_SUPPORTED);
     }
 
+    #[test]
+    fn test_operations_after_close_session() {
+        use crate::api::{
+            decrypt::{C_DecryptFinal, C_DecryptUpdate},
+            digest::{C_DigestFinal, C_DigestInit, C_DigestUpdate},
+            encrypt::{C_EncryptFinal, C_EncryptUpdate},
+            sign::{C_SignFinal, C_SignUpdate},
+        };
+
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_SHA256,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(C_DigestInit(session, &mut mech), cryptoki_sys::CKR_OK);
+        assert_eq!(C_CloseSession(session), cryptoki_sys::CKR_OK);
+
+        let mut data = [0u8; 16];
+        let mut out = [0u8; 64];
+        let mut out_len = out.len() as cryptoki_sys::CK_ULONG;
+        let data_len = data.len() as cryptoki_sys::CK_ULONG;
+
+        for rv in [
+            C_DigestUpdate(session, data.as_mut_ptr(), data_len),
+            C_DigestFinal(session, out.as_mut_ptr(), &mut out_len),
+            C_SignUpdate(session, data.as_mut_ptr(), data_len),
+            C_SignFinal(session, out.as_mut_ptr(), &mut out_len),
+            C_EncryptUpdate(
+                session,
+                data.as_mut_ptr(),
+                data_len,
+                out.as_mut_ptr(),
+                &mut out_len,
+            ),
+            C_EncryptFinal(session, out.as_mut_ptr(), &mut out_len),
+            C_DecryptUpdate(
+                session,
+                data.as_mut_ptr(),
+                data_len,
+                out.as_mut_ptr(),
+                &mut out_len,
+            ),
+            C_DecryptFinal(session, out.as_mut_ptr(), &mut out_len),
+        ] {
+            assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
+        }
+    }
+
+    static NOTIFIED: Mutex<Vec<(CK_SESSION_HANDLE, CK_NOTIFICATION, usize)>> =
+        Mutex::new(Vec::new());
+
+    // the application data tells the mock whether to accept the notification
+    const APP_ACCEPT: usize = 1;
+    const APP_CANCEL: usize = 2;
+    const APP_REMOVED: usize = 3;
+    // the application calls the module from the callback
+    const APP_REENTRANT: usize = 4;
+
+    unsafe extern "C" fn notify(
+        session: CK_SESSION_HANDLE,
+        event: CK_NOTIFICATION,
+        application: cryptoki_sys::CK_VOID_PTR,
+    ) -> CK_RV {
+        NOTIFIED
+            .lock()
+            .unwrap()
+            .push((session, event, application as usize));
+        match application as usize {
+            APP_CANCEL => cryptoki_sys::CKR_CANCEL,
+            APP_REENTRANT => {
+                let mut info = cryptoki_sys::CK_SESSION_INFO::default();
+                C_GetSessionInfo(session, &mut info)
+            }
+            _ => cryptoki_sys::CKR_OK,
+        }
+    }
+
+    fn notifications(application: usize) -> Vec<(CK_SESSION_HANDLE, CK_NOTIFICATION)> {
+        NOTIFIED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, _, app)| *app == application)
+            .map(|(session, event, _)| (*session, *event))
+            .collect()
+    }
+
+    #[test]
+    fn test_open_session_notify_on_logout() {
+        init_for_tests();
+        let mut session = 0;
+        let rv = C_OpenSession(
+            0,
+            cryptoki_sys::CKF_SERIAL_SESSION,
+            APP_ACCEPT as cryptoki_sys::CK_VOID_PTR,
+            Some(notify),
+            &mut session,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert!(notifications(APP_ACCEPT).is_empty());
+
+        assert_eq!(C_Logout(session), cryptoki_sys::CKR_OK);
+        assert_eq!(
+            notifications(APP_ACCEPT),
+            vec![(session, cryptoki_sys::CKN_SURRENDER)]
+        );
+
+        C_CloseSession(session);
+    }
+
+    #[test]
+    fn test_open_session_notify_cancel() {
+        init_for_tests();
+        let mut session = 0;
+        let rv = C_OpenSession(
+            0,
+            cryptoki_sys::CKF_SERIAL_SESSION,
+            APP_CANCEL as cryptoki_sys::CK_VOID_PTR,
+            Some(notify),
+            &mut session,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        let state = {
+            let manager = SESSION_MANAGER.lock().unwrap();
+            let session = manager.get_session(session).unwrap();
+            let session = session.lock().unwrap();
+            session.get_ck_info().state
+        };
+
+        assert_eq!(C_Logout(session), cryptoki_sys::CKR_FUNCTION_CANCELED);
+        assert_eq!(
+            notifications(APP_CANCEL),
+            vec![(session, cryptoki_sys::CKN_SURRENDER)]
+        );
+
+        // the session didn't leave its state
+        let mut info = cryptoki_sys::CK_SESSION_INFO::default();
+        assert_eq!(C_GetSessionInfo(session, &mut info), cryptoki_sys::CKR_OK);
+        assert_eq!(info.state, state);
+
+        C_CloseSession(session);
+    }
+
+    #[test]
+    fn test_open_session_notify_reentrant() {
+        init_for_tests();
+        let mut session = 0;
+        let rv = C_OpenSession(
+            0,
+            cryptoki_sys::CKF_SERIAL_SESSION,
+            APP_REENTRANT as cryptoki_sys::CK_VOID_PTR,
+            Some(notify),
+            &mut session,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // the session isn't locked while the callback runs
+        assert_eq!(C_Logout(session), cryptoki_sys::CKR_OK);
+        assert_eq!(
+            notifications(APP_REENTRANT),
+            vec![(session, cryptoki_sys::CKN_SURRENDER)]
+        );
+
+        C_CloseSession(session);
+    }
+
     #[test]
     fn test_delete_session_invalid() {
         init_for_tests();
@@ -247,4 +476,52 @@ mod tests {
         let rv = C_CancelFunction(0);
         assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_PARALLEL);
     }
+
+    #[test]
+    fn test_notify_device_removed() {
+        init_for_tests();
+
+        // the NetHSM went away, nothing listens on the port 1
+        let slot = SlotBuilder::new()
+            .url("http://127.0.0.1:1/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+
+        let session = {
+            let mut manager = SESSION_MANAGER.lock().unwrap();
+            let session = manager.create_session(
+                0,
+                std::sync::Arc::new(slot),
+                cryptoki_sys::CKF_SERIAL_SESSION,
+            );
+            manager.get_session(session).unwrap().lock().unwrap().notify = SessionNotify::new(
+                session,
+                Some(notify),
+                APP_REMOVED as cryptoki_sys::CK_VOID_PTR,
+            );
+            session
+        };
+
+        let mut data = [0u8; 16];
+        let rv = C_GenerateRandom(session, data.as_mut_ptr(), data.len() as _);
+        assert_eq!(rv, cryptoki_sys::CKR_DEVICE_REMOVED);
+        assert_eq!(
+            notifications(APP_REMOVED),
+            vec![(session, CKN_DEVICE_REMOVED)]
+        );
+
+        // the session can't be used anymore, the application is only notified once
+        let mut info = cryptoki_sys::CK_SESSION_INFO::default();
+        assert_eq!(
+            C_GetSessionInfo(session, &mut info),
+            cryptoki_sys::CKR_DEVICE_REMOVED
+        );
+        let rv = C_GenerateRandom(session, data.as_mut_ptr(), data.len() as _);
+        assert_eq!(rv, cryptoki_sys::CKR_DEVICE_REMOVED);
+        assert_eq!(notifications(APP_REMOVED).len(), 1);
+
+        assert_eq!(C_CloseSession(session), cryptoki_sys::CKR_OK);
+    }
 }
@@ -2,7 +2,7 @@ use cryptoki_sys::{CKR_OK, CK_ULONG};
 use log::{error, trace};
 
 use crate::{
-    backend::mechanism::{CkRawMechanism, Mechanism},
+    backend::mechanism::{CkRawMechanism, Mechanism, MechanismParams},
     lock_session,
 };
 
@@ -28,7 +28,7 @@ pub extern "C" fn C_SignInit(
         Ok(mech) => mech,
         Err(e) => {
             error!("C_SignInit() failed to convert mechanism: {}", e);
-            return cryptoki_sys::CKR_MECHANISM_INVALID;
+            return e.into();
         }
     };
 
@@ -62,7 +62,7 @@ pub extern "C" fn C_Sign(
 
     let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
 
-    let buffer_size = unsafe { *pulSignatureLen } as usize;
+    let buffer_size = unsafe { *pulSignatureLen };
 
     let theoretical_size = match session.sign_theoretical_size() {
         Ok(size) => size,
@@ -73,7 +73,7 @@ pub extern "C" fn C_Sign(
     };
 
     unsafe {
-        std::ptr::write(pulSignatureLen, theoretical_size as CK_ULONG);
+        std::ptr::write(pulSignatureLen, theoretical_size);
     }
 
     if pSignature.is_null() {
@@ -100,7 +100,7 @@ pub extern "C" fn C_Sign(
 
     // double check the buffer size
 
-    if signature.len() > buffer_size {
+    if signature.len() > buffer_size as usize {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
 
@@ -152,7 +152,7 @@ pub extern "C" fn C_SignFinal(
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    let buffer_size = unsafe { *pulSignatureLen } as usize;
+    let buffer_size = unsafe { *pulSignatureLen };
 
     let theoretical_size = match session.sign_theoretical_size() {
         Ok(size) => size,
@@ -163,7 +163,7 @@ pub extern "C" fn C_SignFinal(
     };
 
     unsafe {
-        std::ptr::write(pulSignatureLen, theoretical_size as CK_ULONG);
+        std::ptr::write(pulSignatureLen, theoretical_size);
     }
 
     if pSignature.is_null() {
@@ -189,7 +189,7 @@ pub extern "C" fn C_SignFinal(
 
     // double check the buffer size
 
-    if signature.len() > buffer_size {
+    if signature.len() > buffer_size as usize {
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
 
@@ -235,12 +235,175 @@ pub extern "C" fn C_SignEncryptUpdate(
     cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
 }
 
+// The message-based functions of PKCS#11 v3.0. cryptoki_sys only has the v2.40 function
+// list, so they are exported by name for the applications that load them directly.
+#[no_mangle]
+pub extern "C" fn C_MessageSignInit(
+    hSession: cryptoki_sys::CK_SESSION_HANDLE,
+    pMechanism: cryptoki_sys::CK_MECHANISM_PTR,
+    hKey: cryptoki_sys::CK_OBJECT_HANDLE,
+) -> cryptoki_sys::CK_RV {
+    trace!("C_MessageSignInit() called with hKey {}", hKey);
+
+    let raw_mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+        Some(mech) => mech,
+        None => {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+    };
+
+    let mech = match Mechanism::from_ckraw_mech(&raw_mech) {
+        Ok(mech) => mech,
+        Err(e) => {
+            error!("C_MessageSignInit() failed to convert mechanism: {}", e);
+            return e.into();
+        }
+    };
+
+    lock_session!(hSession, session);
+
+    match session.message_sign_begin(&mech, hKey) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(e) => e.into(),
+    }
+}
+
+// Signs one message, a failure doesn't end the context started by C_MessageSignInit
+#[no_mangle]
+pub extern "C" fn C_SignMessage(
+    hSession: cryptoki_sys::CK_SESSION_HANDLE,
+    pParameter: cryptoki_sys::CK_VOID_PTR,
+    ulParameterLen: cryptoki_sys::CK_ULONG,
+    pData: cryptoki_sys::CK_BYTE_PTR,
+    ulDataLen: cryptoki_sys::CK_ULONG,
+    pSignature: cryptoki_sys::CK_BYTE_PTR,
+    pulSignatureLen: cryptoki_sys::CK_ULONG_PTR,
+) -> cryptoki_sys::CK_RV {
+    trace!("C_SignMessage() called");
+
+    if pData.is_null() || pulSignatureLen.is_null() {
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    lock_session!(hSession, session);
+
+    let size = match session.message_sign_len() {
+        Ok(size) => size,
+        Err(err) => return err.into(),
+    };
+
+    let buffer_size = unsafe { std::ptr::read(pulSignatureLen) };
+    unsafe {
+        std::ptr::write(pulSignatureLen, size);
+    }
+
+    if pSignature.is_null() {
+        // only the size was requested
+        return cryptoki_sys::CKR_OK;
+    }
+
+    if buffer_size < size {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    let parameter = match session.message_sign_mechanism() {
+        Ok(mechanism) => unsafe {
+            MechanismParams::from_raw_message(mechanism, pParameter, ulParameterLen)
+        },
+        Err(err) => return err.into(),
+    };
+    let parameter = match parameter {
+        Ok(parameter) => parameter,
+        Err(rv) => return rv,
+    };
+    let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
+
+    let signature = match session.message_sign_next(parameter, data) {
+        Ok(signature) => signature,
+        Err(err) => return err.into(),
+    };
+
+    unsafe {
+        std::ptr::write(pulSignatureLen, signature.len() as CK_ULONG);
+    }
+
+    // double check the buffer size
+    if signature.len() > buffer_size as usize {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(signature.as_ptr(), pSignature, signature.len());
+    }
+
+    CKR_OK
+}
+
+#[no_mangle]
+pub extern "C" fn C_MessageSignFinal(
+    hSession: cryptoki_sys::CK_SESSION_HANDLE,
+) -> cryptoki_sys::CK_RV {
+    trace!("C_MessageSignFinal() called");
+
+    lock_session!(hSession, session);
+
+    match session.message_sign_end() {
+        Ok(()) => CKR_OK,
+        Err(err) => err.into(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{backend::slot::init_for_tests, data::SESSION_MANAGER};
+    use std::sync::Arc;
+
+    use nethsm_sdk_rs::models::{KeyMechanism, KeyType};
+
+    use crate::{
+        backend::{db::Object, key::key_size, slot::init_for_tests},
+        config::device::SlotBuilder,
+        data::SESSION_MANAGER,
+    };
 
     use super::*;
 
+    #[test]
+    fn test_sign_message_operation_not_initialized() {
+        init_for_tests();
+        let session_handle = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let mut data = [0u8; 4];
+        let mut signature_len: CK_ULONG = 0;
+
+        let rv = C_SignMessage(
+            session_handle,
+            std::ptr::null_mut(),
+            0,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let rv = C_SignMessage(
+            session_handle,
+            std::ptr::null_mut(),
+            0,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+
+        let rv = C_MessageSignFinal(session_handle);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let rv = C_MessageSignInit(session_handle, std::ptr::null_mut(), 0);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+    }
+
     #[test]
     fn test_sign_init_null_mechanism() {
         init_for_tests();
@@ -354,6 +517,265 @@ mod tests {
         assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
     }
 
+    #[test]
+    fn test_sign_size_query() {
+        init_for_tests();
+
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = Object::default();
+        key.id = "ed".to_string();
+        key.size = key_size(&KeyType::Curve25519);
+        key.mechanisms = vec![KeyMechanism::EdDsaSignature];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_EDDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            C_SignInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        let mut data = [0u8; 32];
+        let mut signature_len = 0;
+        let rv = C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(signature_len, 64);
+
+        let mut signature = [0u8; 32];
+        let mut signature_len = signature.len() as CK_ULONG;
+        let rv = C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(signature_len, 64);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_sign_buffer_too_small_retry() {
+        init_for_tests();
+
+        let (session, slot, _) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "ed".to_string();
+        key.size = key_size(&KeyType::Curve25519);
+        key.mechanisms = vec![KeyMechanism::EdDsaSignature];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_EDDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            C_SignInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        let mut data = [0u8; 32];
+        let mut signature = [0u8; 64];
+        let mut signature_len = 1;
+        let rv = C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(signature_len, 64);
+
+        // the operation is still active, the call is repeated with a big enough buffer
+        let rv = C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(signature_len, 64);
+
+        let rv = C_Sign(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            &mut signature_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_sign_final_buffer_too_small_retry() {
+        init_for_tests();
+
+        let (session, slot, requests) = crate::backend::session::tests::mock_session(0);
+        let mut key = Object::default();
+        key.id = "ed".to_string();
+        key.size = key_size(&KeyType::Curve25519);
+        key.mechanisms = vec![KeyMechanism::EdDsaSignature];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_EDDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            C_SignInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        let mut data = [0u8; 32];
+        assert_eq!(
+            C_SignUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG),
+            cryptoki_sys::CKR_OK
+        );
+
+        let mut signature = [0u8; 64];
+        let mut signature_len = 1;
+        let rv = C_SignFinal(session, signature.as_mut_ptr(), &mut signature_len);
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(signature_len, 64);
+
+        let rv = C_SignFinal(session, signature.as_mut_ptr(), &mut signature_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(signature_len, 64);
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys/ed/sign"),
+            1
+        );
+
+        let rv = C_SignFinal(session, signature.as_mut_ptr(), &mut signature_len);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_sign_init_pss_params() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let init = |mechanism, hash_alg, mgf, salt_len| {
+            let mut params = cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS {
+                hashAlg: hash_alg,
+                mgf,
+                sLen: salt_len,
+            };
+            let mut mechanism = cryptoki_sys::CK_MECHANISM {
+                mechanism,
+                pParameter: &mut params as *mut _ as *mut _,
+                ulParameterLen: std::mem::size_of::<cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS>()
+                    as CK_ULONG,
+            };
+            C_SignInit(session, &mut mechanism, 0)
+        };
+
+        // the parameters used by TLS 1.3 get to the key lookup
+        let pss = cryptoki_sys::CKM_RSA_PKCS_PSS;
+        let sha256 = cryptoki_sys::CKM_SHA256;
+        let mgf1_sha256 = cryptoki_sys::CKG_MGF1_SHA256;
+        assert_eq!(
+            init(pss, sha256, mgf1_sha256, 32),
+            cryptoki_sys::CKR_KEY_HANDLE_INVALID
+        );
+        assert_eq!(
+            init(
+                cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS,
+                sha256,
+                mgf1_sha256,
+                32
+            ),
+            cryptoki_sys::CKR_KEY_HANDLE_INVALID
+        );
+
+        // the NetHSM can only sign with the MGF1 of the hash and a salt as long as the hash
+        assert_eq!(
+            init(pss, sha256, cryptoki_sys::CKG_MGF1_SHA1, 32),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+        assert_eq!(
+            init(pss, sha256, mgf1_sha256, 20),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+        assert_eq!(
+            init(
+                cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS,
+                sha256,
+                mgf1_sha256,
+                32
+            ),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+    }
+
+    #[test]
+    fn test_sign_init_operation_active() {
+        init_for_tests();
+
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = Object::default();
+        key.id = "ed".to_string();
+        key.size = key_size(&KeyType::Curve25519);
+        key.mechanisms = vec![KeyMechanism::EdDsaSignature];
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(key);
+
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut mechanism = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_EDDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        assert_eq!(
+            C_SignInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        assert_eq!(
+            C_SignInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OPERATION_ACTIVE
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
     // #[test]
     // fn test_sign_null_signature() {
     //     init_for_tests();
@@ -1,23 +1,17 @@
-use cryptoki_sys::{
-    CKF_RNG, CKF_TOKEN_INITIALIZED, CKF_USER_PIN_INITIALIZED, CKR_OK, CK_SLOT_ID, CK_SLOT_INFO,
-    CK_TOKEN_INFO, CK_ULONG,
-};
-use log::{debug, error, trace, warn};
+use cryptoki_sys::{CKR_OK, CK_SLOT_ID, CK_SLOT_INFO, CK_ULONG};
+use log::{debug, error, trace};
 use nethsm_sdk_rs::{
     apis::default_api,
     models::{HealthStateData, InfoData, SystemState},
 };
 
 use crate::{
-    backend::{
-        events::fetch_slots_state,
-        login::{LoginCtx, UserMode},
-        slot::get_slot,
-    },
-    data::{DEVICE, EVENTS_MANAGER},
+    backend::{events::fetch_slots_state, login::LoginCtx, slot::get_slot, Error},
+    config::device::Device,
+    data::{initialized_device, EVENTS_MANAGER},
     defs::{DEFAULT_FIRMWARE_VERSION, DEFAULT_HARDWARE_VERSION, MECHANISM_LIST},
-    lock_session,
-    utils::{padded_str, version_struct_from_str},
+    lock_session, read_session,
+    utils::padded_str,
 };
 
 pub extern "C" fn C_GetSlotList(
@@ -31,11 +25,20 @@ pub extern "C" fn C_GetSlotList(
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    let Some(device) = DEVICE.get() else {
+    let Some(device) = initialized_device() else {
         error!("Initialization was not performed or failed");
         return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
     };
 
+    write_slot_list(device, pSlotList, pulCount)
+}
+
+// the slot ids are the index of the slots in the configuration, they don't change while the module is loaded
+fn write_slot_list(
+    device: &Device,
+    pSlotList: cryptoki_sys::CK_SLOT_ID_PTR,
+    pulCount: cryptoki_sys::CK_ULONG_PTR,
+) -> cryptoki_sys::CK_RV {
     let count = device.slots.len() as CK_ULONG;
 
     // only the count is requested
@@ -44,28 +47,21 @@ pub extern "C" fn C_GetSlotList(
             std::ptr::write(pulCount, count);
         }
         return cryptoki_sys::CKR_OK;
-    } else {
-        // check if the buffer is large enough
-        if unsafe { *pulCount } < count {
-            unsafe {
-                std::ptr::write(pulCount, count);
-            }
-            return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
-        }
     }
 
-    // list the ids
+    // check if the buffer is large enough, nothing is written to the list otherwise
+    if unsafe { *pulCount } < count {
+        unsafe {
+            std::ptr::write(pulCount, count);
+        }
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
 
-    let id_list: Vec<CK_SLOT_ID> = device
-        .slots
-        .iter()
-        .enumerate()
-        .map(|(i, client)| i as CK_SLOT_ID)
-        .collect();
+    let id_list: Vec<CK_SLOT_ID> = (0..count).collect();
 
     unsafe {
         std::ptr::copy_nonoverlapping(id_list.as_ptr(), pSlotList, id_list.len());
-        std::ptr::write(pulCount, count as CK_ULONG);
+        std::ptr::write(pulCount, count);
     }
 
     cryptoki_sys::CKR_OK
@@ -166,65 +162,9 @@ pub extern "C" fn C_GetTokenInfo(
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    let mut login_ctx = LoginCtx::new(
-        None,
-        slot.administrator.clone(),
-        slot.instances.clone(),
-        slot.retries,
-    );
-
-    let result = login_ctx.try_(
-        default_api::info_get,
-        crate::backend::login::UserMode::Guest,
-    );
-
-    // fetch info from the device
-
-    let info = match result {
+    let token_info = match slot.refresh_token_info() {
         Ok(info) => info,
-        Err(e) => {
-            error!("Error getting info: {:?}", e);
-            return cryptoki_sys::CKR_FUNCTION_FAILED;
-        }
-    };
-
-    let mut serial_number = "unknown".to_string();
-    let mut hardware_version = DEFAULT_HARDWARE_VERSION;
-    let mut firmware_version = DEFAULT_FIRMWARE_VERSION;
-
-    // Try to fech system info
-
-    if login_ctx.can_run_mode(crate::backend::login::UserMode::Administrator) {
-        match login_ctx.try_(default_api::system_info_get, UserMode::Administrator) {
-            Err(e) => {
-                warn!("Error getting system info: {:?}", e);
-            }
-            Ok(system_info) => {
-                serial_number = system_info.entity.device_id;
-                hardware_version = version_struct_from_str(system_info.entity.hardware_version);
-                // The PKCS11 firmware version actually corresponds to the NetHSM software version
-                firmware_version = version_struct_from_str(system_info.entity.software_version);
-            }
-        }
-    }
-
-    let mut flags = CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED | CKF_RNG;
-
-    // if the slot has no password, set the login required flag
-    if !slot.is_connected() {
-        flags |= cryptoki_sys::CKF_LOGIN_REQUIRED;
-        debug!("Login required");
-    }
-
-    let token_info = CK_TOKEN_INFO {
-        label: padded_str(&slot.label),
-        manufacturerID: padded_str(&info.entity.vendor),
-        model: padded_str(&info.entity.product),
-        serialNumber: padded_str(&serial_number),
-        flags,
-        hardwareVersion: hardware_version,
-        firmwareVersion: firmware_version,
-        ..Default::default()
+        Err(rv) => return rv,
     };
 
     unsafe {
@@ -256,11 +196,20 @@ pub extern "C" fn C_GetMechanismList(
         return cryptoki_sys::CKR_ARGUMENTS_BAD;
     }
 
-    if let Err(e) = get_slot(slotID as usize) {
-        return e;
-    }
+    let slot = match get_slot(slotID as usize) {
+        Ok(slot) => slot,
+        Err(e) => return e,
+    };
+
+    write_mechanism_list(slot.mechanism_list(), pMechanismList, pulCount)
+}
 
-    let count = MECHANISM_LIST.len() as CK_ULONG;
+fn write_mechanism_list(
+    mechanisms: &[cryptoki_sys::CK_MECHANISM_TYPE],
+    pMechanismList: cryptoki_sys::CK_MECHANISM_TYPE_PTR,
+    pulCount: cryptoki_sys::CK_ULONG_PTR,
+) -> cryptoki_sys::CK_RV {
+    let count = mechanisms.len() as CK_ULONG;
 
     // only the count is requested
     if pMechanismList.is_null() {
@@ -281,15 +230,8 @@ pub extern "C" fn C_GetMechanismList(
         return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
     }
 
-    // list the ids
-
-    let id_list: Vec<cryptoki_sys::CK_MECHANISM_TYPE> = MECHANISM_LIST
-        .iter()
-        .map(|mechanism| mechanism.ck_type())
-        .collect();
-
     unsafe {
-        std::ptr::copy_nonoverlapping(id_list.as_ptr(), pMechanismList, id_list.len());
+        std::ptr::copy_nonoverlapping(mechanisms.as_ptr(), pMechanismList, mechanisms.len());
     }
 
     CKR_OK
@@ -332,11 +274,15 @@ pub extern "C" fn C_Login(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_Login() called");
 
-    if pPin.is_null() {
-        return cryptoki_sys::CKR_ARGUMENTS_BAD;
-    }
-
-    let pin = unsafe { std::slice::from_raw_parts(pPin, ulPinLen as usize) };
+    // a null PIN is only valid with a length of 0
+    let pin = if pPin.is_null() {
+        if ulPinLen > 0 {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(pPin, ulPinLen as usize) }
+    };
 
     // parse string to utf8
 
@@ -355,6 +301,22 @@ pub extern "C" fn C_Login(
 pub extern "C" fn C_Logout(hSession: cryptoki_sys::CK_SESSION_HANDLE) -> cryptoki_sys::CK_RV {
     trace!("C_Logout() called");
 
+    // the application is asked before the session leaves the user state, the callback can call
+    // the module so the session isn't locked meanwhile
+    let notify = {
+        read_session!(hSession, session);
+        session.notify
+    };
+    if let Some(notify) = notify {
+        let rv = notify.notify(cryptoki_sys::CKN_SURRENDER);
+        if rv != CKR_OK {
+            debug!("The application refused to surrender the session: {}", rv);
+            lock_session!(hSession, session);
+            session.abort_operations();
+            return Error::FunctionCanceled.into();
+        }
+    }
+
     lock_session!(hSession, session);
 
     match session.logout() {
@@ -415,14 +377,18 @@ pub extern "C" fn C_WaitForSlotEvent(
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use cryptoki_sys::{CKF_DONT_BLOCK, CKU_USER, CK_MECHANISM_INFO};
 
     use crate::{
         api::C_Finalize,
         backend::{
             events::{update_slot_state, EventsManager},
+            session::tests::mock_nethsm,
             slot::init_for_tests,
         },
+        config::device::SlotBuilder,
         data::{SESSION_MANAGER, TOKENS_STATE},
     };
 
@@ -542,6 +508,59 @@ mod tests {
         assert_eq!(count, 1);
     }
 
+    fn device_with_slots(count: usize) -> Device {
+        Device {
+            log_file: None,
+            slots: (0..count)
+                .map(|i| {
+                    Arc::new(
+                        SlotBuilder::new()
+                            .label(&format!("slot{}", i))
+                            .build()
+                            .unwrap(),
+                    )
+                })
+                .collect(),
+            enable_set_attribute_value: false,
+        }
+    }
+
+    #[test]
+    fn test_write_slot_list() {
+        for slots in [1, 3] {
+            let device = device_with_slots(slots);
+
+            let mut count = 0;
+            let result = write_slot_list(&device, std::ptr::null_mut(), &mut count);
+            assert_eq!(result, cryptoki_sys::CKR_OK);
+            assert_eq!(count, slots as CK_ULONG);
+
+            // the ids are the same on every call
+            for _ in 0..2 {
+                let mut list = vec![99; slots + 1];
+                let mut count = list.len() as CK_ULONG;
+                let result = write_slot_list(&device, list.as_mut_ptr(), &mut count);
+                assert_eq!(result, cryptoki_sys::CKR_OK);
+                assert_eq!(count, slots as CK_ULONG);
+                let mut expected: Vec<CK_SLOT_ID> = (0..slots as CK_SLOT_ID).collect();
+                expected.push(99);
+                assert_eq!(list, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_slot_list_small_buffer() {
+        let device = device_with_slots(3);
+
+        let mut list = [99; 3];
+        let mut count = 2;
+        let result = write_slot_list(&device, list.as_mut_ptr(), &mut count);
+        assert_eq!(result, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        assert_eq!(count, 3);
+        assert_eq!(list, [99; 3]);
+    }
+
     #[test]
     fn test_get_slot_info_invalid_slot() {
         init_for_tests();
@@ -588,6 +607,24 @@ mod tests {
         assert_eq!(count, MECHANISM_LIST.len() as CK_ULONG);
     }
 
+    #[test]
+    fn test_get_mechanism_list_exact_buffer() {
+        init_for_tests();
+
+        let mut first = vec![0; MECHANISM_LIST.len()];
+        let mut count = first.len() as CK_ULONG;
+        let result = C_GetMechanismList(0, first.as_mut_ptr(), &mut count);
+        assert_eq!(result, cryptoki_sys::CKR_OK);
+        assert_eq!(count, MECHANISM_LIST.len() as CK_ULONG);
+        assert_eq!(first[0], MECHANISM_LIST[0].ck_type());
+
+        // the list is in the same order on every call
+        let mut second = vec![0; MECHANISM_LIST.len()];
+        let result = C_GetMechanismList(0, second.as_mut_ptr(), &mut count);
+        assert_eq!(result, cryptoki_sys::CKR_OK);
+        assert_eq!(first, second);
+    }
+
     #[test]
     fn test_get_mechanism_list_invalid_slot() {
         init_for_tests();
@@ -607,6 +644,19 @@ mod tests {
         assert_eq!(result, cryptoki_sys::CKR_MECHANISM_INVALID);
     }
 
+    #[test]
+    fn test_get_mechanism_info_rsa_pss() {
+        init_for_tests();
+
+        let mut info = CK_MECHANISM_INFO::default();
+        let result = C_GetMechanismInfo(0, cryptoki_sys::CKM_RSA_PKCS_PSS, &mut info);
+        assert_eq!(result, cryptoki_sys::CKR_OK);
+        assert_ne!(info.flags & cryptoki_sys::CKF_SIGN, 0);
+        assert_ne!(info.flags & cryptoki_sys::CKF_VERIFY, 0);
+        assert_eq!(info.ulMinKeySize, 1024);
+        assert_eq!(info.ulMaxKeySize, 8192);
+    }
+
     #[test]
     fn test_get_mechanism_info_invalid_slot() {
         init_for_tests();
@@ -628,7 +678,7 @@ mod tests {
     fn test_get_token_info_invalid_slot() {
         init_for_tests();
 
-        let mut info = CK_TOKEN_INFO::default();
+        let mut info = cryptoki_sys::CK_TOKEN_INFO::default();
         let result = C_GetTokenInfo(99, &mut info);
         assert_eq!(result, cryptoki_sys::CKR_SLOT_ID_INVALID);
     }
@@ -646,10 +696,39 @@ mod tests {
         init_for_tests();
         let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
 
-        let result = C_Login(session, CKU_USER, std::ptr::null_mut(), 0);
+        let result = C_Login(session, CKU_USER, std::ptr::null_mut(), 4);
         assert_eq!(result, cryptoki_sys::CKR_ARGUMENTS_BAD);
     }
 
+    #[test]
+    fn test_login_pin_len_range() {
+        init_for_tests();
+        let (url, _) = mock_nethsm(0);
+        let slot = SlotBuilder::new()
+            .url(&url)
+            .operator_username("operator")
+            .pin_len(4, 8)
+            .build()
+            .unwrap();
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut pin = "123456789".to_string();
+        let result = C_Login(session, CKU_USER, pin.as_mut_ptr(), 3);
+        assert_eq!(result, cryptoki_sys::CKR_PIN_LEN_RANGE);
+        let result = C_Login(session, CKU_USER, pin.as_mut_ptr(), 9);
+        assert_eq!(result, cryptoki_sys::CKR_PIN_LEN_RANGE);
+        // an empty PIN is checked like the others
+        let result = C_Login(session, CKU_USER, std::ptr::null_mut(), 0);
+        assert_eq!(result, cryptoki_sys::CKR_PIN_LEN_RANGE);
+
+        let result = C_Login(session, CKU_USER, pin.as_mut_ptr(), 4);
+        assert_eq!(result, cryptoki_sys::CKR_OK);
+    }
+
     #[test]
     fn test_login_non_utf8_pin() {
         init_for_tests();
@@ -1,8 +1,17 @@
 /*
-    We won't implement these function as it is not a feature of NetHSM.
+    The NetHSM can't verify signatures, only HMACs are verified, in software by the module.
+    For RSA and ECDSA the comparison could only be done by the NetHSM, which has no such
+    feature, so these mechanisms are rejected. C_VerifyRecover is the exception: with
+    CKM_RSA_PKCS the public key operation is done by the module.
 */
 
-use log::trace;
+use cryptoki_sys::CK_ULONG;
+use log::{error, trace};
+
+use crate::{
+    backend::mechanism::{CkRawMechanism, Mechanism},
+    lock_session,
+};
 
 pub extern "C" fn C_VerifyInit(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
@@ -11,9 +20,31 @@ pub extern "C" fn C_VerifyInit(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_VerifyInit() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    let raw_mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+        Some(mech) => mech,
+        None => {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+    };
+
+    let mech = match Mechanism::from_ckraw_mech(&raw_mech) {
+        Ok(mech) => mech,
+        Err(e) => {
+            error!("C_VerifyInit() failed to convert mechanism: {}", e);
+            return e.into();
+        }
+    };
+
+    lock_session!(hSession, session);
+
+    match session.verify_init(&mech, hKey) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(e) => e.into(),
+    }
 }
 
+// The MAC is computed over all the data before being compared in constant time,
+// CKR_OK is only returned once the whole comparison is done.
 pub extern "C" fn C_Verify(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
     pData: cryptoki_sys::CK_BYTE_PTR,
@@ -23,9 +54,28 @@ pub extern "C" fn C_Verify(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_Verify() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    if pData.is_null() || pSignature.is_null() {
+        session.verify_clear();
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
+    let signature = unsafe { std::slice::from_raw_parts(pSignature, ulSignatureLen as usize) };
+
+    let rv = match session.verify(data, signature) {
+        Ok(()) => cryptoki_sys::CKR_OK,
+        Err(err) => err.into(),
+    };
+
+    // the operation is terminated whatever the result
+    session.verify_clear();
+
+    rv
 }
 
+// The data is only fed to the running hash of the HMAC, it isn't kept by the module
 pub extern "C" fn C_VerifyUpdate(
     hSession: cryptoki_sys::CK_SESSION_HANDLE,
     pPart: cryptoki_sys::CK_BYTE_PTR,
@@ -33,7 +83,26 @@ pub extern "C" fn C_VerifyUpdate(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_VerifyUpdate() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    if pPart.is_null() && ulPartLen != 0 {
+        session.verify_clear();
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let part = if ulPartLen == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(pPart, ulPartLen as usize) }
+    };
+
+    match session.verify_update(part) {
+        Ok(()) => cryptoki_sys::CKR_OK,
+        Err(err) => {
+            session.verify_clear();
+            err.into()
+        }
+    }
 }
 
 pub extern "C" fn C_VerifyFinal(
@@ -43,7 +112,24 @@ pub extern "C" fn C_VerifyFinal(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_VerifyFinal() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    if pSignature.is_null() {
+        session.verify_clear();
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let signature = unsafe { std::slice::from_raw_parts(pSignature, ulSignatureLen as usize) };
+
+    let rv = match session.verify_final(signature) {
+        Ok(()) => cryptoki_sys::CKR_OK,
+        Err(err) => err.into(),
+    };
+
+    // the operation is terminated whatever the result
+    session.verify_clear();
+
+    rv
 }
 
 pub extern "C" fn C_VerifyRecoverInit(
@@ -53,7 +139,27 @@ pub extern "C" fn C_VerifyRecoverInit(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_VerifyRecoverInit() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    let raw_mech = match unsafe { CkRawMechanism::from_raw_ptr(pMechanism) } {
+        Some(mech) => mech,
+        None => {
+            return cryptoki_sys::CKR_ARGUMENTS_BAD;
+        }
+    };
+
+    let mech = match Mechanism::from_ckraw_mech(&raw_mech) {
+        Ok(mech) => mech,
+        Err(e) => {
+            error!("C_VerifyRecoverInit() failed to convert mechanism: {}", e);
+            return e.into();
+        }
+    };
+
+    lock_session!(hSession, session);
+
+    match session.verify_recover_init(&mech, hKey) {
+        Ok(_) => cryptoki_sys::CKR_OK,
+        Err(e) => e.into(),
+    }
 }
 
 pub extern "C" fn C_VerifyRecover(
@@ -65,61 +171,320 @@ pub extern "C" fn C_VerifyRecover(
 ) -> cryptoki_sys::CK_RV {
     trace!("C_VerifyRecover() called");
 
-    cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED
+    lock_session!(hSession, session);
+
+    if pSignature.is_null() || pulDataLen.is_null() {
+        session.verify_recover_clear();
+        return cryptoki_sys::CKR_ARGUMENTS_BAD;
+    }
+
+    let signature = unsafe { std::slice::from_raw_parts(pSignature, ulSignatureLen as usize) };
+
+    let data = match session.verify_recover(signature) {
+        Ok(data) => data,
+        Err(err) => {
+            session.verify_recover_clear();
+            return err.into();
+        }
+    };
+
+    let buffer_len = unsafe { std::ptr::read(pulDataLen) } as usize;
+    unsafe {
+        std::ptr::write(pulDataLen, data.len() as CK_ULONG);
+    }
+
+    // the length is asked first, the operation goes on
+    if pData.is_null() {
+        return cryptoki_sys::CKR_OK;
+    }
+    if buffer_len < data.len() {
+        return cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), pData, data.len());
+    }
+    session.verify_recover_clear();
+
+    cryptoki_sys::CKR_OK
 }
 
-// just test that the functions return CKR_FUNCTION_NOT_SUPPORTED
 #[cfg(test)]
 mod tests {
-    use cryptoki_sys::CK_ULONG;
+    use std::sync::Arc;
+
+    use cryptoki_sys::{CK_OBJECT_HANDLE, CK_SESSION_HANDLE};
 
-    use crate::backend::slot::init_for_tests;
+    use crate::{
+        backend::{
+            db::{
+                object::{from_session_secret, Attribute, ObjectKind},
+                Object,
+            },
+            slot::init_for_tests,
+        },
+        config::device::SlotBuilder,
+        data::SESSION_MANAGER,
+    };
 
     use super::*;
 
+    // RFC 4231 test case 1
+    const HMAC_KEY: [u8; 20] = [0x0b; 20];
+    const HMAC_DATA: &[u8] = b"Hi There";
+    const HMAC_SHA256: [u8; 32] = [
+        0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b, 0xf1,
+        0x2b, 0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c, 0x2e, 0x32,
+        0xcf, 0xf7,
+    ];
+
+    // a logged in session with a generic secret
+    fn hmac_session() -> (CK_SESSION_HANDLE, CK_OBJECT_HANDLE) {
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let (key_handle, _) = slot.db.lock().unwrap().add_object(from_session_secret(
+            "hmac",
+            None,
+            HMAC_KEY.to_vec(),
+        ));
+
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        (session, key_handle)
+    }
+
+    fn hmac_mechanism() -> cryptoki_sys::CK_MECHANISM {
+        cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_SHA256_HMAC,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        }
+    }
+
+    #[test]
+    fn test_verify_init_null_mechanism() {
+        init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+        let rv = C_VerifyInit(session, std::ptr::null_mut(), 0);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+    }
+
     #[test]
-    fn test_verify_init() {
+    fn test_verify_init_pss_params() {
         init_for_tests();
-        let rv = C_VerifyInit(0, std::ptr::null_mut(), 0);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+
+        let init = |salt_len| {
+            let mut params = cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS {
+                hashAlg: cryptoki_sys::CKM_SHA256,
+                mgf: cryptoki_sys::CKG_MGF1_SHA256,
+                sLen: salt_len,
+            };
+            let mut mechanism = cryptoki_sys::CK_MECHANISM {
+                mechanism: cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS,
+                pParameter: &mut params as *mut _ as *mut _,
+                ulParameterLen: std::mem::size_of::<cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS>()
+                    as CK_ULONG,
+            };
+            C_VerifyInit(session, &mut mechanism, 0)
+        };
+
+        // the same values as C_SignInit
+        assert_eq!(init(32), cryptoki_sys::CKR_KEY_HANDLE_INVALID);
+        assert_eq!(init(20), cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
     }
 
     #[test]
-    fn test_verify() {
+    fn test_verify_not_initialized() {
         init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
         let mut data = [0u8; 1];
         let mut sig = [0u8; 1];
         let rv = C_Verify(
-            0,
+            session,
             data.as_mut_ptr(),
             data.len() as CK_ULONG,
             sig.as_mut_ptr(),
             sig.len() as CK_ULONG,
         );
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
     }
 
     #[test]
-    fn test_verify_update() {
+    fn test_verify_hmac() {
         init_for_tests();
-        let mut data = [0u8; 1];
-        let rv = C_VerifyUpdate(0, data.as_mut_ptr(), data.len() as CK_ULONG);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let (session, key_handle) = hmac_session();
+
+        let mut data = HMAC_DATA.to_vec();
+        let mut signatures = vec![(HMAC_SHA256, cryptoki_sys::CKR_OK)];
+        // one bit flipped at the start and at the end of the MAC
+        for i in [0, HMAC_SHA256.len() - 1] {
+            let mut flipped = HMAC_SHA256;
+            flipped[i] ^= 1;
+            signatures.push((flipped, cryptoki_sys::CKR_SIGNATURE_INVALID));
+        }
+
+        for (mut signature, expected) in signatures {
+            assert_eq!(
+                C_VerifyInit(session, &mut hmac_mechanism(), key_handle),
+                cryptoki_sys::CKR_OK
+            );
+            let rv = C_Verify(
+                session,
+                data.as_mut_ptr(),
+                data.len() as CK_ULONG,
+                signature.as_mut_ptr(),
+                signature.len() as CK_ULONG,
+            );
+            assert_eq!(rv, expected);
+        }
+
+        // the operation is terminated after C_Verify
+        let mut signature = HMAC_SHA256;
+        let rv = C_Verify(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_verify_init_operation_active() {
+        init_for_tests();
+        let (session, key_handle) = hmac_session();
+
+        assert_eq!(
+            C_VerifyInit(session, &mut hmac_mechanism(), key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        assert_eq!(
+            C_VerifyInit(session, &mut hmac_mechanism(), key_handle),
+            cryptoki_sys::CKR_OPERATION_ACTIVE
+        );
+
+        // C_VerifyFinal terminates the operation, even when the signature is invalid
+        let mut signature = [0u8; 32];
+        let rv = C_VerifyFinal(session, signature.as_mut_ptr(), signature.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_SIGNATURE_INVALID);
+        assert_eq!(
+            C_VerifyInit(session, &mut hmac_mechanism(), key_handle),
+            cryptoki_sys::CKR_OK
+        );
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
+    }
+
+    #[test]
+    fn test_verify_hmac_signature_len() {
+        init_for_tests();
+        let (session, key_handle) = hmac_session();
+
+        assert_eq!(
+            C_VerifyInit(session, &mut hmac_mechanism(), key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        let mut data = HMAC_DATA.to_vec();
+        let mut signature = HMAC_SHA256[..16].to_vec();
+        let rv = C_Verify(
+            session,
+            data.as_mut_ptr(),
+            data.len() as CK_ULONG,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_SIGNATURE_LEN_RANGE);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 
     #[test]
-    fn test_verify_final() {
+    fn test_verify_update_not_initialized() {
         init_for_tests();
+        let session = SESSION_MANAGER.lock().unwrap().setup_dummy_session();
+        let mut data = [0u8; 1];
+        let rv = C_VerifyUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
         let mut sig = [0u8; 1];
-        let rv = C_VerifyFinal(0, sig.as_mut_ptr(), sig.len() as CK_ULONG);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        let rv = C_VerifyFinal(session, sig.as_mut_ptr(), sig.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+    }
+
+    #[test]
+    fn test_verify_multi_part() {
+        init_for_tests();
+        let (session, key_handle) = hmac_session();
+
+        let mut mechanism = hmac_mechanism();
+        assert_eq!(
+            C_VerifyInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        for chunk in HMAC_DATA.chunks(3) {
+            let mut chunk = chunk.to_vec();
+            let rv = C_VerifyUpdate(session, chunk.as_mut_ptr(), chunk.len() as CK_ULONG);
+            assert_eq!(rv, cryptoki_sys::CKR_OK);
+        }
+        // an empty part is allowed
+        assert_eq!(
+            C_VerifyUpdate(session, std::ptr::null_mut(), 0),
+            cryptoki_sys::CKR_OK
+        );
+        let mut mac = HMAC_SHA256;
+        let rv = C_VerifyFinal(session, mac.as_mut_ptr(), mac.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // the operation is over
+        let rv = C_VerifyFinal(session, mac.as_mut_ptr(), mac.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        assert_eq!(
+            C_VerifyInit(session, &mut mechanism, key_handle),
+            cryptoki_sys::CKR_OK
+        );
+        let mut data = HMAC_DATA.to_vec();
+        let rv = C_VerifyUpdate(session, data.as_mut_ptr(), data.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        mac[0] ^= 1;
+        let rv = C_VerifyFinal(session, mac.as_mut_ptr(), mac.len() as CK_ULONG);
+        assert_eq!(rv, cryptoki_sys::CKR_SIGNATURE_INVALID);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 
     #[test]
     fn test_verify_recover_init() {
         init_for_tests();
         let rv = C_VerifyRecoverInit(0, std::ptr::null_mut(), 0);
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_ARGUMENTS_BAD);
+
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(SlotBuilder::new().build().unwrap()),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_RSA_PKCS,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let rv = C_VerifyRecoverInit(session, &mut mech, 0);
+        assert_eq!(rv, cryptoki_sys::CKR_KEY_HANDLE_INVALID);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 
     #[test]
@@ -128,6 +493,7 @@ mod tests {
         let mut sig = [0u8; 1];
         let mut data = [0u8; 1];
         let mut data_len = 0;
+        SESSION_MANAGER.lock().unwrap().delete_session(0);
         let rv = C_VerifyRecover(
             0,
             sig.as_mut_ptr(),
@@ -135,6 +501,118 @@ mod tests {
             data.as_mut_ptr(),
             &mut data_len,
         );
-        assert_eq!(rv, cryptoki_sys::CKR_FUNCTION_NOT_SUPPORTED);
+        assert_eq!(rv, cryptoki_sys::CKR_SESSION_HANDLE_INVALID);
+    }
+
+    #[test]
+    fn test_verify_recover_rsa_pkcs() {
+        init_for_tests();
+        // RSA-1024 key and PKCS#1 v1.5 signature of "hello PKCS#1", made with OpenSSL
+        let modulus = hex_literal::hex!(
+            "eb58dd1872efca047db74865f2b7dab46cffeb6ae02da93b919979bcada4ea87"
+            "77aeae2a8c5e2378c4e8064d379c1b63f1b0308c64a1567043b640f0df6ba3b9"
+            "c1c82755cd45569d085ac8481a360fd71d094d325a94cc1fbb67f8e244f11a57"
+            "73a2c7b4f647380ac52a6ed9136a3aa6a629563c4c1d46518fe2f01ef7ad4d7d"
+        );
+        let mut signature = hex_literal::hex!(
+            "7ae9785e3c2a52fe290cc8651e5aaf9745d95e65eaff33cfe1650fc9143975ce"
+            "d2c4b965a1d3ed78a49d39b5ea00a1a742132b0688813caeaf8781336af9c11e"
+            "49b44c2395be08856433dccaccb74897ad4d892804d906b97b5e6104f06d0564"
+            "648bd4d9ebdc2574c81c9079252edab8baee5705f35553e7a51a71f98527b693"
+        );
+        let slot = SlotBuilder::new().build().unwrap();
+        let key = {
+            let mut key = Object::default();
+            key.kind = ObjectKind::PublicKey;
+            key.set_attr(
+                cryptoki_sys::CKA_KEY_TYPE,
+                Attribute::Ulong(cryptoki_sys::CKK_RSA),
+            );
+            key.set_attr(
+                cryptoki_sys::CKA_MODULUS,
+                Attribute::Bytes(modulus.to_vec()),
+            );
+            key.set_attr(
+                cryptoki_sys::CKA_PUBLIC_EXPONENT,
+                Attribute::Bytes(vec![0x01, 0x00, 0x01]),
+            );
+            slot.db.lock().unwrap().add_object(key).0
+        };
+        let session = SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            Arc::new(slot),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+
+        let mut mech = cryptoki_sys::CK_MECHANISM {
+            mechanism: cryptoki_sys::CKM_ECDSA,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let rv = C_VerifyRecoverInit(session, &mut mech, key);
+        assert_eq!(rv, cryptoki_sys::CKR_KEY_TYPE_INCONSISTENT);
+
+        mech.mechanism = cryptoki_sys::CKM_SHA256_RSA_PKCS;
+        let rv = C_VerifyRecoverInit(session, &mut mech, key);
+        assert_eq!(rv, cryptoki_sys::CKR_MECHANISM_INVALID);
+
+        mech.mechanism = cryptoki_sys::CKM_RSA_PKCS;
+        let rv = C_VerifyRecoverInit(session, &mut mech, key);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+
+        // the length first, then a buffer too small, then the data
+        let mut data_len = 0;
+        let rv = C_VerifyRecover(
+            session,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+            std::ptr::null_mut(),
+            &mut data_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(data_len, 12);
+        let mut data = [0u8; 12];
+        let mut small_len = 4;
+        let rv = C_VerifyRecover(
+            session,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+            data.as_mut_ptr(),
+            &mut small_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+        let rv = C_VerifyRecover(
+            session,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+            data.as_mut_ptr(),
+            &mut data_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        assert_eq!(&data, b"hello PKCS#1");
+
+        // the operation is over
+        let rv = C_VerifyRecover(
+            session,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+            data.as_mut_ptr(),
+            &mut data_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_OPERATION_NOT_INITIALIZED);
+
+        let rv = C_VerifyRecoverInit(session, &mut mech, key);
+        assert_eq!(rv, cryptoki_sys::CKR_OK);
+        signature[0] ^= 1;
+        let rv = C_VerifyRecover(
+            session,
+            signature.as_mut_ptr(),
+            signature.len() as CK_ULONG,
+            data.as_mut_ptr(),
+            &mut data_len,
+        );
+        assert_eq!(rv, cryptoki_sys::CKR_SIGNATURE_INVALID);
+
+        SESSION_MANAGER.lock().unwrap().delete_session(session);
     }
 }
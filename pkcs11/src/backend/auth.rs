@@ -0,0 +1,158 @@
+/*
+    The credentials of a user of the NetHSM. The ones from the configuration are shared by the slot
+    with all its sessions, a change of the PIN by one session is seen by the others.
+*/
+
+use std::sync::{Arc, RwLock};
+
+use nethsm_sdk_rs::apis::configuration::Configuration;
+use zeroize::Zeroizing;
+
+use crate::config::config_file::UserConfig;
+
+pub type SharedCredentials = Arc<RwLock<Credentials>>;
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    // empty when the user has to call C_Login
+    pub password: Zeroizing<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl Credentials {
+    pub fn new(username: String, password: Zeroizing<String>) -> Self {
+        Self { username, password }
+    }
+
+    pub fn from_config(user: &UserConfig) -> Self {
+        Self::new(
+            user.username.clone(),
+            Zeroizing::new(user.password.clone().unwrap_or_default()),
+        )
+    }
+
+    pub fn shared(self) -> SharedCredentials {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn has_password(&self) -> bool {
+        !self.password.is_empty()
+    }
+
+    // the configuration of the instance with the basic auth of the user
+    pub fn apply_to_config(&self, config: &Configuration) -> Configuration {
+        let password = self.has_password().then(|| self.password.to_string());
+        Configuration {
+            basic_auth: Some((self.username.clone(), password)),
+            ..config.clone()
+        }
+    }
+
+    // replaces the password for all the holders of the credentials, the old one is zeroized
+    pub fn rotate(credentials: &RwLock<Credentials>, new_password: Zeroizing<String>) {
+        let mut credentials = match credentials.write() {
+            Ok(credentials) => credentials,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        credentials.password = new_password;
+    }
+}
+
+// reads the credentials even if a writer panicked, the password is replaced in one assignment
+pub fn read_credentials(credentials: &RwLock<Credentials>) -> Credentials {
+    match credentials.read() {
+        Ok(credentials) => credentials.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_from_config() {
+        let credentials = Credentials::from_config(&UserConfig {
+            username: "operator".to_string(),
+            password: Some("password".to_string()),
+        });
+        assert_eq!(credentials.username, "operator");
+        assert_eq!(credentials.password.as_str(), "password");
+
+        let credentials = Credentials::from_config(&UserConfig {
+            username: "operator".to_string(),
+            password: None,
+        });
+        assert!(!credentials.has_password());
+    }
+
+    #[test]
+    fn test_apply_to_config() {
+        let config = Configuration {
+            base_path: "https://localhost:8443/api/v1".to_string(),
+            ..Default::default()
+        };
+
+        let credentials = Credentials::new("admin".to_string(), Zeroizing::new("p4ss".into()));
+        let applied = credentials.apply_to_config(&config);
+        assert_eq!(applied.base_path, config.base_path);
+        assert_eq!(
+            applied.basic_auth,
+            Some(("admin".to_string(), Some("p4ss".to_string())))
+        );
+
+        let credentials = Credentials::new("admin".to_string(), Zeroizing::new(String::new()));
+        assert_eq!(
+            credentials.apply_to_config(&config).basic_auth,
+            Some(("admin".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn test_debug_hides_password() {
+        let credentials = Credentials::new("admin".to_string(), Zeroizing::new("p4ss".into()));
+        assert!(!format!("{:?}", credentials).contains("p4ss"));
+    }
+
+    #[test]
+    fn test_rotate_concurrent_reads() {
+        let shared = Credentials::new("operator".to_string(), Zeroizing::new("0".into())).shared();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..1000 {
+                        let credentials = read_credentials(&shared);
+                        assert_eq!(credentials.username, "operator");
+                        // a reader sees one of the passwords, never an older one than before
+                        let password: u32 = credentials.password.parse().unwrap();
+                        assert!(password >= last);
+                        last = password;
+                    }
+                })
+            })
+            .collect();
+
+        for i in 1..=100 {
+            Credentials::rotate(&shared, Zeroizing::new(i.to_string()));
+        }
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(read_credentials(&shared).password.as_str(), "100");
+    }
+}
@@ -0,0 +1,229 @@
+// AES-CMAC (RFC 4493) for CKM_AES_CMAC. The NetHSM has no MAC endpoint: with the keys stored on
+// it, the CBC encryption with a zero IV is done by the NetHSM and the module only adds the
+// subkeys. When the value of an AES key is known to the module, the cmac crate computes the MAC.
+
+use aes::{Aes128, Aes192, Aes256};
+use cmac::{Cmac, Mac};
+use zeroize::Zeroize;
+
+use super::{
+    db::{object::Attribute, Object},
+    encrypt::encrypt_data,
+    login::LoginCtx,
+    mechanism::Mechanism,
+    Error,
+};
+use cryptoki_sys::{CKA_KEY_TYPE, CKA_VALUE, CKK_AES};
+use log::debug;
+use nethsm_sdk_rs::models::KeyMechanism;
+
+pub const BLOCK_SIZE: usize = 16;
+
+// the constant of the subkey generation, for a block of 128 bits
+const RB: u8 = 0x87;
+
+#[derive(Clone, Debug)]
+pub enum CmacKey {
+    // the value of an AES key known to the module, 16, 24 or 32 bytes
+    Value(Vec<u8>),
+    // an AES key stored on the NetHSM
+    NetHsm { id: String, login_ctx: LoginCtx },
+}
+
+impl CmacKey {
+    pub fn from_object(key: &Object, login_ctx: LoginCtx) -> Result<Self, Error> {
+        // the generic secrets of the module are HMAC keys, only AES keys are used in software
+        match (
+            key.get_attribute(CKA_KEY_TYPE),
+            key.get_attribute(CKA_VALUE),
+        ) {
+            (Some(Attribute::Ulong(CKK_AES)), Some(Attribute::Bytes(value)))
+                if matches!(value.len(), 16 | 24 | 32) =>
+            {
+                Ok(Self::Value(value.clone()))
+            }
+            _ if key.mechanisms.contains(&KeyMechanism::AesEncryptionCbc) => Ok(Self::NetHsm {
+                id: key.id.clone(),
+                login_ctx,
+            }),
+            _ => {
+                debug!("The key {} can't be used for AES-CMAC", key.id);
+                Err(Error::InvalidMechanism(
+                    (key.id.clone(), key.kind),
+                    Mechanism::AesCmac,
+                ))
+            }
+        }
+    }
+
+    pub fn mac(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Value(value) => match value.len() {
+                16 => software_mac::<Cmac<Aes128>>(value, data),
+                24 => software_mac::<Cmac<Aes192>>(value, data),
+                _ => software_mac::<Cmac<Aes256>>(value, data),
+            },
+            Self::NetHsm { id, login_ctx } => subkey_mac(
+                |data| {
+                    encrypt_data(
+                        id,
+                        login_ctx.clone(),
+                        data,
+                        &Mechanism::AesCbc(Some([0; BLOCK_SIZE])),
+                    )
+                },
+                data,
+            ),
+        }
+    }
+}
+
+fn software_mac<M: Mac + cmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut mac =
+        <M as cmac::digest::KeyInit>::new_from_slice(key).map_err(|_| Error::InvalidData)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// RFC 4493 on top of the CBC encryption with a zero IV of data that is a multiple of the block size
+fn subkey_mac(
+    cbc_encrypt: impl Fn(&[u8]) -> Result<Vec<u8>, Error>,
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let l = cbc_encrypt(&[0; BLOCK_SIZE])?;
+    if l.len() != BLOCK_SIZE {
+        return Err(Error::InvalidData);
+    }
+    let k1 = double(&l);
+    let k2 = double(&k1);
+
+    // the last block is xored with K1 if it is complete, otherwise padded and xored with K2
+    let mut message = data.to_vec();
+    let complete = message
+        .chunks(BLOCK_SIZE)
+        .last()
+        .is_some_and(|block| block.len() == BLOCK_SIZE);
+    if !complete {
+        message.push(0x80);
+        message.resize(message.chunks(BLOCK_SIZE).len() * BLOCK_SIZE, 0);
+    }
+    let last = message.len() - BLOCK_SIZE;
+    let subkey = if complete { k1 } else { k2 };
+    message[last..]
+        .iter_mut()
+        .zip(subkey)
+        .for_each(|(b, k)| *b ^= k);
+
+    let output = cbc_encrypt(&message)?;
+    message.zeroize();
+    output
+        .get(output.len().saturating_sub(BLOCK_SIZE)..)
+        .filter(|tag| tag.len() == BLOCK_SIZE)
+        .map(<[u8]>::to_vec)
+        .ok_or(Error::InvalidData)
+}
+
+// the value is a copy of the secret
+impl Drop for CmacKey {
+    fn drop(&mut self) {
+        if let Self::Value(value) = self {
+            value.zeroize();
+        }
+    }
+}
+
+// multiplication by x in GF(2^128), the subkey generation of RFC 4493 section 2.3
+fn double(block: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut output = [0; BLOCK_SIZE];
+    for i in 0..BLOCK_SIZE {
+        let carry = block.get(i + 1).map(|b| b >> 7).unwrap_or(0);
+        output[i] = (block[i] << 1) | carry;
+    }
+    if block[0] & 0x80 != 0 {
+        output[BLOCK_SIZE - 1] ^= RB;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    // RFC 4493 appendix D
+    const KEY: [u8; 16] = hex!("2b7e151628aed2a6abf7158809cf4f3c");
+    const MESSAGE: [u8; 64] = hex!(
+        "6bc1bee22e409f96e93d7e117393172a"
+        "ae2d8a571e03ac9c9eb76fac45af8e51"
+        "30c81c46a35ce411e5fbc1191a0a52ef"
+        "f69f2445df4f9b17ad2b417be66c3710"
+    );
+
+    // the CBC encryption the NetHSM does for the keys stored on it
+    fn aes_cbc(data: &[u8]) -> Result<Vec<u8>, Error> {
+        use aes::cipher::{BlockEncrypt, KeyInit};
+
+        let cipher = Aes128::new(&KEY.into());
+        let mut block = [0; BLOCK_SIZE];
+        let mut output = Vec::new();
+        for chunk in data.chunks(BLOCK_SIZE) {
+            block.iter_mut().zip(chunk).for_each(|(b, d)| *b ^= d);
+            cipher.encrypt_block((&mut block).into());
+            output.extend_from_slice(&block);
+        }
+        Ok(output)
+    }
+
+    const TAGS: [(usize, [u8; 16]); 4] = [
+        (0, hex!("bb1d6929e95937287fa37d129b756746")),
+        (16, hex!("070a16b46b4d4144f79bdd9dd04a287c")),
+        (40, hex!("dfa66747de9ae63030ca32611497c827")),
+        (64, hex!("51f0bebf7e3b9d92fc49741779363cfe")),
+    ];
+
+    #[test]
+    fn test_subkeys() {
+        let l = aes_cbc(&[0; BLOCK_SIZE]).unwrap();
+        assert_eq!(l, hex!("7df76b0c1ab899b33e42f047b91b546f"));
+        let k1 = double(&l);
+        assert_eq!(k1, hex!("fbeed618357133667c85e08f7236a8de"));
+        assert_eq!(double(&k1), hex!("f7ddac306ae266ccf90bc11ee46d513b"));
+    }
+
+    #[test]
+    fn test_aes_cmac_rfc_4493() {
+        let key = CmacKey::Value(KEY.to_vec());
+        for (len, tag) in TAGS {
+            assert_eq!(key.mac(&MESSAGE[..len]).unwrap(), tag);
+        }
+    }
+
+    #[test]
+    fn test_subkey_mac_rfc_4493() {
+        for (len, tag) in TAGS {
+            assert_eq!(subkey_mac(aes_cbc, &MESSAGE[..len]).unwrap(), tag);
+        }
+    }
+
+    #[test]
+    fn test_from_object() {
+        let mut key = Object::default();
+        key.set_attr(CKA_VALUE, Attribute::Bytes(KEY.to_vec()));
+        key.set_attr(CKA_KEY_TYPE, Attribute::Ulong(CKK_AES));
+        assert!(matches!(
+            CmacKey::from_object(&key, LoginCtx::new(None, None, vec![], None)),
+            Ok(CmacKey::Value(_))
+        ));
+
+        // a generic secret of the same length is an HMAC key
+        key.set_attr(
+            CKA_KEY_TYPE,
+            Attribute::Ulong(cryptoki_sys::CKK_GENERIC_SECRET),
+        );
+        assert!(matches!(
+            CmacKey::from_object(&key, LoginCtx::new(None, None, vec![], None)),
+            Err(Error::InvalidMechanism(_, Mechanism::AesCmac))
+        ));
+    }
+}
@@ -57,7 +57,8 @@ impl CkRawAttr {
         if val_ptr.is_null() {
             return None;
         }
-        unsafe { Some(std::ptr::read(val_ptr as *const T)) }
+        // the application doesn't have to align the value
+        unsafe { Some(std::ptr::read_unaligned(val_ptr as *const T)) }
     }
 
     pub fn len(&self) -> cryptoki_sys::CK_ULONG {
@@ -117,7 +118,7 @@ impl CkRawAttrTemplate {
         self.count
     }
 
-    pub fn iter(&self) -> CkRawAttrTemplateIter {
+    pub fn iter(&self) -> CkRawAttrTemplateIter<'_> {
         CkRawAttrTemplateIter {
             tpl: self,
             index: 0,
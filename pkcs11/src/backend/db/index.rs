@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+
+use cryptoki_sys::{CKA_CLASS, CKA_KEY_TYPE, CK_ATTRIBUTE_TYPE, CK_OBJECT_HANDLE, CK_ULONG};
+
+use super::object::{Attribute, Object, ObjectKind};
+
+// Secondary indexes of the objects stored in the database, keyed by the value of CKA_CLASS and CKA_KEY_TYPE.
+// The objects of the NetHSM are also indexed by key ID and kind, a key fetched again keeps its handle.
+#[derive(Debug, Default)]
+pub struct ObjectIndex {
+    by_class: HashMap<CK_ULONG, HashSet<CK_OBJECT_HANDLE>>,
+    by_key_type: HashMap<CK_ULONG, HashSet<CK_OBJECT_HANDLE>>,
+    by_id: HashMap<(String, ObjectKind), CK_OBJECT_HANDLE>,
+}
+
+fn ulong_attr(object: &Object, attr_type: CK_ATTRIBUTE_TYPE) -> Option<CK_ULONG> {
+    match object.get_attribute(attr_type) {
+        Some(Attribute::Ulong(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+impl ObjectIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, handle: CK_OBJECT_HANDLE, object: &Object) {
+        if let Some(class) = ulong_attr(object, CKA_CLASS) {
+            self.by_class.entry(class).or_default().insert(handle);
+        }
+        if let Some(key_type) = ulong_attr(object, CKA_KEY_TYPE) {
+            self.by_key_type.entry(key_type).or_default().insert(handle);
+        }
+        // copies have their own entry
+        if object.copied_from.is_none() {
+            self.by_id.insert((object.id.clone(), object.kind), handle);
+        }
+    }
+
+    pub fn remove(&mut self, handle: CK_OBJECT_HANDLE, object: &Object) {
+        if let Some(class) = ulong_attr(object, CKA_CLASS) {
+            if let Some(handles) = self.by_class.get_mut(&class) {
+                handles.remove(&handle);
+            }
+        }
+        if let Some(key_type) = ulong_attr(object, CKA_KEY_TYPE) {
+            if let Some(handles) = self.by_key_type.get_mut(&key_type) {
+                handles.remove(&handle);
+            }
+        }
+        let id = (object.id.clone(), object.kind);
+        if self.by_id.get(&id) == Some(&handle) {
+            self.by_id.remove(&id);
+        }
+    }
+
+    // an object evicted from the cache keeps its handle, its ID stays indexed
+    pub fn evict(&mut self, handle: CK_OBJECT_HANDLE, object: &Object) {
+        self.remove(handle, object);
+        if object.copied_from.is_none() {
+            self.by_id.insert((object.id.clone(), object.kind), handle);
+        }
+    }
+
+    pub fn remove_id(&mut self, handle: CK_OBJECT_HANDLE, id: &str, kind: ObjectKind) {
+        let id = (id.to_string(), kind);
+        if self.by_id.get(&id) == Some(&handle) {
+            self.by_id.remove(&id);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.by_class.clear();
+        self.by_key_type.clear();
+        self.by_id.clear();
+    }
+
+    pub fn by_id(&self, id: &str, kind: ObjectKind) -> Option<CK_OBJECT_HANDLE> {
+        self.by_id.get(&(id.to_string(), kind)).copied()
+    }
+
+    pub fn by_class(&self, class: CK_ULONG) -> Option<&HashSet<CK_OBJECT_HANDLE>> {
+        self.by_class.get(&class)
+    }
+
+    // number of objects of each class and of each key type
+    pub fn counts(&self) -> (HashMap<CK_ULONG, usize>, HashMap<CK_ULONG, usize>) {
+        let count = |index: &HashMap<CK_ULONG, HashSet<CK_OBJECT_HANDLE>>| {
+            index
+                .iter()
+                .filter(|(_, handles)| !handles.is_empty())
+                .map(|(value, handles)| (*value, handles.len()))
+                .collect()
+        };
+        (count(&self.by_class), count(&self.by_key_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::db::{object::ObjectKind, Db};
+
+    fn key(id: usize, class: CK_ULONG, key_type: CK_ULONG) -> Object {
+        let mut object = Object::default();
+        object.id = format!("key{}", id);
+        object.kind = ObjectKind::from(class);
+        object.set_attr(CKA_CLASS, Attribute::Ulong(class));
+        object.set_attr(CKA_KEY_TYPE, Attribute::Ulong(key_type));
+        object
+    }
+
+    fn find_by_key_type(db: &Db, key_type: CK_ULONG) -> Vec<CK_OBJECT_HANDLE> {
+        db.index
+            .by_key_type
+            .get(&key_type)
+            .map(|handles| handles.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_index_add_remove() {
+        let mut db = Db::new();
+        let (rsa, _) = db.add_object(key(0, cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_RSA));
+        let (ec, _) = db.add_object(key(1, cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_EC));
+        let (aes, _) = db.add_object(key(2, cryptoki_sys::CKO_SECRET_KEY, cryptoki_sys::CKK_AES));
+
+        let mut private = db.find_by_class(cryptoki_sys::CKO_PRIVATE_KEY);
+        private.sort();
+        assert_eq!(private, vec![rsa, ec]);
+        assert_eq!(find_by_key_type(&db, cryptoki_sys::CKK_AES), vec![aes]);
+        assert!(db.find_by_class(cryptoki_sys::CKO_CERTIFICATE).is_empty());
+
+        db.remove(rsa);
+        assert_eq!(db.find_by_class(cryptoki_sys::CKO_PRIVATE_KEY), vec![ec]);
+        assert!(find_by_key_type(&db, cryptoki_sys::CKK_RSA).is_empty());
+
+        db.clear();
+        assert!(db.find_by_class(cryptoki_sys::CKO_SECRET_KEY).is_empty());
+    }
+
+    #[test]
+    fn test_index_replaced_object() {
+        let mut db = Db::new();
+        let (handle, _) =
+            db.add_object(key(0, cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_RSA));
+
+        // same id and kind, the object is replaced
+        let (replaced, _) =
+            db.add_object(key(0, cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_EC));
+        assert_eq!(handle, replaced);
+        assert!(find_by_key_type(&db, cryptoki_sys::CKK_RSA).is_empty());
+        assert_eq!(find_by_key_type(&db, cryptoki_sys::CKK_EC), vec![handle]);
+    }
+
+    #[test]
+    fn test_index_lookup() {
+        let mut db = Db::new();
+        for i in 0..10_000 {
+            let class = if i % 100 == 0 {
+                cryptoki_sys::CKO_CERTIFICATE
+            } else {
+                cryptoki_sys::CKO_PRIVATE_KEY
+            };
+            db.add_object(key(i, class, cryptoki_sys::CKK_RSA));
+        }
+
+        let certificates = db.find_by_class(cryptoki_sys::CKO_CERTIFICATE);
+        assert_eq!(certificates.len(), 100);
+        assert!(certificates.iter().all(|handle| db
+            .object(*handle)
+            .is_some_and(|object| object.kind == ObjectKind::Certificate)));
+        assert_eq!(db.find_by_class(cryptoki_sys::CKO_PRIVATE_KEY).len(), 9_900);
+        assert!(db.find_by_class(cryptoki_sys::CKO_SECRET_KEY).is_empty());
+        assert!(find_by_key_type(&db, cryptoki_sys::CKK_EC).is_empty());
+    }
+
+    #[test]
+    fn test_db_stats() {
+        let mut db = Db::new();
+        for i in 0..9 {
+            let (class, key_type) = match i {
+                0..=4 => (cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_RSA),
+                5..=7 => (cryptoki_sys::CKO_PRIVATE_KEY, cryptoki_sys::CKK_EC),
+                _ => (cryptoki_sys::CKO_SECRET_KEY, cryptoki_sys::CKK_AES),
+            };
+            db.add_object(key(i, class, key_type));
+        }
+        db.set_fetched_all_keys(true);
+
+        let stats = db.stats();
+        assert_eq!(stats.total_objects, 9);
+        assert_eq!(stats.by_class[&cryptoki_sys::CKO_PRIVATE_KEY], 8);
+        assert_eq!(stats.by_class[&cryptoki_sys::CKO_SECRET_KEY], 1);
+        assert_eq!(stats.by_key_type[&cryptoki_sys::CKK_RSA], 5);
+        assert_eq!(stats.by_key_type[&cryptoki_sys::CKK_EC], 3);
+        assert_eq!(stats.by_key_type[&cryptoki_sys::CKK_AES], 1);
+        assert_eq!(stats.evicted_objects, 0);
+        assert!(stats.last_fetch_all.is_some());
+    }
+}
@@ -4,29 +4,120 @@
 // modified from https://github.com/aws/aws-nitro-enclaves-acm
 
 pub mod attr;
+pub mod index;
 pub mod object;
-use cryptoki_sys::CK_OBJECT_HANDLE;
-use std::{collections::HashMap, time::SystemTime};
+use cryptoki_sys::{CK_ATTRIBUTE_TYPE, CK_KEY_TYPE, CK_OBJECT_CLASS, CK_OBJECT_HANDLE, CK_SLOT_ID};
+use log::debug;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
-pub use object::Object;
+use index::ObjectIndex;
+pub use object::{Object, TagAttributes};
+
+use object::{Attribute, ObjectKind};
+
+use crate::backend::{
+    object::KeyRequirements,
+    session::{SessionManager, MAX_LOCAL_HANDLE},
+};
+
+// number of NetHSM objects kept when the slot doesn't set cache_capacity
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
+
+// What is kept of an object removed from the cache, to fetch it again with the same handle
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvictedObject {
+    pub id: String,
+    pub kind: ObjectKind,
+    pub raw_id: Option<Vec<u8>>,
+    pub module_attributes: Vec<(CK_ATTRIBUTE_TYPE, Attribute)>,
+}
+
+// content of the database, for C_NetHSM_GetDbStats and the logs of the searches
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DbStats {
+    pub total_objects: usize,
+    pub by_class: HashMap<CK_OBJECT_CLASS, usize>,
+    pub by_key_type: HashMap<CK_KEY_TYPE, usize>,
+    // objects removed from the cache, they are fetched again when used
+    pub evicted_objects: usize,
+    pub last_fetch_all: Option<SystemTime>,
+}
 
 #[derive(Debug)]
 pub struct Db {
     objects: HashMap<CK_OBJECT_HANDLE, Object>,
-    next_handle: CK_OBJECT_HANDLE,
+    index: ObjectIndex,
+    // the handles are encoded with the slot, see SessionManager::encode_handle
+    slot_id: CK_SLOT_ID,
+    next_handle: u32,
     last_fetchall_timestamp: Option<SystemTime>,
+    // held while all the keys are fetched from the NetHSM, the sessions of the slot
+    // fetching at the same time wait for the first one instead of fetching again
+    fetch_lock: Arc<Mutex<()>>,
+    // vendor attributes of the key tags, from the slot configuration
+    tag_attributes: TagAttributes,
+    // The objects of the NetHSM are kept in a LRU cache of cache_capacity objects. The use
+    // counter orders them, the least recently used one is evicted first. The copies and the
+    // session objects can't be fetched again, they are never evicted.
+    cache_capacity: usize,
+    use_counter: u64,
+    last_use: HashMap<CK_OBJECT_HANDLE, u64>,
+    lru: BTreeMap<u64, CK_OBJECT_HANDLE>,
+    evicted: HashMap<CK_OBJECT_HANDLE, EvictedObject>,
+    // the attributes of the NetHSM objects changed by the module, the NetHSM doesn't return
+    // them when an evicted object is fetched again
+    edited: HashMap<CK_OBJECT_HANDLE, Vec<(CK_ATTRIBUTE_TYPE, Attribute)>>,
 }
 
 impl Db {
     pub fn new() -> Self {
+        Self::for_slot(0)
+    }
+
+    pub fn for_slot(slot_id: CK_SLOT_ID) -> Self {
         Self {
             objects: HashMap::new(),
+            index: ObjectIndex::new(),
+            slot_id,
             // 0 means invalid handle, we need to start from 1
             next_handle: 1,
             last_fetchall_timestamp: None,
+            fetch_lock: Arc::new(Mutex::new(())),
+            tag_attributes: TagAttributes::default(),
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            use_counter: 0,
+            last_use: HashMap::new(),
+            lru: BTreeMap::new(),
+            evicted: HashMap::new(),
+            edited: HashMap::new(),
         }
     }
 
+    pub fn with_cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache_capacity = cache_capacity.max(1);
+        self
+    }
+
+    pub fn with_tag_attributes(mut self, tag_attributes: TagAttributes) -> Self {
+        self.tag_attributes = tag_attributes;
+        self
+    }
+
+    pub fn tag_attributes(&self) -> &TagAttributes {
+        &self.tag_attributes
+    }
+
+    // the lock must not be taken while the database itself is locked
+    pub fn fetch_lock(&self) -> Arc<Mutex<()>> {
+        self.fetch_lock.clone()
+    }
+
+    // the evicted objects are part of the list, they are fetched again when used
     pub fn fetched_all_keys(&self) -> bool {
         self.last_fetchall_timestamp
             .map(|last| {
@@ -46,10 +137,26 @@ impl Db {
         }
     }
 
+    pub fn stats(&self) -> DbStats {
+        let (by_class, by_key_type) = self.index.counts();
+        DbStats {
+            total_objects: self.objects.len(),
+            by_class,
+            by_key_type,
+            evicted_objects: self.evicted.len(),
+            last_fetch_all: self.last_fetchall_timestamp,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn clear(&mut self) {
         self.set_fetched_all_keys(false);
         self.objects.clear();
+        self.index.clear();
+        self.last_use.clear();
+        self.lru.clear();
+        self.evicted.clear();
+        self.edited.clear();
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (CK_OBJECT_HANDLE, &Object)> {
@@ -58,35 +165,217 @@ impl Db {
             .map(|(handle, object)| (*handle, object))
     }
 
-    pub fn add_object(&mut self, object: Object) -> (CK_OBJECT_HANDLE, Object) {
-        // check if the object already exists
+    pub fn add_object(&mut self, mut object: Object) -> (CK_OBJECT_HANDLE, Object) {
+        // a key fetched again replaces the attributes of the existing entry and keeps its handle
+        let found = match object.copied_from {
+            Some(_) => None,
+            None => self.index.by_id(&object.id, object.kind),
+        };
+
+        let handle = match found {
+            Some(handle) => handle,
+            None => self.new_handle(),
+        };
+
+        // the NetHSM doesn't return the attributes kept by the module
+        let kept = match (self.objects.get(&handle), self.evicted.get(&handle)) {
+            (Some(old), _) => old.module_attributes(),
+            (None, Some(evicted)) => evicted.module_attributes.clone(),
+            (None, None) => vec![],
+        };
+        let edited = self.edited.get(&handle).cloned().unwrap_or_default();
+        for (attr_type, attr) in kept.into_iter().chain(edited) {
+            object.set_attr(attr_type, attr);
+        }
+
+        if let Some(old) = self.objects.get(&handle) {
+            self.index.remove(handle, old);
+        }
+        self.index.insert(handle, &object);
+        self.objects.insert(handle, object);
+        self.evicted.remove(&handle);
+
+        self.touch(handle);
+        self.evict_over_capacity();
+
+        (handle, self.objects.get(&handle).unwrap().clone())
+    }
 
-        let found = self
+    // marks the object as the most recently used one
+    pub fn touch(&mut self, handle: CK_OBJECT_HANDLE) {
+        let evictable = self
             .objects
-            .iter_mut()
-            .find(|(_, obj)| obj.id == object.id && obj.kind == object.kind);
+            .get(&handle)
+            .is_some_and(|object| object.is_token() && object.copied_from.is_none());
+        if !evictable {
+            return;
+        }
+        if let Some(previous) = self.last_use.remove(&handle) {
+            self.lru.remove(&previous);
+        }
+        self.use_counter += 1;
+        self.last_use.insert(handle, self.use_counter);
+        self.lru.insert(self.use_counter, handle);
+    }
+
+    fn evict_over_capacity(&mut self) {
+        while self.lru.len() > self.cache_capacity {
+            let Some((_, handle)) = self.lru.pop_first() else {
+                break;
+            };
+            self.last_use.remove(&handle);
+            let Some(object) = self.objects.remove(&handle) else {
+                continue;
+            };
+            self.index.evict(handle, &object);
 
-        if let Some((handle, obj)) = found {
-            *obj = object;
-            return (*handle, obj.clone());
+            // CKA_ID holds the raw ID when the key ID was built from it
+            let raw_id = match object.get_attribute(cryptoki_sys::CKA_ID) {
+                Some(Attribute::Bytes(id)) if id != object.id.as_bytes() => Some(id.clone()),
+                _ => None,
+            };
+            debug!("Evicting the object {} from the cache", object.id);
+            let module_attributes = object.module_attributes();
+            self.evicted.insert(
+                handle,
+                EvictedObject {
+                    id: object.id,
+                    kind: object.kind,
+                    raw_id,
+                    module_attributes,
+                },
+            );
         }
+    }
 
-        // increment the handle
+    // the object of a handle that was evicted, it has to be fetched again from the NetHSM
+    pub fn evicted(&self, handle: CK_OBJECT_HANDLE) -> Option<&EvictedObject> {
+        if !self.is_local(handle) {
+            return None;
+        }
+        self.evicted.get(&handle)
+    }
+
+    // the handles of the evicted objects of a kind, for the searches of all the keys
+    pub fn evicted_handles(&self, kind: Option<ObjectKind>) -> Vec<CK_OBJECT_HANDLE> {
+        self.evicted
+            .iter()
+            .filter(|(_, evicted)| kind.map(|kind| evicted.kind == kind).unwrap_or(true))
+            .map(|(handle, _)| *handle)
+            .collect()
+    }
+
+    // a copy refers to the same key as its original, it always gets a new handle
+    pub fn add_copy(&mut self, original: CK_OBJECT_HANDLE, mut object: Object) -> CK_OBJECT_HANDLE {
+        object.copied_from = Some(original);
 
-        let handle = self.next_handle;
-        self.next_handle += 1;
+        let handle = self.new_handle();
 
+        self.index.insert(handle, &object);
         self.objects.insert(handle, object);
+        handle
+    }
 
-        (handle, self.objects.get(&handle).unwrap().clone())
+    // the local part of the handles has 24 bits, the counter wraps and skips the handles in use
+    fn new_handle(&mut self) -> CK_OBJECT_HANDLE {
+        loop {
+            let handle = SessionManager::encode_handle(self.slot_id, self.next_handle);
+            self.next_handle = match self.next_handle {
+                MAX_LOCAL_HANDLE => 1,
+                next => next + 1,
+            };
+            if !self.objects.contains_key(&handle) && !self.evicted.contains_key(&handle) {
+                return handle;
+            }
+        }
+    }
+
+    // a handle of another slot is never valid, even if its local part is
+    fn is_local(&self, handle: CK_OBJECT_HANDLE) -> bool {
+        let (slot_id, _) = SessionManager::decode_handle(handle);
+        if slot_id != self.slot_id {
+            debug!("The handle {} belongs to the slot {}", handle, slot_id);
+            return false;
+        }
+        true
     }
 
     pub fn object(&self, handle: CK_OBJECT_HANDLE) -> Option<&Object> {
+        if !self.is_local(handle) {
+            return None;
+        }
         self.objects.get(&handle)
     }
 
+    // the class and the key type of an object can't change, the index stays valid
+    pub fn object_mut(&mut self, handle: CK_OBJECT_HANDLE) -> Option<&mut Object> {
+        if !self.is_local(handle) {
+            return None;
+        }
+        self.objects.get_mut(&handle)
+    }
+
+    // Replaces an object changed by the module. The changes of a NetHSM object are kept, they
+    // are applied again if the object is evicted and fetched.
+    pub fn update_object(&mut self, handle: CK_OBJECT_HANDLE, object: Object) -> Option<()> {
+        let stored = self.object_mut(handle)?;
+        if stored.is_token() && stored.copied_from.is_none() {
+            let changed = object.changed_attributes(stored);
+            let edited = self.edited.entry(handle).or_default();
+            for (attr_type, attr) in changed {
+                edited.retain(|(edited_type, _)| *edited_type != attr_type);
+                edited.push((attr_type, attr));
+            }
+        }
+        self.objects.insert(handle, object);
+        Some(())
+    }
+
     pub fn remove(&mut self, handle: CK_OBJECT_HANDLE) -> Option<Object> {
-        self.objects.remove(&handle)
+        if !self.is_local(handle) {
+            return None;
+        }
+        if let Some(evicted) = self.evicted.remove(&handle) {
+            self.index.remove_id(handle, &evicted.id, evicted.kind);
+        }
+        if let Some(last_use) = self.last_use.remove(&handle) {
+            self.lru.remove(&last_use);
+        }
+        self.edited.remove(&handle);
+        let object = self.objects.remove(&handle)?;
+        self.index.remove(handle, &object);
+        Some(object)
+    }
+
+    pub fn find_by_class(&self, class: CK_OBJECT_CLASS) -> Vec<CK_OBJECT_HANDLE> {
+        self.index
+            .by_class(class)
+            .map(|handles| handles.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // the objects of the database matching the class, the ID and CKA_TOKEN of a search,
+    // the objects without CKA_TOKEN are session objects
+    pub fn find_by_template(&self, requirements: &KeyRequirements) -> Vec<CK_OBJECT_HANDLE> {
+        self.objects
+            .iter()
+            .filter(|(_, object)| {
+                requirements
+                    .kind
+                    .map(|kind| object.kind == kind)
+                    .unwrap_or(true)
+                    && requirements
+                        .id
+                        .as_ref()
+                        .map(|id| object.id == *id)
+                        .unwrap_or(true)
+                    && requirements
+                        .token
+                        .map(|token| object.is_token() == token)
+                        .unwrap_or(true)
+            })
+            .map(|(handle, _)| *handle)
+            .collect()
     }
 }
 
@@ -105,5 +394,123 @@ mod tests {
         let (handle2, object2) = db.add_object(object.clone());
         assert_eq!(handle1, handle2);
         assert_eq!(object1.id, object2.id);
+        assert_eq!(db.iter().count(), 1);
+
+        // a copy doesn't replace its original
+        let copy = db.add_copy(handle1, object.clone());
+        assert_ne!(copy, handle1);
+        assert_eq!(db.add_object(object.clone()).0, handle1);
+
+        // once removed, the key gets a new handle
+        db.remove(handle1);
+        let (handle3, _) = db.add_object(object);
+        assert_ne!(handle3, handle1);
+        assert_ne!(handle3, copy);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut db = Db::new().with_cache_capacity(2);
+        let token_key = |id: &str| {
+            let mut object = Object::default();
+            object.id = id.to_string();
+            object.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(true));
+            object
+        };
+        let mut session_object = Object::default();
+        session_object.id = "session".to_string();
+
+        let session_handle = db.add_object(session_object).0;
+        let (first, _) = db.add_object(token_key("key0"));
+        let (second, _) = db.add_object(token_key("key1"));
+        db.set_fetched_all_keys(true);
+        assert!(db.fetched_all_keys());
+
+        // key1 is the least recently used one
+        db.touch(first);
+        let (third, _) = db.add_object(token_key("key2"));
+        assert!(db.object(second).is_none());
+        assert_eq!(
+            db.evicted(second).map(|evicted| evicted.id.as_str()),
+            Some("key1")
+        );
+        assert!(db.object(first).is_some());
+        assert!(db.object(third).is_some());
+        assert!(db.object(session_handle).is_some());
+        // the evicted key is still listed
+        assert!(db.fetched_all_keys());
+        assert_eq!(db.evicted_handles(None), vec![second]);
+        assert!(db.evicted_handles(Some(ObjectKind::Certificate)).is_empty());
+
+        // the key fetched again gets its handle back
+        assert_eq!(db.add_object(token_key("key1")).0, second);
+        assert!(db.evicted(second).is_none());
+        assert!(db.object(first).is_none());
+    }
+
+    #[test]
+    fn test_edits_kept_on_eviction() {
+        let mut db = Db::new().with_cache_capacity(1);
+        let token_key = |id: &str| {
+            let mut object = Object::default();
+            object.id = id.to_string();
+            object.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(true));
+            object
+        };
+
+        let (first, mut object) = db.add_object(token_key("key0"));
+        object.set_attr(
+            cryptoki_sys::CKA_SUBJECT,
+            Attribute::Bytes(b"subject".to_vec()),
+        );
+        db.update_object(first, object).unwrap();
+        db.add_object(token_key("key1"));
+        assert!(db.evicted(first).is_some());
+
+        // the NetHSM returns the key without the change
+        let (handle, object) = db.add_object(token_key("key0"));
+        assert_eq!(handle, first);
+        assert_eq!(
+            object.get_attribute(cryptoki_sys::CKA_SUBJECT),
+            Some(&Attribute::Bytes(b"subject".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_handle_counter_wraps() {
+        let mut db = Db::new();
+        let mut object = Object::default();
+        object.id = "first".to_string();
+        let (first, _) = db.add_object(object.clone());
+        assert_eq!(SessionManager::decode_handle(first), (0, 1));
+
+        // the counter starts again at 1, which is still used
+        db.next_handle = MAX_LOCAL_HANDLE;
+        object.id = "last".to_string();
+        let (last, _) = db.add_object(object.clone());
+        assert_eq!(SessionManager::decode_handle(last), (0, MAX_LOCAL_HANDLE));
+        object.id = "wrapped".to_string();
+        let (wrapped, _) = db.add_object(object);
+        assert_eq!(SessionManager::decode_handle(wrapped), (0, 2));
+    }
+
+    #[test]
+    fn test_handles_of_slots() {
+        let mut db0 = Db::for_slot(0);
+        let mut db1 = Db::for_slot(1);
+        let mut object = Object::default();
+        object.id = "id".to_string();
+
+        let (handle0, _) = db0.add_object(object.clone());
+        let (handle1, _) = db1.add_object(object);
+        assert_ne!(handle0, handle1);
+        assert_eq!(SessionManager::decode_handle(handle0), (0, 1));
+        assert_eq!(SessionManager::decode_handle(handle1), (1, 1));
+
+        // the handle of an object of another slot is unknown
+        assert!(db0.object(handle1).is_none());
+        assert!(db1.object(handle0).is_none());
+        assert!(db1.remove(handle0).is_none());
+        assert!(db0.object(handle0).is_some());
     }
 }
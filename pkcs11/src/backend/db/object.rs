@@ -3,27 +3,32 @@ use base64ct::{Base64, Encoding};
 // Copyright 2023 Nitrokey
 // SPDX-License-Identifier: Apache-2.0
 use cryptoki_sys::{
-    CKA_ALLOWED_MECHANISMS, CKA_ALWAYS_AUTHENTICATE, CKA_ALWAYS_SENSITIVE,
-    CKA_CERTIFICATE_CATEGORY, CKA_CERTIFICATE_TYPE, CKA_CLASS, CKA_DECRYPT, CKA_DERIVE,
-    CKA_EC_PARAMS, CKA_EC_POINT, CKA_ENCRYPT, CKA_EXTRACTABLE, CKA_ID, CKA_ISSUER,
+    CKA_ALLOWED_MECHANISMS, CKA_ALWAYS_AUTHENTICATE, CKA_ALWAYS_SENSITIVE, CKA_APPLICATION,
+    CKA_CERTIFICATE_CATEGORY, CKA_CERTIFICATE_TYPE, CKA_CLASS, CKA_COEFFICIENT, CKA_COPYABLE,
+    CKA_DECRYPT, CKA_DERIVE, CKA_DESTROYABLE, CKA_EC_PARAMS, CKA_EC_POINT, CKA_ENCRYPT,
+    CKA_END_DATE, CKA_EXPONENT_1, CKA_EXPONENT_2, CKA_EXTRACTABLE, CKA_ID, CKA_ISSUER,
     CKA_KEY_GEN_MECHANISM, CKA_KEY_TYPE, CKA_LABEL, CKA_LOCAL, CKA_MODIFIABLE, CKA_MODULUS,
-    CKA_MODULUS_BITS, CKA_NEVER_EXTRACTABLE, CKA_PRIVATE, CKA_PUBLIC_EXPONENT, CKA_SENSITIVE,
-    CKA_SIGN, CKA_SIGN_RECOVER, CKA_SUBJECT, CKA_TOKEN, CKA_TRUSTED, CKA_UNWRAP, CKA_VALUE,
-    CKA_VALUE_LEN, CKA_VERIFY, CKA_VERIFY_RECOVER, CKA_WRAP, CKA_WRAP_WITH_TRUSTED, CKC_X_509,
-    CK_ATTRIBUTE_TYPE, CK_KEY_TYPE, CK_MECHANISM_TYPE, CK_OBJECT_CLASS, CK_ULONG,
+    CKA_MODULUS_BITS, CKA_NEVER_EXTRACTABLE, CKA_OBJECT_ID, CKA_PRIME_1, CKA_PRIME_2, CKA_PRIVATE,
+    CKA_PRIVATE_EXPONENT, CKA_PUBLIC_EXPONENT, CKA_SENSITIVE, CKA_SIGN, CKA_SIGN_RECOVER,
+    CKA_START_DATE, CKA_SUBJECT, CKA_TOKEN, CKA_TRUSTED, CKA_UNWRAP, CKA_VALUE, CKA_VALUE_LEN,
+    CKA_VERIFY, CKA_VERIFY_RECOVER, CKA_WRAP, CKA_WRAP_WITH_TRUSTED, CKC_X_509, CK_ATTRIBUTE_TYPE,
+    CK_KEY_TYPE, CK_MECHANISM_TYPE, CK_OBJECT_CLASS, CK_OBJECT_HANDLE, CK_SESSION_HANDLE, CK_ULONG,
     CK_UNAVAILABLE_INFORMATION,
 };
 use der::{asn1::OctetString, DecodePem, Encode};
-use log::{debug, trace};
+use log::{debug, error, trace};
 use nethsm_sdk_rs::models::{KeyMechanism, KeyType, PublicKey};
 use std::collections::HashMap;
 use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::backend::{
     key::{key_size, key_type_to_asn1},
     mechanism::Mechanism,
     Error,
 };
+use crate::defs::MECHANISM_LIST;
 
 use super::attr::{self, CkRawAttrTemplate};
 
@@ -35,103 +40,306 @@ use super::attr::{self, CkRawAttrTemplate};
 /// Since there is no R/W session support these objects are created
 /// from the user provisioned database.
 
-#[derive(Debug, Clone)]
-pub enum Attr {
-    Bytes(Vec<u8>),
-    CkBbool([u8; size_of::<cryptoki_sys::CK_BBOOL>()]),
-    CkByte([u8; size_of::<cryptoki_sys::CK_BYTE>()]),
-    CkKeyType([u8; size_of::<cryptoki_sys::CK_KEY_TYPE>()]),
-    CkCertType([u8; size_of::<cryptoki_sys::CK_CERTIFICATE_TYPE>()]),
-    CkCertCategory([u8; size_of::<cryptoki_sys::CK_ULONG>()]),
-    CkMechanismType([u8; size_of::<cryptoki_sys::CK_MECHANISM_TYPE>()]),
-    CkObjectClass([u8; size_of::<cryptoki_sys::CK_OBJECT_CLASS>()]),
-    CkUlong([u8; size_of::<cryptoki_sys::CK_ULONG>()]),
-    #[allow(dead_code)]
-    Sensitive,
+// value of a CKA_START_DATE or CKA_END_DATE attribute, stored as ASCII digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CkDate {
+    pub year: [u8; 4],
+    pub month: [u8; 2],
+    pub day: [u8; 2],
 }
 
-impl Attr {
-    const CK_TRUE: Self = Self::CkBbool([cryptoki_sys::CK_TRUE; 1]);
-    const CK_FALSE: Self = Self::CkBbool([cryptoki_sys::CK_FALSE; 1]);
-
-    pub fn as_bytes(&self) -> &[u8] {
-        match self {
-            Self::CkBbool(v) => v,
-            Self::CkByte(v) => v,
-            Self::CkKeyType(v) => v,
-            Self::CkCertType(v) => v,
-            Self::CkCertCategory(v) => v,
-            Self::CkMechanismType(v) => v,
-            Self::CkObjectClass(v) => v,
-            Self::CkUlong(v) => v,
-            Self::Bytes(v) => v,
-            Self::Sensitive => &[0u8; 0],
+impl CkDate {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != size_of::<cryptoki_sys::CK_DATE>()
+            || !bytes.iter().all(|b| b.is_ascii_digit())
+        {
+            return None;
         }
+
+        Some(Self {
+            year: [bytes[0], bytes[1], bytes[2], bytes[3]],
+            month: [bytes[4], bytes[5]],
+            day: [bytes[6], bytes[7]],
+        })
     }
 
-    #[allow(dead_code)]
-    fn from_ck_byte(src: cryptoki_sys::CK_BYTE) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkByte(src.to_le_bytes())
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(size_of::<cryptoki_sys::CK_DATE>());
+        bytes.extend_from_slice(&self.year);
+        bytes.extend_from_slice(&self.month);
+        bytes.extend_from_slice(&self.day);
+        bytes
     }
+}
+
+// not exported by cryptoki_sys
+const CKA_VENDOR_DEFINED: CK_ATTRIBUTE_TYPE = 0x80000000;
+
+// PKCS#11 v3.0, unique across the objects of the token
+pub const CKA_UNIQUE_ID: CK_ATTRIBUTE_TYPE = 0x00000004;
+
+// Vendor attributes counting how many times a session used a key to sign or decrypt,
+// and the number of uses allowed. They are kept by the session, not by the object.
+pub const CKA_NETHSM_USAGE_COUNT: CK_ATTRIBUTE_TYPE = CKA_VENDOR_DEFINED | 0x100;
+pub const CKA_NETHSM_MAX_USAGE_COUNT: CK_ATTRIBUTE_TYPE = CKA_VENDOR_DEFINED | 0x101;
+
+// first vendor attribute of the tags when the slot doesn't set vendor_attr_base
+pub const DEFAULT_VENDOR_ATTR_BASE: CK_ATTRIBUTE_TYPE = 0x200;
+
+// Vendor attributes of the NetHSM key tags. The tags listed in the tag_attributes of the slot
+// configuration are numbered from vendor_attr_base: the tag at the index N is the attribute
+// CKA_VENDOR_DEFINED | (vendor_attr_base + N). A key having the tag has the attribute, its
+// value is the tag name in UTF-8, a key without the tag doesn't have the attribute.
+// The mapping is the same for C_GetAttributeValue, C_FindObjectsInit and C_CreateObject.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagAttributes {
+    base: CK_ATTRIBUTE_TYPE,
+    tags: Vec<String>,
+}
 
-    fn from_ck_key_type(src: cryptoki_sys::CK_KEY_TYPE) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkKeyType(src.to_le_bytes())
+impl TagAttributes {
+    pub fn new(tags: Vec<String>, base: Option<CK_ATTRIBUTE_TYPE>) -> Self {
+        Self {
+            base: base.unwrap_or(DEFAULT_VENDOR_ATTR_BASE),
+            tags,
+        }
     }
 
-    #[allow(dead_code)]
-    fn from_ck_cert_type(src: cryptoki_sys::CK_CERTIFICATE_TYPE) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkCertType(src.to_le_bytes())
+    // the attributes of the tags must not take the numbers of the usage counters
+    pub fn overlaps_usage_count(&self) -> bool {
+        let end = self.base + self.tags.len() as CK_ATTRIBUTE_TYPE;
+        [CKA_NETHSM_USAGE_COUNT, CKA_NETHSM_MAX_USAGE_COUNT]
+            .into_iter()
+            .map(|attr_type| attr_type & !CKA_VENDOR_DEFINED)
+            .any(|n| self.base <= n && n < end)
     }
 
-    #[allow(dead_code)]
-    fn from_ck_cert_category(src: cryptoki_sys::CK_ULONG) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkCertCategory(src.to_le_bytes())
+    pub fn attribute(&self, tag: &str) -> Option<CK_ATTRIBUTE_TYPE> {
+        self.tags
+            .iter()
+            .position(|t| t == tag)
+            .map(|index| CKA_VENDOR_DEFINED | (self.base + index as CK_ATTRIBUTE_TYPE))
     }
 
-    fn from_ck_mechanism_type(src: cryptoki_sys::CK_MECHANISM_TYPE) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkMechanismType(src.to_le_bytes())
+    pub fn tag(&self, attr_type: CK_ATTRIBUTE_TYPE) -> Option<&str> {
+        if attr_type & CKA_VENDOR_DEFINED == 0 {
+            return None;
+        }
+        let index = (attr_type & !CKA_VENDOR_DEFINED).checked_sub(self.base)?;
+        self.tags.get(index as usize).map(String::as_str)
     }
 
-    #[allow(dead_code)]
-    fn from_ck_mechanism_type_vec(src: Vec<cryptoki_sys::CK_MECHANISM_TYPE>) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::Bytes(src.iter().flat_map(|x| x.to_le_bytes()).collect())
+    // sets the attributes of the tags of the key on its objects, unmapped tags are ignored
+    pub fn apply(&self, object: &mut Object, key_tags: &[String]) {
+        for tag in key_tags {
+            if let Some(attr_type) = self.attribute(tag) {
+                object.set_attr(attr_type, Attribute::Bytes(tag.as_bytes().to_vec()));
+            }
+        }
     }
 
-    fn from_ck_object_class(src: cryptoki_sys::CK_OBJECT_CLASS) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkObjectClass(src.to_le_bytes())
+    // the tags asked by the vendor attributes of a C_CreateObject template
+    pub fn tags_from_template(&self, template: &CkRawAttrTemplate) -> Result<Vec<String>, Error> {
+        let mut tags = vec![];
+        for attr in template.iter() {
+            let Some(tag) = self.tag(attr.type_()) else {
+                continue;
+            };
+            if attr.val_bytes() != Some(tag.as_bytes()) {
+                debug!(
+                    "The value of the attribute {:#x} must be the tag {}",
+                    attr.type_(),
+                    tag
+                );
+                return Err(Error::InvalidAttribute(attr.type_()));
+            }
+            tags.push(tag.to_string());
+        }
+        Ok(tags)
     }
 
-    fn from_ck_ulong(src: cryptoki_sys::CK_ULONG) -> Self {
-        #[cfg(target_endian = "little")]
-        Self::CkUlong(src.to_le_bytes())
+    // the tag attributes of a C_FindObjectsInit template, with the value the object must have
+    pub fn template_filter(
+        &self,
+        template: &CkRawAttrTemplate,
+    ) -> Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)> {
+        template
+            .iter()
+            .filter(|attr| self.tag(attr.type_()).is_some())
+            .map(|attr| (attr.type_(), attr.val_bytes().unwrap_or_default().to_vec()))
+            .collect()
     }
 }
 
-impl PartialEq<Attr> for Attr {
-    fn eq(&self, other: &Attr) -> bool {
-        self.as_bytes() == other.as_bytes()
+// The attributes the NetHSM can't store, the module keeps them while it runs. They are not
+// covered by CKA_MODIFIABLE, the copy on the NetHSM isn't changed. The NetHSM doesn't give the
+// length of the generic keys, it is known for the keys created by the module.
+pub const MODULE_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 2] = [CKA_OBJECT_ID, CKA_VALUE_LEN];
+
+// Whether an AES key may wrap is taken from its creation template and kept like the module
+// attributes. The RSA keys get them from their mechanisms each time they are fetched.
+const SECRET_KEY_WRAP_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 2] = [CKA_WRAP, CKA_UNWRAP];
+
+// attributes fixed when the object is created, see the PKCS#11 section 4
+const READ_ONLY_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 18] = [
+    CKA_CLASS,
+    CKA_KEY_TYPE,
+    CKA_CERTIFICATE_TYPE,
+    CKA_LOCAL,
+    CKA_KEY_GEN_MECHANISM,
+    CKA_ALWAYS_SENSITIVE,
+    CKA_NEVER_EXTRACTABLE,
+    CKA_ALLOWED_MECHANISMS,
+    CKA_VALUE,
+    CKA_VALUE_LEN,
+    CKA_MODULUS,
+    CKA_MODULUS_BITS,
+    CKA_PUBLIC_EXPONENT,
+    CKA_EC_PARAMS,
+    CKA_EC_POINT,
+    CKA_NETHSM_USAGE_COUNT,
+    CKA_NETHSM_MAX_USAGE_COUNT,
+    CKA_UNIQUE_ID,
+];
+
+// attributes protecting the value of a key
+const SENSITIVE_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 4] =
+    [CKA_SENSITIVE, CKA_EXTRACTABLE, CKA_WRAP, CKA_UNWRAP];
+
+// the secret parts of an RSA private key, the NetHSM never gives them out: the private exponent
+// and the CRT components. The EC keys only have CKA_VALUE, the RSA keys have none.
+const PRIVATE_KEY_SECRET_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 6] = [
+    CKA_PRIVATE_EXPONENT,
+    CKA_PRIME_1,
+    CKA_PRIME_2,
+    CKA_EXPONENT_1,
+    CKA_EXPONENT_2,
+    CKA_COEFFICIENT,
+];
+
+// the type of the value of each attribute, see the PKCS#11 section 4
+const BOOL_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 22] = [
+    CKA_TOKEN,
+    CKA_PRIVATE,
+    CKA_MODIFIABLE,
+    CKA_COPYABLE,
+    CKA_DESTROYABLE,
+    CKA_TRUSTED,
+    CKA_SENSITIVE,
+    CKA_ENCRYPT,
+    CKA_DECRYPT,
+    CKA_WRAP,
+    CKA_UNWRAP,
+    CKA_SIGN,
+    CKA_SIGN_RECOVER,
+    CKA_VERIFY,
+    CKA_VERIFY_RECOVER,
+    CKA_DERIVE,
+    CKA_EXTRACTABLE,
+    CKA_LOCAL,
+    CKA_NEVER_EXTRACTABLE,
+    CKA_ALWAYS_SENSITIVE,
+    CKA_ALWAYS_AUTHENTICATE,
+    CKA_WRAP_WITH_TRUSTED,
+];
+
+const ULONG_ATTRIBUTES: [CK_ATTRIBUTE_TYPE; 8] = [
+    CKA_CLASS,
+    CKA_KEY_TYPE,
+    CKA_CERTIFICATE_TYPE,
+    CKA_CERTIFICATE_CATEGORY,
+    CKA_MODULUS_BITS,
+    CKA_VALUE_LEN,
+    CKA_NETHSM_USAGE_COUNT,
+    CKA_NETHSM_MAX_USAGE_COUNT,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    Bool(bool),
+    Ulong(CK_ULONG),
+    Bytes(Vec<u8>),
+    MechanismType(CK_MECHANISM_TYPE),
+    MechanismList(Vec<CK_MECHANISM_TYPE>),
+    Date(CkDate),
+    // the attribute exists but its value can't be revealed
+    #[allow(dead_code)]
+    Sensitive,
+}
+
+impl Attribute {
+    // parse the raw value of an attribute received from the application
+    pub fn from_raw(attr_type: CK_ATTRIBUTE_TYPE, bytes: &[u8]) -> Option<Self> {
+        const ULONG_SIZE: usize = size_of::<CK_ULONG>();
+
+        fn read_ulong(bytes: &[u8]) -> Option<CK_ULONG> {
+            Some(CK_ULONG::from_ne_bytes(bytes.try_into().ok()?))
+        }
+
+        if BOOL_ATTRIBUTES.contains(&attr_type) {
+            return match bytes {
+                [value] => Some(Self::Bool(*value != cryptoki_sys::CK_FALSE)),
+                _ => None,
+            };
+        }
+
+        if ULONG_ATTRIBUTES.contains(&attr_type) {
+            return read_ulong(bytes).map(Self::Ulong);
+        }
+
+        match attr_type {
+            CKA_KEY_GEN_MECHANISM => read_ulong(bytes).map(Self::MechanismType),
+            CKA_ALLOWED_MECHANISMS => {
+                let chunks = bytes.chunks_exact(ULONG_SIZE);
+                if !chunks.remainder().is_empty() {
+                    return None;
+                }
+                chunks
+                    .map(read_ulong)
+                    .collect::<Option<Vec<_>>>()
+                    .map(Self::MechanismList)
+            }
+            CKA_START_DATE | CKA_END_DATE => CkDate::from_bytes(bytes).map(Self::Date),
+            _ => Some(Self::Bytes(bytes.to_vec())),
+        }
+    }
+
+    // raw value of the attribute as expected by the application
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Bool(true) => vec![cryptoki_sys::CK_TRUE],
+            Self::Bool(false) => vec![cryptoki_sys::CK_FALSE],
+            Self::Ulong(v) | Self::MechanismType(v) => v.to_ne_bytes().to_vec(),
+            Self::Bytes(v) => v.clone(),
+            Self::MechanismList(list) => list.iter().flat_map(|m| m.to_ne_bytes()).collect(),
+            Self::Date(date) => date.to_bytes(),
+            Self::Sensitive => vec![],
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub enum ObjectKind {
     PrivateKey,
     PublicKey,
     SecretKey,
     Certificate,
+    // CKO_DATA, only kept by the module as session objects
+    Data,
+    #[default]
     Other,
 }
 
-impl Default for ObjectKind {
-    fn default() -> Self {
-        Self::Other
+impl ObjectKind {
+    pub fn ck_class(&self) -> Option<CK_OBJECT_CLASS> {
+        match self {
+            Self::PrivateKey => Some(cryptoki_sys::CKO_PRIVATE_KEY),
+            Self::PublicKey => Some(cryptoki_sys::CKO_PUBLIC_KEY),
+            Self::SecretKey => Some(cryptoki_sys::CKO_SECRET_KEY),
+            Self::Certificate => Some(cryptoki_sys::CKO_CERTIFICATE),
+            Self::Data => Some(cryptoki_sys::CKO_DATA),
+            Self::Other => None,
+        }
     }
 }
 
@@ -142,6 +350,7 @@ impl From<CK_OBJECT_CLASS> for ObjectKind {
             cryptoki_sys::CKO_PUBLIC_KEY => Self::PublicKey,
             cryptoki_sys::CKO_SECRET_KEY => Self::SecretKey,
             cryptoki_sys::CKO_CERTIFICATE => Self::Certificate,
+            cryptoki_sys::CKO_DATA => Self::Data,
             _ => Self::Other,
         }
     }
@@ -149,13 +358,18 @@ impl From<CK_OBJECT_CLASS> for ObjectKind {
 
 #[derive(Debug, Clone, Default)]
 pub struct Object {
-    attrs: HashMap<cryptoki_sys::CK_ATTRIBUTE_TYPE, Attr>,
+    attrs: HashMap<cryptoki_sys::CK_ATTRIBUTE_TYPE, Attribute>,
     pub kind: ObjectKind,
     pub id: String,
     pub size: Option<usize>, // the size of the object in bytes
     pub mechanisms: Vec<KeyMechanism>,
+    // the handle of the object this one was copied from with C_CopyObject
+    pub copied_from: Option<CK_OBJECT_HANDLE>,
+    // the session that created a session object, the object is destroyed with it
+    pub owner: Option<CK_SESSION_HANDLE>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct KeyPair {
     pub public_key: Object,
@@ -165,10 +379,34 @@ pub struct KeyPair {
 struct KeyData {
     key_type: CK_KEY_TYPE,
     key_size: Option<usize>,
-    attrs: HashMap<CK_ATTRIBUTE_TYPE, Attr>,
+    attrs: HashMap<CK_ATTRIBUTE_TYPE, Attribute>,
+}
+
+// The NetHSM only gives the modulus, with no key length. Its encoding may start with a zero
+// byte, only the bits from the highest one set count.
+fn rsa_modulus_bit_len(modulus_bytes: &[u8]) -> u32 {
+    match modulus_bytes.iter().position(|byte| *byte != 0) {
+        Some(start) => {
+            let bytes = (modulus_bytes.len() - start) as u32;
+            bytes * 8 - modulus_bytes[start].leading_zeros()
+        }
+        None => 0,
+    }
 }
 
 fn configure_rsa(key_data: &PublicKey) -> Result<KeyData, Error> {
+    // C_UnwrapKey decrypts the wrapped key with RSA-OAEP
+    let unwrap = key_data.mechanisms.iter().any(|mech| {
+        matches!(
+            mech,
+            KeyMechanism::RsaDecryptionOaepMd5
+                | KeyMechanism::RsaDecryptionOaepSha1
+                | KeyMechanism::RsaDecryptionOaepSha224
+                | KeyMechanism::RsaDecryptionOaepSha256
+                | KeyMechanism::RsaDecryptionOaepSha384
+                | KeyMechanism::RsaDecryptionOaepSha512
+        )
+    });
     let key_data = key_data
         .public
         .as_ref()
@@ -187,20 +425,18 @@ fn configure_rsa(key_data: &PublicKey) -> Result<KeyData, Error> {
 
     let mut attrs = HashMap::new();
 
-    let size = modulus.len();
-    attrs.insert(CKA_KEY_TYPE, Attr::from_ck_key_type(cryptoki_sys::CKK_RSA));
-    attrs.insert(CKA_DERIVE, Attr::CK_FALSE);
-    attrs.insert(CKA_DECRYPT, Attr::CK_TRUE);
-    attrs.insert(CKA_SIGN, Attr::CK_TRUE);
-    attrs.insert(CKA_SIGN_RECOVER, Attr::CK_FALSE);
-    attrs.insert(CKA_UNWRAP, Attr::CK_FALSE);
-    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attr::CK_FALSE);
-    attrs.insert(CKA_MODULUS, Attr::Bytes(modulus));
-    attrs.insert(CKA_PUBLIC_EXPONENT, Attr::Bytes(public_exponent));
-    attrs.insert(
-        CKA_MODULUS_BITS,
-        Attr::from_ck_ulong((size * 8) as CK_ULONG),
-    );
+    let bits = rsa_modulus_bit_len(&modulus);
+    let size = (bits as usize).div_ceil(8);
+    attrs.insert(CKA_KEY_TYPE, Attribute::Ulong(cryptoki_sys::CKK_RSA));
+    attrs.insert(CKA_DERIVE, Attribute::Bool(false));
+    attrs.insert(CKA_DECRYPT, Attribute::Bool(true));
+    attrs.insert(CKA_SIGN, Attribute::Bool(true));
+    attrs.insert(CKA_SIGN_RECOVER, Attribute::Bool(false));
+    attrs.insert(CKA_UNWRAP, Attribute::Bool(unwrap));
+    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attribute::Bool(false));
+    attrs.insert(CKA_MODULUS, Attribute::Bytes(modulus));
+    attrs.insert(CKA_PUBLIC_EXPONENT, Attribute::Bytes(public_exponent));
+    attrs.insert(CKA_MODULUS_BITS, Attribute::Ulong(bits as CK_ULONG));
 
     Ok(KeyData {
         key_type: cryptoki_sys::CKK_RSA,
@@ -251,15 +487,17 @@ fn configure_ec(key_data: &PublicKey) -> Result<KeyData, Error> {
 
     let mut attrs = HashMap::new();
 
-    attrs.insert(CKA_KEY_TYPE, Attr::from_ck_key_type(key_type));
-    attrs.insert(CKA_DERIVE, Attr::CK_TRUE);
-    attrs.insert(CKA_DECRYPT, Attr::CK_FALSE);
-    attrs.insert(CKA_SIGN, Attr::CK_TRUE);
-    attrs.insert(CKA_SIGN_RECOVER, Attr::CK_FALSE);
-    attrs.insert(CKA_UNWRAP, Attr::CK_FALSE);
-    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attr::CK_FALSE);
-    attrs.insert(CKA_EC_PARAMS, Attr::Bytes(ec_params));
-    attrs.insert(CKA_EC_POINT, Attr::Bytes(encoded_points));
+    attrs.insert(CKA_KEY_TYPE, Attribute::Ulong(key_type));
+    // the NetHSM has no ECDH mechanism and its Curve25519 keys are Ed25519 keys, none of the
+    // mechanisms of a key allows a derivation
+    attrs.insert(CKA_DERIVE, Attribute::Bool(false));
+    attrs.insert(CKA_DECRYPT, Attribute::Bool(false));
+    attrs.insert(CKA_SIGN, Attribute::Bool(true));
+    attrs.insert(CKA_SIGN_RECOVER, Attribute::Bool(false));
+    attrs.insert(CKA_UNWRAP, Attribute::Bool(false));
+    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attribute::Bool(false));
+    attrs.insert(CKA_EC_PARAMS, Attribute::Bytes(ec_params));
+    attrs.insert(CKA_EC_POINT, Attribute::Bytes(encoded_points));
 
     Ok(KeyData {
         key_type,
@@ -269,27 +507,29 @@ fn configure_ec(key_data: &PublicKey) -> Result<KeyData, Error> {
 }
 
 // should be an aes key ??
-fn configure_generic() -> Result<KeyData, Error> {
+fn configure_generic(key_data: &PublicKey) -> Result<KeyData, Error> {
+    // AES-CMAC, computed with the CBC encryption of the NetHSM
+    let cmac = key_data
+        .mechanisms
+        .contains(&KeyMechanism::AesEncryptionCbc);
     let mut attrs = HashMap::new();
 
-    attrs.insert(
-        CKA_CLASS,
-        Attr::from_ck_object_class(cryptoki_sys::CKO_SECRET_KEY),
-    );
+    attrs.insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_SECRET_KEY));
     attrs.insert(
         CKA_KEY_TYPE,
-        Attr::from_ck_key_type(cryptoki_sys::CKK_GENERIC_SECRET),
+        Attribute::Ulong(cryptoki_sys::CKK_GENERIC_SECRET),
     );
-    attrs.insert(CKA_DERIVE, Attr::CK_FALSE);
-    attrs.insert(CKA_DECRYPT, Attr::CK_TRUE);
-    attrs.insert(CKA_ENCRYPT, Attr::CK_TRUE);
-    attrs.insert(CKA_SIGN, Attr::CK_FALSE);
-    attrs.insert(CKA_SIGN_RECOVER, Attr::CK_FALSE);
-    attrs.insert(CKA_UNWRAP, Attr::CK_FALSE);
-    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attr::CK_FALSE);
-    attrs.insert(CKA_VALUE_LEN, Attr::from_ck_ulong(0));
-    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attr::CK_FALSE);
-    attrs.insert(CKA_VERIFY, Attr::CK_FALSE);
+    // no AES based key derivation on the NetHSM
+    attrs.insert(CKA_DERIVE, Attribute::Bool(false));
+    attrs.insert(CKA_DECRYPT, Attribute::Bool(true));
+    attrs.insert(CKA_ENCRYPT, Attribute::Bool(true));
+    attrs.insert(CKA_SIGN, Attribute::Bool(cmac));
+    attrs.insert(CKA_SIGN_RECOVER, Attribute::Bool(false));
+    attrs.insert(CKA_UNWRAP, Attribute::Bool(false));
+    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attribute::Bool(false));
+    // no CKA_VALUE_LEN, the NetHSM doesn't give the length of the key
+    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(false));
+    attrs.insert(CKA_VERIFY, Attribute::Bool(cmac));
 
     Ok(KeyData {
         key_type: cryptoki_sys::CKK_GENERIC_SECRET,
@@ -298,6 +538,62 @@ fn configure_generic() -> Result<KeyData, Error> {
     })
 }
 
+// CKA_UNIQUE_ID is a string, it is stored with a terminating null byte
+fn unique_id_attr(unique_id: &str) -> Attribute {
+    let mut bytes = unique_id.as_bytes().to_vec();
+    bytes.push(0);
+    Attribute::Bytes(bytes)
+}
+
+static UNIQUE_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A random UUID (RFC 4122 version 4) for the objects that only live in the module.
+// If the OS can't give random bytes, a counter still keeps the IDs unique in the process.
+pub fn session_unique_id() -> Attribute {
+    let mut bytes = [0u8; 16];
+    if let Err(err) = getrandom::getrandom(&mut bytes) {
+        error!("Failed to get random bytes for a unique ID: {}", err);
+        let counter = UNIQUE_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        bytes[..8].copy_from_slice(&time.to_be_bytes());
+        bytes[8..].copy_from_slice(&counter.to_be_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(bytes);
+    unique_id_attr(&format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+// The PKCS#11 mechanisms of a NetHSM key: the encryption and decryption of an AES key are both
+// CKM_AES_CBC, each mechanism is listed once. Only the mechanisms the token advertises are kept.
+// The AES keys that can encrypt also have CKM_AES_CMAC.
+fn allowed_mechanisms(mechanisms: &[KeyMechanism]) -> Vec<CK_MECHANISM_TYPE> {
+    let mut allowed = Vec::new();
+    for mechanism in mechanisms {
+        let ck_type = Mechanism::from(*mechanism).ck_type();
+        if !allowed.contains(&ck_type)
+            && MECHANISM_LIST.iter().any(|mech| mech.ck_type() == ck_type)
+        {
+            allowed.push(ck_type);
+        }
+    }
+    if mechanisms.contains(&KeyMechanism::AesEncryptionCbc) {
+        allowed.push(Mechanism::AesCmac.ck_type());
+    }
+    allowed
+}
+
 pub fn from_key_data(
     key_data: PublicKey,
     id: &str,
@@ -306,33 +602,31 @@ pub fn from_key_data(
     let mut attrs = HashMap::new();
 
     if let Some(raw_id) = raw_id {
-        attrs.insert(CKA_ID, Attr::Bytes(raw_id));
+        attrs.insert(CKA_ID, Attribute::Bytes(raw_id));
     } else {
-        attrs.insert(CKA_ID, Attr::Bytes(id.as_bytes().to_vec()));
+        attrs.insert(CKA_ID, Attribute::Bytes(id.as_bytes().to_vec()));
     }
 
-    attrs.insert(
-        CKA_CLASS,
-        Attr::from_ck_object_class(cryptoki_sys::CKO_PRIVATE_KEY),
-    );
-    attrs.insert(CKA_LABEL, Attr::Bytes(id.as_bytes().to_vec()));
+    attrs.insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_PRIVATE_KEY));
+    attrs.insert(CKA_LABEL, Attribute::Bytes(id.as_bytes().to_vec()));
     attrs.insert(
         CKA_KEY_GEN_MECHANISM,
-        Attr::from_ck_mechanism_type(CK_UNAVAILABLE_INFORMATION),
+        Attribute::MechanismType(CK_UNAVAILABLE_INFORMATION),
     );
-    attrs.insert(CKA_LOCAL, Attr::CK_FALSE);
-    attrs.insert(CKA_MODIFIABLE, Attr::CK_FALSE);
-    attrs.insert(CKA_TOKEN, Attr::CK_TRUE);
-    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attr::CK_FALSE);
-    attrs.insert(CKA_SENSITIVE, Attr::CK_TRUE);
-    attrs.insert(CKA_ALWAYS_SENSITIVE, Attr::CK_TRUE);
-    attrs.insert(CKA_EXTRACTABLE, Attr::CK_FALSE);
-    attrs.insert(CKA_NEVER_EXTRACTABLE, Attr::CK_TRUE);
-    attrs.insert(CKA_PRIVATE, Attr::CK_TRUE);
-    attrs.insert(CKA_VERIFY_RECOVER, Attr::CK_FALSE);
-    attrs.insert(CKA_VALUE, Attr::Bytes(vec![]));
-    attrs.insert(CKA_TRUSTED, Attr::CK_FALSE);
-    attrs.insert(CKA_WRAP, Attr::CK_FALSE);
+    attrs.insert(CKA_LOCAL, Attribute::Bool(false));
+    attrs.insert(CKA_MODIFIABLE, Attribute::Bool(false));
+    attrs.insert(CKA_TOKEN, Attribute::Bool(true));
+    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(false));
+    attrs.insert(CKA_SENSITIVE, Attribute::Bool(true));
+    attrs.insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(true));
+    attrs.insert(CKA_EXTRACTABLE, Attribute::Bool(false));
+    attrs.insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(true));
+    attrs.insert(CKA_PRIVATE, Attribute::Bool(true));
+    attrs.insert(CKA_VERIFY_RECOVER, Attribute::Bool(false));
+    attrs.insert(CKA_VALUE, Attribute::Bytes(vec![]));
+    attrs.insert(CKA_TRUSTED, Attribute::Bool(false));
+    attrs.insert(CKA_WRAP, Attribute::Bool(false));
+    attrs.insert(CKA_UNIQUE_ID, unique_id_attr(id));
 
     let key_attrs = match key_data.r#type {
         KeyType::Rsa => configure_rsa(&key_data)?,
@@ -341,20 +635,13 @@ pub fn from_key_data(
         | KeyType::EcP256
         | KeyType::EcP384
         | KeyType::EcP521 => configure_ec(&key_data)?,
-        KeyType::Generic => configure_generic()?,
+        KeyType::Generic => configure_generic(&key_data)?,
     };
     attrs.extend(key_attrs.attrs);
 
-    let ck_mech_list: Vec<CK_MECHANISM_TYPE> = key_data
-        .mechanisms
-        .iter()
-        .map(|mech| Mechanism::from(*mech))
-        .map(|m: Mechanism| m.ck_type())
-        .collect();
-
     attrs.insert(
         CKA_ALLOWED_MECHANISMS,
-        Attr::from_ck_mechanism_type_vec(ck_mech_list),
+        Attribute::MechanismList(allowed_mechanisms(&key_data.mechanisms)),
     );
 
     let private_key = Object {
@@ -363,6 +650,8 @@ pub fn from_key_data(
         id: id.to_string(),
         size: key_attrs.key_size,
         mechanisms: key_data.mechanisms.clone(),
+        copied_from: None,
+        owner: None,
     };
 
     if key_data.r#type == KeyType::Generic {
@@ -380,43 +669,168 @@ pub fn from_key_data(
         id: id.to_string(),
         size: key_attrs.key_size,
         mechanisms: vec![],
+        copied_from: None,
+        owner: None,
     };
 
     public_key
         .attrs
-        .insert(CKA_ALLOWED_MECHANISMS, Attr::Bytes(vec![]));
-    public_key.attrs.insert(
-        CKA_CLASS,
-        Attr::from_ck_object_class(cryptoki_sys::CKO_PUBLIC_KEY),
-    );
+        .insert(CKA_ALLOWED_MECHANISMS, Attribute::Bytes(vec![]));
+    public_key
+        .attrs
+        .insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_PUBLIC_KEY));
+    // the key IDs of the NetHSM are alphanumeric, the suffixes can't collide with another key
     public_key
         .attrs
-        .insert(CKA_KEY_TYPE, Attr::from_ck_key_type(key_attrs.key_type));
+        .insert(CKA_UNIQUE_ID, unique_id_attr(&format!("{}.pub", id)));
+    public_key
+        .attrs
+        .insert(CKA_KEY_TYPE, Attribute::Ulong(key_attrs.key_type));
 
-    public_key.attrs.insert(CKA_PRIVATE, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_SENSITIVE, Attr::CK_FALSE);
+    public_key.attrs.insert(CKA_PRIVATE, Attribute::Bool(false));
+    public_key
+        .attrs
+        .insert(CKA_SENSITIVE, Attribute::Bool(false));
     public_key
         .attrs
-        .insert(CKA_ALWAYS_SENSITIVE, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_EXTRACTABLE, Attr::CK_FALSE);
+        .insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(false));
+    // the public part is given out by the NetHSM, nothing protects it
     public_key
         .attrs
-        .insert(CKA_NEVER_EXTRACTABLE, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_DECRYPT, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_ENCRYPT, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_SIGN, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_VERIFY, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_DERIVE, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_SIGN_RECOVER, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_UNWRAP, Attr::CK_FALSE);
-    public_key.attrs.insert(CKA_WRAP, Attr::CK_FALSE);
+        .insert(CKA_EXTRACTABLE, Attribute::Bool(true));
     public_key
         .attrs
-        .insert(CKA_WRAP_WITH_TRUSTED, Attr::CK_FALSE);
+        .insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(false));
+    public_key.attrs.insert(CKA_DECRYPT, Attribute::Bool(false));
+    public_key.attrs.insert(CKA_ENCRYPT, Attribute::Bool(false));
+    public_key.attrs.insert(CKA_SIGN, Attribute::Bool(false));
+    // the module verifies the RSA-PSS signatures of the keys that sign with it
+    let verify = key_data.mechanisms.iter().any(|mechanism| {
+        matches!(
+            mechanism,
+            KeyMechanism::RsaSignaturePssMd5
+                | KeyMechanism::RsaSignaturePssSha1
+                | KeyMechanism::RsaSignaturePssSha224
+                | KeyMechanism::RsaSignaturePssSha256
+                | KeyMechanism::RsaSignaturePssSha384
+                | KeyMechanism::RsaSignaturePssSha512
+        )
+    });
+    public_key.attrs.insert(CKA_VERIFY, Attribute::Bool(verify));
+    public_key.attrs.insert(CKA_DERIVE, Attribute::Bool(false));
+    public_key
+        .attrs
+        .insert(CKA_SIGN_RECOVER, Attribute::Bool(false));
+    public_key.attrs.insert(CKA_UNWRAP, Attribute::Bool(false));
+    // the public key of an RSA key that unwraps with RSA-OAEP wraps with it
+    let wrap = key_data.r#type == KeyType::Rsa
+        && matches!(attrs.get(&CKA_UNWRAP), Some(Attribute::Bool(true)));
+    public_key.attrs.insert(CKA_WRAP, Attribute::Bool(wrap));
+    // C_VerifyRecover is done by the module with the RSA public keys
+    public_key.attrs.insert(
+        CKA_VERIFY_RECOVER,
+        Attribute::Bool(key_data.r#type == KeyType::Rsa),
+    );
+    public_key
+        .attrs
+        .insert(CKA_WRAP_WITH_TRUSTED, Attribute::Bool(false));
 
     Ok(vec![public_key, private_key])
 }
 
+// a secret generated by the module, it is not stored on the NetHSM and only lives in the slot database
+pub fn from_session_secret(id: &str, raw_id: Option<Vec<u8>>, value: Vec<u8>) -> Object {
+    let mut attrs = HashMap::new();
+
+    attrs.insert(
+        CKA_ID,
+        Attribute::Bytes(raw_id.unwrap_or_else(|| id.as_bytes().to_vec())),
+    );
+    attrs.insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_SECRET_KEY));
+    attrs.insert(
+        CKA_KEY_TYPE,
+        Attribute::Ulong(cryptoki_sys::CKK_GENERIC_SECRET),
+    );
+    attrs.insert(CKA_LABEL, Attribute::Bytes(id.as_bytes().to_vec()));
+    attrs.insert(
+        CKA_KEY_GEN_MECHANISM,
+        Attribute::MechanismType(cryptoki_sys::CKM_GENERIC_SECRET_KEY_GEN),
+    );
+    attrs.insert(CKA_LOCAL, Attribute::Bool(true));
+    attrs.insert(CKA_MODIFIABLE, Attribute::Bool(false));
+    attrs.insert(CKA_TOKEN, Attribute::Bool(false));
+    attrs.insert(CKA_PRIVATE, Attribute::Bool(true));
+    attrs.insert(CKA_SENSITIVE, Attribute::Bool(false));
+    attrs.insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(false));
+    attrs.insert(CKA_EXTRACTABLE, Attribute::Bool(true));
+    attrs.insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(false));
+    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(false));
+    attrs.insert(CKA_TRUSTED, Attribute::Bool(false));
+    attrs.insert(CKA_WRAP_WITH_TRUSTED, Attribute::Bool(false));
+    // the NetHSM can't use the secret, it can only be used by the module to verify HMACs
+    attrs.insert(CKA_ENCRYPT, Attribute::Bool(false));
+    attrs.insert(CKA_DECRYPT, Attribute::Bool(false));
+    attrs.insert(CKA_SIGN, Attribute::Bool(false));
+    attrs.insert(CKA_VERIFY, Attribute::Bool(true));
+    attrs.insert(CKA_DERIVE, Attribute::Bool(false));
+    attrs.insert(CKA_WRAP, Attribute::Bool(false));
+    attrs.insert(CKA_UNWRAP, Attribute::Bool(false));
+    attrs.insert(
+        CKA_ALLOWED_MECHANISMS,
+        Attribute::MechanismList(vec![
+            cryptoki_sys::CKM_MD5_HMAC,
+            cryptoki_sys::CKM_SHA_1_HMAC,
+            cryptoki_sys::CKM_SHA224_HMAC,
+            cryptoki_sys::CKM_SHA256_HMAC,
+            cryptoki_sys::CKM_SHA384_HMAC,
+            cryptoki_sys::CKM_SHA512_HMAC,
+        ]),
+    );
+    attrs.insert(CKA_VALUE_LEN, Attribute::Ulong(value.len() as CK_ULONG));
+    attrs.insert(CKA_UNIQUE_ID, session_unique_id());
+
+    let size = value.len();
+    attrs.insert(CKA_VALUE, Attribute::Bytes(value));
+
+    Object {
+        attrs,
+        kind: ObjectKind::SecretKey,
+        id: id.to_string(),
+        size: Some(size),
+        mechanisms: vec![],
+        copied_from: None,
+        owner: None,
+    }
+}
+
+// A data object without value, the attributes of the template are set over these defaults.
+// CKA_APPLICATION is empty when the application doesn't set it.
+pub fn from_session_data(id: &str) -> Object {
+    let mut attrs = HashMap::new();
+
+    attrs.insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_DATA));
+    attrs.insert(CKA_LABEL, Attribute::Bytes(vec![]));
+    attrs.insert(CKA_APPLICATION, Attribute::Bytes(vec![]));
+    attrs.insert(CKA_OBJECT_ID, Attribute::Bytes(vec![]));
+    attrs.insert(CKA_VALUE, Attribute::Bytes(vec![]));
+    attrs.insert(CKA_TOKEN, Attribute::Bool(false));
+    attrs.insert(CKA_PRIVATE, Attribute::Bool(true));
+    attrs.insert(CKA_MODIFIABLE, Attribute::Bool(true));
+    attrs.insert(CKA_COPYABLE, Attribute::Bool(true));
+    attrs.insert(CKA_DESTROYABLE, Attribute::Bool(true));
+    attrs.insert(CKA_UNIQUE_ID, session_unique_id());
+
+    Object {
+        attrs,
+        kind: ObjectKind::Data,
+        id: id.to_string(),
+        size: None,
+        mechanisms: vec![],
+        copied_from: None,
+        owner: None,
+    }
+}
+
 pub fn from_cert_data(
     cert: Vec<u8>,
     key_id: &str,
@@ -431,45 +845,43 @@ pub fn from_cert_data(
 
     let mut attrs = HashMap::new();
     if let Some(raw_id) = raw_id {
-        attrs.insert(CKA_ID, Attr::Bytes(raw_id));
+        attrs.insert(CKA_ID, Attribute::Bytes(raw_id));
     } else {
-        attrs.insert(CKA_ID, Attr::Bytes(key_id.as_bytes().to_vec()));
+        attrs.insert(CKA_ID, Attribute::Bytes(key_id.as_bytes().to_vec()));
     }
-    attrs.insert(
-        CKA_CLASS,
-        Attr::from_ck_object_class(cryptoki_sys::CKO_CERTIFICATE),
-    );
-    attrs.insert(CKA_LABEL, Attr::Bytes(key_id.as_bytes().to_vec()));
+    attrs.insert(CKA_CLASS, Attribute::Ulong(cryptoki_sys::CKO_CERTIFICATE));
+    attrs.insert(CKA_LABEL, Attribute::Bytes(key_id.as_bytes().to_vec()));
     attrs.insert(
         CKA_KEY_GEN_MECHANISM,
-        Attr::from_ck_mechanism_type(CK_UNAVAILABLE_INFORMATION),
+        Attribute::MechanismType(CK_UNAVAILABLE_INFORMATION),
     );
-    attrs.insert(CKA_LOCAL, Attr::CK_TRUE);
-    attrs.insert(CKA_MODIFIABLE, Attr::CK_FALSE);
-    attrs.insert(CKA_TOKEN, Attr::CK_TRUE);
-    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attr::CK_FALSE);
-    attrs.insert(CKA_SENSITIVE, Attr::CK_FALSE);
-    attrs.insert(CKA_ALWAYS_SENSITIVE, Attr::CK_FALSE);
-    attrs.insert(CKA_EXTRACTABLE, Attr::CK_FALSE);
-    attrs.insert(CKA_NEVER_EXTRACTABLE, Attr::CK_TRUE);
-    attrs.insert(CKA_PRIVATE, Attr::CK_FALSE);
+    attrs.insert(CKA_LOCAL, Attribute::Bool(true));
+    attrs.insert(CKA_MODIFIABLE, Attribute::Bool(false));
+    attrs.insert(CKA_TOKEN, Attribute::Bool(true));
+    attrs.insert(CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(false));
+    attrs.insert(CKA_SENSITIVE, Attribute::Bool(false));
+    attrs.insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(false));
+    attrs.insert(CKA_EXTRACTABLE, Attribute::Bool(false));
+    attrs.insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(true));
+    attrs.insert(CKA_PRIVATE, Attribute::Bool(false));
     attrs.insert(
         CKA_CERTIFICATE_TYPE,
-        Attr::from_ck_cert_type(cryptoki_sys::CKC_X_509),
+        Attribute::Ulong(cryptoki_sys::CKC_X_509),
     );
-    attrs.insert(CKA_VALUE, Attr::Bytes(cert_der));
-    attrs.insert(CKA_VALUE_LEN, Attr::from_ck_ulong(length as CK_ULONG));
+    attrs.insert(CKA_VALUE, Attribute::Bytes(cert_der));
+    attrs.insert(CKA_VALUE_LEN, Attribute::Ulong(length as CK_ULONG));
     attrs.insert(
         CKA_SUBJECT,
-        Attr::Bytes(cert.tbs_certificate.subject.to_der().map_err(Error::Der)?),
+        Attribute::Bytes(cert.tbs_certificate.subject.to_der().map_err(Error::Der)?),
     );
     attrs.insert(
         CKA_ISSUER,
-        Attr::Bytes(cert.tbs_certificate.issuer.to_der().map_err(Error::Der)?),
+        Attribute::Bytes(cert.tbs_certificate.issuer.to_der().map_err(Error::Der)?),
     );
-    attrs.insert(CKA_TRUSTED, Attr::CK_TRUE);
-    attrs.insert(CKA_CERTIFICATE_TYPE, Attr::from_ck_cert_type(CKC_X_509));
-    attrs.insert(CKA_CERTIFICATE_CATEGORY, Attr::from_ck_cert_category(0));
+    attrs.insert(CKA_TRUSTED, Attribute::Bool(true));
+    attrs.insert(CKA_CERTIFICATE_TYPE, Attribute::Ulong(CKC_X_509));
+    attrs.insert(CKA_UNIQUE_ID, unique_id_attr(&format!("{}.cert", key_id)));
+    attrs.insert(CKA_CERTIFICATE_CATEGORY, Attribute::Ulong(0));
 
     Ok(Object {
         attrs,
@@ -477,44 +889,770 @@ pub fn from_cert_data(
         id: key_id.to_owned(),
         size: Some(length),
         mechanisms: vec![],
+        copied_from: None,
+        owner: None,
     })
 }
 
 impl Object {
-    pub fn attr(&self, attr_type: cryptoki_sys::CK_ATTRIBUTE_TYPE) -> Option<&Attr> {
+    pub fn get_attribute(&self, attr_type: cryptoki_sys::CK_ATTRIBUTE_TYPE) -> Option<&Attribute> {
         self.attrs.get(&attr_type)
     }
 
+    // the value of a sensitive or unextractable key known to the module is never revealed
+    fn value_protected(&self) -> bool {
+        matches!(self.kind, ObjectKind::PrivateKey | ObjectKind::SecretKey)
+            && matches!(self.get_attribute(CKA_VALUE), Some(Attribute::Bytes(value)) if !value.is_empty())
+            && (matches!(
+                self.get_attribute(CKA_SENSITIVE),
+                Some(Attribute::Bool(true))
+            ) || matches!(
+                self.get_attribute(CKA_EXTRACTABLE),
+                Some(Attribute::Bool(false))
+            ))
+    }
+
+    pub fn is_private(&self) -> bool {
+        matches!(self.get_attribute(CKA_PRIVATE), Some(Attribute::Bool(true)))
+    }
+
+    // a key with CKA_WRAP_WITH_TRUSTED can only be wrapped with a key with CKA_TRUSTED
+    pub fn can_be_wrapped_with(&self, wrapping_key: &Object) -> bool {
+        !matches!(
+            self.get_attribute(CKA_WRAP_WITH_TRUSTED),
+            Some(Attribute::Bool(true))
+        ) || matches!(
+            wrapping_key.get_attribute(CKA_TRUSTED),
+            Some(Attribute::Bool(true))
+        )
+    }
+
+    pub fn is_token(&self) -> bool {
+        matches!(self.get_attribute(CKA_TOKEN), Some(Attribute::Bool(true)))
+    }
+
+    // CKA_COPYABLE defaults to true
+    pub fn is_copyable(&self) -> bool {
+        !matches!(
+            self.get_attribute(CKA_COPYABLE),
+            Some(Attribute::Bool(false))
+        )
+    }
+
+    // Sets the protection of a key when it is created. CKA_ALWAYS_SENSITIVE and
+    // CKA_NEVER_EXTRACTABLE keep the history, they are read-only afterwards.
+    pub fn set_initial_sensitivity(&mut self, sensitive: bool, extractable: bool) {
+        self.attrs.insert(CKA_SENSITIVE, Attribute::Bool(sensitive));
+        self.attrs
+            .insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(sensitive));
+        self.attrs
+            .insert(CKA_EXTRACTABLE, Attribute::Bool(extractable));
+        self.attrs
+            .insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(!extractable));
+    }
+
+    pub fn module_attributes(&self) -> Vec<(CK_ATTRIBUTE_TYPE, Attribute)> {
+        let wrap_attributes = match self.kind {
+            ObjectKind::SecretKey => &SECRET_KEY_WRAP_ATTRIBUTES[..],
+            _ => &[],
+        };
+        MODULE_ATTRIBUTES
+            .iter()
+            .chain(wrap_attributes)
+            .filter_map(|attr_type| Some((*attr_type, self.get_attribute(*attr_type)?.clone())))
+            .collect()
+    }
+
+    // the attributes that are new or changed compared to another version of the object
+    pub fn changed_attributes(&self, old: &Object) -> Vec<(CK_ATTRIBUTE_TYPE, Attribute)> {
+        self.attrs
+            .iter()
+            .filter(|(attr_type, attr)| old.attrs.get(attr_type) != Some(attr))
+            .map(|(attr_type, attr)| (*attr_type, attr.clone()))
+            .collect()
+    }
+
+    // the attributes are not checked, callers only set the ones they are allowed to change
+    pub fn set_attr(&mut self, attr_type: cryptoki_sys::CK_ATTRIBUTE_TYPE, attr: Attribute) {
+        self.attrs.insert(attr_type, attr);
+    }
+
+    // Applies the attributes of a template over the ones of the object, for C_CopyObject and
+    // C_SetAttributeValue. Nothing is changed if one of the attributes can't be applied.
+    pub fn merge_template(
+        &mut self,
+        template: &CkRawAttrTemplate,
+        overwrite_sensitive: bool,
+    ) -> Result<(), Error> {
+        let mut merged = Vec::with_capacity(template.len());
+
+        for raw_attr in template.iter() {
+            let attr_type = raw_attr.type_();
+            if READ_ONLY_ATTRIBUTES.contains(&attr_type)
+                || (!overwrite_sensitive && SENSITIVE_ATTRIBUTES.contains(&attr_type))
+            {
+                debug!("Tried to change the read-only attribute {:?}", attr_type);
+                return Err(Error::AttributeReadOnly(attr_type));
+            }
+
+            let bytes = match raw_attr.val_bytes() {
+                Some(bytes) => bytes,
+                None if raw_attr.len() == 0 => &[],
+                None => return Err(Error::InvalidAttribute(attr_type)),
+            };
+            let attr =
+                Attribute::from_raw(attr_type, bytes).ok_or(Error::InvalidAttribute(attr_type))?;
+            merged.push((attr_type, attr));
+        }
+
+        // a key that lost its protection once can't claim to have always had it
+        for (attr_type, attr) in merged.iter() {
+            match (*attr_type, attr) {
+                (CKA_SENSITIVE, Attribute::Bool(false)) => {
+                    self.attrs
+                        .insert(CKA_ALWAYS_SENSITIVE, Attribute::Bool(false));
+                }
+                (CKA_EXTRACTABLE, Attribute::Bool(true)) => {
+                    self.attrs
+                        .insert(CKA_NEVER_EXTRACTABLE, Attribute::Bool(false));
+                }
+                _ => {}
+            }
+        }
+
+        self.attrs.extend(merged);
+        Ok(())
+    }
+
+    // An attribute that exists but isn't revealed, the ones that don't exist for the key type,
+    // like the CRT components of an EC key or of a public key, are invalid instead.
+    fn attribute_sensitive(&self, attr_type: CK_ATTRIBUTE_TYPE) -> bool {
+        match self.kind {
+            ObjectKind::PrivateKey => match self.get_attribute(CKA_KEY_TYPE) {
+                Some(Attribute::Ulong(cryptoki_sys::CKK_EC | cryptoki_sys::CKK_EC_EDWARDS)) => {
+                    attr_type == CKA_VALUE
+                }
+                _ => PRIVATE_KEY_SECRET_ATTRIBUTES.contains(&attr_type),
+            },
+            _ => attr_type == CKA_VALUE && self.value_protected(),
+        }
+    }
+
+    // Every attribute of the template is filled, the ones that can't be are marked unavailable.
+    // When several attributes fail for different reasons, a sensitive attribute is reported
+    // first, then an invalid one, then a buffer too small.
     pub fn fill_attr_template(&self, tpl: &mut CkRawAttrTemplate) -> cryptoki_sys::CK_RV {
-        let mut rcode = cryptoki_sys::CKR_OK;
+        let mut sensitive = false;
+        let mut invalid = false;
+        let mut too_small = false;
 
         for mut raw_attr in tpl.iter() {
-            match self.attr(raw_attr.type_()) {
+            if self.attribute_sensitive(raw_attr.type_()) {
+                sensitive = true;
+                raw_attr.set_unavailable();
+                continue;
+            }
+
+            match self.get_attribute(raw_attr.type_()) {
                 Some(attr) => {
                     let sres = match attr {
-                        Attr::Sensitive => {
-                            rcode = cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE;
+                        Attribute::Sensitive => {
+                            sensitive = true;
                             raw_attr.set_unavailable();
                             continue;
                         }
-                        a => raw_attr.set_val_bytes(a.as_bytes()),
+                        a => raw_attr.set_val_bytes(&a.to_bytes()),
                     };
                     if matches!(sres, Err(attr::Error::BufTooSmall)) {
-                        rcode = cryptoki_sys::CKR_BUFFER_TOO_SMALL;
+                        too_small = true;
                         raw_attr.set_unavailable();
                     }
                 }
                 None => {
-                    rcode = cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID;
+                    invalid = true;
                     raw_attr.set_unavailable();
                 }
             };
             debug!(
-                "fill_attr_template: {:?} | code : {:?}",
+                "fill_attr_template: {:?} | sensitive: {}, invalid: {}, too small: {}",
                 raw_attr.type_(),
-                rcode
+                sensitive,
+                invalid,
+                too_small
+            );
+        }
+
+        if sensitive {
+            cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE
+        } else if invalid {
+            cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID
+        } else if too_small {
+            cryptoki_sys::CKR_BUFFER_TOO_SMALL
+        } else {
+            cryptoki_sys::CKR_OK
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bool_attribute() {
+        let attr = Attribute::from_raw(CKA_SIGN, &[cryptoki_sys::CK_TRUE]).unwrap();
+        assert_eq!(attr, Attribute::Bool(true));
+        assert_eq!(attr.to_bytes(), vec![cryptoki_sys::CK_TRUE]);
+
+        let attr = Attribute::from_raw(CKA_TOKEN, &[cryptoki_sys::CK_FALSE]).unwrap();
+        assert_eq!(attr, Attribute::Bool(false));
+        assert_eq!(attr.to_bytes(), vec![cryptoki_sys::CK_FALSE]);
+
+        assert_eq!(Attribute::from_raw(CKA_SIGN, &[1, 0]), None);
+    }
+
+    #[test]
+    fn test_ulong_attribute() {
+        let raw = cryptoki_sys::CKO_SECRET_KEY.to_ne_bytes();
+        let attr = Attribute::from_raw(CKA_CLASS, &raw).unwrap();
+        assert_eq!(attr, Attribute::Ulong(cryptoki_sys::CKO_SECRET_KEY));
+        assert_eq!(attr.to_bytes(), raw.to_vec());
+
+        assert_eq!(Attribute::from_raw(CKA_VALUE_LEN, &[1]), None);
+    }
+
+    #[test]
+    fn test_bytes_attribute() {
+        let attr = Attribute::from_raw(CKA_LABEL, b"label").unwrap();
+        assert_eq!(attr, Attribute::Bytes(b"label".to_vec()));
+        assert_eq!(attr.to_bytes(), b"label".to_vec());
+    }
+
+    #[test]
+    fn test_mechanism_type_attribute() {
+        let raw = cryptoki_sys::CKM_AES_KEY_GEN.to_ne_bytes();
+        let attr = Attribute::from_raw(CKA_KEY_GEN_MECHANISM, &raw).unwrap();
+        assert_eq!(
+            attr,
+            Attribute::MechanismType(cryptoki_sys::CKM_AES_KEY_GEN)
+        );
+        assert_eq!(attr.to_bytes(), raw.to_vec());
+    }
+
+    #[test]
+    fn test_mechanism_list_attribute() {
+        let list = vec![cryptoki_sys::CKM_RSA_PKCS, cryptoki_sys::CKM_ECDSA];
+        let raw: Vec<u8> = list.iter().flat_map(|m| m.to_ne_bytes()).collect();
+        let attr = Attribute::from_raw(CKA_ALLOWED_MECHANISMS, &raw).unwrap();
+        assert_eq!(attr, Attribute::MechanismList(list));
+        assert_eq!(attr.to_bytes(), raw);
+
+        assert_eq!(
+            Attribute::from_raw(CKA_ALLOWED_MECHANISMS, &raw[..raw.len() - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_date_attribute() {
+        let attr = Attribute::from_raw(CKA_START_DATE, b"20240131").unwrap();
+        assert_eq!(
+            attr,
+            Attribute::Date(CkDate {
+                year: *b"2024",
+                month: *b"01",
+                day: *b"31",
+            })
+        );
+        assert_eq!(attr.to_bytes(), b"20240131".to_vec());
+
+        assert_eq!(Attribute::from_raw(CKA_END_DATE, b"2024-1-31"), None);
+        assert_eq!(Attribute::from_raw(CKA_END_DATE, b"2024013a"), None);
+    }
+
+    fn bool_attr(attr_type: CK_ATTRIBUTE_TYPE, value: &mut u8) -> cryptoki_sys::CK_ATTRIBUTE {
+        cryptoki_sys::CK_ATTRIBUTE {
+            type_: attr_type,
+            pValue: value as *mut u8 as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: 1,
+        }
+    }
+
+    fn merge(
+        object: &mut Object,
+        template: &mut [cryptoki_sys::CK_ATTRIBUTE],
+        overwrite_sensitive: bool,
+    ) -> Result<(), Error> {
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) }
+                .unwrap();
+        object.merge_template(&template, overwrite_sensitive)
+    }
+
+    #[test]
+    fn test_merge_template() {
+        let mut object = Object::default();
+        object.set_attr(CKA_TOKEN, Attribute::Bool(true));
+
+        let mut token = cryptoki_sys::CK_FALSE;
+        let mut label = b"label".to_vec();
+        let mut template = [
+            bool_attr(CKA_TOKEN, &mut token),
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_LABEL,
+                pValue: label.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: label.len() as CK_ULONG,
+            },
+        ];
+        merge(&mut object, &mut template, false).unwrap();
+
+        assert_eq!(
+            object.get_attribute(CKA_TOKEN),
+            Some(&Attribute::Bool(false))
+        );
+        assert_eq!(
+            object.get_attribute(CKA_LABEL),
+            Some(&Attribute::Bytes(b"label".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_merge_template_read_only() {
+        let mut object = Object::default();
+        let mut class = cryptoki_sys::CKO_SECRET_KEY.to_ne_bytes();
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_CLASS,
+            pValue: class.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: class.len() as CK_ULONG,
+        }];
+
+        // even the SO can't change the class of an object
+        assert!(matches!(
+            merge(&mut object, &mut template, true),
+            Err(Error::AttributeReadOnly(CKA_CLASS))
+        ));
+        assert!(object.get_attribute(CKA_CLASS).is_none());
+    }
+
+    #[test]
+    fn test_merge_template_sensitive() {
+        for attr_type in [CKA_SENSITIVE, CKA_EXTRACTABLE, CKA_WRAP, CKA_UNWRAP] {
+            let mut object = Object::default();
+            let mut derive = cryptoki_sys::CK_TRUE;
+            let mut value = cryptoki_sys::CK_TRUE;
+            // the attribute applied before the rejected one is not kept either
+            let mut template = [
+                bool_attr(CKA_DERIVE, &mut derive),
+                bool_attr(attr_type, &mut value),
+            ];
+
+            assert!(matches!(
+                merge(&mut object, &mut template, false),
+                Err(Error::AttributeReadOnly(t)) if t == attr_type
+            ));
+            assert!(object.get_attribute(CKA_DERIVE).is_none());
+
+            merge(&mut object, &mut template, true).unwrap();
+            assert_eq!(
+                object.get_attribute(attr_type),
+                Some(&Attribute::Bool(true))
+            );
+        }
+    }
+
+    #[test]
+    fn test_sensitivity_history() {
+        // the keys of the NetHSM never leave it
+        let key_data = PublicKey::new(
+            vec![nethsm_sdk_rs::models::KeyMechanism::AesDecryptionCbc],
+            KeyType::Generic,
+            nethsm_sdk_rs::models::KeyRestrictions::new(),
+            0,
+        );
+        let mut objects = from_key_data(key_data, "aes", None).unwrap();
+        let key = &mut objects[0];
+        assert_eq!(
+            key.get_attribute(CKA_ALWAYS_SENSITIVE),
+            Some(&Attribute::Bool(true))
+        );
+        assert_eq!(
+            key.get_attribute(CKA_NEVER_EXTRACTABLE),
+            Some(&Attribute::Bool(true))
+        );
+
+        // the history can't be rewritten, even by the SO
+        for attr_type in [CKA_ALWAYS_SENSITIVE, CKA_NEVER_EXTRACTABLE] {
+            let mut value = cryptoki_sys::CK_FALSE;
+            let mut template = [bool_attr(attr_type, &mut value)];
+            assert!(matches!(
+                merge(key, &mut template, true),
+                Err(Error::AttributeReadOnly(t)) if t == attr_type
+            ));
+        }
+
+        // once the protection is removed, it was not always there
+        let mut object = from_session_secret("secret", None, vec![1; 16]);
+        object.set_initial_sensitivity(true, false);
+        let mut sensitive = cryptoki_sys::CK_FALSE;
+        let mut extractable = cryptoki_sys::CK_TRUE;
+        let mut template = [
+            bool_attr(CKA_SENSITIVE, &mut sensitive),
+            bool_attr(CKA_EXTRACTABLE, &mut extractable),
+        ];
+        merge(&mut object, &mut template, true).unwrap();
+        assert_eq!(
+            object.get_attribute(CKA_ALWAYS_SENSITIVE),
+            Some(&Attribute::Bool(false))
+        );
+        assert_eq!(
+            object.get_attribute(CKA_NEVER_EXTRACTABLE),
+            Some(&Attribute::Bool(false))
+        );
+    }
+
+    #[test]
+    fn test_generic_key_sign() {
+        // the CMAC of a key of the NetHSM needs its CBC encryption
+        for (mechanism, cmac) in [
+            (KeyMechanism::AesEncryptionCbc, true),
+            (KeyMechanism::AesDecryptionCbc, false),
+        ] {
+            let key_data = PublicKey::new(vec![mechanism], KeyType::Generic, Default::default(), 0);
+            let key = &from_key_data(key_data, "aes", None).unwrap()[0];
+            assert_eq!(key.get_attribute(CKA_SIGN), Some(&Attribute::Bool(cmac)));
+            assert_eq!(key.get_attribute(CKA_VERIFY), Some(&Attribute::Bool(cmac)));
+        }
+    }
+
+    #[test]
+    fn test_unique_id() {
+        let first = from_session_secret("first", None, vec![1; 16]);
+        let second = from_session_secret("second", None, vec![1; 16]);
+
+        let unique_id = |object: &Object| match object.get_attribute(CKA_UNIQUE_ID) {
+            Some(Attribute::Bytes(bytes)) => bytes.clone(),
+            other => panic!("unexpected unique ID {:?}", other),
+        };
+        assert_ne!(unique_id(&first), unique_id(&second));
+
+        // a null-terminated version 4 UUID
+        for object in [&first, &second] {
+            let bytes = unique_id(object);
+            assert_eq!(bytes.last(), Some(&0));
+            let uuid = std::str::from_utf8(&bytes[..bytes.len() - 1]).unwrap();
+            assert_eq!(uuid.len(), 36);
+            for (i, c) in uuid.chars().enumerate() {
+                match i {
+                    8 | 13 | 18 | 23 => assert_eq!(c, '-'),
+                    14 => assert_eq!(c, '4'),
+                    19 => assert!(matches!(c, '8' | '9' | 'a' | 'b')),
+                    _ => assert!(c.is_ascii_hexdigit()),
+                }
+            }
+        }
+
+        // the keys of the NetHSM are identified by their ID
+        let key_data = PublicKey::new(vec![], KeyType::Generic, Default::default(), 0);
+        let key = &from_key_data(key_data, "aes", None).unwrap()[0];
+        assert_eq!(unique_id(key), b"aes\0".to_vec());
+
+        let mut object = first.clone();
+        let mut value = *b"id";
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_UNIQUE_ID,
+            pValue: value.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: value.len() as CK_ULONG,
+        }];
+        assert!(matches!(
+            merge(&mut object, &mut template, true),
+            Err(Error::AttributeReadOnly(CKA_UNIQUE_ID))
+        ));
+    }
+
+    #[test]
+    fn test_protected_value() {
+        let mut value = [0u8; 16];
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_VALUE,
+            pValue: value.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: value.len() as CK_ULONG,
+        }];
+        let mut fill = |object: &Object| {
+            let mut template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) }
+                    .unwrap();
+            object.fill_attr_template(&mut template)
+        };
+
+        let mut object = from_session_secret("secret", None, vec![1; 16]);
+        assert_eq!(fill(&object), cryptoki_sys::CKR_OK);
+
+        object.set_initial_sensitivity(true, true);
+        assert_eq!(fill(&object), cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE);
+
+        object.set_initial_sensitivity(false, false);
+        assert_eq!(fill(&object), cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE);
+    }
+
+    #[test]
+    fn test_private_key_secret_attributes() {
+        let mut object = Object {
+            kind: ObjectKind::PrivateKey,
+            ..Default::default()
+        };
+        object
+            .attrs
+            .insert(CKA_LABEL, Attribute::Bytes(b"key".to_vec()));
+        object
+            .attrs
+            .insert(CKA_KEY_TYPE, Attribute::Ulong(cryptoki_sys::CKK_RSA));
+
+        let fill = |types: &[CK_ATTRIBUTE_TYPE], len: usize| {
+            let mut values = vec![[0u8; 16]; types.len()];
+            let mut template: Vec<cryptoki_sys::CK_ATTRIBUTE> = types
+                .iter()
+                .zip(values.iter_mut())
+                .map(|(type_, value)| cryptoki_sys::CK_ATTRIBUTE {
+                    type_: *type_,
+                    pValue: value.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                    ulValueLen: len as CK_ULONG,
+                })
+                .collect();
+            let rv = {
+                let mut raw = unsafe {
+                    CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len())
+                }
+                .unwrap();
+                object.fill_attr_template(&mut raw)
+            };
+            (
+                rv,
+                template.iter().map(|a| a.ulValueLen).collect::<Vec<_>>(),
+            )
+        };
+
+        for attr_type in PRIVATE_KEY_SECRET_ATTRIBUTES {
+            let (rv, lens) = fill(&[attr_type], 16);
+            assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE);
+            assert_eq!(lens, [CK_UNAVAILABLE_INFORMATION]);
+        }
+
+        // an RSA private key has no CKA_VALUE
+        let (rv, lens) = fill(&[CKA_VALUE], 16);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID);
+        assert_eq!(lens, [CK_UNAVAILABLE_INFORMATION]);
+
+        // the other attributes are still filled
+        let (rv, lens) = fill(&[CKA_LABEL, CKA_PRIME_1], 16);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE);
+        assert_eq!(lens, [3, CK_UNAVAILABLE_INFORMATION]);
+
+        let (rv, lens) = fill(&[CKA_LABEL, CKA_SUBJECT], 16);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID);
+        assert_eq!(lens, [3, CK_UNAVAILABLE_INFORMATION]);
+
+        // a sensitive attribute is reported before an invalid one or a small buffer,
+        // whatever their order
+        let (rv, _) = fill(&[CKA_SUBJECT, CKA_PRIME_1, CKA_LABEL], 1);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE);
+        let (rv, _) = fill(&[CKA_LABEL, CKA_SUBJECT], 1);
+        assert_eq!(rv, cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID);
+        let (rv, _) = fill(&[CKA_LABEL], 1);
+        assert_eq!(rv, cryptoki_sys::CKR_BUFFER_TOO_SMALL);
+    }
+
+    #[test]
+    fn test_allowed_mechanisms() {
+        assert_eq!(
+            allowed_mechanisms(&[
+                KeyMechanism::AesEncryptionCbc,
+                KeyMechanism::AesDecryptionCbc
+            ]),
+            vec![cryptoki_sys::CKM_AES_CBC, cryptoki_sys::CKM_AES_CMAC]
+        );
+        assert_eq!(
+            allowed_mechanisms(&[
+                KeyMechanism::RsaSignaturePkcs1,
+                KeyMechanism::RsaDecryptionOaepSha256,
+                KeyMechanism::RsaDecryptionPkcs1,
+                KeyMechanism::RsaDecryptionOaepSha1,
+            ]),
+            vec![cryptoki_sys::CKM_RSA_PKCS, cryptoki_sys::CKM_RSA_PKCS_OAEP]
+        );
+        assert!(allowed_mechanisms(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_rsa_modulus_bit_len() {
+        assert_eq!(rsa_modulus_bit_len(&[0xc5; 256]), 2048);
+        let mut padded = vec![0; 513];
+        padded[1..].fill(0xff);
+        assert_eq!(rsa_modulus_bit_len(&padded), 4096);
+        // a modulus whose highest byte has leading 0 bits
+        let mut short = vec![0xff; 256];
+        short[0] = 0x01;
+        assert_eq!(rsa_modulus_bit_len(&short), 2041);
+        assert_eq!(rsa_modulus_bit_len(&[0, 0]), 0);
+        assert_eq!(rsa_modulus_bit_len(&[]), 0);
+    }
+
+    #[test]
+    fn test_rsa_modulus_bits_attribute() {
+        let rsa_key = |modulus: &[u8]| {
+            let mut public_data = nethsm_sdk_rs::models::KeyPublicData::new();
+            public_data.modulus = Some(Base64::encode_string(modulus));
+            public_data.public_exponent = Some("AQAB".to_string());
+            let mut key_data = PublicKey::new(
+                vec![nethsm_sdk_rs::models::KeyMechanism::RsaSignaturePkcs1],
+                KeyType::Rsa,
+                nethsm_sdk_rs::models::KeyRestrictions::new(),
+                0,
+            );
+            key_data.public = Some(Box::new(public_data));
+            from_key_data(key_data, "rsa", None).unwrap()
+        };
+
+        let mut padded = vec![0; 513];
+        padded[1..].fill(0xff);
+        for (modulus, bits, size) in [(vec![0xc5; 256], 2048, 256), (padded, 4096, 512)] {
+            for object in rsa_key(&modulus) {
+                assert_eq!(
+                    object.get_attribute(CKA_MODULUS_BITS),
+                    Some(&Attribute::Ulong(bits))
+                );
+                assert_eq!(object.size, Some(size));
+                // the modulus is returned as the NetHSM encoded it
+                assert_eq!(
+                    object.get_attribute(CKA_MODULUS),
+                    Some(&Attribute::Bytes(modulus.clone()))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rsa_secret_attributes() {
+        let mut public_data = nethsm_sdk_rs::models::KeyPublicData::new();
+        public_data.modulus = Some("AQAB".to_string());
+        public_data.public_exponent = Some("AQAB".to_string());
+        let mut key_data = PublicKey::new(
+            vec![nethsm_sdk_rs::models::KeyMechanism::RsaSignaturePkcs1],
+            KeyType::Rsa,
+            nethsm_sdk_rs::models::KeyRestrictions::new(),
+            0,
+        );
+        key_data.public = Some(Box::new(public_data));
+        let objects = from_key_data(key_data, "rsa", None).unwrap();
+        let find = |kind| objects.iter().find(|o| o.kind == kind).unwrap();
+        let (private_key, public_key) = (find(ObjectKind::PrivateKey), find(ObjectKind::PublicKey));
+
+        let fill = |object: &Object, attr_type| {
+            let mut value = [0u8; 16];
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: attr_type,
+                pValue: value.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: value.len() as CK_ULONG,
+            }];
+            let mut raw =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+            let rv = object.fill_attr_template(&mut raw);
+            (rv, template[0].ulValueLen)
+        };
+
+        // the private key has them but doesn't reveal them, the public key doesn't have them
+        for attr_type in [
+            CKA_PRIVATE_EXPONENT,
+            CKA_PRIME_1,
+            CKA_PRIME_2,
+            CKA_EXPONENT_1,
+            CKA_EXPONENT_2,
+            CKA_COEFFICIENT,
+        ] {
+            assert_eq!(
+                fill(private_key, attr_type),
+                (
+                    cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE,
+                    CK_UNAVAILABLE_INFORMATION
+                )
+            );
+            assert_eq!(
+                fill(public_key, attr_type),
+                (
+                    cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID,
+                    CK_UNAVAILABLE_INFORMATION
+                )
             );
         }
-        rcode
+        assert_eq!(fill(public_key, CKA_MODULUS), (cryptoki_sys::CKR_OK, 3));
+
+        // an EC key only has a secret value
+        let mut ec_key = private_key.clone();
+        ec_key
+            .attrs
+            .insert(CKA_KEY_TYPE, Attribute::Ulong(cryptoki_sys::CKK_EC));
+        ec_key.attrs.remove(&CKA_MODULUS);
+        assert_eq!(
+            fill(&ec_key, CKA_VALUE),
+            (
+                cryptoki_sys::CKR_ATTRIBUTE_SENSITIVE,
+                CK_UNAVAILABLE_INFORMATION
+            )
+        );
+        assert_eq!(
+            fill(&ec_key, CKA_PRIME_1),
+            (
+                cryptoki_sys::CKR_ATTRIBUTE_TYPE_INVALID,
+                CK_UNAVAILABLE_INFORMATION
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_template_invalid_value() {
+        let mut object = Object::default();
+        let mut value = [cryptoki_sys::CK_TRUE; 2];
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_TOKEN,
+            pValue: value.as_mut_ptr() as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: value.len() as CK_ULONG,
+        }];
+
+        assert!(matches!(
+            merge(&mut object, &mut template, false),
+            Err(Error::InvalidAttribute(CKA_TOKEN))
+        ));
+    }
+
+    #[test]
+    fn test_sensitive_attribute() {
+        assert!(Attribute::Sensitive.to_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_tag_attributes_from_template() {
+        let tags = TagAttributes::new(vec!["prod".into(), "team-a".into()], Some(0x300));
+        assert_eq!(tags.tag(CKA_VENDOR_DEFINED | 0x301), Some("team-a"));
+        assert_eq!(tags.tag(0x301), None);
+        assert_eq!(tags.tag(CKA_VENDOR_DEFINED | 0x302), None);
+
+        let mut value = b"team-a".to_vec();
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_VENDOR_DEFINED | 0x301,
+            pValue: value.as_mut_ptr() as _,
+            ulValueLen: value.len() as _,
+        }];
+        let raw = unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        assert_eq!(tags.tags_from_template(&raw).unwrap(), vec!["team-a"]);
+
+        // the value must be the name of the tag
+        template[0].type_ = CKA_VENDOR_DEFINED | 0x300;
+        let raw = unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        assert!(tags.tags_from_template(&raw).is_err());
+
+        assert!(!tags.overlaps_usage_count());
+        assert!(
+            TagAttributes::new(vec!["prod".into(), "team-a".into()], Some(0xff))
+                .overlaps_usage_count()
+        );
     }
 }
@@ -1,20 +1,55 @@
 use base64ct::{Base64, Encoding};
-use log::trace;
+use cryptoki_sys::CKA_ALWAYS_AUTHENTICATE;
+use log::{debug, trace};
 use nethsm_sdk_rs::apis::default_api;
 
 use super::{
-    db::Object,
+    db::{object::Attribute, Object},
+    encrypt::ENCRYPT_BLOCK_SIZE,
     login::{self, LoginCtx},
     mechanism::{MechMode, Mechanism},
     Error,
 };
 
+// estimation of the length of a decrypted output, it can be bigger than the real length but never smaller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecryptedDataLen(pub usize);
+
+impl DecryptedDataLen {
+    // with RSA the ciphertext has the length of the modulus.
+    // None if the mechanism can't be used to decrypt
+    pub fn estimate_from_mechanism_and_ciphertext_len(
+        mechanism: &Mechanism,
+        ciphertext_len: usize,
+    ) -> Option<Self> {
+        let len = match mechanism {
+            // no padding with the NetHSM, the plaintext has the size of the ciphertext
+            Mechanism::AesCbc(_) | Mechanism::RsaX509 => ciphertext_len,
+            // PKCS#1 v1.5 padding is at least 11 bytes
+            Mechanism::RsaPkcs(_) => ciphertext_len.saturating_sub(11),
+            // OAEP padding is 2 * hash length + 2 bytes
+            Mechanism::RsaPkcsOaep(digest) => {
+                ciphertext_len.saturating_sub(2 * digest.output_size() + 2)
+            }
+            _ => return None,
+        };
+        Some(Self(len))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DecryptCtx {
     pub mechanism: Mechanism,
     pub key_id: String,
     pub data: Vec<u8>,
     login_ctx: LoginCtx,
+    // some complete blocks were already decrypted by decrypt_available_data
+    parts_decrypted: bool,
+    // plaintext of a C_DecryptFinal that returned CKR_BUFFER_TOO_SMALL, given back on the retry
+    pub pending_output: Option<Vec<u8>>,
+    // like for signing, a key with CKA_ALWAYS_AUTHENTICATE needs a CKU_CONTEXT_SPECIFIC login
+    pub requires_context_login: bool,
+    pub context_logged_in: bool,
 }
 
 impl DecryptCtx {
@@ -43,18 +78,117 @@ impl DecryptCtx {
             key_id: key.id.clone(),
             data: Vec::new(),
             login_ctx,
+            parts_decrypted: false,
+            pending_output: None,
+            requires_context_login: matches!(
+                key.get_attribute(CKA_ALWAYS_AUTHENTICATE),
+                Some(Attribute::Bool(true))
+            ),
+            context_logged_in: false,
         })
     }
+
+    pub fn output_len(&self, ciphertext_len: usize) -> Result<usize, Error> {
+        DecryptedDataLen::estimate_from_mechanism_and_ciphertext_len(
+            &self.mechanism,
+            ciphertext_len,
+        )
+        .map(|len| len.0)
+        .ok_or_else(|| Error::InvalidMechanismMode(MechMode::Decrypt, self.mechanism.clone()))
+    }
+
     pub fn update(&mut self, data: &[u8]) {
         self.data.extend_from_slice(data);
     }
 
+    // Length of the output of decrypt_available_data once the data is added. Only AES-CBC
+    // without padding can be decrypted before all the ciphertext is known.
+    pub fn available_len(&self, data_len: usize) -> Result<usize, Error> {
+        match self.mechanism {
+            Mechanism::AesCbc(_) => {
+                Ok((self.data.len() + data_len) / ENCRYPT_BLOCK_SIZE * ENCRYPT_BLOCK_SIZE)
+            }
+            _ => Err(Error::InvalidMechanismMode(
+                MechMode::Decrypt,
+                self.mechanism.clone(),
+            )),
+        }
+    }
+
+    // Length of the output of decrypt_streamed once the data is added. The last block is
+    // always kept for decrypt_final, a padding would have to be removed from it.
+    pub fn streamed_len(&self, data_len: usize) -> usize {
+        match self.mechanism {
+            Mechanism::AesCbc(_) => {
+                (self.data.len() + data_len).saturating_sub(1) / ENCRYPT_BLOCK_SIZE
+                    * ENCRYPT_BLOCK_SIZE
+            }
+            _ => 0,
+        }
+    }
+
+    // Decrypts the blocks that can't be the last one, for C_DecryptUpdate: what is buffered
+    // stays under two blocks.
+    pub fn decrypt_streamed(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.streamed_len(0);
+        self.decrypt_blocks(len)
+    }
+
+    // Decrypts the complete blocks received, for C_DecryptVerifyUpdate that needs the
+    // plaintext of every part. Only the last incomplete block stays buffered.
+    pub fn decrypt_available_data(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.available_len(0)?;
+        self.decrypt_blocks(len)
+    }
+
+    fn decrypt_blocks(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let chunk = self.take_blocks(len);
+        let output = self.decrypt_data(&chunk)?;
+        self.chain_iv(&chunk);
+        self.parts_decrypted = true;
+
+        Ok(output)
+    }
+
+    fn take_blocks(&mut self, len: usize) -> Vec<u8> {
+        self.data.drain(..len).collect()
+    }
+
+    // with CBC the next blocks are decrypted with the last ciphertext block as IV
+    fn chain_iv(&mut self, ciphertext: &[u8]) {
+        if let Mechanism::AesCbc(ref mut iv) = self.mechanism {
+            if ciphertext.len() >= ENCRYPT_BLOCK_SIZE {
+                let mut last_block = [0; ENCRYPT_BLOCK_SIZE];
+                last_block.copy_from_slice(&ciphertext[ciphertext.len() - ENCRYPT_BLOCK_SIZE..]);
+                *iv = Some(last_block);
+            }
+        }
+    }
+
     pub fn decrypt_final(&mut self) -> Result<Vec<u8>, Error> {
         if self.data.is_empty() {
+            // everything was decrypted by decrypt_available_data
+            if self.parts_decrypted {
+                return Ok(Vec::new());
+            }
             return Err(Error::InvalidEncryptedDataLength);
         }
 
-        let b64_message = Base64::encode_string(self.data.as_slice());
+        let data = std::mem::take(&mut self.data);
+        self.decrypt_data(&data)
+    }
+
+    fn decrypt_data(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.requires_context_login && !self.context_logged_in {
+            debug!("The key {} needs a context specific login", self.key_id);
+            return Err(Error::NotLoggedIn(login::UserMode::Operator));
+        }
+
+        let b64_message = Base64::encode_string(data);
 
         let mode = self
             .mechanism
@@ -90,3 +224,85 @@ impl DecryptCtx {
         Ok(Base64::decode_vec(&output.entity.decrypted)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::mechanism::MechDigest;
+
+    use super::*;
+
+    fn aes_ctx() -> DecryptCtx {
+        DecryptCtx {
+            mechanism: Mechanism::AesCbc(Some([0; ENCRYPT_BLOCK_SIZE])),
+            key_id: "aes".to_string(),
+            data: Vec::new(),
+            login_ctx: LoginCtx::new(None, None, vec![], None),
+            parts_decrypted: false,
+            pending_output: None,
+            requires_context_login: false,
+            context_logged_in: false,
+        }
+    }
+
+    #[test]
+    fn test_complete_blocks() {
+        let mut ctx = aes_ctx();
+        ctx.update(&[1; 2 * ENCRYPT_BLOCK_SIZE + 5]);
+        assert_eq!(ctx.available_len(11).unwrap(), 3 * ENCRYPT_BLOCK_SIZE);
+
+        let chunk = ctx.take_blocks(ctx.available_len(0).unwrap());
+        assert_eq!(chunk.len(), 2 * ENCRYPT_BLOCK_SIZE);
+        assert_eq!(ctx.data.len(), 5);
+
+        // the last block is kept, even when it is complete
+        assert_eq!(ctx.streamed_len(0), 0);
+        assert_eq!(ctx.streamed_len(11), 0);
+        assert_eq!(ctx.streamed_len(12), ENCRYPT_BLOCK_SIZE);
+        assert!(ctx.decrypt_streamed().unwrap().is_empty());
+
+        // nothing was decrypted yet, an empty ciphertext is still an error
+        let mut ctx = aes_ctx();
+        assert!(ctx.decrypt_available_data().unwrap().is_empty());
+        assert!(matches!(
+            ctx.decrypt_final(),
+            Err(Error::InvalidEncryptedDataLength)
+        ));
+
+        let mut ctx = aes_ctx();
+        ctx.mechanism = Mechanism::RsaX509;
+        assert!(ctx.available_len(256).is_err());
+        assert_eq!(ctx.streamed_len(256), 0);
+    }
+
+    #[test]
+    fn test_chain_iv() {
+        let mut ctx = aes_ctx();
+        let mut ciphertext = vec![1; ENCRYPT_BLOCK_SIZE];
+        ciphertext.extend_from_slice(&[2; ENCRYPT_BLOCK_SIZE]);
+        ctx.chain_iv(&ciphertext);
+        assert_eq!(ctx.mechanism.iv(), Some([2; ENCRYPT_BLOCK_SIZE]));
+    }
+
+    fn estimate(mechanism: Mechanism, ciphertext_len: usize) -> Option<usize> {
+        DecryptedDataLen::estimate_from_mechanism_and_ciphertext_len(&mechanism, ciphertext_len)
+            .map(|len| len.0)
+    }
+
+    #[test]
+    fn test_decrypted_data_len() {
+        assert_eq!(estimate(Mechanism::AesCbc(None), 48), Some(48));
+        assert_eq!(estimate(Mechanism::RsaX509, 256), Some(256));
+        assert_eq!(estimate(Mechanism::RsaPkcs(None), 256), Some(245));
+        assert_eq!(
+            estimate(Mechanism::RsaPkcsOaep(MechDigest::Sha1), 256),
+            Some(214)
+        );
+        assert_eq!(
+            estimate(Mechanism::RsaPkcsOaep(MechDigest::Sha256), 256),
+            Some(190)
+        );
+        // the ciphertext is too short, the decryption will fail
+        assert_eq!(estimate(Mechanism::RsaPkcs(None), 4), Some(0));
+        assert_eq!(estimate(Mechanism::EdDsa, 64), None);
+    }
+}
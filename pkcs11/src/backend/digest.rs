@@ -0,0 +1,98 @@
+use cryptoki_sys::{CKA_SENSITIVE, CKA_VALUE};
+use log::debug;
+use sha2::Digest;
+
+use super::{
+    db::{
+        object::{Attribute, ObjectKind},
+        Object,
+    },
+    mechanism::MechDigest,
+    Error,
+};
+
+// Digesting is not a feature of the NetHSM, it is done in software by the module
+#[derive(Clone, Debug)]
+enum Hasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha224(sha2::Sha224),
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+#[derive(Clone, Debug)]
+pub struct DigestCtx {
+    pub digest: MechDigest,
+    hasher: Hasher,
+}
+
+impl DigestCtx {
+    pub fn init(digest: MechDigest) -> Self {
+        let hasher = match digest {
+            MechDigest::Md5 => Hasher::Md5(md5::Md5::new()),
+            MechDigest::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            MechDigest::Sha224 => Hasher::Sha224(sha2::Sha224::new()),
+            MechDigest::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            MechDigest::Sha384 => Hasher::Sha384(sha2::Sha384::new()),
+            MechDigest::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        };
+
+        Self { digest, hasher }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self.hasher {
+            Hasher::Md5(ref mut h) => h.update(data),
+            Hasher::Sha1(ref mut h) => h.update(data),
+            Hasher::Sha224(ref mut h) => h.update(data),
+            Hasher::Sha256(ref mut h) => h.update(data),
+            Hasher::Sha384(ref mut h) => h.update(data),
+            Hasher::Sha512(ref mut h) => h.update(data),
+        }
+    }
+
+    // Continues the digest with the value of a secret key.
+    // Keys stored on the NetHSM never leave it, so only keys with a readable value can be digested.
+    pub fn update_key(&mut self, key: &Object) -> Result<(), Error> {
+        if key.kind != ObjectKind::SecretKey {
+            debug!(
+                "Tried to digest a key that is not a secret key: {:?}",
+                key.kind
+            );
+            return Err(Error::KeyIndigestible);
+        }
+
+        let sensitive = matches!(
+            key.get_attribute(CKA_SENSITIVE),
+            Some(Attribute::Bool(true))
+        );
+
+        match key.get_attribute(CKA_VALUE) {
+            Some(Attribute::Bytes(value)) if !sensitive && !value.is_empty() => {
+                self.update(value);
+                Ok(())
+            }
+            _ => {
+                debug!("The value of the key {} is not available", key.id);
+                Err(Error::KeyIndigestible)
+            }
+        }
+    }
+
+    pub fn output_size(&self) -> usize {
+        self.digest.output_size()
+    }
+
+    pub fn digest_final(&self) -> Vec<u8> {
+        match self.hasher.clone() {
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha224(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha384(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use base64ct::{Base64, Encoding};
+use cryptoki_sys::{CKA_KEY_TYPE, CKA_MODULUS, CKA_PUBLIC_EXPONENT, CKK_RSA, CK_ULONG};
 use log::{debug, trace};
 use nethsm_sdk_rs::apis::default_api;
+use zeroize::Zeroize;
 
 use crate::backend::mechanism::MechMode;
 use crate::backend::ApiError;
@@ -8,7 +10,7 @@ use crate::backend::ApiError;
 use super::Error;
 
 use super::{
-    db::Object,
+    db::{object::Attribute, Object},
     login::{self, LoginCtx},
     mechanism::Mechanism,
 };
@@ -16,12 +18,42 @@ use super::{
 // we only handle AES-CBC for now that has a block size of 16
 pub const ENCRYPT_BLOCK_SIZE: usize = 16;
 
+// the PKCS#1 v1.5 padding takes at least 11 bytes (RFC 8017 section 7.2.1)
+const RSA_PKCS_PADDING_LEN: usize = 11;
+
+// length of the output of an encryption, computed without calling the NetHSM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncryptedDataLen(pub usize);
+
+impl EncryptedDataLen {
+    // the NetHSM only encrypts with AES-CBC, without padding: the output has the size of the input.
+    // None if the mechanism can't be used to encrypt
+    pub fn from_mechanism_and_plaintext_len(
+        mechanism: &Mechanism,
+        plaintext_len: usize,
+    ) -> Option<Self> {
+        match mechanism {
+            Mechanism::AesCbc(_) => Some(Self(plaintext_len)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct EncryptCtx {
     pub mechanism: Mechanism,
     pub key_id: String,
-    pub data: Vec<u8>,
+    // the start of a block that isn't complete yet, kept until more data or encrypt_final
+    pub partial_block: [u8; ENCRYPT_BLOCK_SIZE],
+    pub partial_len: usize,
     login_ctx: LoginCtx,
+    // output of a C_EncryptFinal that returned CKR_BUFFER_TOO_SMALL, given back on the retry
+    pub pending_output: Option<Vec<u8>>,
+    // the modulus and the public exponent for CKM_RSA_PKCS, the NetHSM can't encrypt with RSA
+    // so it is done by the module
+    rsa_public_key: Option<(Vec<u8>, Vec<u8>)>,
+    // the data given to update with RSA, encrypted in one block by encrypt_final
+    pub rsa_data: Vec<u8>,
 }
 
 impl EncryptCtx {
@@ -30,6 +62,10 @@ impl EncryptCtx {
             return Err(Error::NotLoggedIn(login::UserMode::Operator));
         }
 
+        if mechanism == Mechanism::RsaPkcs(None) {
+            return Self::init_rsa(mechanism, key, login_ctx);
+        }
+
         let api_mech = match mechanism.to_api_mech(MechMode::Encrypt) {
             Some(mech) => mech,
             None => {
@@ -55,51 +91,191 @@ impl EncryptCtx {
         Ok(Self {
             mechanism,
             key_id: key.id.clone(),
-            data: Vec::new(),
+            partial_block: [0; ENCRYPT_BLOCK_SIZE],
+            partial_len: 0,
             login_ctx,
+            pending_output: None,
+            rsa_public_key: None,
+            rsa_data: Vec::new(),
         })
     }
 
-    pub fn add_data(&mut self, data: &[u8]) {
-        self.data.extend_from_slice(data);
+    fn init_rsa(mechanism: Mechanism, key: &Object, login_ctx: LoginCtx) -> Result<Self, Error> {
+        let rsa_public_key = match (
+            key.get_attribute(CKA_KEY_TYPE),
+            key.get_attribute(CKA_MODULUS),
+            key.get_attribute(CKA_PUBLIC_EXPONENT),
+        ) {
+            (
+                Some(Attribute::Ulong(CKK_RSA)),
+                Some(Attribute::Bytes(modulus)),
+                Some(Attribute::Bytes(public_exponent)),
+            ) => (modulus.clone(), public_exponent.clone()),
+            _ => {
+                debug!("The key {} is not an RSA key", key.id);
+                return Err(Error::InvalidMechanism(
+                    (key.id.clone(), key.kind),
+                    mechanism,
+                ));
+            }
+        };
+
+        Ok(Self {
+            mechanism,
+            key_id: key.id.clone(),
+            partial_block: [0; ENCRYPT_BLOCK_SIZE],
+            partial_len: 0,
+            login_ctx,
+            pending_output: None,
+            rsa_public_key: Some(rsa_public_key),
+            rsa_data: Vec::new(),
+        })
     }
 
-    pub fn get_biggest_chunk_len(&self) -> usize {
-        let full_blocks = self.data.len() / ENCRYPT_BLOCK_SIZE;
+    // the length of the modulus, without its leading zeros
+    fn rsa_modulus_len(&self) -> Option<usize> {
+        self.rsa_public_key.as_ref().map(|(modulus, _)| {
+            let start = modulus
+                .iter()
+                .position(|b| *b != 0)
+                .unwrap_or(modulus.len());
+            modulus.len() - start
+        })
+    }
 
-        full_blocks * ENCRYPT_BLOCK_SIZE
+    pub fn output_len(&self, plaintext_len: usize) -> Result<usize, Error> {
+        if let Some(len) = self.rsa_modulus_len() {
+            return Ok(len);
+        }
+        EncryptedDataLen::from_mechanism_and_plaintext_len(&self.mechanism, plaintext_len)
+            .map(|len| len.0)
+            .ok_or_else(|| Error::InvalidMechanismMode(MechMode::Encrypt, self.mechanism.clone()))
     }
 
-    pub fn encrypt_available_data(&mut self) -> Result<Vec<u8>, Error> {
-        let chunk_size = self.get_biggest_chunk_len();
+    // Length of what update returns for this much data: the complete blocks, nothing with RSA
+    pub fn update_len(&self, data_len: usize) -> usize {
+        if self.rsa_public_key.is_some() {
+            return 0;
+        }
+        (self.partial_len + data_len) / ENCRYPT_BLOCK_SIZE * ENCRYPT_BLOCK_SIZE
+    }
+
+    // The NetHSM encrypt endpoint takes the whole message in a JSON body, it can't be streamed.
+    // Instead of accumulating the data until encrypt_final, the complete blocks are sent
+    // right away with the buffered partial block in front, the rest becomes the new partial
+    // block.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.rsa_public_key.is_some() {
+            self.rsa_data.extend_from_slice(data);
+            return Ok(Vec::new());
+        }
+
+        let chunk_len = self.update_len(data.len());
 
-        // if there is no data to encrypt, return an empty vector
-        if chunk_size == 0 {
+        // not enough for a block, nothing to encrypt yet
+        if chunk_len == 0 {
+            self.partial_block[self.partial_len..self.partial_len + data.len()]
+                .copy_from_slice(data);
+            self.partial_len += data.len();
             return Ok(Vec::new());
         }
 
-        // drain the data to encrypt from the data vector
+        let (input, rest) = data.split_at(chunk_len - self.partial_len);
+        let mut chunk = Vec::with_capacity(chunk_len);
+        chunk.extend_from_slice(&self.partial_block[..self.partial_len]);
+        chunk.extend_from_slice(input);
 
-        let input_data = self.data.drain(..chunk_size).collect::<Vec<u8>>();
-        encrypt_data(
+        let output = encrypt_data(
             &self.key_id,
             self.login_ctx.clone(),
-            &input_data,
+            &chunk,
             &self.mechanism,
-        )
+        );
+        chunk.zeroize();
+        let output = output?;
+
+        self.partial_block.zeroize();
+        self.partial_block[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+        self.chain_iv(&output);
+
+        Ok(output)
+    }
+
+    // with CBC the next chunk is encrypted with the last block of the previous one as IV,
+    // so that encrypting chunk by chunk gives the same result as encrypting everything at once
+    fn chain_iv(&mut self, output: &[u8]) {
+        if let Mechanism::AesCbc(ref mut iv) = self.mechanism {
+            if output.len() >= ENCRYPT_BLOCK_SIZE {
+                let mut last_block = [0; ENCRYPT_BLOCK_SIZE];
+                last_block.copy_from_slice(&output[output.len() - ENCRYPT_BLOCK_SIZE..]);
+                *iv = Some(last_block);
+            }
+        }
+    }
+
+    // Length of what encrypt_final returns: the complete AES-CBC blocks were returned by update,
+    // nothing is left. A partial block can't be encrypted without padding, it is an error.
+    pub fn final_output_len(&self) -> Result<CK_ULONG, Error> {
+        if let Some(len) = self.rsa_modulus_len() {
+            return Ok(len as CK_ULONG);
+        }
+        if self.partial_len != 0 {
+            return Err(Error::InvalidDataLength);
+        }
+        Ok(0)
     }
 
     pub fn encrypt_final(&self) -> Result<Vec<u8>, Error> {
-        encrypt_data(
-            &self.key_id,
-            self.login_ctx.clone(),
-            self.data.as_slice(),
-            &self.mechanism,
-        )
+        if let Some((modulus, public_exponent)) = &self.rsa_public_key {
+            return rsa_pkcs_encrypt(modulus, public_exponent, &self.rsa_data);
+        }
+
+        // the complete blocks were already sent by update. Without padding, AES-CBC can't
+        // encrypt the partial block left, it is refused without calling the NetHSM.
+        if self.partial_len != 0 {
+            debug!(
+                "The data to encrypt isn't a multiple of the block size, {} bytes are left",
+                self.partial_len
+            );
+            return Err(Error::InvalidDataLength);
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+// EM = 0x00 || 0x02 || PS || 0x00 || M with at least 8 random non-zero bytes in PS
+// (RFC 8017 section 7.2.1), then the public key operation
+fn rsa_pkcs_encrypt(modulus: &[u8], public_exponent: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let start = modulus
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(modulus.len());
+    let len = modulus.len() - start;
+    if data.len() + RSA_PKCS_PADDING_LEN > len {
+        return Err(Error::InvalidDataLength);
     }
+
+    let mut encoded = vec![0; len];
+    encoded[1] = 0x02;
+    let padding = &mut encoded[2..len - data.len() - 1];
+    getrandom::getrandom(padding).map_err(Error::Random)?;
+    for byte in padding.iter_mut() {
+        while *byte == 0 {
+            let mut new = [0];
+            getrandom::getrandom(&mut new).map_err(Error::Random)?;
+            *byte = new[0];
+        }
+    }
+    encoded[len - data.len()..].copy_from_slice(data);
+
+    let output = super::rsa::public_op(modulus, public_exponent, &encoded);
+    encoded.zeroize();
+    output.ok_or(Error::InvalidData)
 }
 
-fn encrypt_data(
+pub(super) fn encrypt_data(
     key_id: &str,
     mut login_ctx: LoginCtx,
     data: &[u8],
@@ -147,3 +323,122 @@ fn encrypt_data(
 
     Ok(Base64::decode_vec(&output.entity.encrypted)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aes_ctx() -> EncryptCtx {
+        EncryptCtx {
+            mechanism: Mechanism::AesCbc(Some([0; ENCRYPT_BLOCK_SIZE])),
+            key_id: "aes".to_string(),
+            partial_block: [0; ENCRYPT_BLOCK_SIZE],
+            partial_len: 0,
+            login_ctx: LoginCtx::new(None, None, vec![], None),
+            pending_output: None,
+            rsa_public_key: None,
+            rsa_data: Vec::new(),
+        }
+    }
+
+    // a 512 bits RSA key, d is the private exponent
+    const RSA_MODULUS: [u8; 64] = hex_literal::hex!("96371aff791e65e04af0ded5d18f183f64e76e7ea05f2a725ae0f56b0c1ba00381fc8cbf8dc6e5a908e5502f589a9d98e7d5f25e69506c55b02595f28217209b");
+    const RSA_D: [u8; 64] = hex_literal::hex!("51129ebca177198eeb8383112dd0284b16bf9db0340808d5bd5ccab822c9ca1b26deb9540932f2500f25ad1230f8d1d7eec2281da15f2f2b65417e8136d19a29");
+
+    #[test]
+    fn test_rsa_pkcs_roundtrip() {
+        let mut ctx = EncryptCtx {
+            mechanism: Mechanism::RsaPkcs(None),
+            rsa_public_key: Some((RSA_MODULUS.to_vec(), vec![0x01, 0x00, 0x01])),
+            ..aes_ctx()
+        };
+        assert_eq!(ctx.output_len(5).unwrap(), 64);
+        assert_eq!(ctx.update_len(5), 0);
+        assert_eq!(ctx.final_output_len().unwrap(), 64);
+
+        assert!(ctx.update(b"hel").unwrap().is_empty());
+        assert!(ctx.update(b"lo").unwrap().is_empty());
+        let first = ctx.encrypt_final().unwrap();
+        let second = ctx.encrypt_final().unwrap();
+        assert_eq!(first.len(), 64);
+        // the padding is random
+        assert_ne!(first, second);
+
+        for ciphertext in [first, second] {
+            let encoded =
+                crate::backend::rsa::public_op(&RSA_MODULUS, &RSA_D, &ciphertext).unwrap();
+            assert_eq!(&encoded[..2], &[0x00, 0x02]);
+            let separator = encoded[2..].iter().position(|b| *b == 0).unwrap() + 2;
+            assert_eq!(separator, 64 - 6);
+            assert_eq!(&encoded[separator + 1..], b"hello");
+        }
+
+        // 11 bytes of the modulus are taken by the padding
+        assert!(rsa_pkcs_encrypt(&RSA_MODULUS, &[1, 0, 1], &[1; 53]).is_ok());
+        assert!(matches!(
+            rsa_pkcs_encrypt(&RSA_MODULUS, &[1, 0, 1], &[1; 54]),
+            Err(Error::InvalidDataLength)
+        ));
+    }
+
+    #[test]
+    fn test_update_len() {
+        let mut ctx = aes_ctx();
+        assert_eq!(ctx.update_len(10 * 1024 * 1024 + 5), 10 * 1024 * 1024);
+        assert_eq!(ctx.update_len(ENCRYPT_BLOCK_SIZE - 1), 0);
+
+        // the partial block is completed by the next data
+        assert!(ctx.update(&[1; ENCRYPT_BLOCK_SIZE - 1]).unwrap().is_empty());
+        assert_eq!(ctx.partial_len, ENCRYPT_BLOCK_SIZE - 1);
+        assert_eq!(ctx.update_len(1), ENCRYPT_BLOCK_SIZE);
+        assert_eq!(
+            ctx.update_len(ENCRYPT_BLOCK_SIZE + 2),
+            2 * ENCRYPT_BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn test_encrypted_data_len() {
+        assert_eq!(
+            EncryptedDataLen::from_mechanism_and_plaintext_len(&Mechanism::AesCbc(None), 32),
+            Some(EncryptedDataLen(32))
+        );
+        assert_eq!(
+            EncryptedDataLen::from_mechanism_and_plaintext_len(
+                &Mechanism::RsaPkcsOaep(crate::backend::mechanism::MechDigest::Sha256),
+                32
+            ),
+            None
+        );
+        assert_eq!(aes_ctx().output_len(48).unwrap(), 48);
+    }
+
+    #[test]
+    fn test_final_output_len() {
+        let mut ctx = aes_ctx();
+        assert_eq!(ctx.final_output_len().unwrap(), 0);
+        assert!(ctx.encrypt_final().unwrap().is_empty());
+
+        // the full blocks are sent by update, only the partial block is left for the end
+        ctx.update(&[0; 3]).unwrap();
+        assert!(matches!(
+            ctx.final_output_len(),
+            Err(Error::InvalidDataLength)
+        ));
+        assert!(matches!(ctx.encrypt_final(), Err(Error::InvalidDataLength)));
+    }
+
+    #[test]
+    fn test_chain_iv() {
+        let mut ctx = aes_ctx();
+
+        // an incomplete output doesn't change the IV
+        ctx.chain_iv(&[1; ENCRYPT_BLOCK_SIZE - 1]);
+        assert_eq!(ctx.mechanism.iv(), Some([0; ENCRYPT_BLOCK_SIZE]));
+
+        let mut output = vec![1; ENCRYPT_BLOCK_SIZE];
+        output.extend_from_slice(&[2; ENCRYPT_BLOCK_SIZE]);
+        ctx.chain_iv(&output);
+        assert_eq!(ctx.mechanism.iv(), Some([2; ENCRYPT_BLOCK_SIZE]));
+    }
+}
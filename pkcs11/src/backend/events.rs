@@ -2,7 +2,7 @@ use cryptoki_sys::CK_SLOT_ID;
 use log::error;
 use nethsm_sdk_rs::{apis::default_api, models::SystemState};
 
-use crate::data::{DEVICE, EVENTS_MANAGER, TOKENS_STATE};
+use crate::data::{initialized_device, EVENTS_MANAGER, TOKENS_STATE};
 
 use super::login::LoginCtx;
 
@@ -36,7 +36,7 @@ pub fn update_slot_state(slot_id: CK_SLOT_ID, present: bool) {
 }
 
 pub fn fetch_slots_state() -> Result<(), cryptoki_sys::CK_RV> {
-    let Some(device) = DEVICE.get() else {
+    let Some(device) = initialized_device() else {
         error!("Initialization was not performed or failed");
         return Err(cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED);
     };
@@ -1,29 +1,41 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use super::{
-    db::{self, attr::CkRawAttrTemplate, Db, Object},
+    db::{
+        self,
+        attr::{CkRawAttr, CkRawAttrTemplate},
+        object::Attribute,
+        Db, Object,
+    },
     login::{self, LoginCtx},
     Error,
 };
 use crate::{
     backend::{self, db::object::ObjectKind, mechanism::Mechanism, ApiError},
-    data::{DEVICE, KEY_ALIASES},
+    data::{initialized_device, KEY_ALIASES},
 };
 use base64ct::{Base64, Encoding};
 use cryptoki_sys::{
-    CKA_CLASS, CKA_DECRYPT, CKA_EC_PARAMS, CKA_ENCRYPT, CKA_ID, CKA_KEY_TYPE, CKA_LABEL,
-    CKA_MODULUS_BITS, CKA_PRIME_1, CKA_PRIME_2, CKA_PUBLIC_EXPONENT, CKA_SIGN, CKA_VALUE,
-    CKA_VALUE_LEN, CKK_EC, CKK_EC_EDWARDS, CKK_GENERIC_SECRET, CKK_RSA, CK_KEY_TYPE,
-    CK_OBJECT_CLASS, CK_OBJECT_HANDLE, CK_ULONG,
+    CKA_CLASS, CKA_DECRYPT, CKA_EC_PARAMS, CKA_ENCRYPT, CKA_EXTRACTABLE, CKA_ID, CKA_KEY_TYPE,
+    CKA_LABEL, CKA_MODULUS_BITS, CKA_PRIME_1, CKA_PRIME_2, CKA_PUBLIC_EXPONENT, CKA_SENSITIVE,
+    CKA_SIGN, CKA_TRUSTED, CKA_VALUE, CKA_VALUE_LEN, CKA_WRAP_WITH_TRUSTED, CKK_AES, CKK_EC,
+    CKK_EC_EDWARDS, CKK_GENERIC_SECRET, CKK_RSA, CK_KEY_TYPE, CK_OBJECT_CLASS, CK_OBJECT_HANDLE,
+    CK_ULONG,
 };
 use der::{oid::ObjectIdentifier, Decode};
 use log::{debug, error, trace};
 use nethsm_sdk_rs::{
     apis::default_api,
-    models::{KeyGenerateRequestData, KeyItem, KeyPrivateData, KeyType, PrivateKey},
+    models::{
+        KeyGenerateRequestData, KeyItem, KeyPrivateData, KeyPublicData, KeyRestrictions, KeyType,
+        PrivateKey, PublicKey,
+    },
 };
 
 #[derive(Debug, Default)]
@@ -42,6 +54,34 @@ pub struct ParsedAttributes {
     pub value_len: Option<CK_ULONG>,
     pub modulus_bits: Option<CK_ULONG>,
     pub raw_id: Option<Vec<u8>>,
+    pub trusted: bool,
+    pub wrap_with_trusted: bool,
+    pub sensitive: Option<bool>,
+    pub extractable: Option<bool>,
+    // the NetHSM tags of the key, from the vendor attributes of the template
+    pub tags: Vec<String>,
+}
+
+pub fn parse_key_id_from_attr(attr: &CkRawAttr) -> Option<(String, Option<Vec<u8>>)> {
+    attr.val_bytes().map(parse_key_id)
+}
+
+// The NetHSM key IDs are ASCII alphanumeric. Any other CKA_ID is given to the NetHSM hex
+// encoded and kept as raw bytes, the object returns the bytes the application set.
+pub fn parse_key_id(bytes: &[u8]) -> (String, Option<Vec<u8>>) {
+    if bytes.iter().all(u8::is_ascii_alphanumeric) {
+        // only ASCII, the bytes are valid UTF-8
+        return (String::from_utf8_lossy(bytes).into_owned(), None);
+    }
+    (hex::encode(bytes), Some(bytes.to_vec()))
+}
+
+fn read_bool(attr: &CkRawAttr) -> bool {
+    matches!(
+        attr.val_bytes()
+            .and_then(|val| Attribute::from_raw(attr.type_(), val)),
+        Some(Attribute::Bool(true))
+    )
 }
 
 pub fn parse_attributes(template: &CkRawAttrTemplate) -> Result<ParsedAttributes, Error> {
@@ -65,22 +105,9 @@ pub fn parse_attributes(template: &CkRawAttrTemplate) -> Result<ParsedAttributes
                 None => return Err(Error::InvalidAttribute(CKA_CLASS)),
             },
             CKA_ID => {
-                if let Some(bytes) = attr.val_bytes() {
-                    let str_result = String::from_utf8(bytes.to_vec());
-                    let mut output = None;
-                    if let Ok(str) = str_result {
-                        // check if the string contains only alphanumeric characters
-                        if str.chars().all(|c| c.is_alphanumeric()) {
-                            output = Some(str);
-                        }
-                    }
-
-                    if output.is_none() {
-                        // store as hex value string
-                        output = Some(hex::encode(bytes));
-                        parsed.raw_id = Some(bytes.to_vec());
-                    }
-                    parsed.id = output;
+                if let Some((id, raw_id)) = parse_key_id_from_attr(&attr) {
+                    parsed.id = Some(id);
+                    parsed.raw_id = raw_id;
                 }
             }
             CKA_LABEL => {
@@ -110,25 +137,13 @@ pub fn parse_attributes(template: &CkRawAttrTemplate) -> Result<ParsedAttributes
             }
 
             CKA_SIGN => {
-                if let Some(val) = attr.val_bytes() {
-                    if val[0] == 1 {
-                        parsed.sign = true;
-                    }
-                }
+                parsed.sign = read_bool(&attr);
             }
             CKA_ENCRYPT => {
-                if let Some(val) = attr.val_bytes() {
-                    if val[0] == 1 {
-                        parsed.encrypt = true;
-                    }
-                }
+                parsed.encrypt = read_bool(&attr);
             }
             CKA_DECRYPT => {
-                if let Some(val) = attr.val_bytes() {
-                    if val[0] == 1 {
-                        parsed.decrypt = true;
-                    }
-                }
+                parsed.decrypt = read_bool(&attr);
             }
             CKA_PUBLIC_EXPONENT => {
                 parsed.public_exponent = attr.val_bytes().map(|val| val.to_vec());
@@ -145,6 +160,18 @@ pub fn parse_attributes(template: &CkRawAttrTemplate) -> Result<ParsedAttributes
             CKA_MODULUS_BITS => {
                 parsed.modulus_bits = unsafe { attr.read_value::<CK_ULONG>() };
             }
+            CKA_TRUSTED => {
+                parsed.trusted = read_bool(&attr);
+            }
+            CKA_WRAP_WITH_TRUSTED => {
+                parsed.wrap_with_trusted = read_bool(&attr);
+            }
+            CKA_SENSITIVE => {
+                parsed.sensitive = Some(read_bool(&attr));
+            }
+            CKA_EXTRACTABLE => {
+                parsed.extractable = Some(read_bool(&attr));
+            }
 
             _ => {
                 debug!("Attribute not supported: {:?}", attr.type_());
@@ -155,6 +182,15 @@ pub fn parse_attributes(template: &CkRawAttrTemplate) -> Result<ParsedAttributes
     Ok(parsed)
 }
 
+// only the SO can mark a key as trusted for wrapping
+fn check_trusted(parsed: &ParsedAttributes, login_ctx: &LoginCtx) -> Result<(), Error> {
+    if parsed.trusted && !login_ctx.can_run_mode(login::UserMode::Administrator) {
+        debug!("Only the SO can set CKA_TRUSTED");
+        return Err(Error::AttributeReadOnly(CKA_TRUSTED));
+    }
+    Ok(())
+}
+
 fn upload_certificate(
     parsed_template: &ParsedAttributes,
     mut login_ctx: LoginCtx,
@@ -172,7 +208,7 @@ fn upload_certificate(
         }
     };
 
-    let Some(device) = DEVICE.get() else {
+    let Some(device) = initialized_device() else {
         error!("Initialization was not performed or failed");
         return Err(Error::LibraryNotInitialized);
     };
@@ -199,9 +235,12 @@ fn upload_certificate(
 
 pub fn create_key_from_template(
     template: CkRawAttrTemplate,
-    mut login_ctx: LoginCtx,
+    tag_attributes: &db::TagAttributes,
+    login_ctx: LoginCtx,
 ) -> Result<(String, ObjectKind, Option<Vec<u8>>), Error> {
-    let parsed = parse_attributes(&template)?;
+    let mut parsed = parse_attributes(&template)?;
+    parsed.tags = tag_attributes.tags_from_template(&template)?;
+    check_trusted(&parsed, &login_ctx)?;
 
     debug!("key_class: {:?}", parsed.key_class);
     debug!("key_type: {:?}", parsed.key_type);
@@ -220,6 +259,90 @@ pub fn create_key_from_template(
         return upload_certificate(&parsed, login_ctx);
     }
 
+    let raw_id = parsed.raw_id.clone();
+    let id = upload_private_key(parsed, login_ctx)?;
+
+    Ok((id, key_class, raw_id))
+}
+
+// C_UnwrapKey: the value of the secret key comes from the unwrapped data, returns the ID and
+// the raw ID of the imported key
+pub fn import_unwrapped_key(
+    template: &CkRawAttrTemplate,
+    value: &[u8],
+    tag_attributes: &db::TagAttributes,
+    login_ctx: LoginCtx,
+) -> Result<(String, Option<Vec<u8>>), Error> {
+    let mut parsed = parse_attributes(template)?;
+    parsed.tags = tag_attributes.tags_from_template(template)?;
+    check_trusted(&parsed, &login_ctx)?;
+
+    // the unwrapped key must be described by the template (PKCS#11 section 5.18.6)
+    match parsed.key_class {
+        Some(ObjectKind::SecretKey) => {}
+        Some(_) => return Err(Error::TemplateInconsistent(CKA_CLASS)),
+        None => return Err(Error::TemplateIncomplete(CKA_CLASS)),
+    }
+    if parsed.value.is_some() {
+        return Err(Error::TemplateInconsistent(CKA_VALUE));
+    }
+    if parsed
+        .value_len
+        .is_some_and(|len| len != value.len() as CK_ULONG)
+    {
+        return Err(Error::TemplateInconsistent(CKA_VALUE_LEN));
+    }
+    // the NetHSM never gives out the key, it stays sensitive and unextractable
+    if parsed.sensitive == Some(false) {
+        return Err(Error::TemplateInconsistent(CKA_SENSITIVE));
+    }
+    if parsed.extractable == Some(true) {
+        return Err(Error::TemplateInconsistent(CKA_EXTRACTABLE));
+    }
+
+    // the AES keys are generic keys on the NetHSM
+    match parsed.key_type {
+        Some(CKK_AES) => {
+            if !matches!(value.len(), 16 | 24 | 32) {
+                return Err(Error::KeySizeRange(value.len() as CK_ULONG));
+            }
+        }
+        Some(CKK_GENERIC_SECRET) => {}
+        Some(_) => return Err(Error::TemplateInconsistent(CKA_KEY_TYPE)),
+        None => return Err(Error::TemplateIncomplete(CKA_KEY_TYPE)),
+    }
+    parsed.key_type = Some(CKK_GENERIC_SECRET);
+    parsed.value = Some(value.to_vec());
+
+    let raw_id = parsed.raw_id.clone();
+    let id = upload_private_key(parsed, login_ctx)?;
+
+    Ok((id, raw_id))
+}
+
+// C_NetHSM_ImportKey: the key comes from a PEM file instead of a template, the key ID is the
+// CKA_ID of the key
+pub fn import_pem_key(
+    pem: &[u8],
+    key_id: &[u8],
+    key_type: CK_KEY_TYPE,
+    login_ctx: LoginCtx,
+) -> Result<(String, Option<Vec<u8>>), Error> {
+    let mut parsed = super::pkcs8::parse_private_key_pem(pem, key_type)?;
+    if !key_id.is_empty() {
+        let (id, raw_id) = parse_key_id(key_id);
+        parsed.id = Some(id);
+        parsed.raw_id = raw_id;
+    }
+
+    let raw_id = parsed.raw_id.clone();
+    let id = upload_private_key(parsed, login_ctx)?;
+
+    Ok((id, raw_id))
+}
+
+// imports the key on the NetHSM, returns its ID
+fn upload_private_key(parsed: ParsedAttributes, mut login_ctx: LoginCtx) -> Result<String, Error> {
     let (r#type, key) = match parsed
         .key_type
         .ok_or(Error::InvalidAttribute(CKA_KEY_TYPE))?
@@ -321,7 +444,11 @@ pub fn create_key_from_template(
         mechanisms,
         r#type,
         private: key,
-        restrictions: None,
+        restrictions: (!parsed.tags.is_empty()).then(|| {
+            Box::new(KeyRestrictions {
+                tags: Some(parsed.tags),
+            })
+        }),
     };
 
     let id = if let Some(id) = parsed.id {
@@ -360,7 +487,7 @@ pub fn create_key_from_template(
         }
     }?;
 
-    Ok((id, key_class, parsed.raw_id))
+    Ok(id)
 }
 
 const KEYTYPE_EC_P224: ObjectIdentifier = der::oid::db::rfc5912::SECP_224_R_1;
@@ -368,6 +495,8 @@ const KEYTYPE_EC_P256: ObjectIdentifier = der::oid::db::rfc5912::SECP_256_R_1;
 const KEYTYPE_EC_P384: ObjectIdentifier = der::oid::db::rfc5912::SECP_384_R_1;
 const KEYTYPE_EC_P521: ObjectIdentifier = der::oid::db::rfc5912::SECP_521_R_1;
 const KEYTYPE_CURVE25519: ObjectIdentifier = der::oid::db::rfc8410::ID_ED_25519;
+// the NetHSM has no X25519 keys, its Curve25519 keys are Ed25519 and it has no ECDH
+const KEYTYPE_X25519: ObjectIdentifier = der::oid::db::rfc8410::ID_X_25519;
 
 pub fn key_type_to_asn1(key_type: KeyType) -> Option<ObjectIdentifier> {
     Some(match key_type {
@@ -410,10 +539,252 @@ fn key_type_from_params(params: &[u8]) -> Option<KeyType> {
     } else if oid == KEYTYPE_EC_P521 {
         Some(KeyType::EcP521)
     } else {
+        if oid == KEYTYPE_X25519 {
+            debug!("The NetHSM can't generate X25519 keys, only Ed25519 ones");
+        }
         None
     }
 }
 
+static SESSION_PUBLIC_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// the attributes of a public key that give its value, they are set by from_key_data
+const PUBLIC_KEY_VALUE_ATTRIBUTES: [cryptoki_sys::CK_ATTRIBUTE_TYPE; 6] = [
+    CKA_CLASS,
+    CKA_KEY_TYPE,
+    cryptoki_sys::CKA_MODULUS,
+    CKA_PUBLIC_EXPONENT,
+    CKA_EC_PARAMS,
+    cryptoki_sys::CKA_EC_POINT,
+];
+
+// The NetHSM only stores key pairs, a public key created alone is a session object of the
+// module. It is built like the public keys fetched from the NetHSM, the other attributes of the
+// template are set over it.
+pub fn public_key_from_template(
+    template: &CkRawAttrTemplate,
+    login_ctx: &LoginCtx,
+) -> Result<Object, Error> {
+    let parsed = parse_attributes(template)?;
+    check_trusted(&parsed, login_ctx)?;
+
+    let attr_bytes = |attr_type| {
+        template
+            .iter()
+            .find(|attr| attr.type_() == attr_type)
+            .and_then(|attr| attr.val_bytes().map(<[u8]>::to_vec))
+    };
+
+    let mut public = KeyPublicData::new();
+    let key_type = match parsed
+        .key_type
+        .ok_or(Error::MissingAttribute(CKA_KEY_TYPE))?
+    {
+        CKK_RSA => {
+            let modulus = attr_bytes(cryptoki_sys::CKA_MODULUS)
+                .ok_or(Error::MissingAttribute(cryptoki_sys::CKA_MODULUS))?;
+            let public_exponent = parsed
+                .public_exponent
+                .ok_or(Error::MissingAttribute(CKA_PUBLIC_EXPONENT))?;
+            public.modulus = Some(Base64::encode_string(&modulus));
+            public.public_exponent = Some(Base64::encode_string(&public_exponent));
+            KeyType::Rsa
+        }
+        ck_type @ (CKK_EC | CKK_EC_EDWARDS) => {
+            let ec_type = key_type_from_params(
+                &parsed
+                    .ec_params
+                    .ok_or(Error::MissingAttribute(CKA_EC_PARAMS))?,
+            )
+            .ok_or(Error::InvalidAttribute(CKA_EC_PARAMS))?;
+            if (ec_type == KeyType::Curve25519) != (ck_type == CKK_EC_EDWARDS) {
+                return Err(Error::TemplateInconsistent(CKA_KEY_TYPE));
+            }
+
+            // CKA_EC_POINT is the DER encoding of the point in an octet string
+            let point = attr_bytes(cryptoki_sys::CKA_EC_POINT)
+                .ok_or(Error::MissingAttribute(cryptoki_sys::CKA_EC_POINT))?;
+            let point = der::asn1::OctetString::from_der(&point)
+                .map_err(|_| Error::InvalidAttribute(cryptoki_sys::CKA_EC_POINT))?;
+            public.data = Some(Base64::encode_string(point.as_bytes()));
+            ec_type
+        }
+        _ => return Err(Error::InvalidAttribute(CKA_KEY_TYPE)),
+    };
+
+    let mut key_data = PublicKey::new(vec![], key_type, KeyRestrictions::new(), 0);
+    key_data.public = Some(Box::new(public));
+
+    let id = format!(
+        "session_public_{}",
+        SESSION_PUBLIC_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut object = db::object::from_key_data(key_data, &id, None)?
+        .into_iter()
+        .find(|object| object.kind == ObjectKind::PublicKey)
+        .ok_or(Error::InvalidAttribute(CKA_KEY_TYPE))?;
+
+    object.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(false));
+    object.set_attr(cryptoki_sys::CKA_MODIFIABLE, Attribute::Bool(true));
+    object.set_attr(db::object::CKA_UNIQUE_ID, db::object::session_unique_id());
+    // the module encrypts and verifies the RSA-PSS signatures with the RSA public keys
+    object.set_attr(CKA_ENCRYPT, Attribute::Bool(key_type == KeyType::Rsa));
+    object.set_attr(
+        cryptoki_sys::CKA_VERIFY,
+        Attribute::Bool(key_type == KeyType::Rsa),
+    );
+
+    for attr in template.iter() {
+        let attr_type = attr.type_();
+        if PUBLIC_KEY_VALUE_ATTRIBUTES.contains(&attr_type) {
+            continue;
+        }
+        let bytes = match attr.val_bytes() {
+            Some(bytes) => bytes,
+            None if attr.len() == 0 => &[],
+            None => return Err(Error::InvalidAttribute(attr_type)),
+        };
+        let value =
+            Attribute::from_raw(attr_type, bytes).ok_or(Error::InvalidAttribute(attr_type))?;
+        object.set_attr(attr_type, value);
+    }
+
+    if object.is_token() {
+        debug!("Tried to create a public key on the token");
+        return Err(Error::TemplateInconsistent(cryptoki_sys::CKA_TOKEN));
+    }
+    Ok(object)
+}
+
+// maximum length of the NetHSM random endpoint
+pub const GENERIC_SECRET_MAX_LEN: CK_ULONG = 1024;
+
+static SESSION_SECRET_COUNTER: AtomicU64 = AtomicU64::new(0);
+static SESSION_DATA_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// the attributes of the template of a data object, the other ones are ignored
+const DATA_ATTRIBUTES: [cryptoki_sys::CK_ATTRIBUTE_TYPE; 8] = [
+    CKA_CLASS,
+    CKA_LABEL,
+    cryptoki_sys::CKA_APPLICATION,
+    cryptoki_sys::CKA_OBJECT_ID,
+    CKA_VALUE,
+    cryptoki_sys::CKA_TOKEN,
+    cryptoki_sys::CKA_PRIVATE,
+    cryptoki_sys::CKA_MODIFIABLE,
+];
+
+// The NetHSM can't store data objects, they are session objects of the module. Several of them
+// can have the same label, the ID of the object is generated.
+pub fn data_object_from_template(template: &CkRawAttrTemplate) -> Result<Object, Error> {
+    let id = format!(
+        "session_data_{}",
+        SESSION_DATA_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut object = db::object::from_session_data(&id);
+
+    for attr in template.iter() {
+        let attr_type = attr.type_();
+        if !DATA_ATTRIBUTES.contains(&attr_type) {
+            debug!("Ignoring the attribute {:?} of a data object", attr_type);
+            continue;
+        }
+        let bytes = match attr.val_bytes() {
+            Some(bytes) => bytes,
+            None if attr.len() == 0 => &[],
+            None => return Err(Error::InvalidAttribute(attr_type)),
+        };
+        let value =
+            Attribute::from_raw(attr_type, bytes).ok_or(Error::InvalidAttribute(attr_type))?;
+        object.set_attr(attr_type, value);
+    }
+
+    if object.is_token() {
+        debug!("Tried to create a data object on the token");
+        return Err(Error::TemplateInconsistent(cryptoki_sys::CKA_TOKEN));
+    }
+    Ok(object)
+}
+
+// CKA_VALUE_LEN of a secret key to generate, in bytes
+fn secret_key_len(mechanism: &Mechanism, value_len: Option<CK_ULONG>) -> Result<CK_ULONG, Error> {
+    let len = value_len.ok_or(Error::TemplateIncomplete(CKA_VALUE_LEN))?;
+
+    let valid = match mechanism {
+        Mechanism::GenerateAes => matches!(len, 16 | 24 | 32),
+        _ => (1..=GENERIC_SECRET_MAX_LEN).contains(&len),
+    };
+
+    if !valid {
+        return Err(Error::KeySizeRange(len));
+    }
+    Ok(len)
+}
+
+// the mechanism info of CKM_RSA_PKCS_KEY_PAIR_GEN only gives the smallest and largest size
+fn check_rsa_key_len(modulus_bits: Option<CK_ULONG>) -> Result<(), Error> {
+    let bits = modulus_bits.ok_or(Error::TemplateIncomplete(CKA_MODULUS_BITS))?;
+    if !Mechanism::RSA_GEN_KEY_BITS.contains(&bits) {
+        return Err(Error::KeySizeRange(bits));
+    }
+    Ok(())
+}
+
+// the NetHSM always generates the RSA keys with the public exponent 65537
+fn check_public_exponent(exponent: Option<&[u8]>) -> Result<(), Error> {
+    let Some(exponent) = exponent else {
+        return Ok(());
+    };
+    let start = exponent
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(exponent.len());
+    if exponent[start..] != [0x01, 0x00, 0x01] {
+        debug!("Unsupported RSA public exponent {}", hex::encode(exponent));
+        return Err(Error::TemplateInconsistent(CKA_PUBLIC_EXPONENT));
+    }
+    Ok(())
+}
+
+// the NetHSM has no generic secret type, the secret is random data kept by the module
+fn generate_session_secret(
+    parsed: ParsedAttributes,
+    mut login_ctx: LoginCtx,
+    db: Arc<Mutex<db::Db>>,
+) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
+    let len = secret_key_len(&Mechanism::GenerateGeneric, parsed.value_len)?;
+
+    let data = login_ctx.try_(
+        |api_config| {
+            default_api::random_post(
+                api_config,
+                nethsm_sdk_rs::models::RandomRequestData { length: len as i32 },
+            )
+        },
+        login::UserMode::Operator,
+    )?;
+    let value = Base64::decode_vec(&data.entity.random)?;
+
+    let id = parsed.id.unwrap_or_else(|| {
+        format!(
+            "session_secret_{}",
+            SESSION_SECRET_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    });
+    let mut object = db::object::from_session_secret(&id, parsed.raw_id, value);
+    object.set_attr(CKA_TRUSTED, Attribute::Bool(parsed.trusted));
+    object.set_attr(
+        CKA_WRAP_WITH_TRUSTED,
+        Attribute::Bool(parsed.wrap_with_trusted),
+    );
+    object.set_initial_sensitivity(
+        parsed.sensitive.unwrap_or(false),
+        parsed.extractable.unwrap_or(true),
+    );
+
+    Ok(vec![db.lock()?.add_object(object)])
+}
+
 pub fn generate_key_from_template(
     template: &CkRawAttrTemplate,
     public_template: Option<&CkRawAttrTemplate>,
@@ -423,21 +794,47 @@ pub fn generate_key_from_template(
 ) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
     let parsed = parse_attributes(template)?;
     let parsed_public = public_template.map(parse_attributes).transpose()?;
+    check_trusted(&parsed, &login_ctx)?;
 
-    let api_mechs = mechanism.get_all_possible_api_mechs();
+    if matches!(mechanism, Mechanism::GenerateGeneric) {
+        return generate_session_secret(parsed, login_ctx, db);
+    }
 
-    let length = parsed.value_len.or(parsed.modulus_bits).or(parsed_public
-        .as_ref()
-        .and_then(|p| p.value_len.or(p.modulus_bits)));
+    let mut api_mechs = mechanism.get_all_possible_api_mechs();
+
+    let length = match mechanism {
+        // CKA_VALUE_LEN is in bytes, the NetHSM expects the length in bits
+        Mechanism::GenerateAes => Some(secret_key_len(mechanism, parsed.value_len)? * 8),
+        _ => parsed.value_len.or(parsed.modulus_bits).or(parsed_public
+            .as_ref()
+            .and_then(|p| p.value_len.or(p.modulus_bits))),
+    };
 
     trace!("length: {:?}", length);
 
+    if matches!(mechanism, Mechanism::GenerateRsa) {
+        check_rsa_key_len(length)?;
+        check_public_exponent(
+            parsed_public
+                .as_ref()
+                .and_then(|p| p.public_exponent.as_deref())
+                .or(parsed.public_exponent.as_deref()),
+        )?;
+    }
+
     let mut key_type = mechanism.to_key_type();
 
     if let Some(public) = parsed_public {
         if let Some(ec_params) = public.ec_params {
-            key_type =
-                key_type_from_params(&ec_params).ok_or(Error::InvalidAttribute(CKA_EC_PARAMS))?;
+            key_type = key_type_from_params(&ec_params).ok_or(Error::MechanismParamInvalid)?;
+            // CKM_EC_KEY_PAIR_GEN also generates Ed25519 keys when given the id-EdDSA OID
+            match (mechanism, key_type) {
+                (Mechanism::GenerateEc, KeyType::Curve25519) => {
+                    api_mechs = Mechanism::GenerateEd.get_all_possible_api_mechs();
+                }
+                (Mechanism::GenerateEd, KeyType::Curve25519) | (Mechanism::GenerateEc, _) => {}
+                _ => return Err(Error::MechanismParamInvalid),
+            }
         }
     }
 
@@ -492,12 +889,17 @@ pub fn fetch_key(
         }
     };
 
-    let objects = db::object::from_key_data(key_data, key_id, raw_id)?;
+    let tags = key_data.restrictions.tags.clone().unwrap_or_default();
+    let mut objects = db::object::from_key_data(key_data, key_id, raw_id)?;
 
     let mut result = Vec::new();
 
     let mut db = db.lock()?;
 
+    for object in objects.iter_mut() {
+        db.tag_attributes().apply(object, &tags);
+    }
+
     for object in objects {
         let r = db.add_object(object.clone());
         result.push((r.0, r.1.clone()));
@@ -536,7 +938,7 @@ fn extract_key_id_location_header(headers: HashMap<String, String>) -> Result<St
     let location_header = headers.get("location").ok_or(Error::InvalidData)?;
     let key_id = location_header
         .split('/')
-        .last()
+        .next_back()
         .ok_or(Error::InvalidData)?
         .split('?')
         .next()
@@ -577,3 +979,280 @@ pub fn fetch_one(
     }
     Ok(acc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_id_from_attr() {
+        let parse = |value: &[u8]| {
+            let mut value = value.to_vec();
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_ID,
+                pValue: value.as_mut_ptr() as _,
+                ulValueLen: value.len() as _,
+            }];
+            let template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+            let attr = template.iter().next().unwrap();
+            parse_key_id_from_attr(&attr)
+        };
+
+        assert_eq!(parse(b"myKey01"), Some(("myKey01".to_string(), None)));
+
+        // a SHA-1 thumbprint, as set by the certificate tools
+        let thumbprint: [u8; 20] = <sha1::Sha1 as sha1::Digest>::digest(b"certificate").into();
+        assert_eq!(
+            parse(&thumbprint),
+            Some((hex::encode(thumbprint), Some(thumbprint.to_vec())))
+        );
+
+        // valid UTF-8, but not a NetHSM key ID
+        assert_eq!(
+            parse("clé".as_bytes()),
+            Some(("636cc3a9".to_string(), Some("clé".as_bytes().to_vec())))
+        );
+        assert_eq!(
+            parse(b"my-key"),
+            Some(("6d792d6b6579".to_string(), Some(b"my-key".to_vec())))
+        );
+    }
+
+    #[test]
+    fn test_aes_key_len() {
+        for len in [16, 24, 32] {
+            assert_eq!(
+                secret_key_len(&Mechanism::GenerateAes, Some(len)).unwrap(),
+                len
+            );
+        }
+
+        assert!(matches!(
+            secret_key_len(&Mechanism::GenerateAes, Some(20)),
+            Err(Error::KeySizeRange(20))
+        ));
+        assert!(matches!(
+            secret_key_len(&Mechanism::GenerateAes, None),
+            Err(Error::TemplateIncomplete(CKA_VALUE_LEN))
+        ));
+    }
+
+    #[test]
+    fn test_rsa_key_len() {
+        for bits in [2048, 3072, 4096] {
+            assert!(check_rsa_key_len(Some(bits)).is_ok());
+        }
+        for bits in [1024, 2560, 8192] {
+            assert!(matches!(
+                check_rsa_key_len(Some(bits)),
+                Err(Error::KeySizeRange(b)) if b == bits
+            ));
+        }
+        assert!(matches!(
+            check_rsa_key_len(None),
+            Err(Error::TemplateIncomplete(CKA_MODULUS_BITS))
+        ));
+    }
+
+    #[test]
+    fn test_rsa_public_exponent() {
+        assert!(check_public_exponent(None).is_ok());
+        assert!(check_public_exponent(Some(&[0x01, 0x00, 0x01])).is_ok());
+        assert!(check_public_exponent(Some(&[0x00, 0x01, 0x00, 0x01])).is_ok());
+        for exponent in [&[0x02][..], &[0x01], &[0x03], &[], &[0x01, 0x00, 0x00]] {
+            assert!(matches!(
+                check_public_exponent(Some(exponent)),
+                Err(Error::TemplateInconsistent(CKA_PUBLIC_EXPONENT))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_generic_secret_len() {
+        assert_eq!(
+            secret_key_len(&Mechanism::GenerateGeneric, Some(20)).unwrap(),
+            20
+        );
+        assert!(matches!(
+            secret_key_len(&Mechanism::GenerateGeneric, Some(0)),
+            Err(Error::KeySizeRange(0))
+        ));
+        assert!(matches!(
+            secret_key_len(
+                &Mechanism::GenerateGeneric,
+                Some(GENERIC_SECRET_MAX_LEN + 1)
+            ),
+            Err(Error::KeySizeRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_secret_object() {
+        let object = db::object::from_session_secret("secret", None, vec![1; 20]);
+        assert_eq!(object.kind, ObjectKind::SecretKey);
+        assert_eq!(object.size, Some(20));
+        assert!(matches!(
+            object.get_attribute(CKA_VALUE_LEN),
+            Some(Attribute::Ulong(20))
+        ));
+        assert!(matches!(
+            object.get_attribute(CKA_KEY_TYPE),
+            Some(Attribute::Ulong(CKK_GENERIC_SECRET))
+        ));
+
+        // the value is readable so the secret can be digested
+        let mut digest =
+            crate::backend::digest::DigestCtx::init(crate::backend::mechanism::MechDigest::Sha256);
+        assert!(digest.update_key(&object).is_ok());
+    }
+
+    #[test]
+    fn test_generate_x25519() {
+        // the named curve id-X25519 (1.3.101.110)
+        let mut ec_params = hex_literal::hex!("06032b656e");
+        assert_eq!(key_type_from_params(&ec_params), None);
+
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_EC_PARAMS,
+            pValue: ec_params.as_mut_ptr() as _,
+            ulValueLen: ec_params.len() as _,
+        }];
+        let public_template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        let mut empty: [cryptoki_sys::CK_ATTRIBUTE; 0] = [];
+        let private_template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(empty.as_mut_ptr(), 0) }.unwrap();
+        // refused before the NetHSM is contacted
+        assert!(matches!(
+            generate_key_from_template(
+                &private_template,
+                Some(&public_template),
+                &Mechanism::GenerateEc,
+                LoginCtx::new(None, None, vec![], None),
+                Arc::new(Mutex::new(db::Db::new())),
+            ),
+            Err(Error::MechanismParamInvalid)
+        ));
+    }
+
+    #[test]
+    fn test_import_unwrapped_key_template() {
+        let import = |attrs: &[(cryptoki_sys::CK_ATTRIBUTE_TYPE, Vec<u8>)], value: &[u8]| {
+            let mut attrs = attrs.to_vec();
+            let mut template: Vec<_> = attrs
+                .iter_mut()
+                .map(|(type_, bytes)| cryptoki_sys::CK_ATTRIBUTE {
+                    type_: *type_,
+                    pValue: bytes.as_mut_ptr() as _,
+                    ulValueLen: bytes.len() as _,
+                })
+                .collect();
+            let template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) }
+                    .unwrap();
+            import_unwrapped_key(
+                &template,
+                value,
+                &db::TagAttributes::default(),
+                LoginCtx::new(None, None, vec![], None),
+            )
+        };
+        let ulong = |value: CK_ULONG| value.to_ne_bytes().to_vec();
+        let class = (CKA_CLASS, ulong(cryptoki_sys::CKO_SECRET_KEY));
+        let aes = (CKA_KEY_TYPE, ulong(CKK_AES));
+
+        assert!(matches!(
+            import(std::slice::from_ref(&aes), &[0; 16]),
+            Err(Error::TemplateIncomplete(CKA_CLASS))
+        ));
+        assert!(matches!(
+            import(std::slice::from_ref(&class), &[0; 16]),
+            Err(Error::TemplateIncomplete(CKA_KEY_TYPE))
+        ));
+        assert!(matches!(
+            import(
+                &[
+                    (CKA_CLASS, ulong(cryptoki_sys::CKO_PRIVATE_KEY)),
+                    aes.clone()
+                ],
+                &[0; 16]
+            ),
+            Err(Error::TemplateInconsistent(CKA_CLASS))
+        ));
+
+        // an AES-256 key that unwraps to 16 bytes
+        assert!(matches!(
+            import(
+                &[class.clone(), aes.clone(), (CKA_VALUE_LEN, ulong(32))],
+                &[0; 16]
+            ),
+            Err(Error::TemplateInconsistent(CKA_VALUE_LEN))
+        ));
+        assert!(matches!(
+            import(&[class.clone(), aes.clone()], &[0; 20]),
+            Err(Error::KeySizeRange(20))
+        ));
+
+        // the key can't be given out by the NetHSM
+        assert!(matches!(
+            import(
+                &[class.clone(), aes.clone(), (CKA_SENSITIVE, vec![0])],
+                &[0; 16]
+            ),
+            Err(Error::TemplateInconsistent(CKA_SENSITIVE))
+        ));
+        assert!(matches!(
+            import(&[class, aes, (CKA_EXTRACTABLE, vec![1])], &[0; 16]),
+            Err(Error::TemplateInconsistent(CKA_EXTRACTABLE))
+        ));
+    }
+
+    #[test]
+    fn test_session_secret_sensitivity() {
+        let mut sensitive = cryptoki_sys::CK_TRUE;
+        let mut extractable = cryptoki_sys::CK_FALSE;
+        let mut template = [
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_SENSITIVE,
+                pValue: &mut sensitive as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: 1,
+            },
+            cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_EXTRACTABLE,
+                pValue: &mut extractable as *mut _ as cryptoki_sys::CK_VOID_PTR,
+                ulValueLen: 1,
+            },
+        ];
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) }
+                .unwrap();
+        let parsed = parse_attributes(&template).unwrap();
+        assert_eq!(parsed.sensitive, Some(true));
+        assert_eq!(parsed.extractable, Some(false));
+
+        // the history follows the attributes of the creation template
+        let mut object = db::object::from_session_secret("secret", None, vec![1; 20]);
+        object.set_initial_sensitivity(true, false);
+        for (attr_type, value) in [
+            (cryptoki_sys::CKA_ALWAYS_SENSITIVE, true),
+            (cryptoki_sys::CKA_NEVER_EXTRACTABLE, true),
+        ] {
+            assert_eq!(
+                object.get_attribute(attr_type),
+                Some(&Attribute::Bool(value))
+            );
+        }
+
+        let object = db::object::from_session_secret("secret", None, vec![1; 20]);
+        for (attr_type, value) in [
+            (cryptoki_sys::CKA_ALWAYS_SENSITIVE, false),
+            (cryptoki_sys::CKA_NEVER_EXTRACTABLE, false),
+        ] {
+            assert_eq!(
+                object.get_attribute(attr_type),
+                Some(&Attribute::Bool(value))
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use cryptoki_sys::{
+    CKF_OS_LOCKING_OK, CKR_ARGUMENTS_BAD, CKR_CANT_LOCK, CKR_OK, CK_C_INITIALIZE_ARGS,
+    CK_DESTROYMUTEX, CK_LOCKMUTEX, CK_RV, CK_UNLOCKMUTEX, CK_VOID_PTR,
+};
+use log::{debug, error};
+
+use crate::data::APP_MUTEX;
+
+// A mutex created with the functions given by the application in CK_C_INITIALIZE_ARGS.
+// It is taken around the calls using a session when the application doesn't let
+// the module rely on the locking of the OS.
+#[derive(Debug)]
+pub struct AppMutex {
+    mutex: CK_VOID_PTR,
+    destroy: CK_DESTROYMUTEX,
+    lock: CK_LOCKMUTEX,
+    unlock: CK_UNLOCKMUTEX,
+}
+
+// the mutex is an opaque pointer only used through the functions of the application
+unsafe impl Send for AppMutex {}
+unsafe impl Sync for AppMutex {}
+
+impl Drop for AppMutex {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            let rv = unsafe { destroy(self.mutex) };
+            if rv != CKR_OK {
+                error!("Failed to destroy the mutex of the application: {}", rv);
+            }
+        }
+    }
+}
+
+pub struct AppMutexGuard(Arc<AppMutex>);
+
+impl Drop for AppMutexGuard {
+    fn drop(&mut self) {
+        if let Some(unlock) = self.0.unlock {
+            let rv = unsafe { unlock(self.0.mutex) };
+            if rv != CKR_OK {
+                error!("Failed to unlock the mutex of the application: {}", rv);
+            }
+        }
+    }
+}
+
+impl AppMutex {
+    // Chooses the locking to use from the arguments of C_Initialize:
+    // - no mutex functions: the locking of the OS is used, whatever the flags
+    // - mutex functions and CKF_OS_LOCKING_OK: the module can choose, it uses the OS
+    // - only mutex functions: they have to be used
+    pub fn from_init_args(args: &CK_C_INITIALIZE_ARGS) -> Result<Option<Self>, CK_RV> {
+        let functions = [
+            args.CreateMutex.is_some(),
+            args.DestroyMutex.is_some(),
+            args.LockMutex.is_some(),
+            args.UnlockMutex.is_some(),
+        ];
+
+        // the functions must be all given or none of them
+        if functions.iter().all(|f| !f) {
+            return Ok(None);
+        }
+        if !functions.iter().all(|f| *f) {
+            return Err(CKR_ARGUMENTS_BAD);
+        }
+
+        if args.flags & CKF_OS_LOCKING_OK != 0 {
+            debug!("Mutex functions given with CKF_OS_LOCKING_OK, using the OS locking");
+            return Ok(None);
+        }
+
+        let mut mutex: CK_VOID_PTR = std::ptr::null_mut();
+        let rv = match args.CreateMutex {
+            Some(create) => unsafe { create(&mut mutex) },
+            None => CKR_ARGUMENTS_BAD,
+        };
+        if rv != CKR_OK {
+            error!(
+                "Failed to create a mutex with the application functions: {}",
+                rv
+            );
+            return Err(CKR_CANT_LOCK);
+        }
+
+        Ok(Some(Self {
+            mutex,
+            destroy: args.DestroyMutex,
+            lock: args.LockMutex,
+            unlock: args.UnlockMutex,
+        }))
+    }
+
+    pub fn lock(self: &Arc<Self>) -> Result<AppMutexGuard, CK_RV> {
+        if let Some(lock) = self.lock {
+            let rv = unsafe { lock(self.mutex) };
+            if rv != CKR_OK {
+                error!("Failed to lock the mutex of the application: {}", rv);
+                return Err(rv);
+            }
+        }
+
+        Ok(AppMutexGuard(self.clone()))
+    }
+}
+
+// Takes the mutex of the application if one was given to C_Initialize,
+// it is released when the guard is dropped.
+pub fn lock_app_mutex() -> Result<Option<AppMutexGuard>, CK_RV> {
+    let Some(mutex) = APP_MUTEX.read().map_err(|_| CKR_CANT_LOCK)?.clone() else {
+        return Ok(None);
+    };
+
+    mutex.lock().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use cryptoki_sys::CK_VOID_PTR_PTR;
+
+    use super::*;
+
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+    static LOCKED: AtomicUsize = AtomicUsize::new(0);
+    static UNLOCKED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn create(mutex: CK_VOID_PTR_PTR) -> CK_RV {
+        CREATED.fetch_add(1, Ordering::SeqCst);
+        *mutex = 1 as CK_VOID_PTR;
+        CKR_OK
+    }
+
+    unsafe extern "C" fn create_fails(_: CK_VOID_PTR_PTR) -> CK_RV {
+        cryptoki_sys::CKR_HOST_MEMORY
+    }
+
+    unsafe extern "C" fn destroy(_: CK_VOID_PTR) -> CK_RV {
+        DESTROYED.fetch_add(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn lock(_: CK_VOID_PTR) -> CK_RV {
+        LOCKED.fetch_add(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn unlock(_: CK_VOID_PTR) -> CK_RV {
+        UNLOCKED.fetch_add(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    fn init_args(flags: cryptoki_sys::CK_FLAGS, functions: bool) -> CK_C_INITIALIZE_ARGS {
+        CK_C_INITIALIZE_ARGS {
+            CreateMutex: functions.then_some(create as _),
+            DestroyMutex: functions.then_some(destroy as _),
+            LockMutex: functions.then_some(lock as _),
+            UnlockMutex: functions.then_some(unlock as _),
+            flags,
+            pReserved: std::ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn test_os_locking() {
+        assert!(AppMutex::from_init_args(&init_args(0, false))
+            .unwrap()
+            .is_none());
+        assert!(
+            AppMutex::from_init_args(&init_args(CKF_OS_LOCKING_OK, false))
+                .unwrap()
+                .is_none()
+        );
+        // the module may choose, the functions are not used
+        assert!(
+            AppMutex::from_init_args(&init_args(CKF_OS_LOCKING_OK, true))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_invalid_locking_args() {
+        let mut args = init_args(0, true);
+        args.UnlockMutex = None;
+        assert_eq!(
+            AppMutex::from_init_args(&args).unwrap_err(),
+            CKR_ARGUMENTS_BAD
+        );
+
+        let mut args = init_args(0, true);
+        args.CreateMutex = Some(create_fails);
+        assert_eq!(AppMutex::from_init_args(&args).unwrap_err(), CKR_CANT_LOCK);
+    }
+
+    #[test]
+    fn test_custom_locking() {
+        let mutex = AppMutex::from_init_args(&init_args(0, true))
+            .unwrap()
+            .unwrap();
+        assert_eq!(CREATED.load(Ordering::SeqCst), 1);
+
+        let mutex = Arc::new(mutex);
+        {
+            let _guard = mutex.lock().unwrap();
+            assert_eq!(LOCKED.load(Ordering::SeqCst), 1);
+            assert_eq!(UNLOCKED.load(Ordering::SeqCst), 0);
+        }
+        assert_eq!(LOCKED.load(Ordering::SeqCst), 1);
+        assert_eq!(UNLOCKED.load(Ordering::SeqCst), 1);
+
+        drop(mutex);
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,7 +1,7 @@
 use cryptoki_sys::{
-    CKR_ARGUMENTS_BAD, CKR_DEVICE_ERROR, CKR_OK, CKR_PIN_INCORRECT, CKR_USER_NOT_LOGGED_IN,
-    CKR_USER_TYPE_INVALID, CKS_RO_PUBLIC_SESSION, CKS_RW_SO_FUNCTIONS, CKS_RW_USER_FUNCTIONS,
-    CKU_CONTEXT_SPECIFIC, CKU_SO, CKU_USER, CK_RV, CK_STATE, CK_USER_TYPE,
+    CKR_ARGUMENTS_BAD, CKR_DEVICE_ERROR, CKR_OK, CKR_PIN_INCORRECT, CKR_PIN_LEN_RANGE,
+    CKR_USER_NOT_LOGGED_IN, CKR_USER_TYPE_INVALID, CKS_RO_PUBLIC_SESSION, CKS_RW_SO_FUNCTIONS,
+    CKS_RW_USER_FUNCTIONS, CKU_CONTEXT_SPECIFIC, CKU_SO, CKU_USER, CK_RV, CK_STATE, CK_USER_TYPE,
 };
 use log::{debug, error, trace, warn};
 use nethsm_sdk_rs::{
@@ -9,20 +9,38 @@ use nethsm_sdk_rs::{
     models::UserRole,
     ureq,
 };
-use std::{thread, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use zeroize::Zeroizing;
 
-use crate::config::config_file::{RetryConfig, UserConfig};
+use crate::config::config_file::RetryConfig;
 
-use super::{ApiError, Error};
+use super::{
+    auth::{read_credentials, Credentials, SharedCredentials},
+    ApiError, Error,
+};
 
 #[derive(Debug, Clone)]
 pub struct LoginCtx {
-    operator: Option<UserConfig>,
-    administrator: Option<UserConfig>,
+    // the credentials of the slot until C_Login replaces them for this session
+    operator: Option<SharedCredentials>,
+    administrator: Option<SharedCredentials>,
     instances: Vec<Configuration>,
     index: usize,
     ck_state: CK_STATE,
     retries: Option<RetryConfig>,
+    // shared with the clones given to the operation contexts of the session
+    removed: Arc<AtomicBool>,
+    // The role chosen by C_Login, the credentials of the other role aren't used until
+    // C_Logout. None when the credentials come from the configuration.
+    role: Option<UserMode>,
 }
 
 #[derive(Debug, Clone)]
@@ -31,6 +49,7 @@ pub enum LoginError {
     UserNotPresent,
     BadArgument,
     IncorrectPin,
+    PinLenRange,
 }
 
 impl From<LoginError> for CK_RV {
@@ -40,6 +59,7 @@ impl From<LoginError> for CK_RV {
             LoginError::UserNotPresent => CKR_USER_TYPE_INVALID,
             LoginError::BadArgument => CKR_ARGUMENTS_BAD,
             LoginError::IncorrectPin => CKR_PIN_INCORRECT,
+            LoginError::PinLenRange => CKR_PIN_LEN_RANGE,
         }
     }
 }
@@ -57,14 +77,15 @@ impl std::fmt::Display for LoginError {
             LoginError::UserNotPresent => write!(f, "Username not cofigured for this user"),
             LoginError::BadArgument => write!(f, "Bad argument"),
             LoginError::IncorrectPin => write!(f, "Incorrect pin"),
+            LoginError::PinLenRange => write!(f, "Pin length out of range"),
         }
     }
 }
 
 impl LoginCtx {
     pub fn new(
-        operator: Option<UserConfig>,
-        administrator: Option<UserConfig>,
+        operator: Option<SharedCredentials>,
+        administrator: Option<SharedCredentials>,
         instances: Vec<Configuration>,
         retries: Option<RetryConfig>,
     ) -> Self {
@@ -87,9 +108,16 @@ impl LoginCtx {
             retries,
             index: 0,
             ck_state,
+            removed: Arc::new(AtomicBool::new(false)),
+            role: None,
         }
     }
 
+    // an instance stopped answering while this context was used
+    pub fn instance_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
     pub fn login(&mut self, user_type: CK_USER_TYPE, pin: String) -> Result<(), LoginError> {
         trace!("Login as {:?} with pin", user_type);
 
@@ -98,23 +126,11 @@ impl LoginCtx {
             CKU_SO => {
                 trace!("administrator: {:?}", self.administrator);
 
-                self.administrator = match self.administrator.as_ref() {
-                    None => return Err(LoginError::UserNotPresent),
-                    Some(user) => Some(UserConfig {
-                        password: Some(pin),
-                        ..user.clone()
-                    }),
-                };
+                self.administrator = Some(with_pin(&self.administrator, pin)?);
                 (UserStatus::Administrator, self.administrator())
             }
             CKU_USER => {
-                self.operator = match self.operator.as_ref() {
-                    None => return Err(LoginError::UserNotPresent),
-                    Some(user) => Some(UserConfig {
-                        password: Some(pin),
-                        ..user.clone()
-                    }),
-                };
+                self.operator = Some(with_pin(&self.operator, pin)?);
                 (UserStatus::Operator, self.operator())
             }
             _ => return Err(LoginError::BadArgument),
@@ -125,10 +141,10 @@ impl LoginCtx {
         let config = expected.1.ok_or(LoginError::UserNotPresent)?;
 
         if get_current_user_status(&config) == expected.0 {
-            self.ck_state = match expected.0 {
-                UserStatus::Operator => CKS_RW_USER_FUNCTIONS,
-                UserStatus::Administrator => CKS_RW_SO_FUNCTIONS,
-                UserStatus::LoggedOut => CKS_RO_PUBLIC_SESSION,
+            (self.ck_state, self.role) = match expected.0 {
+                UserStatus::Operator => (CKS_RW_USER_FUNCTIONS, Some(UserMode::Operator)),
+                UserStatus::Administrator => (CKS_RW_SO_FUNCTIONS, Some(UserMode::Administrator)),
+                UserStatus::LoggedOut => (CKS_RO_PUBLIC_SESSION, None),
             };
             Ok(())
         } else {
@@ -137,17 +153,46 @@ impl LoginCtx {
         }
     }
 
+    // C_Login(CKU_CONTEXT_SPECIFIC): the PIN of the operator is checked again, the credentials of
+    // the session are not changed
+    pub fn verify_pin(&mut self, pin: String) -> Result<(), LoginError> {
+        let user = Some(with_pin(&self.operator, pin)?);
+        let config = self
+            .next_instance()
+            .and_then(|instance| get_user_api_config(&user, &instance))
+            .ok_or(LoginError::UserNotPresent)?;
+
+        if get_current_user_status(&config) == UserStatus::Operator {
+            Ok(())
+        } else {
+            error!("Failed to verify the pin of the operator");
+            Err(LoginError::IncorrectPin)
+        }
+    }
+
     fn next_instance(&mut self) -> Option<Configuration> {
         self.index = (self.index + 1) % self.instances.len();
         self.instances.get(self.index).cloned()
     }
 
+    // a role can be used if it is the one the session logged in with, or if the session didn't
+    // call C_Login
+    fn role_allowed(&self, mode: UserMode) -> bool {
+        self.role.is_none() || self.role == Some(mode)
+    }
+
     fn operator(&mut self) -> Option<Configuration> {
+        if !self.role_allowed(UserMode::Operator) {
+            return None;
+        }
         self.next_instance()
             .and_then(|instance| get_user_api_config(&self.operator, &instance))
     }
 
     fn administrator(&mut self) -> Option<Configuration> {
+        if !self.role_allowed(UserMode::Administrator) {
+            return None;
+        }
         self.next_instance()
             .and_then(|instance| get_user_api_config(&self.administrator, &instance))
     }
@@ -168,18 +213,21 @@ impl LoginCtx {
 
         // trace!("Checking if user can run mode: {:?}", mode);
 
+        let operator = || self.role_allowed(UserMode::Operator) && user_is_valid(&self.operator);
+        let administrator =
+            || self.role_allowed(UserMode::Administrator) && user_is_valid(&self.administrator);
+
         match mode {
-            UserMode::Operator => user_is_valid(&self.operator),
-            UserMode::Administrator => user_is_valid(&self.administrator),
+            UserMode::Operator => operator(),
+            UserMode::Administrator => administrator(),
             UserMode::Guest => true,
-            UserMode::OperatorOrAdministrator => {
-                user_is_valid(&self.operator) || user_is_valid(&self.administrator)
-            }
+            UserMode::OperatorOrAdministrator => operator() || administrator(),
         }
     }
 
     pub fn logout(&mut self) {
         self.ck_state = CKS_RO_PUBLIC_SESSION;
+        self.role = None;
     }
 
     pub fn get_config_user_mode(&mut self, user_mode: &UserMode) -> Option<Configuration> {
@@ -236,6 +284,7 @@ impl LoginCtx {
                     {
                         if retry_count == retry_limit {
                             error!("Retry count exceeded after {retry_limit} attempts, instance is unreachable: {err}");
+                            self.removed.store(true, Ordering::Relaxed);
                             return Err(ApiError::InstanceRemoved);
                         }
 
@@ -256,35 +305,42 @@ impl LoginCtx {
     pub fn change_pin(&mut self, pin: String) -> CK_RV {
         let options = match self.ck_state {
             CKS_RW_SO_FUNCTIONS => {
-                let username = match self.administrator {
-                    Some(ref user) => user.username.clone(),
+                let user = match self.administrator {
+                    Some(ref user) => user.clone(),
                     None => return CKR_USER_NOT_LOGGED_IN,
                 };
 
-                (username, UserMode::Administrator)
+                (user, UserMode::Administrator)
             }
             CKS_RW_USER_FUNCTIONS => {
-                let username = match self.operator {
-                    Some(ref user) => user.username.clone(),
+                let user = match self.operator {
+                    Some(ref user) => user.clone(),
                     None => return CKR_USER_NOT_LOGGED_IN,
                 };
 
-                (username, UserMode::Operator)
+                (user, UserMode::Operator)
             }
             _ => return CKR_USER_NOT_LOGGED_IN,
         };
 
+        let username = read_credentials(&options.0).username;
+        let passphrase = pin.clone();
+
         match self.try_(
             |config| {
                 default_api::users_user_id_passphrase_post(
                     config,
-                    &options.0,
-                    nethsm_sdk_rs::models::UserPassphrasePostData { passphrase: pin },
+                    &username,
+                    nethsm_sdk_rs::models::UserPassphrasePostData { passphrase },
                 )
             },
             options.1,
         ) {
-            Ok(_) => CKR_OK,
+            // the sessions sharing the credentials keep working with the new PIN
+            Ok(_) => {
+                Credentials::rotate(&options.0, Zeroizing::new(pin));
+                CKR_OK
+            }
             Err(err) => {
                 error!("Failed to change pin: {:?}", err);
                 CKR_DEVICE_ERROR
@@ -336,37 +392,39 @@ pub fn get_current_user_status(
 }
 // Check if the user is logged in and then return the configuration to connect as this user
 fn get_user_api_config(
-    user: &Option<UserConfig>,
+    user: &Option<SharedCredentials>,
     api_config: &nethsm_sdk_rs::apis::configuration::Configuration,
 ) -> Option<nethsm_sdk_rs::apis::configuration::Configuration> {
     user.as_ref().and_then(|user| {
-        let config = api_config.clone();
-        if user.password.is_none() {
-            None
-        } else {
-            Some(Configuration {
-                basic_auth: Some((user.username.clone(), user.password.clone())),
-                ..config
-            })
-        }
+        let user = read_credentials(user);
+        user.has_password()
+            .then(|| user.apply_to_config(api_config))
     })
 }
 
-fn user_is_valid(user: &Option<UserConfig>) -> bool {
+fn user_is_valid(user: &Option<SharedCredentials>) -> bool {
     user.as_ref()
         .map(|user| {
-            !user.username.is_empty()
-                && user
-                    .password
-                    .as_ref()
-                    .map(|password| !password.is_empty())
-                    .unwrap_or(false)
+            let user = read_credentials(user);
+            !user.username.is_empty() && user.has_password()
         })
         .unwrap_or(false)
 }
 
+// the credentials given to C_Login only belong to the session
+fn with_pin(
+    user: &Option<SharedCredentials>,
+    pin: String,
+) -> Result<SharedCredentials, LoginError> {
+    let user = user.as_ref().ok_or(LoginError::UserNotPresent)?;
+    let username = read_credentials(user).username;
+    Ok(Credentials::new(username, Zeroizing::new(pin)).shared())
+}
+
 #[cfg(test)]
 mod test {
+    use crate::config::config_file::UserConfig;
+
     use super::*;
     #[test]
     fn test_user_is_valid() {
@@ -379,8 +437,47 @@ mod test {
             password: None,
         };
 
-        assert!(user_is_valid(&Some(user)));
+        assert!(user_is_valid(&Some(
+            Credentials::from_config(&user).shared()
+        )));
         assert!(!user_is_valid(&None));
-        assert!(!user_is_valid(&Some(empty_password_user)));
+        assert!(!user_is_valid(&Some(
+            Credentials::from_config(&empty_password_user).shared()
+        )));
+    }
+
+    #[test]
+    fn test_change_pin_rotates_slot_credentials() {
+        let (slot, _) = crate::backend::session::tests::mock_slot(0);
+
+        let mut session_ctx =
+            LoginCtx::new(slot.operator.clone(), None, slot.instances.clone(), None);
+        let mut other_ctx = session_ctx.clone();
+        assert_eq!(session_ctx.change_pin("new password".to_string()), CKR_OK);
+
+        let password = read_credentials(slot.operator.as_ref().unwrap()).password;
+        assert_eq!(password.as_str(), "new password");
+        let config = other_ctx.get_config_user_mode(&UserMode::Operator).unwrap();
+        assert_eq!(
+            config.basic_auth,
+            Some(("operator".to_string(), Some("new password".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_login_keeps_slot_credentials() {
+        let (slot, _) = crate::backend::session::tests::mock_slot(0);
+
+        let mut login_ctx =
+            LoginCtx::new(slot.operator.clone(), None, slot.instances.clone(), None);
+        assert!(login_ctx.login(CKU_USER, "other".to_string()).is_ok());
+        let config = login_ctx.get_config_user_mode(&UserMode::Operator).unwrap();
+        assert_eq!(
+            config.basic_auth,
+            Some(("operator".to_string(), Some("other".to_string())))
+        );
+
+        let password = read_credentials(slot.operator.as_ref().unwrap()).password;
+        assert_eq!(password.as_str(), "password");
     }
 }
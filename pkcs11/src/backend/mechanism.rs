@@ -2,25 +2,18 @@
 // Copyright 2023 Nitrokey
 // SPDX-License-Identifier: Apache-2.0
 
-use cryptoki_sys::{CKM_RSA_PKCS_OAEP, CK_MECHANISM_TYPE, CK_ULONG};
-use log::trace;
+use cryptoki_sys::{
+    CKK_AES, CKK_EC, CKK_EC_EDWARDS, CKK_GENERIC_SECRET, CKK_RSA, CKM_RSA_PKCS_OAEP, CK_KEY_TYPE,
+    CK_MECHANISM_TYPE, CK_RV, CK_ULONG,
+};
+use log::{debug, trace};
 use nethsm_sdk_rs::models::{DecryptMode, EncryptMode, KeyMechanism, KeyType, SignMode};
 
 // from https://github.com/aws/aws-nitro-enclaves-acm/blob/main/src/vtok_p11/src/backend/mech.rs
-#[derive(Debug)]
-pub enum CkRawError {
-    MechParamTypeMismatch,
-    NullPtrDeref,
-}
-
 #[derive(Debug)]
 pub struct CkRawMechanism {
     ptr: *mut cryptoki_sys::CK_MECHANISM,
 }
-pub trait MechParams {}
-impl MechParams for cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS {}
-impl MechParams for cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS {}
-impl MechParams for [cryptoki_sys::CK_BYTE; 16] {}
 
 impl CkRawMechanism {
     pub unsafe fn from_raw_ptr(ptr: *mut cryptoki_sys::CK_MECHANISM) -> Option<Self> {
@@ -35,38 +28,160 @@ impl CkRawMechanism {
         unsafe { (*self.ptr).mechanism }
     }
 
-    // Note: marking this unsafe, even if it breaks our pattern of using object constructors
-    // to cover unsafe FFI code.
-    // Reading the wrong data type is bad, mkay?
-    pub unsafe fn params<T: MechParams>(&self) -> Result<Option<T>, CkRawError> {
-        let param_ptr = (*self.ptr).pParameter;
-        let param_len = (*self.ptr).ulParameterLen;
-        if param_ptr.is_null() || param_len == 0 {
-            return Ok(None);
-        }
-        if std::mem::size_of::<T>() != param_len as usize {
-            return Err(CkRawError::MechParamTypeMismatch);
+    // the parameters are checked against the mechanism type
+    pub fn params(&self) -> Result<MechanismParams, CK_RV> {
+        unsafe {
+            MechanismParams::from_raw(
+                (*self.ptr).mechanism,
+                (*self.ptr).pParameter,
+                (*self.ptr).ulParameterLen,
+            )
         }
-        Ok(Some(std::ptr::read(param_ptr as *const T)))
     }
+
     pub fn len(&self) -> CK_ULONG {
         unsafe { (*self.ptr).ulParameterLen }
     }
 }
 
+// CK_GCM_MESSAGE_PARAMS from PKCS#11 3.0, cryptoki-sys doesn't have it
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CK_GCM_MESSAGE_PARAMS {
+    pub pIv: cryptoki_sys::CK_BYTE_PTR,
+    pub ulIvLen: CK_ULONG,
+    pub ulIvFixedBits: CK_ULONG,
+    pub ivGenerator: CK_ULONG,
+    pub pTag: cryptoki_sys::CK_BYTE_PTR,
+    pub ulTagBits: CK_ULONG,
+}
+
+// The parameters of a mechanism, read from the (pParameter, ulParameterLen) of a CK_MECHANISM or
+// of a message function.
+#[derive(Clone, Copy, Debug)]
+pub enum MechanismParams {
+    None,
+    // the IV of AES-CBC is a single AES block
+    Iv([u8; 16]),
+    GcmParams(cryptoki_sys::CK_GCM_PARAMS),
+    RsaOaepParams(cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS),
+    RsaPssParams(cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS),
+    Ecdh1DeriveParams(cryptoki_sys::CK_ECDH1_DERIVE_PARAMS),
+    MessageGcmParams(CK_GCM_MESSAGE_PARAMS),
+}
+
+impl MechanismParams {
+    // Reads the parameters of the mechanism type_. They are required for the mechanisms that
+    // can't work without them and optional for the others, a mechanism that takes no
+    // parameters ignores them.
+    //
+    // ptr must be null or point to len readable bytes.
+    pub unsafe fn from_raw(
+        type_: CK_MECHANISM_TYPE,
+        ptr: cryptoki_sys::CK_VOID_PTR,
+        len: CK_ULONG,
+    ) -> Result<Self, CK_RV> {
+        let params = match type_ {
+            cryptoki_sys::CKM_AES_CBC | cryptoki_sys::CKM_AES_CBC_PAD => {
+                read_params(ptr, len)?.map(Self::Iv)
+            }
+            cryptoki_sys::CKM_AES_GCM => read_params(ptr, len)?.map(Self::GcmParams),
+            cryptoki_sys::CKM_RSA_PKCS_OAEP => read_params(ptr, len)?.map(Self::RsaOaepParams),
+            cryptoki_sys::CKM_RSA_PKCS_PSS => read_params(ptr, len)?.map(Self::RsaPssParams),
+            cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS
+            | cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS
+            | cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS
+            | cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS
+            | cryptoki_sys::CKM_SHA512_RSA_PKCS_PSS => {
+                // the digest is given by the mechanism
+                return Ok(read_params(ptr, len)?
+                    .map(Self::RsaPssParams)
+                    .unwrap_or(Self::None));
+            }
+            cryptoki_sys::CKM_ECDH1_DERIVE | cryptoki_sys::CKM_ECDH1_COFACTOR_DERIVE => {
+                read_params(ptr, len)?.map(Self::Ecdh1DeriveParams)
+            }
+            _ => return Ok(Self::None),
+        };
+
+        params.ok_or_else(|| {
+            debug!("Missing parameters for the mechanism {}", type_);
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        })
+    }
+
+    // Reads the parameters given to a message function (C_EncryptMessage, C_SignMessage...)
+    // for the mechanism type_ of the message context.
+    //
+    // ptr must be null or point to len readable bytes.
+    pub unsafe fn from_raw_message(
+        type_: CK_MECHANISM_TYPE,
+        ptr: cryptoki_sys::CK_VOID_PTR,
+        len: CK_ULONG,
+    ) -> Result<Self, CK_RV> {
+        match type_ {
+            cryptoki_sys::CKM_AES_GCM => read_params(ptr, len)?
+                .map(Self::MessageGcmParams)
+                .ok_or(cryptoki_sys::CKR_MECHANISM_PARAM_INVALID),
+            // the other mechanisms of the module take no parameter per message
+            _ if ptr.is_null() || len == 0 => Ok(Self::None),
+            _ => {
+                debug!("Parameters given to a message of the mechanism {}", type_);
+                Err(cryptoki_sys::CKR_MECHANISM_PARAM_INVALID)
+            }
+        }
+    }
+}
+
+// None if there are no parameters, an error if they don't have the size of T
+unsafe fn read_params<T: Copy>(
+    ptr: cryptoki_sys::CK_VOID_PTR,
+    len: CK_ULONG,
+) -> Result<Option<T>, CK_RV> {
+    if ptr.is_null() || len == 0 {
+        return Ok(None);
+    }
+    if std::mem::size_of::<T>() != len as usize {
+        debug!(
+            "Mechanism parameters of {} bytes, expected {}",
+            len,
+            std::mem::size_of::<T>()
+        );
+        return Err(cryptoki_sys::CKR_MECHANISM_PARAM_INVALID);
+    }
+    Ok(Some(std::ptr::read_unaligned(ptr as *const T)))
+}
+
 #[derive(Debug)]
 pub enum Error {
-    CkRaw(CkRawError),
     UnknownMech(CK_MECHANISM_TYPE),
     UnknownDigest(CK_MECHANISM_TYPE),
+    // the parameters are valid but the NetHSM can't use them
+    UnsupportedParams,
+    // the parameters don't fit the mechanism
+    InvalidParams,
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match &self {
-            Error::CkRaw(e) => write!(f, "CkRaw error: {:?}", e),
             Error::UnknownMech(t) => write!(f, "Unknown mechanism {}", t),
             Error::UnknownDigest(t) => write!(f, "Unknown digest {}", t),
+            Error::UnsupportedParams => write!(f, "Unsupported mechanism parameters"),
+            Error::InvalidParams => write!(f, "Invalid mechanism parameters"),
+        }
+    }
+}
+
+// the same return values for all the *_init functions
+impl From<Error> for cryptoki_sys::CK_RV {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::UnknownMech(_) | Error::UnknownDigest(_) => cryptoki_sys::CKR_MECHANISM_INVALID,
+            Error::UnsupportedParams | Error::InvalidParams => {
+                cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+            }
         }
     }
 }
@@ -82,6 +197,29 @@ pub enum MechDigest {
 }
 
 impl MechDigest {
+    pub fn ck_mech(&self) -> CK_MECHANISM_TYPE {
+        match self {
+            Self::Md5 => cryptoki_sys::CKM_MD5,
+            Self::Sha1 => cryptoki_sys::CKM_SHA_1,
+            Self::Sha224 => cryptoki_sys::CKM_SHA224,
+            Self::Sha256 => cryptoki_sys::CKM_SHA256,
+            Self::Sha384 => cryptoki_sys::CKM_SHA384,
+            Self::Sha512 => cryptoki_sys::CKM_SHA512,
+        }
+    }
+
+    // the MGF1 function using this hash, MD5 has none
+    pub fn mgf1(&self) -> Option<cryptoki_sys::CK_RSA_PKCS_MGF_TYPE> {
+        match self {
+            Self::Md5 => None,
+            Self::Sha1 => Some(cryptoki_sys::CKG_MGF1_SHA1),
+            Self::Sha224 => Some(cryptoki_sys::CKG_MGF1_SHA224),
+            Self::Sha256 => Some(cryptoki_sys::CKG_MGF1_SHA256),
+            Self::Sha384 => Some(cryptoki_sys::CKG_MGF1_SHA384),
+            Self::Sha512 => Some(cryptoki_sys::CKG_MGF1_SHA512),
+        }
+    }
+
     pub fn from_ck_mech(mech: CK_MECHANISM_TYPE) -> Option<Self> {
         match mech {
             cryptoki_sys::CKM_MD5 => Some(Self::Md5),
@@ -93,6 +231,89 @@ impl MechDigest {
             _ => None,
         }
     }
+
+    // DER header of the DigestInfo of the hash (RFC 8017 section 9.2), the hash follows it
+    pub fn digest_info_prefix(&self) -> &'static [u8] {
+        match self {
+            Self::Md5 => &[
+                0x30, 0x20, 0x30, 0x0c, 0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x05,
+                0x05, 0x00, 0x04, 0x10,
+            ],
+            Self::Sha1 => &[
+                0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04,
+                0x14,
+            ],
+            Self::Sha224 => &[
+                0x30, 0x2d, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x04, 0x05, 0x00, 0x04, 0x1c,
+            ],
+            Self::Sha256 => &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            Self::Sha384 => &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+            Self::Sha512 => &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+        }
+    }
+
+    // the hash of a DigestInfo, if it is one of a known hash
+    pub fn from_digest_info(data: &[u8]) -> Option<Self> {
+        [
+            Self::Md5,
+            Self::Sha1,
+            Self::Sha224,
+            Self::Sha256,
+            Self::Sha384,
+            Self::Sha512,
+        ]
+        .into_iter()
+        .find(|digest| {
+            let prefix = digest.digest_info_prefix();
+            data.len() == prefix.len() + digest.output_size() && data.starts_with(prefix)
+        })
+    }
+
+    // size of the hash in bytes
+    pub fn output_size(&self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha224 => 28,
+            Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+
+    // size of the internal block of the hash function in bytes, used by HMAC
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::Md5 | Self::Sha1 | Self::Sha224 | Self::Sha256 => 64,
+            Self::Sha384 | Self::Sha512 => 128,
+        }
+    }
+}
+
+// The NetHSM signs with MGF1 using the hash of the message and a salt as long as the hash,
+// which is what TLS 1.3 asks for. Other parameters would give signatures that don't verify.
+fn check_pss_params(
+    params: &cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS,
+    digest: MechDigest,
+) -> Result<(), Error> {
+    if digest.mgf1() != Some(params.mgf) || params.sLen != digest.output_size() as CK_ULONG {
+        debug!(
+            "Unsupported PSS parameters: mgf {}, salt length {} with {:?}",
+            params.mgf, params.sLen, digest
+        );
+        return Err(Error::UnsupportedParams);
+    }
+    Ok(())
 }
 
 pub type InitializationVector = Option<[u8; 16]>;
@@ -107,6 +328,8 @@ pub enum Mechanism {
     RsaX509,
     EdDsa,
     Ecdsa(Option<MechDigest>),
+    Hmac(MechDigest),
+    AesCmac,
     GenerateGeneric,
     GenerateAes,
     GenerateRsa,
@@ -140,11 +363,65 @@ impl From<KeyMechanism> for Mechanism {
     }
 }
 
+// The hash mechanism of a DigestInfo (the known header followed by a hash of the right length),
+// as given to CKM_RSA_PKCS by the applications that hash on their own
+pub fn is_digestinfo_prefix(bytes: &[u8]) -> Option<CK_MECHANISM_TYPE> {
+    MechDigest::from_digest_info(bytes).map(|digest| digest.ck_mech())
+}
+
+// The key types a mechanism can be used with. The AES keys of the NetHSM are generic keys,
+// the HMAC keys are the secrets generated by the module.
+pub fn mechanism_key_type_compatible(mechanism: CK_MECHANISM_TYPE, key_type: CK_KEY_TYPE) -> bool {
+    match mechanism {
+        cryptoki_sys::CKM_AES_CBC
+        | cryptoki_sys::CKM_AES_CBC_PAD
+        | cryptoki_sys::CKM_AES_ECB
+        | cryptoki_sys::CKM_AES_CTR
+        | cryptoki_sys::CKM_AES_GCM
+        | cryptoki_sys::CKM_AES_KEY_WRAP
+        | cryptoki_sys::CKM_AES_KEY_WRAP_PAD
+        | cryptoki_sys::CKM_AES_CMAC => {
+            matches!(key_type, CKK_AES | CKK_GENERIC_SECRET)
+        }
+        cryptoki_sys::CKM_RSA_PKCS
+        | cryptoki_sys::CKM_SHA1_RSA_PKCS
+        | cryptoki_sys::CKM_SHA224_RSA_PKCS
+        | cryptoki_sys::CKM_SHA256_RSA_PKCS
+        | cryptoki_sys::CKM_SHA384_RSA_PKCS
+        | cryptoki_sys::CKM_SHA512_RSA_PKCS
+        | cryptoki_sys::CKM_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_SHA512_RSA_PKCS_PSS
+        | cryptoki_sys::CKM_RSA_PKCS_OAEP
+        | cryptoki_sys::CKM_RSA_X_509 => key_type == CKK_RSA,
+        cryptoki_sys::CKM_ECDSA
+        | cryptoki_sys::CKM_ECDSA_SHA1
+        | cryptoki_sys::CKM_ECDSA_SHA224
+        | cryptoki_sys::CKM_ECDSA_SHA256
+        | cryptoki_sys::CKM_ECDSA_SHA384
+        | cryptoki_sys::CKM_ECDSA_SHA512
+        | cryptoki_sys::CKM_ECDH1_DERIVE
+        | cryptoki_sys::CKM_ECDH1_COFACTOR_DERIVE => key_type == CKK_EC,
+        cryptoki_sys::CKM_EDDSA => key_type == CKK_EC_EDWARDS,
+        cryptoki_sys::CKM_MD5_HMAC
+        | cryptoki_sys::CKM_SHA_1_HMAC
+        | cryptoki_sys::CKM_SHA224_HMAC
+        | cryptoki_sys::CKM_SHA256_HMAC
+        | cryptoki_sys::CKM_SHA384_HMAC
+        | cryptoki_sys::CKM_SHA512_HMAC => key_type == CKK_GENERIC_SECRET,
+        _ => false,
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum MechMode {
     Sign,
     Encrypt,
     Decrypt,
+    Verify,
 }
 
 /// The token supported mechanisms and their capabilities.
@@ -156,6 +433,8 @@ pub enum MechMode {
 impl Mechanism {
     const RSA_MIN_KEY_BITS: cryptoki_sys::CK_ULONG = 1024;
     const RSA_MAX_KEY_BITS: cryptoki_sys::CK_ULONG = 8192;
+    // the existing keys can have other sizes, the NetHSM only generates these ones
+    pub const RSA_GEN_KEY_BITS: [cryptoki_sys::CK_ULONG; 3] = [2048, 3072, 4096];
     const EC_MIN_KEY_BITS: cryptoki_sys::CK_ULONG = 224;
     const EC_MAX_KEY_BITS: cryptoki_sys::CK_ULONG = 521;
     const ED_MIN_KEY_BITS: cryptoki_sys::CK_ULONG = 256;
@@ -189,7 +468,11 @@ impl Mechanism {
 
     pub fn to_key_type(&self) -> KeyType {
         match self {
-            Self::AesCbc(_) | Self::GenerateAes | Self::GenerateGeneric => KeyType::Generic,
+            Self::AesCbc(_)
+            | Self::Hmac(_)
+            | Self::AesCmac
+            | Self::GenerateAes
+            | Self::GenerateGeneric => KeyType::Generic,
             Self::RsaPkcs(_)
             | Self::RsaPkcsOaep(_)
             | Self::RsaPkcsPss(_, _)
@@ -230,6 +513,10 @@ impl Mechanism {
             ],
             Self::Ecdsa(_) | Self::GenerateEc => vec![KeyMechanism::EcdsaSignature],
             Self::EdDsa | Self::GenerateEd => vec![KeyMechanism::EdDsaSignature],
+            // HMAC is computed by the module, the NetHSM has no mechanism for it
+            Self::Hmac(_) => vec![],
+            // the NetHSM does the CBC encryption of AES-CMAC
+            Self::AesCmac => vec![KeyMechanism::AesEncryptionCbc],
         }
     }
 
@@ -270,71 +557,99 @@ impl Mechanism {
                 },
                 _ => None,
             },
+            // the NetHSM can't verify
+            MechMode::Verify => None,
         }
     }
 
     pub fn from_ckraw_mech(raw_mech: &CkRawMechanism) -> Result<Self, Error> {
-        let mech = match raw_mech.type_() {
-            cryptoki_sys::CKM_AES_KEY_GEN => Self::GenerateAes,
-            cryptoki_sys::CKM_RSA_PKCS_KEY_PAIR_GEN => Self::GenerateRsa,
-            cryptoki_sys::CKM_EC_KEY_PAIR_GEN => Self::GenerateEc,
-            cryptoki_sys::CKM_EC_EDWARDS_KEY_PAIR_GEN => Self::GenerateEd,
-            cryptoki_sys::CKM_GENERIC_SECRET_KEY_GEN => Self::GenerateGeneric,
-            cryptoki_sys::CKM_AES_CBC => {
-                let params = unsafe { raw_mech.params::<[cryptoki_sys::CK_BYTE; 16]>() }
-                    .map_err(Error::CkRaw)?;
-
-                let params = params.ok_or(Error::CkRaw(CkRawError::NullPtrDeref))?;
-
-                Self::AesCbc(Some(params))
-            }
+        // the parameters of the mechanisms the NetHSM doesn't have aren't checked
+        let mech = match (raw_mech.type_(), raw_mech.params()) {
+            (cryptoki_sys::CKM_AES_KEY_GEN, _) => Self::GenerateAes,
+            (cryptoki_sys::CKM_RSA_PKCS_KEY_PAIR_GEN, _) => Self::GenerateRsa,
+            (cryptoki_sys::CKM_EC_KEY_PAIR_GEN, _) => Self::GenerateEc,
+            (cryptoki_sys::CKM_EC_EDWARDS_KEY_PAIR_GEN, _) => Self::GenerateEd,
+            (cryptoki_sys::CKM_GENERIC_SECRET_KEY_GEN, _) => Self::GenerateGeneric,
+            (cryptoki_sys::CKM_AES_CBC, Ok(MechanismParams::Iv(iv))) => Self::AesCbc(Some(iv)),
 
-            cryptoki_sys::CKM_RSA_PKCS => Self::RsaPkcs(None),
-            cryptoki_sys::CKM_SHA1_RSA_PKCS => Self::RsaPkcs(Some(MechDigest::Sha1)),
-            cryptoki_sys::CKM_SHA224_RSA_PKCS => Self::RsaPkcs(Some(MechDigest::Sha224)),
-            cryptoki_sys::CKM_SHA256_RSA_PKCS => Self::RsaPkcs(Some(MechDigest::Sha256)),
-            cryptoki_sys::CKM_SHA384_RSA_PKCS => Self::RsaPkcs(Some(MechDigest::Sha384)),
-            cryptoki_sys::CKM_SHA512_RSA_PKCS => Self::RsaPkcs(Some(MechDigest::Sha512)),
-
-            cryptoki_sys::CKM_RSA_PKCS_PSS => {
-                let params = unsafe { raw_mech.params::<cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS>() }
-                    .map_err(Error::CkRaw)?;
-                let params = params.ok_or(Error::CkRaw(CkRawError::NullPtrDeref))?;
+            (cryptoki_sys::CKM_RSA_PKCS, _) => Self::RsaPkcs(None),
+            (cryptoki_sys::CKM_SHA1_RSA_PKCS, _) => Self::RsaPkcs(Some(MechDigest::Sha1)),
+            (cryptoki_sys::CKM_SHA224_RSA_PKCS, _) => Self::RsaPkcs(Some(MechDigest::Sha224)),
+            (cryptoki_sys::CKM_SHA256_RSA_PKCS, _) => Self::RsaPkcs(Some(MechDigest::Sha256)),
+            (cryptoki_sys::CKM_SHA384_RSA_PKCS, _) => Self::RsaPkcs(Some(MechDigest::Sha384)),
+            (cryptoki_sys::CKM_SHA512_RSA_PKCS, _) => Self::RsaPkcs(Some(MechDigest::Sha512)),
 
+            (cryptoki_sys::CKM_RSA_PKCS_PSS, Ok(MechanismParams::RsaPssParams(params))) => {
                 let hash_alg = params.hashAlg;
 
                 trace!("params.hashAlg: {:?}", hash_alg);
-                Self::RsaPkcsPss(
-                    MechDigest::from_ck_mech(params.hashAlg)
-                        .ok_or(Error::UnknownDigest(hash_alg))?,
-                    false,
-                )
+                let digest = MechDigest::from_ck_mech(params.hashAlg)
+                    .ok_or(Error::UnknownDigest(hash_alg))?;
+                check_pss_params(&params, digest)?;
+                Self::RsaPkcsPss(digest, false)
+            }
+            (
+                type_ @ (cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA512_RSA_PKCS_PSS),
+                Ok(params),
+            ) => {
+                let digest = match type_ {
+                    cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS => MechDigest::Sha1,
+                    cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS => MechDigest::Sha224,
+                    cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS => MechDigest::Sha256,
+                    cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS => MechDigest::Sha384,
+                    _ => MechDigest::Sha512,
+                };
+                // the parameters are optional here, the digest is given by the mechanism
+                if let MechanismParams::RsaPssParams(params) = params {
+                    if params.hashAlg != digest.ck_mech() {
+                        debug!(
+                            "The PSS hash {} doesn't match the mechanism",
+                            params.hashAlg
+                        );
+                        return Err(Error::UnsupportedParams);
+                    }
+                    check_pss_params(&params, digest)?;
+                }
+                Self::RsaPkcsPss(digest, true)
             }
-            cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS => Self::RsaPkcsPss(MechDigest::Sha1, true),
-            cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS => Self::RsaPkcsPss(MechDigest::Sha224, true),
-            cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS => Self::RsaPkcsPss(MechDigest::Sha256, true),
-            cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS => Self::RsaPkcsPss(MechDigest::Sha384, true),
-            cryptoki_sys::CKM_SHA512_RSA_PKCS_PSS => Self::RsaPkcsPss(MechDigest::Sha512, true),
-
-            cryptoki_sys::CKM_RSA_PKCS_OAEP => {
-                let params = unsafe { raw_mech.params::<cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS>() }
-                    .map_err(Error::CkRaw)?;
-                let params = params.ok_or(Error::CkRaw(CkRawError::NullPtrDeref))?;
 
+            (cryptoki_sys::CKM_RSA_PKCS_OAEP, Ok(MechanismParams::RsaOaepParams(params))) => {
                 Self::RsaPkcsOaep(
                     MechDigest::from_ck_mech(params.hashAlg)
                         .ok_or(Error::UnknownDigest(params.hashAlg))?,
                 )
             }
 
-            cryptoki_sys::CKM_RSA_X_509 => Self::RsaX509,
-            cryptoki_sys::CKM_ECDSA => Self::Ecdsa(None),
-            cryptoki_sys::CKM_ECDSA_SHA1 => Self::Ecdsa(Some(MechDigest::Sha1)),
-            cryptoki_sys::CKM_ECDSA_SHA224 => Self::Ecdsa(Some(MechDigest::Sha224)),
-            cryptoki_sys::CKM_ECDSA_SHA256 => Self::Ecdsa(Some(MechDigest::Sha256)),
-            cryptoki_sys::CKM_ECDSA_SHA384 => Self::Ecdsa(Some(MechDigest::Sha384)),
-            cryptoki_sys::CKM_ECDSA_SHA512 => Self::Ecdsa(Some(MechDigest::Sha512)),
-            cryptoki_sys::CKM_EDDSA => Self::EdDsa,
+            (cryptoki_sys::CKM_RSA_X_509, _) => Self::RsaX509,
+            (cryptoki_sys::CKM_ECDSA, _) => Self::Ecdsa(None),
+            (cryptoki_sys::CKM_ECDSA_SHA1, _) => Self::Ecdsa(Some(MechDigest::Sha1)),
+            (cryptoki_sys::CKM_ECDSA_SHA224, _) => Self::Ecdsa(Some(MechDigest::Sha224)),
+            (cryptoki_sys::CKM_ECDSA_SHA256, _) => Self::Ecdsa(Some(MechDigest::Sha256)),
+            (cryptoki_sys::CKM_ECDSA_SHA384, _) => Self::Ecdsa(Some(MechDigest::Sha384)),
+            (cryptoki_sys::CKM_ECDSA_SHA512, _) => Self::Ecdsa(Some(MechDigest::Sha512)),
+            (cryptoki_sys::CKM_EDDSA, _) => Self::EdDsa,
+            (cryptoki_sys::CKM_MD5_HMAC, _) => Self::Hmac(MechDigest::Md5),
+            (cryptoki_sys::CKM_SHA_1_HMAC, _) => Self::Hmac(MechDigest::Sha1),
+            (cryptoki_sys::CKM_SHA224_HMAC, _) => Self::Hmac(MechDigest::Sha224),
+            (cryptoki_sys::CKM_SHA256_HMAC, _) => Self::Hmac(MechDigest::Sha256),
+            (cryptoki_sys::CKM_SHA384_HMAC, _) => Self::Hmac(MechDigest::Sha384),
+            (cryptoki_sys::CKM_SHA512_HMAC, _) => Self::Hmac(MechDigest::Sha512),
+            (cryptoki_sys::CKM_AES_CMAC, _) => Self::AesCmac,
+            (
+                cryptoki_sys::CKM_AES_CBC
+                | cryptoki_sys::CKM_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA1_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA224_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_SHA512_RSA_PKCS_PSS
+                | cryptoki_sys::CKM_RSA_PKCS_OAEP,
+                _,
+            ) => return Err(Error::InvalidParams),
             _ => return Err(Error::UnknownMech(raw_mech.type_())),
         };
 
@@ -379,6 +694,13 @@ impl Mechanism {
             // should not exist
             Self::Ecdsa(Some(MechDigest::Md5)) => cryptoki_sys::CKM_ECDSA,
             Self::EdDsa => cryptoki_sys::CKM_EDDSA,
+            Self::Hmac(MechDigest::Md5) => cryptoki_sys::CKM_MD5_HMAC,
+            Self::Hmac(MechDigest::Sha1) => cryptoki_sys::CKM_SHA_1_HMAC,
+            Self::Hmac(MechDigest::Sha224) => cryptoki_sys::CKM_SHA224_HMAC,
+            Self::Hmac(MechDigest::Sha256) => cryptoki_sys::CKM_SHA256_HMAC,
+            Self::Hmac(MechDigest::Sha384) => cryptoki_sys::CKM_SHA384_HMAC,
+            Self::Hmac(MechDigest::Sha512) => cryptoki_sys::CKM_SHA512_HMAC,
+            Self::AesCmac => cryptoki_sys::CKM_AES_CMAC,
 
             Self::GenerateAes => cryptoki_sys::CKM_AES_KEY_GEN,
             Self::GenerateRsa => cryptoki_sys::CKM_RSA_PKCS_KEY_PAIR_GEN,
@@ -391,14 +713,17 @@ impl Mechanism {
     pub fn ck_info(&self) -> cryptoki_sys::CK_MECHANISM_INFO {
         let (min_bits, max_bits) = match self {
             // Self::Digest(_) => (0, 0),
-            Self::AesCbc(_) | Self::GenerateAes => (128, 256),
-            Self::RsaPkcs(_) | Self::RsaPkcsPss(_, _) | Self::RsaX509 | Self::GenerateRsa => {
+            Self::AesCbc(_) | Self::GenerateAes | Self::AesCmac => (128, 256),
+            Self::RsaPkcs(_) | Self::RsaPkcsPss(_, _) | Self::RsaX509 => {
                 (Self::RSA_MIN_KEY_BITS, Self::RSA_MAX_KEY_BITS)
             }
+            Self::GenerateRsa => (Self::RSA_GEN_KEY_BITS[0], Self::RSA_GEN_KEY_BITS[2]),
             Self::Ecdsa(_) | Self::GenerateEc => (Self::EC_MIN_KEY_BITS, Self::EC_MAX_KEY_BITS),
             Self::RsaPkcsOaep(_) => (Self::RSA_MIN_KEY_BITS, Self::RSA_MAX_KEY_BITS),
             Self::EdDsa | Self::GenerateEd => (Self::ED_MIN_KEY_BITS, Self::ED_MAX_KEY_BITS),
             Self::GenerateGeneric => (128, 256),
+            // the key sizes of HMAC mechanisms are in bytes
+            Self::Hmac(_) => (1, super::key::GENERIC_SECRET_MAX_LEN),
         };
         cryptoki_sys::CK_MECHANISM_INFO {
             ulMinKeySize: min_bits,
@@ -431,14 +756,21 @@ impl Mechanism {
                         | cryptoki_sys::CKF_GENERATE
                 }
                 // Self::Digest(_) => cryptoki_sys::CKF_DIGEST,
-                // Single-part CKM_RSA_PKCS also has encrypt/decrypt
+                // Single-part CKM_RSA_PKCS also has encrypt/decrypt, the encryption is done by
+                // the module
+                Self::RsaPkcs(None) => {
+                    cryptoki_sys::CKF_SIGN
+                        | cryptoki_sys::CKF_ENCRYPT
+                        | cryptoki_sys::CKF_DECRYPT
+                        | cryptoki_sys::CKF_GENERATE_KEY_PAIR
+                }
                 Self::RsaPkcs(_) | Self::GenerateRsa => {
                     cryptoki_sys::CKF_SIGN
                         | cryptoki_sys::CKF_DECRYPT
                         | cryptoki_sys::CKF_GENERATE_KEY_PAIR
                 }
-                // Multi-part CKM_RSA_PKCS has sign only
-                Self::RsaPkcsPss(_, _) => cryptoki_sys::CKF_SIGN,
+                // the module verifies with the public key
+                Self::RsaPkcsPss(_, _) => cryptoki_sys::CKF_SIGN | cryptoki_sys::CKF_VERIFY,
 
                 // "RAW" RSA has decrypt only
                 Self::RsaX509 => cryptoki_sys::CKF_DECRYPT,
@@ -453,6 +785,9 @@ impl Mechanism {
                 Self::EdDsa | Self::GenerateEd => {
                     cryptoki_sys::CKF_SIGN | cryptoki_sys::CKF_GENERATE_KEY_PAIR
                 }
+                // HMAC is only verified by the module
+                Self::Hmac(_) => cryptoki_sys::CKF_VERIFY,
+                Self::AesCmac => cryptoki_sys::CKF_SIGN | cryptoki_sys::CKF_VERIFY,
             }
     }
 
@@ -528,7 +863,8 @@ impl Mechanism {
                     s
                 }
             }
-            Self::EdDsa => key_size.unwrap_or((Self::ED_MAX_KEY_BITS / 8) as usize),
+            // Ed25519 is the only curve, its 255 bits keys use 32 bytes
+            Self::EdDsa => (Self::ED_MAX_KEY_BITS / 8) as usize,
             _ => (Self::RSA_MAX_KEY_BITS / 8) as usize,
         }
     }
@@ -559,7 +895,229 @@ impl Mechanism {
             Self::RsaPkcsPss(_, _) => key_size,
             Self::Ecdsa(_) => key_size * 2,
             Self::EdDsa => key_size * 2,
+            Self::AesCmac => super::cmac::BLOCK_SIZE,
             _ => key_size,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params_of<T>(type_: CK_MECHANISM_TYPE, params: &mut T) -> Result<MechanismParams, CK_RV> {
+        unsafe {
+            MechanismParams::from_raw(type_, params as *mut T as _, std::mem::size_of::<T>() as _)
+        }
+    }
+
+    #[test]
+    fn test_mechanism_params_none() {
+        for type_ in [cryptoki_sys::CKM_EDDSA, cryptoki_sys::CKM_AES_KEY_GEN] {
+            let params = unsafe { MechanismParams::from_raw(type_, std::ptr::null_mut(), 0) };
+            assert!(matches!(params, Ok(MechanismParams::None)));
+        }
+        // ignored by the mechanisms that take no parameters
+        let mut garbage = [0u8; 3];
+        assert!(matches!(
+            params_of(cryptoki_sys::CKM_ECDSA, &mut garbage),
+            Ok(MechanismParams::None)
+        ));
+
+        // required by the others
+        for type_ in [
+            cryptoki_sys::CKM_AES_CBC,
+            cryptoki_sys::CKM_AES_GCM,
+            cryptoki_sys::CKM_RSA_PKCS_OAEP,
+            cryptoki_sys::CKM_RSA_PKCS_PSS,
+            cryptoki_sys::CKM_ECDH1_DERIVE,
+        ] {
+            let params = unsafe { MechanismParams::from_raw(type_, std::ptr::null_mut(), 0) };
+            assert_eq!(
+                params.unwrap_err(),
+                cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+            );
+        }
+    }
+
+    #[test]
+    fn test_mechanism_params_iv() {
+        let mut iv = [7u8; 16];
+        let params = params_of(cryptoki_sys::CKM_AES_CBC, &mut iv);
+        assert!(matches!(params, Ok(MechanismParams::Iv(p)) if p == iv));
+
+        let mut short = [7u8; 8];
+        let params = params_of(cryptoki_sys::CKM_AES_CBC, &mut short);
+        assert_eq!(
+            params.unwrap_err(),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+    }
+
+    #[test]
+    fn test_mechanism_params_gcm() {
+        let mut iv = [0u8; 12];
+        let mut gcm = cryptoki_sys::CK_GCM_PARAMS {
+            pIv: iv.as_mut_ptr(),
+            ulIvLen: 12,
+            ulIvBits: 96,
+            pAAD: std::ptr::null_mut(),
+            ulAADLen: 0,
+            ulTagBits: 128,
+        };
+        let params = params_of(cryptoki_sys::CKM_AES_GCM, &mut gcm);
+        assert!(matches!(params, Ok(MechanismParams::GcmParams(p)) if p.ulTagBits == 128));
+
+        let mut message = CK_GCM_MESSAGE_PARAMS {
+            pIv: iv.as_mut_ptr(),
+            ulIvLen: 12,
+            ulIvFixedBits: 0,
+            ivGenerator: 0,
+            pTag: std::ptr::null_mut(),
+            ulTagBits: 96,
+        };
+        let params = unsafe {
+            MechanismParams::from_raw_message(
+                cryptoki_sys::CKM_AES_GCM,
+                &mut message as *mut _ as _,
+                std::mem::size_of::<CK_GCM_MESSAGE_PARAMS>() as _,
+            )
+        };
+        assert!(matches!(params, Ok(MechanismParams::MessageGcmParams(p)) if p.ulTagBits == 96));
+        let params = unsafe {
+            MechanismParams::from_raw_message(cryptoki_sys::CKM_AES_GCM, std::ptr::null_mut(), 0)
+        };
+        assert_eq!(
+            params.unwrap_err(),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+
+        // EdDSA takes no parameter per message, an empty one is accepted
+        let mut nonce = [1u8];
+        let params = unsafe {
+            MechanismParams::from_raw_message(cryptoki_sys::CKM_EDDSA, nonce.as_mut_ptr() as _, 0)
+        };
+        assert!(matches!(params, Ok(MechanismParams::None)));
+        let params = unsafe {
+            MechanismParams::from_raw_message(cryptoki_sys::CKM_EDDSA, nonce.as_mut_ptr() as _, 1)
+        };
+        assert_eq!(
+            params.unwrap_err(),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+    }
+
+    #[test]
+    fn test_mechanism_params_rsa() {
+        let mut oaep = cryptoki_sys::CK_RSA_PKCS_OAEP_PARAMS {
+            hashAlg: cryptoki_sys::CKM_SHA256,
+            mgf: cryptoki_sys::CKG_MGF1_SHA256,
+            source: 0,
+            pSourceData: std::ptr::null_mut(),
+            ulSourceDataLen: 0,
+        };
+        let params = params_of(cryptoki_sys::CKM_RSA_PKCS_OAEP, &mut oaep);
+        assert!(
+            matches!(params, Ok(MechanismParams::RsaOaepParams(p)) if p.hashAlg == cryptoki_sys::CKM_SHA256)
+        );
+
+        let mut pss = cryptoki_sys::CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: cryptoki_sys::CKM_SHA384,
+            mgf: cryptoki_sys::CKG_MGF1_SHA384,
+            sLen: 48,
+        };
+        for type_ in [
+            cryptoki_sys::CKM_RSA_PKCS_PSS,
+            cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS,
+        ] {
+            let params = params_of(type_, &mut pss);
+            assert!(matches!(params, Ok(MechanismParams::RsaPssParams(p)) if p.sLen == 48));
+        }
+        // optional when the digest is given by the mechanism
+        let params = unsafe {
+            MechanismParams::from_raw(
+                cryptoki_sys::CKM_SHA384_RSA_PKCS_PSS,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        assert!(matches!(params, Ok(MechanismParams::None)));
+
+        // the OAEP parameters don't have the size of the PSS ones
+        let params = params_of(cryptoki_sys::CKM_RSA_PKCS_PSS, &mut oaep);
+        assert_eq!(
+            params.unwrap_err(),
+            cryptoki_sys::CKR_MECHANISM_PARAM_INVALID
+        );
+    }
+
+    #[test]
+    fn test_mechanism_params_ecdh1_derive() {
+        let mut public = [4u8; 65];
+        let mut ecdh = cryptoki_sys::CK_ECDH1_DERIVE_PARAMS {
+            kdf: cryptoki_sys::CKD_NULL,
+            ulSharedDataLen: 0,
+            pSharedData: std::ptr::null_mut(),
+            ulPublicDataLen: public.len() as _,
+            pPublicData: public.as_mut_ptr(),
+        };
+        let params = params_of(cryptoki_sys::CKM_ECDH1_DERIVE, &mut ecdh);
+        assert!(
+            matches!(params, Ok(MechanismParams::Ecdh1DeriveParams(p)) if p.ulPublicDataLen == 65)
+        );
+    }
+
+    #[test]
+    fn test_is_digestinfo_prefix() {
+        for digest in [
+            MechDigest::Md5,
+            MechDigest::Sha1,
+            MechDigest::Sha224,
+            MechDigest::Sha256,
+            MechDigest::Sha384,
+            MechDigest::Sha512,
+        ] {
+            let mut data = digest.digest_info_prefix().to_vec();
+            data.extend(vec![0x42; digest.output_size()]);
+            assert_eq!(is_digestinfo_prefix(&data), Some(digest.ck_mech()));
+
+            // the header alone, or with a hash of another length
+            assert_eq!(is_digestinfo_prefix(digest.digest_info_prefix()), None);
+            data.push(0x42);
+            assert_eq!(is_digestinfo_prefix(&data), None);
+        }
+        assert_eq!(is_digestinfo_prefix(&[0x42; 32]), None);
+    }
+
+    #[test]
+    fn test_mechanism_key_type_compatible() {
+        for (mechanism, key_type) in [
+            (cryptoki_sys::CKM_AES_CBC, CKK_AES),
+            (cryptoki_sys::CKM_AES_CBC, CKK_GENERIC_SECRET),
+            (cryptoki_sys::CKM_RSA_PKCS_OAEP, CKK_RSA),
+            (cryptoki_sys::CKM_SHA256_RSA_PKCS_PSS, CKK_RSA),
+            (cryptoki_sys::CKM_ECDSA_SHA256, CKK_EC),
+            (cryptoki_sys::CKM_ECDH1_DERIVE, CKK_EC),
+            (cryptoki_sys::CKM_EDDSA, CKK_EC_EDWARDS),
+            (cryptoki_sys::CKM_SHA256_HMAC, CKK_GENERIC_SECRET),
+        ] {
+            assert!(mechanism_key_type_compatible(mechanism, key_type));
+        }
+
+        for (mechanism, key_type) in [
+            (cryptoki_sys::CKM_AES_GCM, CKK_RSA),
+            (cryptoki_sys::CKM_AES_CBC, CKK_EC),
+            (cryptoki_sys::CKM_RSA_PKCS, CKK_EC),
+            (cryptoki_sys::CKM_RSA_X_509, CKK_AES),
+            (cryptoki_sys::CKM_ECDSA, CKK_RSA),
+            (cryptoki_sys::CKM_ECDSA, CKK_EC_EDWARDS),
+            (cryptoki_sys::CKM_ECDH1_DERIVE, CKK_EC_EDWARDS),
+            (cryptoki_sys::CKM_EDDSA, CKK_EC),
+            (cryptoki_sys::CKM_SHA256_HMAC, CKK_AES),
+            (cryptoki_sys::CKM_SHA256_HMAC, CKK_RSA),
+            (cryptoki_sys::CKM_SHA256, CKK_GENERIC_SECRET),
+        ] {
+            assert!(!mechanism_key_type_compatible(mechanism, key_type));
+        }
+    }
+}
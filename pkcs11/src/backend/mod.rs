@@ -3,29 +3,42 @@ use std::sync::PoisonError;
 use self::{
     db::object::ObjectKind,
     login::{LoginError, UserMode},
-    mechanism::{MechMode, Mechanism},
+    mechanism::{MechDigest, MechMode, Mechanism},
 };
 use cryptoki_sys::{
-    CKR_ARGUMENTS_BAD, CKR_ATTRIBUTE_VALUE_INVALID, CKR_CRYPTOKI_NOT_INITIALIZED, CKR_DATA_INVALID,
-    CKR_DATA_LEN_RANGE, CKR_DEVICE_ERROR, CKR_DEVICE_MEMORY, CKR_DEVICE_REMOVED,
-    CKR_ENCRYPTED_DATA_LEN_RANGE, CKR_KEY_HANDLE_INVALID, CKR_MECHANISM_INVALID,
-    CKR_OPERATION_ACTIVE, CKR_OPERATION_NOT_INITIALIZED, CKR_TOKEN_NOT_PRESENT,
-    CKR_USER_NOT_LOGGED_IN, CK_ATTRIBUTE_TYPE, CK_OBJECT_HANDLE, CK_RV,
+    CKR_ACTION_PROHIBITED, CKR_ARGUMENTS_BAD, CKR_ATTRIBUTE_READ_ONLY, CKR_ATTRIBUTE_VALUE_INVALID,
+    CKR_CRYPTOKI_NOT_INITIALIZED, CKR_DATA_INVALID, CKR_DATA_LEN_RANGE, CKR_DEVICE_ERROR,
+    CKR_DEVICE_MEMORY, CKR_DEVICE_REMOVED, CKR_ENCRYPTED_DATA_LEN_RANGE, CKR_FUNCTION_CANCELED,
+    CKR_FUNCTION_FAILED, CKR_KEY_FUNCTION_NOT_PERMITTED, CKR_KEY_HANDLE_INVALID,
+    CKR_KEY_INDIGESTIBLE, CKR_KEY_NOT_WRAPPABLE, CKR_KEY_SIZE_RANGE, CKR_KEY_TYPE_INCONSISTENT,
+    CKR_MECHANISM_INVALID, CKR_MECHANISM_PARAM_INVALID, CKR_OPERATION_ACTIVE,
+    CKR_OPERATION_NOT_INITIALIZED, CKR_SESSION_READ_ONLY, CKR_SIGNATURE_INVALID,
+    CKR_SIGNATURE_LEN_RANGE, CKR_TEMPLATE_INCOMPLETE, CKR_TEMPLATE_INCONSISTENT,
+    CKR_TOKEN_NOT_PRESENT, CKR_USER_NOT_LOGGED_IN, CK_ATTRIBUTE_TYPE, CK_KEY_TYPE,
+    CK_MECHANISM_TYPE, CK_OBJECT_HANDLE, CK_RV, CK_ULONG,
 };
 use log::error;
 use nethsm_sdk_rs::apis;
 
+pub mod auth;
+pub mod cmac;
 pub mod db;
 pub mod decrypt;
+pub mod digest;
 pub mod encrypt;
 pub mod events;
 pub mod key;
+pub mod locking;
 pub mod login;
 pub mod mechanism;
 pub mod object;
+pub mod pkcs8;
+pub mod rsa;
 pub mod session;
 pub mod sign;
 pub mod slot;
+pub mod verify;
+pub mod wrap;
 
 #[derive(Debug, Clone)]
 pub struct ResponseContent {
@@ -89,6 +102,27 @@ pub enum Error {
     InvalidDataLength,
     InvalidData,
     InvalidEncryptedDataLength,
+    KeyIndigestible,
+    TemplateIncomplete(CK_ATTRIBUTE_TYPE),
+    TemplateInconsistent(CK_ATTRIBUTE_TYPE),
+    KeySizeRange(CK_ULONG),
+    InvalidSignature,
+    InvalidSignatureLength,
+    FunctionCanceled,
+    ActionProhibited,
+    AttributeReadOnly(CK_ATTRIBUTE_TYPE),
+    KeyNotWrappable,
+    KeyFunctionNotPermitted,
+    MechanismParamInvalid,
+    UnsupportedKeyFormat,
+    // a token object changed in a read-only session
+    SessionReadOnly,
+    // the digest is disabled in the configuration of the slot
+    DigestDisabled(MechDigest),
+    KeyTypeInconsistent(CK_MECHANISM_TYPE, CK_KEY_TYPE),
+    // the mechanism is disabled in the configuration of the slot
+    MechanismDisabled(Mechanism),
+    Random(getrandom::Error),
 }
 
 impl From<ApiError> for Error {
@@ -130,6 +164,24 @@ impl From<Error> for CK_RV {
             Error::Pem(_) => CKR_DEVICE_ERROR,
             Error::InvalidEncryptedDataLength => CKR_ENCRYPTED_DATA_LEN_RANGE,
             Error::InvalidData => CKR_DATA_INVALID,
+            Error::KeyIndigestible => CKR_KEY_INDIGESTIBLE,
+            Error::TemplateIncomplete(_) => CKR_TEMPLATE_INCOMPLETE,
+            Error::TemplateInconsistent(_) => CKR_TEMPLATE_INCONSISTENT,
+            Error::KeySizeRange(_) => CKR_KEY_SIZE_RANGE,
+            Error::InvalidSignature => CKR_SIGNATURE_INVALID,
+            Error::InvalidSignatureLength => CKR_SIGNATURE_LEN_RANGE,
+            Error::FunctionCanceled => CKR_FUNCTION_CANCELED,
+            Error::ActionProhibited => CKR_ACTION_PROHIBITED,
+            Error::AttributeReadOnly(_) => CKR_ATTRIBUTE_READ_ONLY,
+            Error::KeyNotWrappable => CKR_KEY_NOT_WRAPPABLE,
+            Error::KeyFunctionNotPermitted => CKR_KEY_FUNCTION_NOT_PERMITTED,
+            Error::MechanismParamInvalid => CKR_MECHANISM_PARAM_INVALID,
+            Error::UnsupportedKeyFormat => CKR_ARGUMENTS_BAD,
+            Error::SessionReadOnly => CKR_SESSION_READ_ONLY,
+            Error::DigestDisabled(_) => CKR_MECHANISM_INVALID,
+            Error::MechanismDisabled(_) => CKR_MECHANISM_INVALID,
+            Error::Random(_) => CKR_FUNCTION_FAILED,
+            Error::KeyTypeInconsistent(_, _) => CKR_KEY_TYPE_INCONSISTENT,
             Error::InvalidDataLength => CKR_DATA_LEN_RANGE,
             Error::InvalidObjectHandle(_) => CKR_KEY_HANDLE_INVALID,
             Error::OperationNotInitialized => CKR_OPERATION_NOT_INITIALIZED,
@@ -171,6 +223,33 @@ impl std::fmt::Display for Error {
             Error::Pem(err) => format!("PEM error: {:?}", err),
             Error::InvalidEncryptedDataLength => "Invalid encrypted data length".to_string(),
             Error::InvalidData => "Invalid input data".to_string(),
+            Error::KeyIndigestible => "The value of the key cannot be digested".to_string(),
+            Error::TemplateIncomplete(attr) => {
+                format!("The template is missing the attribute {:?}", attr)
+            }
+            Error::TemplateInconsistent(attr) => {
+                format!("The attribute {:?} conflicts with the template", attr)
+            }
+            Error::KeySizeRange(len) => format!("Unsupported key length: {}", len),
+            Error::InvalidSignature => "The signature is not valid".to_string(),
+            Error::InvalidSignatureLength => "Invalid signature length".to_string(),
+            Error::FunctionCanceled => "The function was canceled by the application".to_string(),
+            Error::ActionProhibited => "The action is prohibited for this object".to_string(),
+            Error::AttributeReadOnly(attr) => format!("The attribute {:?} is read-only", attr),
+            Error::KeyNotWrappable => "The key can only be wrapped with a trusted key".to_string(),
+            Error::KeyFunctionNotPermitted => "The key can't be used for this function".to_string(),
+            Error::MechanismParamInvalid => "Invalid mechanism parameter".to_string(),
+            Error::UnsupportedKeyFormat => "Unsupported private key format".to_string(),
+            Error::SessionReadOnly => "The session is read-only".to_string(),
+            Error::DigestDisabled(digest) => format!("The digest {:?} is disabled", digest),
+            Error::MechanismDisabled(mechanism) => {
+                format!("The mechanism {:?} is disabled", mechanism)
+            }
+            Error::Random(err) => format!("Failed to get random bytes: {}", err),
+            Error::KeyTypeInconsistent(mechanism, key_type) => format!(
+                "The mechanism {:#x} can't be used with the key type {:#x}",
+                mechanism, key_type
+            ),
             Error::InvalidDataLength => "Invalid input data length".to_string(),
             Error::InvalidObjectHandle(handle) => {
                 format!("Object handle does not exist: {}", handle)
@@ -1,11 +1,16 @@
-use cryptoki_sys::{CKA_CLASS, CKA_ID, CKA_LABEL, CK_OBJECT_CLASS, CK_SESSION_HANDLE};
+use cryptoki_sys::{
+    CKA_APPLICATION, CKA_CLASS, CKA_ID, CKA_LABEL, CKA_TOKEN, CK_BBOOL, CK_FALSE, CK_OBJECT_CLASS,
+    CK_SESSION_HANDLE,
+};
 use log::{debug, trace};
 
 use super::{
     db::{
         attr::{CkRawAttr, CkRawAttrTemplate},
-        object::ObjectKind,
+        object::{Attribute, ObjectKind, MODULE_ATTRIBUTES},
+        Object,
     },
+    key::parse_key_id_from_attr,
     session::Session,
     Error,
 };
@@ -15,13 +20,19 @@ use super::{
 pub struct EnumCtx {
     pub handles: Vec<CK_SESSION_HANDLE>,
     index: usize,
+    // the login state of the session when the search started, the handles were filtered with it
+    pub logged_in: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct KeyRequirements {
     pub kind: Option<ObjectKind>,
+    // CKA_ID of the template, raw_id holds the bytes when they aren't a valid NetHSM ID
     pub id: Option<String>,
     pub raw_id: Option<Vec<u8>>,
+    pub label: Option<String>,
+    // CKA_TOKEN of the template, the session objects only live in the module
+    pub token: Option<bool>,
 }
 
 fn parse_key_requirements(template: Option<CkRawAttrTemplate>) -> Result<KeyRequirements, Error> {
@@ -30,6 +41,8 @@ fn parse_key_requirements(template: Option<CkRawAttrTemplate>) -> Result<KeyRequ
             let mut key_id = None;
             let mut kind = None;
             let mut raw_id = None;
+            let mut label = None;
+            let mut token = None;
             for attr in template.iter() {
                 debug!("attr {:?}: {:?}", attr.type_(), attr.val_bytes());
 
@@ -37,43 +50,58 @@ fn parse_key_requirements(template: Option<CkRawAttrTemplate>) -> Result<KeyRequ
                     kind = unsafe { attr.read_value::<CK_OBJECT_CLASS>() }.map(ObjectKind::from)
                 }
 
+                if attr.type_() == CKA_TOKEN {
+                    token = unsafe { attr.read_value::<CK_BBOOL>() }.map(|value| value != CK_FALSE)
+                }
+
                 if attr.type_() == CKA_ID {
-                    if let Some(bytes) = attr.val_bytes() {
-                        let str_result = String::from_utf8(bytes.to_vec());
-                        let mut output = None;
-                        if let Ok(str) = str_result {
-                            // check if the string contains only alphanumeric characters
-                            if str.chars().all(|c| c.is_alphanumeric()) {
-                                output = Some(str);
-                            }
-                        }
-
-                        if output.is_none() {
-                            // store as hex value string
-                            output = Some(hex::encode(bytes));
-                            raw_id = Some(bytes.to_vec());
-                        }
-                        key_id = output;
+                    if let Some((id, raw)) = parse_key_id_from_attr(&attr) {
+                        key_id = Some(id);
+                        raw_id = raw;
                     }
                 }
-                if attr.type_() == CKA_LABEL && key_id.is_none() {
-                    key_id = Some(parse_str_from_attr(&attr)?);
+                if attr.type_() == CKA_LABEL {
+                    label = Some(parse_str_from_attr(&attr)?);
                 }
             }
             Ok(KeyRequirements {
                 kind,
                 id: key_id,
                 raw_id,
+                label,
+                token,
             })
         }
         None => Ok(KeyRequirements {
             kind: None,
             id: None,
             raw_id: None,
+            label: None,
+            token: None,
         }),
     }
 }
 
+impl KeyRequirements {
+    // the ID of the key on the NetHSM, CKA_LABEL is used when the template has no CKA_ID
+    pub fn nethsm_id(&self) -> Option<String> {
+        self.id.clone().or_else(|| self.label.clone())
+    }
+
+    // the session objects only live in the module, they are matched on their attributes
+    pub fn matches_attributes(&self, object: &Object) -> bool {
+        let id = self
+            .raw_id
+            .clone()
+            .or_else(|| self.id.as_ref().map(|id| id.as_bytes().to_vec()));
+        let matches = |attr_type, value: Option<Vec<u8>>| match value {
+            Some(value) => object.get_attribute(attr_type) == Some(&Attribute::Bytes(value)),
+            None => true,
+        };
+        matches(CKA_ID, id) && matches(CKA_LABEL, self.label.clone().map(String::into_bytes))
+    }
+}
+
 fn parse_str_from_attr(attr: &CkRawAttr) -> Result<String, Error> {
     let bytes = attr
         .val_bytes()
@@ -86,15 +114,85 @@ impl EnumCtx {
         session: &mut Session,
         template: Option<CkRawAttrTemplate>,
     ) -> Result<Self, Error> {
+        // the statistics walk the whole database
+        if log::log_enabled!(log::Level::Debug) {
+            debug!(
+                "Searching objects, database: {:?}",
+                session.db.lock()?.stats()
+            );
+        }
+
+        // The NetHSM can't search on the tags or on the attributes kept by the module, nor on
+        // CKA_APPLICATION of the data objects, the objects are filtered on them
+        let tag_filter = match template {
+            Some(ref template) => {
+                let mut filter = session
+                    .db
+                    .lock()?
+                    .tag_attributes()
+                    .template_filter(template);
+                filter.extend(
+                    template
+                        .iter()
+                        .filter(|attr| {
+                            MODULE_ATTRIBUTES.contains(&attr.type_())
+                                || attr.type_() == CKA_APPLICATION
+                        })
+                        .map(|attr| (attr.type_(), attr.val_bytes().unwrap_or_default().to_vec())),
+                );
+                filter
+            }
+            None => vec![],
+        };
         let key_req = parse_key_requirements(template)?;
 
-        let handles = session.find_key(key_req)?;
-        Ok(EnumCtx::new(handles))
+        let mut handles = session.find_key(key_req)?;
+
+        // the evicted objects are fetched again to be filtered
+        if !tag_filter.is_empty() {
+            handles.retain(|handle| {
+                session.get_object(*handle).is_ok_and(|object| {
+                    tag_filter.iter().all(|(attr_type, value)| {
+                        object
+                            .get_attribute(*attr_type)
+                            .is_some_and(|attr| attr.to_bytes() == *value)
+                    })
+                })
+            });
+        }
+
+        // Private objects are hidden until the user is logged in. The filter is applied once,
+        // a login during the search doesn't add the private objects to it.
+        let logged_in = session.is_logged_in();
+        if !logged_in {
+            handles.retain(|handle| {
+                session
+                    .get_object(*handle)
+                    .is_ok_and(|object| !object.is_private())
+            });
+        }
+
+        Ok(EnumCtx::new(handles, logged_in))
     }
 
-    pub fn new(handles: Vec<CK_SESSION_HANDLE>) -> Self {
-        Self { handles, index: 0 }
+    pub fn new(handles: Vec<CK_SESSION_HANDLE>, logged_in: bool) -> Self {
+        Self {
+            handles,
+            index: 0,
+            logged_in,
+        }
+    }
+    // ends the search, the handles that were not returned are dropped
+    pub fn finalize(&mut self) {
+        debug!(
+            "Search finished, {} objects returned out of {} found",
+            self.index,
+            self.handles.len()
+        );
+        self.handles.clear();
+        self.index = 0;
     }
+
     pub fn next_chunck(&mut self, chunk_size: usize) -> Vec<CK_SESSION_HANDLE> {
         let mut result = Vec::new();
         for _ in 0..chunk_size {
@@ -171,7 +269,9 @@ mod tests {
         let res = parse_key_requirements(template)?;
 
         assert_eq!(res.kind, None);
-        assert_eq!(res.id, Some("test".to_string()));
+        assert_eq!(res.id, None);
+        assert_eq!(res.label, Some("test".to_string()));
+        assert_eq!(res.nethsm_id(), Some("test".to_string()));
         assert_eq!(res.raw_id, None);
 
         Ok(())
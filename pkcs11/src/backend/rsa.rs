@@ -0,0 +1,171 @@
+// The RSA public key operation, for C_VerifyRecover. The NetHSM only uses its private keys, the
+// public exponent is applied by the module with a Montgomery exponentiation on 32 bits limbs.
+// Only public data goes through it, it doesn't need to run in constant time.
+
+use std::cmp::Ordering;
+
+// s^e mod n, on big-endian integers. The output has the length of the modulus.
+// None if the modulus is even or the input isn't smaller than the modulus.
+pub fn public_op(modulus: &[u8], exponent: &[u8], input: &[u8]) -> Option<Vec<u8>> {
+    let start = modulus.iter().position(|b| *b != 0)?;
+    let modulus = &modulus[start..];
+    if modulus[modulus.len() - 1] & 1 == 0 {
+        return None;
+    }
+
+    let len = modulus.chunks(4).len();
+    let n = to_limbs(modulus, len);
+    let base = to_limbs_checked(input, len)?;
+    if compare(&base, &n) != Ordering::Less {
+        return None;
+    }
+
+    let n0_inv = n0_inv(n[0]);
+    let r2 = montgomery_r2(&n);
+    let mut one = vec![0; len];
+    one[0] = 1;
+
+    let base = mont_mul(&base, &r2, &n, n0_inv);
+    let mut acc = mont_mul(&one, &r2, &n, n0_inv);
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            acc = mont_mul(&acc, &acc, &n, n0_inv);
+            if (byte >> bit) & 1 == 1 {
+                acc = mont_mul(&acc, &base, &n, n0_inv);
+            }
+        }
+    }
+    let result = mont_mul(&acc, &one, &n, n0_inv);
+
+    Some(from_limbs(&result, modulus.len()))
+}
+
+// little-endian limbs of a big-endian integer
+fn to_limbs(bytes: &[u8], len: usize) -> Vec<u32> {
+    let mut limbs = vec![0; len];
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        limbs[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+    limbs
+}
+
+// the leading zeros are ignored, None if the integer doesn't fit
+fn to_limbs_checked(bytes: &[u8], len: usize) -> Option<Vec<u32>> {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    if bytes.len() - start > len * 4 {
+        return None;
+    }
+    Some(to_limbs(&bytes[start..], len))
+}
+
+fn from_limbs(limbs: &[u32], len: usize) -> Vec<u8> {
+    (0..len)
+        .rev()
+        .map(|i| (limbs[i / 4] >> (8 * (i % 4))) as u8)
+        .collect()
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+// a - b, the borrow out is dropped
+fn sub_assign(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0;
+    for (x, y) in a.iter_mut().zip(b) {
+        let (d, b1) = x.overflowing_sub(*y);
+        let (d, b2) = d.overflowing_sub(borrow);
+        *x = d;
+        borrow = (b1 || b2) as u32;
+    }
+}
+
+// -n^-1 mod 2^32, by Newton's iteration
+fn n0_inv(n0: u32) -> u32 {
+    let mut inv: u32 = 1;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+// R^2 mod n with R = 2^(32 * len), by doubling 1
+fn montgomery_r2(n: &[u32]) -> Vec<u32> {
+    let mut x = vec![0; n.len()];
+    x[0] = 1;
+    for _ in 0..64 * n.len() {
+        let mut carry = 0;
+        for limb in x.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry == 1 || compare(&x, n) != Ordering::Less {
+            sub_assign(&mut x, n);
+        }
+    }
+    x
+}
+
+// a * b / R mod n
+fn mont_mul(a: &[u32], b: &[u32], n: &[u32], n0_inv: u32) -> Vec<u32> {
+    let len = n.len();
+    let mut t = vec![0u32; len + 2];
+    for a_i in a {
+        let mut carry = 0u64;
+        for j in 0..len {
+            let s = t[j] as u64 + *a_i as u64 * b[j] as u64 + carry;
+            t[j] = s as u32;
+            carry = s >> 32;
+        }
+        let s = t[len] as u64 + carry;
+        t[len] = s as u32;
+        t[len + 1] = (s >> 32) as u32;
+
+        let m = t[0].wrapping_mul(n0_inv);
+        let mut carry = (t[0] as u64 + m as u64 * n[0] as u64) >> 32;
+        for j in 1..len {
+            let s = t[j] as u64 + m as u64 * n[j] as u64 + carry;
+            t[j - 1] = s as u32;
+            carry = s >> 32;
+        }
+        let s = t[len] as u64 + carry;
+        t[len - 1] = s as u32;
+        t[len] = t[len + 1] + (s >> 32) as u32;
+        t[len + 1] = 0;
+    }
+
+    let mut result = t[..len].to_vec();
+    if t[len] != 0 || compare(&result, n) != Ordering::Less {
+        sub_assign(&mut result, n);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_public_op() {
+        // n = 61 * 53, e = 17
+        assert_eq!(
+            public_op(&[0x0c, 0xa1], &[17], &[65]),
+            Some(vec![0x0a, 0xe6])
+        );
+        assert_eq!(public_op(&[0x0c, 0xa1], &[17], &[0x0c, 0xa1]), None);
+        // even modulus
+        assert_eq!(public_op(&[0x0c, 0xa2], &[17], &[65]), None);
+    }
+
+    #[test]
+    fn test_multi_limb_public_op() {
+        // (2^64 - 59) is prime, 3^(p - 1) = 1 mod p
+        let p = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc5];
+        let exponent = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc4];
+        assert_eq!(
+            public_op(&p, &exponent, &[3]),
+            Some(vec![0, 0, 0, 0, 0, 0, 0, 1])
+        );
+    }
+}
@@ -1,39 +1,194 @@
 use std::{
     collections::HashMap,
+    path::{Path, PathBuf},
     sync::{atomic::Ordering, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
 };
 
 use cryptoki_sys::{
-    CKR_OK, CK_FLAGS, CK_OBJECT_HANDLE, CK_RV, CK_SESSION_HANDLE, CK_SESSION_INFO, CK_SLOT_ID,
-    CK_USER_TYPE,
+    CKA_ID, CKA_KEY_TYPE, CKA_MODIFIABLE, CKA_OBJECT_ID, CKA_PRIVATE, CKA_SUBJECT, CKA_TOKEN,
+    CKA_TRUSTED, CKA_UNWRAP, CKA_VALUE, CKA_VALUE_LEN, CKA_WRAP, CKR_DEVICE_REMOVED, CKR_OK,
+    CKS_RO_USER_FUNCTIONS, CKS_RW_SO_FUNCTIONS, CKS_RW_USER_FUNCTIONS, CKU_CONTEXT_SPECIFIC,
+    CK_ATTRIBUTE_TYPE, CK_BBOOL, CK_FALSE, CK_FLAGS, CK_MECHANISM_TYPE, CK_NOTIFICATION, CK_NOTIFY,
+    CK_OBJECT_HANDLE, CK_RV, CK_SESSION_HANDLE, CK_SESSION_INFO, CK_SLOT_ID, CK_ULONG,
+    CK_USER_TYPE, CK_VOID_PTR,
 };
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use nethsm_sdk_rs::apis::default_api;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
 use crate::{
     backend::{login::UserMode, Error},
-    config::device::Slot,
-    data::THREADS_ALLOWED,
+    config::device::{Device, Slot},
+    data::{SESSION_MANAGER, THREADS_ALLOWED},
 };
 
+#[cfg(test)]
+use crate::config::device::SlotBuilder;
+
 use super::{
-    db::{attr::CkRawAttrTemplate, object::ObjectKind, Db, Object},
+    db::{
+        attr::CkRawAttrTemplate,
+        object::{
+            session_unique_id, Attribute, ObjectKind, CKA_NETHSM_MAX_USAGE_COUNT, CKA_UNIQUE_ID,
+        },
+        Db, Object,
+    },
     decrypt::DecryptCtx,
+    digest::DigestCtx,
     encrypt::EncryptCtx,
-    key::{create_key_from_template, fetch_certificate, fetch_key, generate_key_from_template},
-    login::LoginCtx,
-    mechanism::Mechanism,
+    key::{
+        create_key_from_template, data_object_from_template, fetch_certificate, fetch_key,
+        generate_key_from_template, import_pem_key, import_unwrapped_key, parse_attributes,
+        public_key_from_template,
+    },
+    login::{LoginCtx, LoginError},
+    mechanism::{mechanism_key_type_compatible, MechDigest, Mechanism, MechanismParams},
     object::{EnumCtx, KeyRequirements},
-    sign::SignCtx,
+    sign::{MessageSignCtx, SignCtx},
+    verify::{VerifyCtx, VerifyRecoverCtx},
+    wrap::WrapOutputLen,
 };
 
+// a saved session state older than this is ignored when restoring
+const SESSION_STATE_MAX_AGE: Duration = Duration::from_secs(60);
+
+// metadata of an open session, the login state and the operation contexts are not kept
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SessionState {
+    pub handle: CK_SESSION_HANDLE,
+    pub slot_id: CK_SLOT_ID,
+    pub slot_label: String,
+    pub flags: CK_FLAGS,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SessionManagerState {
+    pub next_session_handle: CK_SESSION_HANDLE,
+    pub sessions: Vec<SessionState>,
+}
+
+impl SessionManagerState {
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        std::fs::write(path, data)
+    }
+
+    // returns None if there is no saved state or if it is stale, the file is removed once read so
+    // that the same sessions are never restored twice
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let fresh = metadata
+            .modified()?
+            .elapsed()
+            .map(|age| age < SESSION_STATE_MAX_AGE)
+            .unwrap_or(false);
+
+        if !fresh {
+            debug!("Ignoring stale session state {}", path.display());
+            std::fs::remove_file(path)?;
+            return Ok(None);
+        }
+
+        let data = std::fs::read(path)?;
+        std::fs::remove_file(path)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+}
+
+// the slots may share the same session_state_path, each one saves its sessions to its own file
+pub fn slot_state_path(path: &Path, slot_id: CK_SLOT_ID) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", slot_id));
+    path.with_file_name(file_name)
+}
+
+// save the sessions of the slots configured with a session_state_path
+pub fn save_sessions_state(device: &Device) {
+    let manager = SESSION_MANAGER.lock().unwrap();
+
+    for (slot_id, slot) in device.slots.iter().enumerate() {
+        let Some(path) = &slot.session_state_path else {
+            continue;
+        };
+
+        let path = slot_state_path(path, slot_id as CK_SLOT_ID);
+        let state = manager.export_state(slot_id as CK_SLOT_ID, slot);
+        if let Err(err) = state.save(&path) {
+            error!(
+                "Failed to save the sessions of slot {} to {}: {:?}",
+                slot_id,
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+// restore the sessions saved by save_sessions_state
+pub fn restore_sessions_state(device: &Device) {
+    let mut manager = SESSION_MANAGER.lock().unwrap();
+
+    for (slot_id, slot) in device.slots.iter().enumerate() {
+        let Some(path) = &slot.session_state_path else {
+            continue;
+        };
+
+        let path = slot_state_path(path, slot_id as CK_SLOT_ID);
+        match SessionManagerState::load(&path) {
+            // the restored sessions are only usable with a NetHSM that is still reachable
+            Ok(Some(_)) if slot.test_connection().is_err() => warn!(
+                "Not restoring the sessions of slot {}: the NetHSM is not reachable",
+                slot_id
+            ),
+            Ok(Some(state)) => {
+                let restored = manager.import_state(state, slot_id as CK_SLOT_ID, slot.clone());
+                debug!("Restored {} sessions for slot {}", restored, slot_id);
+            }
+            Ok(None) => {}
+            Err(err) => warn!(
+                "Failed to restore the sessions of slot {} from {}: {:?}",
+                slot_id,
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SessionManager {
     pub sessions: HashMap<CK_SESSION_HANDLE, Arc<Mutex<Session>>>,
     pub next_session_handle: CK_SESSION_HANDLE,
 }
 
+// The object handles carry the slot of the object in their upper 8 bits, the handles of the
+// databases of two slots never collide. The configuration can't have more than MAX_SLOTS slots.
+const HANDLE_SLOT_SHIFT: u32 = 24;
+const HANDLE_LOCAL_MASK: CK_OBJECT_HANDLE = (1 << HANDLE_SLOT_SHIFT) - 1;
+pub const MAX_SLOTS: usize = 256;
+pub const MAX_LOCAL_HANDLE: u32 = HANDLE_LOCAL_MASK as u32;
+
 impl SessionManager {
+    pub fn encode_handle(slot_id: CK_SLOT_ID, local_handle: u32) -> CK_OBJECT_HANDLE {
+        ((slot_id & 0xff) << HANDLE_SLOT_SHIFT)
+            | (local_handle as CK_OBJECT_HANDLE & HANDLE_LOCAL_MASK)
+    }
+
+    pub fn decode_handle(handle: CK_OBJECT_HANDLE) -> (CK_SLOT_ID, u32) {
+        (
+            (handle >> HANDLE_SLOT_SHIFT) & 0xff,
+            (handle & HANDLE_LOCAL_MASK) as u32,
+        )
+    }
+
     pub fn new() -> Self {
         Self {
             sessions: HashMap::new(),
@@ -47,8 +202,9 @@ impl SessionManager {
         slot: Arc<Slot>,
         flags: CK_FLAGS,
     ) -> CK_SESSION_HANDLE {
-        let session = Session::new(slot_id, slot, flags);
         let handle = self.next_session_handle;
+        let mut session = Session::new(slot_id, slot, flags);
+        session.handle = handle;
         self.sessions.insert(handle, Arc::new(Mutex::new(session)));
 
         self.next_session_handle += 1;
@@ -59,11 +215,43 @@ impl SessionManager {
         self.sessions.get(&handle).cloned()
     }
 
+    // the session objects are destroyed with their session
     pub fn delete_session(
         &mut self,
         handle: CK_SESSION_HANDLE,
     ) -> Option<(CK_SESSION_HANDLE, Arc<Mutex<Session>>)> {
-        self.sessions.remove_entry(&handle)
+        let deleted = self.sessions.remove_entry(&handle)?;
+        if let Ok(session) = deleted.1.lock() {
+            session.destroy_session_objects();
+        }
+        Some(deleted)
+    }
+
+    // called by C_Finalize: the operations of every session are aborted, the sessions
+    // are closed and the keys cached for every slot are dropped
+    pub fn finalize_all(&mut self, device: &Device) {
+        for (_, session) in self.sessions.drain() {
+            if let Ok(mut session) = session.lock() {
+                session.abort_operations();
+            }
+        }
+
+        for slot in device.slots.iter() {
+            // the prefetch of the keys would fill the database again
+            let prefetch = slot
+                .prefetch
+                .lock()
+                .ok()
+                .and_then(|mut prefetch| prefetch.take());
+            if let Some(handle) = prefetch {
+                if handle.join().is_err() {
+                    warn!("The prefetch of the keys panicked");
+                }
+            }
+            if let Ok(mut db) = slot.db.lock() {
+                db.clear();
+            }
+        }
     }
 
     pub fn delete_all_slot_sessions(&mut self, slot_id: CK_SLOT_ID) {
@@ -74,47 +262,188 @@ impl SessionManager {
             }
         });
         for handle in deleted_sessions.iter() {
-            self.sessions.remove(handle);
+            self.delete_session(*handle);
+        }
+    }
+
+    // export the sessions of a slot, the active operations are aborted
+    pub fn export_state(&self, slot_id: CK_SLOT_ID, slot: &Slot) -> SessionManagerState {
+        let mut sessions: Vec<SessionState> = self
+            .sessions
+            .iter()
+            .filter_map(|(handle, session)| {
+                let mut session = session.lock().unwrap();
+                if session.slot_id != slot_id {
+                    return None;
+                }
+                session.abort_operations();
+                Some(SessionState {
+                    handle: *handle,
+                    slot_id,
+                    slot_label: slot.label.clone(),
+                    flags: session.flags,
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.handle);
+
+        SessionManagerState {
+            next_session_handle: self.next_session_handle,
+            sessions,
+        }
+    }
+
+    // restore the sessions of a slot, returns the number of restored sessions
+    pub fn import_state(
+        &mut self,
+        state: SessionManagerState,
+        slot_id: CK_SLOT_ID,
+        slot: Arc<Slot>,
+    ) -> usize {
+        let mut restored = 0;
+
+        for saved in state.sessions {
+            // the configuration may have changed since the state was saved
+            if saved.slot_id != slot_id || saved.slot_label != slot.label {
+                warn!(
+                    "Not restoring session {}: slot {} is not {} anymore",
+                    saved.handle, saved.slot_id, saved.slot_label
+                );
+                continue;
+            }
+            // the module was reloaded without the process restarting
+            if self.sessions.contains_key(&saved.handle) {
+                debug!("Session {} is still open", saved.handle);
+                continue;
+            }
+
+            let mut session = Session::new(slot_id, slot.clone(), saved.flags);
+            session.handle = saved.handle;
+            self.sessions
+                .insert(saved.handle, Arc::new(Mutex::new(session)));
+            restored += 1;
         }
+
+        self.next_session_handle = self.next_session_handle.max(state.next_session_handle);
+
+        restored
     }
 
     // test only function to setup a session how we want it
     #[allow(dead_code)]
     #[cfg(test)]
-    pub fn set_session(&mut self, handle: CK_SESSION_HANDLE, session: Session) {
+    pub fn set_session(&mut self, handle: CK_SESSION_HANDLE, mut session: Session) {
+        session.handle = handle;
         self.sessions.insert(handle, Arc::new(Mutex::new(session)));
     }
 
     // test only function to setup a blank session
     #[cfg(test)]
     pub fn setup_dummy_session(&mut self) -> cryptoki_sys::CK_SESSION_HANDLE {
-        self.create_session(
-            0,
-            Arc::new(Slot {
-                administrator: None,
-                retries: None,
-                db: Arc::new(Mutex::new(Db::new())),
-                description: None,
-                instances: vec![],
-                label: "test".to_string(),
-                operator: None,
-            }),
-            0,
-        )
+        self.create_session(0, Arc::new(SlotBuilder::new().build().unwrap()), 0)
     }
 }
 
 #[derive(Debug)]
 pub struct Session {
+    // the handle given by the SessionManager, 0 until then
+    pub handle: CK_SESSION_HANDLE,
     pub slot_id: CK_SLOT_ID,
     pub login_ctx: LoginCtx,
     pub flags: CK_FLAGS,
     pub device_error: CK_RV,
     pub db: Arc<Mutex<Db>>,
     pub sign_ctx: Option<SignCtx>,
+    pub message_sign_ctx: Option<MessageSignCtx>,
     pub encrypt_ctx: Option<EncryptCtx>,
     pub decrypt_ctx: Option<DecryptCtx>,
+    pub digest_ctx: Option<DigestCtx>,
+    pub verify_ctx: Option<VerifyCtx>,
+    pub verify_recover_ctx: Option<VerifyRecoverCtx>,
     pub enum_ctx: Option<EnumCtx>,
+    pub notify: Option<SessionNotify>,
+    // counted when a signature or a decryption ends, forgotten when the session is closed
+    pub key_usage: HashMap<CK_OBJECT_HANDLE, KeyUsage>,
+    pub sign_key: Option<CK_OBJECT_HANDLE>,
+    pub decrypt_key: Option<CK_OBJECT_HANDLE>,
+    pub strict_digestinfo_validation: bool,
+    pub min_pin_len: u32,
+    pub max_pin_len: u32,
+    pub disable_sha1: bool,
+    pub disable_rsa_pkcs_decrypt: bool,
+    pub prefetch_on_login: bool,
+    pub prefetch: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+// Number of times the session used a key, and the limit set with CKA_NETHSM_MAX_USAGE_COUNT
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyUsage {
+    pub count: CK_ULONG,
+    pub max: Option<CK_ULONG>,
+}
+
+impl KeyUsage {
+    pub fn exhausted(&self) -> bool {
+        self.max.is_some_and(|max| self.count >= max)
+    }
+}
+
+// Not defined by PKCS#11, the application is told that the NetHSM can't be reached anymore.
+// The session has to be closed and opened again once it is back.
+pub const CKN_DEVICE_REMOVED: CK_NOTIFICATION = CKN_VENDOR_DEFINED | 1;
+const CKN_VENDOR_DEFINED: CK_NOTIFICATION = 0x80000000;
+
+// Held by the session macros while a function runs, the removal of the device noticed
+// during the call is recorded once the session is unlocked.
+pub struct DeviceRemovalCheck(pub Arc<Mutex<Session>>);
+
+impl Drop for DeviceRemovalCheck {
+    fn drop(&mut self) {
+        let notify = match self.0.lock() {
+            Ok(mut session) => session.mark_device_removed(),
+            Err(_) => None,
+        };
+        // the callback can call the module, the session is unlocked
+        if let Some(notify) = notify {
+            let rv = notify.notify(CKN_DEVICE_REMOVED);
+            if rv != CKR_OK {
+                debug!("The application returned {} for the removal", rv);
+            }
+        }
+    }
+}
+
+// The callback given to C_OpenSession, with the handle of the session and the pointer
+// of the application it has to be called with.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionNotify {
+    handle: CK_SESSION_HANDLE,
+    callback: CK_NOTIFY,
+    application: CK_VOID_PTR,
+}
+
+// the pointer is opaque to the module, it is only given back to the callback
+unsafe impl Send for SessionNotify {}
+
+impl SessionNotify {
+    pub fn new(
+        handle: CK_SESSION_HANDLE,
+        callback: CK_NOTIFY,
+        application: CK_VOID_PTR,
+    ) -> Option<Self> {
+        callback.map(|_| Self {
+            handle,
+            callback,
+            application,
+        })
+    }
+
+    pub fn notify(&self, event: CK_NOTIFICATION) -> CK_RV {
+        match self.callback {
+            Some(callback) => unsafe { callback(self.handle, event, self.application) },
+            None => CKR_OK,
+        }
+    }
 }
 
 impl Session {
@@ -127,17 +456,62 @@ impl Session {
         );
 
         Self {
+            handle: 0,
             login_ctx,
             slot_id,
             flags,
             db: slot.db.clone(),
             device_error: CKR_OK,
             sign_ctx: None,
+            message_sign_ctx: None,
             encrypt_ctx: None,
             decrypt_ctx: None,
+            digest_ctx: None,
+            verify_ctx: None,
+            verify_recover_ctx: None,
             enum_ctx: None,
+            notify: None,
+            key_usage: HashMap::new(),
+            sign_key: None,
+            decrypt_key: None,
+            strict_digestinfo_validation: slot.strict_digestinfo_validation,
+            min_pin_len: slot.min_pin_len,
+            max_pin_len: slot.max_pin_len,
+            disable_sha1: slot.disable_sha1,
+            disable_rsa_pkcs_decrypt: slot.disable_rsa_pkcs_decrypt,
+            prefetch_on_login: slot.prefetch_on_login,
+            prefetch: slot.prefetch.clone(),
+        }
+    }
+    pub fn abort_operations(&mut self) {
+        // the buffered data can be plaintext, it is wiped before being freed
+        self.sign_clear();
+        self.message_sign_ctx = None;
+        self.encrypt_clear();
+        self.decrypt_clear();
+        self.digest_ctx = None;
+        self.verify_ctx = None;
+        self.verify_recover_ctx = None;
+        self.enum_ctx = None;
+    }
+
+    pub fn device_removed(&self) -> bool {
+        self.device_error == CKR_DEVICE_REMOVED || self.login_ctx.instance_removed()
+    }
+
+    // The first time the NetHSM is found unreachable, the operations are aborted and the
+    // callback to notify the application is returned. The session stays unusable afterwards.
+    pub fn mark_device_removed(&mut self) -> Option<SessionNotify> {
+        if self.device_error == CKR_DEVICE_REMOVED || !self.login_ctx.instance_removed() {
+            return None;
         }
+
+        warn!("The NetHSM of the slot {} was removed", self.slot_id);
+        self.device_error = CKR_DEVICE_REMOVED;
+        self.abort_operations();
+        self.notify
     }
+
     pub fn get_ck_info(&self) -> CK_SESSION_INFO {
         let state = self.login_ctx.ck_state();
 
@@ -150,12 +524,74 @@ impl Session {
     }
 
     pub fn login(&mut self, user_type: CK_USER_TYPE, pin: String) -> Result<(), Error> {
-        Ok(self.login_ctx.login(user_type, pin)?)
+        // the token has no protected authentication path, the PIN is always given
+        if !(self.min_pin_len..=self.max_pin_len).contains(&(pin.len() as u32)) {
+            return Err(LoginError::PinLenRange.into());
+        }
+        if user_type == CKU_CONTEXT_SPECIFIC {
+            return self.context_login(pin);
+        }
+        self.login_ctx.login(user_type, pin)?;
+        if self.prefetch_on_login {
+            self.prefetch_keys();
+        }
+        Ok(())
+    }
+
+    // authorizes the next signature or decryption of a key with CKA_ALWAYS_AUTHENTICATE, the
+    // login state of the session doesn't change
+    fn context_login(&mut self, pin: String) -> Result<(), Error> {
+        if !self.is_logged_in() {
+            return Err(Error::NotLoggedIn(UserMode::Operator));
+        }
+        let sign_ctx = self
+            .sign_ctx
+            .as_mut()
+            .filter(|ctx| ctx.requires_context_login);
+        let decrypt_ctx = self
+            .decrypt_ctx
+            .as_mut()
+            .filter(|ctx| ctx.requires_context_login);
+        if sign_ctx.is_none() && decrypt_ctx.is_none() {
+            return Err(Error::OperationNotInitialized);
+        }
+        self.login_ctx.verify_pin(pin)?;
+        if let Some(sign_ctx) = sign_ctx {
+            sign_ctx.context_logged_in = true;
+        }
+        if let Some(decrypt_ctx) = decrypt_ctx {
+            decrypt_ctx.context_logged_in = true;
+        }
+        Ok(())
     }
 
     // ignore logout for now
     pub fn logout(&mut self) -> Result<(), Error> {
         self.login_ctx.logout();
+
+        // the handles of a search started logged in can include private objects
+        if self.enum_ctx.as_ref().is_some_and(|ctx| ctx.logged_in) {
+            self.enum_ctx = None;
+        }
+        Ok(())
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        matches!(
+            self.login_ctx.ck_state(),
+            CKS_RO_USER_FUNCTIONS | CKS_RW_USER_FUNCTIONS | CKS_RW_SO_FUNCTIONS
+        )
+    }
+
+    // objects with CKA_PRIVATE set can only be used once the user is logged in
+    pub fn check_object_access(&self, object: &Object) -> Result<(), Error> {
+        if object.is_private() && !self.is_logged_in() {
+            debug!(
+                "Tried to use the private object {} without login",
+                object.id
+            );
+            return Err(Error::NotLoggedIn(UserMode::Operator));
+        }
         Ok(())
     }
 
@@ -175,8 +611,14 @@ impl Session {
             None => Err(Error::OperationNotInitialized),
         }
     }
-    pub fn enum_final(&mut self) {
-        self.enum_ctx = None;
+    pub fn enum_final(&mut self) -> Result<(), Error> {
+        match self.enum_ctx.take() {
+            Some(mut enum_ctx) => {
+                enum_ctx.finalize();
+                Ok(())
+            }
+            None => Err(Error::OperationNotInitialized),
+        }
     }
 
     pub fn sign_init(
@@ -184,7 +626,7 @@ impl Session {
         mechanism: &Mechanism,
         key_handle: CK_OBJECT_HANDLE,
     ) -> Result<(), Error> {
-        if self.sign_ctx.is_some() {
+        if self.sign_ctx.is_some() || self.message_sign_ctx.is_some() {
             return Err(Error::OperationActive);
         }
 
@@ -193,34 +635,37 @@ impl Session {
 
         // get key id from the handle
 
-        let key = {
-            let db = self.db.lock()?;
-            match db.object(key_handle) {
-                Some(object) => Ok(object.clone()),
-
-                None => {
-                    error!("Failed to get key: invalid handle");
-                    Err(Error::InvalidObjectHandle(key_handle))
-                }
+        let key = match self.get_object(key_handle) {
+            Ok(object) => object,
+            Err(err) => {
+                error!("Failed to get key: {:?}", err);
+                return Err(err);
             }
-        }?;
+        };
 
-        self.sign_ctx = Some(SignCtx::init(
-            mechanism.clone(),
-            key,
-            self.login_ctx.clone(),
-        )?);
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
+        self.check_key_usage(key_handle)?;
+
+        self.sign_ctx = Some(
+            SignCtx::init(mechanism.clone(), key, self.login_ctx.clone())?
+                .with_strict_digestinfo(self.strict_digestinfo_validation),
+        );
+        self.sign_key = Some(key_handle);
 
         Ok(())
     }
 
-    pub fn sign_theoretical_size(&self) -> Result<usize, Error> {
+    pub fn sign_theoretical_size(&self) -> Result<CK_ULONG, Error> {
         let sign_ctx = self
             .sign_ctx
             .as_ref()
             .ok_or(Error::OperationNotInitialized)?;
 
-        Ok(sign_ctx.get_theoretical_size())
+        Ok(sign_ctx
+            .pending_output
+            .as_ref()
+            .map_or(sign_ctx.output_len(), |output| output.len() as CK_ULONG))
     }
 
     pub fn sign_update(&mut self, data: &[u8]) -> Result<(), Error> {
@@ -239,15 +684,239 @@ impl Session {
             .as_mut()
             .ok_or(Error::OperationNotInitialized)?;
 
-        sign_ctx.sign_final()
+        // the NetHSM is only asked once, a retry after CKR_BUFFER_TOO_SMALL gets the same signature
+        if let Some(signature) = &sign_ctx.pending_output {
+            return Ok(signature.clone());
+        }
+
+        let signature = sign_ctx.sign_final()?;
+        sign_ctx.pending_output = Some(signature.clone());
+        sign_ctx.context_logged_in = false;
+        self.count_key_usage(self.sign_key);
+        Ok(signature)
     }
 
+    // If the buffer of C_Sign turns out too small for the signature, the application calls it
+    // again with the same data and gets the signature of the first call.
     pub fn sign(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        self.sign_update(data)?;
+        let sign_ctx = self
+            .sign_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        if sign_ctx.pending_output.is_none() {
+            sign_ctx.update(data);
+        }
         self.sign_final()
     }
 
+    pub fn message_sign_begin(
+        &mut self,
+        mechanism: &Mechanism,
+        key_handle: CK_OBJECT_HANDLE,
+    ) -> Result<(), Error> {
+        if self.sign_ctx.is_some() || self.message_sign_ctx.is_some() {
+            return Err(Error::OperationActive);
+        }
+
+        let key = self.get_object(key_handle)?;
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
+        self.check_key_usage(key_handle)?;
+
+        self.message_sign_ctx = Some(
+            MessageSignCtx::init(mechanism.clone(), key, key_handle, self.login_ctx.clone())?
+                .with_strict_digestinfo(self.strict_digestinfo_validation),
+        );
+
+        Ok(())
+    }
+
+    pub fn message_sign_len(&self) -> Result<CK_ULONG, Error> {
+        let ctx = self
+            .message_sign_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        Ok(ctx.output_len())
+    }
+
+    // the mechanism of the message context, to read the parameters of a message
+    pub fn message_sign_mechanism(&self) -> Result<CK_MECHANISM_TYPE, Error> {
+        self.message_sign_ctx
+            .as_ref()
+            .map(MessageSignCtx::mechanism_type)
+            .ok_or(Error::OperationNotInitialized)
+    }
+
+    // signs one complete message, the context stays active for the next ones
+    pub fn message_sign_next(
+        &mut self,
+        parameter: MechanismParams,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let ctx = self
+            .message_sign_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+        let key_handle = ctx.key_handle;
+
+        self.check_key_usage(key_handle)?;
+        let signature = ctx.sign(parameter, data)?;
+        self.count_key_usage(Some(key_handle));
+        Ok(signature)
+    }
+
+    pub fn message_sign_end(&mut self) -> Result<(), Error> {
+        match self.message_sign_ctx.take() {
+            Some(_) => Ok(()),
+            None => Err(Error::OperationNotInitialized),
+        }
+    }
+
+    // the session objects are only seen by the session that created them
+    fn owns(&self, object: &Object) -> bool {
+        object
+            .owner
+            .map(|owner| owner == self.handle)
+            .unwrap_or(true)
+    }
+
+    pub fn destroy_session_objects(&self) {
+        let Ok(mut db) = self.db.lock() else {
+            return;
+        };
+        let owned: Vec<CK_OBJECT_HANDLE> = db
+            .iter()
+            .filter(|(_, object)| object.owner == Some(self.handle))
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in owned {
+            db.remove(handle);
+        }
+    }
+
+    pub fn key_usage(&self, handle: CK_OBJECT_HANDLE) -> KeyUsage {
+        self.key_usage.get(&handle).copied().unwrap_or_default()
+    }
+
+    // The NetHSM doesn't store a subject with the keys, the subject of a private key is taken
+    // from the certificate with the same CKA_ID and kept on the key once found. A certificate
+    // that isn't cached is fetched from the NetHSM.
+    pub fn certificate_subject(&self, handle: CK_OBJECT_HANDLE) -> Option<Attribute> {
+        let (id, key_id, is_token) = {
+            let db = self.db.lock().unwrap();
+            let key = db.object(handle)?;
+            (
+                key.id.clone(),
+                key.get_attribute(CKA_ID)?.clone(),
+                key.is_token(),
+            )
+        };
+        let cached = self
+            .db
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, object)| {
+                object.kind == ObjectKind::Certificate
+                    && object.get_attribute(CKA_ID) == Some(&key_id)
+            })
+            .and_then(|(_, cert)| cert.get_attribute(CKA_SUBJECT).cloned());
+        let subject = match cached {
+            Some(subject) => subject,
+            None if is_token => {
+                let raw_id = match &key_id {
+                    Attribute::Bytes(bytes) => Some(bytes.clone()),
+                    _ => None,
+                };
+                // the db is locked by fetch_certificate to add the certificate
+                match fetch_certificate(&id, raw_id, self.login_ctx.clone(), self.db.clone()) {
+                    Ok(certs) => certs
+                        .into_iter()
+                        .find_map(|(_, cert)| cert.get_attribute(CKA_SUBJECT).cloned())?,
+                    Err(err) => {
+                        debug!("No certificate for the key {}: {:?}", id, err);
+                        return None;
+                    }
+                }
+            }
+            None => return None,
+        };
+        self.db
+            .lock()
+            .unwrap()
+            .object_mut(handle)?
+            .set_attr(CKA_SUBJECT, subject.clone());
+        Some(subject)
+    }
+
+    // the objects without a key type are left to the operation
+    fn check_key_type(&self, key: &Object, mechanism: CK_MECHANISM_TYPE) -> Result<(), Error> {
+        match key.get_attribute(CKA_KEY_TYPE) {
+            Some(Attribute::Ulong(key_type))
+                if !mechanism_key_type_compatible(mechanism, *key_type) =>
+            {
+                debug!(
+                    "The mechanism {:#x} can't be used with the key {}",
+                    mechanism, key.id
+                );
+                Err(Error::KeyTypeInconsistent(mechanism, *key_type))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_key_usage(&self, handle: CK_OBJECT_HANDLE) -> Result<(), Error> {
+        if self.key_usage(handle).exhausted() {
+            debug!("The key {} was used the maximum number of times", handle);
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+        Ok(())
+    }
+
+    fn count_key_usage(&mut self, handle: Option<CK_OBJECT_HANDLE>) {
+        if let Some(handle) = handle {
+            self.key_usage.entry(handle).or_default().count += 1;
+        }
+    }
+
+    // The limit is kept by the session like the count, so it can be set on the keys
+    // stored on the NetHSM too. It can't be changed along with other attributes.
+    pub fn set_max_usage_count(
+        &mut self,
+        handle: CK_OBJECT_HANDLE,
+        template: &CkRawAttrTemplate,
+    ) -> Result<(), Error> {
+        let object = self.get_object(handle)?;
+        self.check_object_access(&object)?;
+
+        let mut max = None;
+        for raw_attr in template.iter() {
+            let attr_type = raw_attr.type_();
+            if attr_type != CKA_NETHSM_MAX_USAGE_COUNT {
+                return Err(Error::AttributeReadOnly(attr_type));
+            }
+            match raw_attr
+                .val_bytes()
+                .and_then(|bytes| Attribute::from_raw(attr_type, bytes))
+            {
+                Some(Attribute::Ulong(value)) => max = Some(value),
+                _ => return Err(Error::InvalidAttribute(attr_type)),
+            }
+        }
+
+        self.key_usage.entry(handle).or_default().max = max;
+        Ok(())
+    }
+
     pub fn sign_clear(&mut self) {
+        if let Some(ctx) = self.sign_ctx.as_mut() {
+            ctx.data.zeroize();
+            if let Some(output) = ctx.pending_output.as_mut() {
+                output.zeroize();
+            }
+        }
         self.sign_ctx = None;
     }
 
@@ -262,17 +931,16 @@ impl Session {
 
         // get key id from the handle
 
-        let key = {
-            let db = self.db.lock()?;
-            match db.object(key_handle) {
-                Some(object) => Ok(object.clone()),
-
-                None => {
-                    error!("Failed to get key: invalid handle");
-                    Err(Error::InvalidObjectHandle(key_handle))
-                }
+        let key = match self.get_object(key_handle) {
+            Ok(object) => object,
+            Err(err) => {
+                error!("Failed to get key: {:?}", err);
+                return Err(err);
             }
-        }?;
+        };
+
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
 
         self.encrypt_ctx = Some(EncryptCtx::init(
             mechanism.clone(),
@@ -283,54 +951,87 @@ impl Session {
         Ok(())
     }
 
-    pub fn encrypt_add_data(&mut self, data: &[u8]) -> Result<(), Error> {
+    pub fn encrypt_theoretical_size(&self, input_len: usize) -> Result<usize, Error> {
         let encrypt_ctx = self
             .encrypt_ctx
-            .as_mut()
+            .as_ref()
             .ok_or(Error::OperationNotInitialized)?;
 
-        encrypt_ctx.add_data(data);
-        Ok(())
+        encrypt_ctx.output_len(input_len)
     }
 
-    pub fn encrypt_available_data(&mut self) -> Result<Vec<u8>, Error> {
+    // Length of the ciphertext returned by encrypt_update for this much data
+    pub fn encrypt_update_len(&self, data_len: usize) -> Result<usize, Error> {
         let encrypt_ctx = self
             .encrypt_ctx
-            .as_mut()
+            .as_ref()
             .ok_or(Error::OperationNotInitialized)?;
 
-        encrypt_ctx.encrypt_available_data()
+        Ok(encrypt_ctx.update_len(data_len))
     }
 
     pub fn encrypt_update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        self.encrypt_add_data(data)?;
-        self.encrypt_available_data()
+        let encrypt_ctx = self
+            .encrypt_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        encrypt_ctx.update(data)
     }
 
-    pub fn encrypt_get_theoretical_final_size(&self) -> Result<usize, Error> {
+    pub fn encrypt_final_output_len(&self) -> Result<CK_ULONG, Error> {
         let encrypt_ctx = self
             .encrypt_ctx
             .as_ref()
             .ok_or(Error::OperationNotInitialized)?;
 
-        Ok(encrypt_ctx.get_biggest_chunk_len())
+        match &encrypt_ctx.pending_output {
+            Some(output) => Ok(output.len() as CK_ULONG),
+            None => encrypt_ctx.final_output_len(),
+        }
     }
 
+    // Like sign_final, the NetHSM is contacted once however many times the caller retries
     pub fn encrypt_final(&mut self) -> Result<Vec<u8>, Error> {
         let encrypt_ctx = self
             .encrypt_ctx
             .as_mut()
             .ok_or(Error::OperationNotInitialized)?;
 
-        encrypt_ctx.encrypt_final()
+        if let Some(output) = &encrypt_ctx.pending_output {
+            return Ok(output.clone());
+        }
+
+        let output = encrypt_ctx.encrypt_final()?;
+        encrypt_ctx.pending_output = Some(output.clone());
+        Ok(output)
     }
 
     pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        self.encrypt_add_data(data)?;
-        self.encrypt_final()
+        let encrypt_ctx = self
+            .encrypt_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        // a retry after CKR_BUFFER_TOO_SMALL repeats the data that was already encrypted
+        if let Some(output) = &encrypt_ctx.pending_output {
+            return Ok(output.clone());
+        }
+
+        let mut output = encrypt_ctx.update(data)?;
+        output.extend_from_slice(&encrypt_ctx.encrypt_final()?);
+        encrypt_ctx.pending_output = Some(output.clone());
+        Ok(output)
     }
 
     pub fn encrypt_clear(&mut self) {
+        if let Some(ctx) = self.encrypt_ctx.as_mut() {
+            ctx.partial_block.zeroize();
+            ctx.rsa_data.zeroize();
+            if let Some(output) = ctx.pending_output.as_mut() {
+                output.zeroize();
+            }
+        }
         self.encrypt_ctx = None;
     }
 
@@ -345,41 +1046,66 @@ impl Session {
 
         // get key id from the handle
 
-        let key = {
-            let db = self.db.lock()?;
-            match db.object(key_handle) {
-                Some(object) => Ok(object.clone()),
+        let key = match self.get_object(key_handle) {
+            Ok(object) => object,
+            Err(err) => {
+                error!("Failed to get key: {:?}", err);
+                return Err(err);
+            }
+        };
 
-                None => {
-                    error!("Failed to get key: invalid handle");
-                    Err(Error::InvalidObjectHandle(key_handle))
-                }
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
+        self.check_key_usage(key_handle)?;
+
+        if *mechanism == Mechanism::RsaPkcs(None) {
+            if self.disable_rsa_pkcs_decrypt {
+                return Err(Error::MechanismDisabled(mechanism.clone()));
             }
-        }?;
+            // still needed by legacy protocols, so it is only discouraged
+            warn!("CKM_RSA_PKCS decryption is open to padding oracle attacks, CKM_RSA_PKCS_OAEP should be used instead");
+        }
 
         self.decrypt_ctx = Some(DecryptCtx::init(
             mechanism.clone(),
             &key,
             self.login_ctx.clone(),
         )?);
+        self.decrypt_key = Some(key_handle);
 
         Ok(())
     }
 
-    // only adds data to the decrypt context, does not decrypt anything
-    pub fn decrypt_update(&mut self, data: &[u8]) -> Result<(), Error> {
+    // Length of the plaintext returned by decrypt_update for this much ciphertext
+    pub fn decrypt_update_len(&self, data_len: usize) -> Result<usize, Error> {
+        let decrypt_ctx = self
+            .decrypt_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        Ok(decrypt_ctx.streamed_len(data_len))
+    }
+
+    // With AES-CBC, the blocks that can't be the last one are decrypted. The other mechanisms
+    // need the whole ciphertext, it is only buffered.
+    pub fn decrypt_update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
         let decrypt_ctx = self
             .decrypt_ctx
             .as_mut()
             .ok_or(Error::OperationNotInitialized)?;
 
         decrypt_ctx.update(data);
-        Ok(())
+        decrypt_ctx.decrypt_streamed()
     }
 
     // For now we go safe and lazy and just return the same size as the input
-    pub fn decrypt_theoretical_size(&self, input_size: usize) -> usize {
-        input_size
+    pub fn decrypt_theoretical_size(&self, input_size: usize) -> Result<usize, Error> {
+        let decrypt_ctx = self
+            .decrypt_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        decrypt_ctx.output_len(input_size)
     }
 
     pub fn decrypt_theoretical_final_size(&self) -> Result<usize, Error> {
@@ -388,38 +1114,311 @@ impl Session {
             .as_ref()
             .ok_or(Error::OperationNotInitialized)?;
 
-        Ok(decrypt_ctx.data.len())
+        Ok(decrypt_ctx
+            .pending_output
+            .as_ref()
+            .map_or(decrypt_ctx.data.len(), Vec::len))
     }
 
+    // Like sign_final, the NetHSM is contacted once however many times the caller retries
     pub fn decrypt_final(&mut self) -> Result<Vec<u8>, Error> {
         let decrypt_ctx = self
             .decrypt_ctx
             .as_mut()
             .ok_or(Error::OperationNotInitialized)?;
 
-        decrypt_ctx.decrypt_final()
+        if let Some(output) = &decrypt_ctx.pending_output {
+            return Ok(output.clone());
+        }
+
+        let decrypted = decrypt_ctx.decrypt_final()?;
+        decrypt_ctx.pending_output = Some(decrypted.clone());
+        decrypt_ctx.context_logged_in = false;
+        self.count_key_usage(self.decrypt_key);
+        Ok(decrypted)
     }
 
     pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        self.decrypt_update(data)?;
+        let decrypt_ctx = self
+            .decrypt_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        // a retry after CKR_BUFFER_TOO_SMALL repeats the data that was already decrypted
+        if decrypt_ctx.pending_output.is_none() {
+            decrypt_ctx.update(data);
+        }
         self.decrypt_final()
     }
 
+    // Length of the plaintext returned by decrypt_verify_update for this much ciphertext
+    pub fn decrypt_verify_update_len(&self, data_len: usize) -> Result<usize, Error> {
+        match (&self.decrypt_ctx, &self.verify_ctx) {
+            (Some(decrypt_ctx), Some(_)) => decrypt_ctx.available_len(data_len),
+            _ => Err(Error::OperationNotInitialized),
+        }
+    }
+
+    // The complete blocks received are decrypted and their plaintext is fed to the
+    // verification, the operations are then ended by decrypt_final and verify_final.
+    pub fn decrypt_verify_update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let (Some(decrypt_ctx), Some(verify_ctx)) =
+            (self.decrypt_ctx.as_mut(), self.verify_ctx.as_mut())
+        else {
+            return Err(Error::OperationNotInitialized);
+        };
+
+        decrypt_ctx.update(data);
+        let plaintext = decrypt_ctx.decrypt_available_data()?;
+        verify_ctx.update(&plaintext);
+
+        Ok(plaintext)
+    }
+
     pub fn decrypt_clear(&mut self) {
+        if let Some(ctx) = self.decrypt_ctx.as_mut() {
+            ctx.data.zeroize();
+            if let Some(output) = ctx.pending_output.as_mut() {
+                output.zeroize();
+            }
+        }
         self.decrypt_ctx = None;
     }
 
-    pub fn get_object(&self, handle: CK_OBJECT_HANDLE) -> Option<Object> {
-        let db = self.db.lock().unwrap();
+    pub fn digest_init(&mut self, digest: MechDigest) -> Result<(), Error> {
+        if self.digest_ctx.is_some() {
+            return Err(Error::OperationActive);
+        }
+
+        if digest == MechDigest::Sha1 {
+            if self.disable_sha1 {
+                return Err(Error::DigestDisabled(digest));
+            }
+            // still needed by legacy protocols, so it is only discouraged
+            warn!("CKM_SHA_1 is weak, SHA-256 or stronger should be used instead");
+        }
+
+        self.digest_ctx = Some(DigestCtx::init(digest));
+
+        Ok(())
+    }
+
+    pub fn digest_update(&mut self, data: &[u8]) -> Result<(), Error> {
+        let digest_ctx = self
+            .digest_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        digest_ctx.update(data);
+        Ok(())
+    }
+
+    pub fn digest_key(&mut self, key_handle: CK_OBJECT_HANDLE) -> Result<(), Error> {
+        if self.digest_ctx.is_none() {
+            return Err(Error::OperationNotInitialized);
+        }
+
+        let key = match self.get_object(key_handle) {
+            Ok(object) => object,
+            Err(err) => {
+                error!("Failed to get key: {:?}", err);
+                return Err(err);
+            }
+        };
+
+        let digest_ctx = self
+            .digest_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        digest_ctx.update_key(&key)
+    }
+
+    pub fn digest_theoretical_size(&self) -> Result<usize, Error> {
+        let digest_ctx = self
+            .digest_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        Ok(digest_ctx.output_size())
+    }
+
+    pub fn digest_final(&mut self) -> Result<Vec<u8>, Error> {
+        let digest_ctx = self
+            .digest_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        Ok(digest_ctx.digest_final())
+    }
+
+    pub fn digest(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.digest_update(data)?;
+        self.digest_final()
+    }
+
+    pub fn digest_clear(&mut self) {
+        self.digest_ctx = None;
+    }
+
+    pub fn verify_init(
+        &mut self,
+        mechanism: &Mechanism,
+        key_handle: CK_OBJECT_HANDLE,
+    ) -> Result<(), Error> {
+        if self.verify_ctx.is_some() {
+            return Err(Error::OperationActive);
+        }
+
+        let key = match self.get_object(key_handle) {
+            Ok(object) => object,
+            Err(err) => {
+                error!("Failed to get key: {:?}", err);
+                return Err(err);
+            }
+        };
+
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
+
+        self.verify_ctx = Some(VerifyCtx::init(
+            mechanism.clone(),
+            &key,
+            self.login_ctx.clone(),
+        )?);
+
+        Ok(())
+    }
+
+    pub fn verify_update(&mut self, data: &[u8]) -> Result<(), Error> {
+        let verify_ctx = self
+            .verify_ctx
+            .as_mut()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        verify_ctx.update(data);
+        Ok(())
+    }
+
+    pub fn verify_final(&mut self, signature: &[u8]) -> Result<(), Error> {
+        let verify_ctx = self
+            .verify_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        // the session may have logged out since C_VerifyInit
+        if verify_ctx.private && !self.is_logged_in() {
+            return Err(Error::NotLoggedIn(UserMode::Operator));
+        }
+
+        verify_ctx.verify_final(signature)
+    }
+
+    pub fn verify(&mut self, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+        self.verify_update(data)?;
+        self.verify_final(signature)
+    }
+
+    pub fn verify_clear(&mut self) {
+        self.verify_ctx = None;
+    }
+
+    pub fn verify_recover_init(
+        &mut self,
+        mechanism: &Mechanism,
+        key_handle: CK_OBJECT_HANDLE,
+    ) -> Result<(), Error> {
+        if self.verify_recover_ctx.is_some() {
+            return Err(Error::OperationActive);
+        }
+
+        let key = self.get_object(key_handle)?;
+        self.check_object_access(&key)?;
+        self.check_key_type(&key, mechanism.ck_type())?;
+
+        self.verify_recover_ctx = Some(VerifyRecoverCtx::init(mechanism.clone(), &key)?);
+
+        Ok(())
+    }
+
+    // the context is kept, the application can ask for the length of the data first
+    pub fn verify_recover(&self, signature: &[u8]) -> Result<Vec<u8>, Error> {
+        let verify_recover_ctx = self
+            .verify_recover_ctx
+            .as_ref()
+            .ok_or(Error::OperationNotInitialized)?;
+
+        if verify_recover_ctx.private && !self.is_logged_in() {
+            return Err(Error::NotLoggedIn(UserMode::Operator));
+        }
+
+        verify_recover_ctx.recover(signature)
+    }
+
+    pub fn verify_recover_clear(&mut self) {
+        self.verify_recover_ctx = None;
+    }
+
+    pub fn get_object(&self, handle: CK_OBJECT_HANDLE) -> Result<Object, Error> {
+        // an object evicted from the cache is fetched again, it keeps its handle
+        let evicted = self.db.lock()?.evicted(handle).cloned();
+        if let Some(evicted) = evicted {
+            let login_ctx = self.login_ctx.clone();
+            let fetched = match evicted.kind {
+                ObjectKind::Certificate => {
+                    fetch_certificate(&evicted.id, evicted.raw_id, login_ctx, self.db.clone())
+                }
+                _ => fetch_key(&evicted.id, evicted.raw_id, login_ctx, self.db.clone()),
+            };
+            if let Err(err) = fetched {
+                debug!(
+                    "Failed to fetch the evicted object {}: {:?}",
+                    evicted.id, err
+                );
+                return Err(err);
+            }
+        }
 
-        db.object(handle).cloned()
+        let mut db = self.db.lock()?;
+        db.touch(handle);
+        db.object(handle)
+            .filter(|object| self.owns(object))
+            .cloned()
+            .ok_or(Error::InvalidObjectHandle(handle))
     }
 
     pub(super) fn find_key(
         &mut self,
         requirements: KeyRequirements,
     ) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
-        let mut result = match requirements.id {
+        // the session objects are only in the database, the NetHSM isn't asked for them, their ID
+        // is generated so CKA_ID and CKA_LABEL are matched instead
+        let mut session_objects = match requirements.token {
+            Some(true) => vec![],
+            _ => {
+                let db = self.db.lock()?;
+                let mut handles = db.find_by_template(&KeyRequirements {
+                    id: None,
+                    token: Some(false),
+                    ..requirements.clone()
+                });
+                handles.retain(|handle| {
+                    db.object(*handle).is_some_and(|object| {
+                        self.owns(object) && requirements.matches_attributes(object)
+                    })
+                });
+                handles
+            }
+        };
+        // the NetHSM has no data objects
+        if requirements.token == Some(false) || requirements.kind == Some(ObjectKind::Data) {
+            return Ok(session_objects);
+        }
+        let token = requirements.token;
+        let key_id = requirements.nethsm_id();
+        let list_all = key_id.is_none();
+
+        let mut result = match key_id {
             Some(key_id) => {
                 // try to search in the db first
                 let mut results: Vec<(CK_OBJECT_HANDLE, Object)> = {
@@ -427,6 +1426,7 @@ impl Session {
                     db.iter()
                         .filter(|(_, obj)| {
                             obj.id == key_id
+                                && obj.is_token()
                                 && requirements.kind.map(|k| k == obj.kind).unwrap_or(true)
                         })
                         .map(|(handle, obj)| (handle, obj.clone()))
@@ -481,65 +1481,166 @@ impl Session {
         if let Some(kind) = requirements.kind {
             result.retain(|(_, obj)| obj.kind == kind);
         }
+        if token == Some(true) {
+            result.retain(|(_, obj)| obj.is_token());
+        }
 
-        Ok(result.iter().map(|(handle, _)| *handle).collect())
+        let mut handles: Vec<CK_OBJECT_HANDLE> = result.iter().map(|(handle, _)| *handle).collect();
+        // the evicted keys are listed without fetching them, they are fetched when used
+        if list_all {
+            let evicted = self.db.lock()?.evicted_handles(requirements.kind);
+            session_objects.extend(evicted);
+        }
+        for handle in session_objects {
+            if !handles.contains(&handle) {
+                handles.push(handle);
+            }
+        }
+        Ok(handles)
     }
 
     fn fetch_all_keys(
         &mut self,
         kind: Option<ObjectKind>,
     ) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
+        fetch_all_keys(&self.db, &mut self.login_ctx, kind)
+    }
+
+    // Fills the database in the background so that the first operation doesn't wait for the
+    // keys. The foreground searches wait for it on the fetch lock of the database.
+    fn prefetch_keys(&self) {
+        if !THREADS_ALLOWED.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut prefetch = match self.prefetch.lock() {
+            Ok(prefetch) => prefetch,
+            Err(_) => return,
+        };
+        // another session of the slot is already on it
+        if prefetch
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
         {
-            let db = self.db.lock()?;
+            return;
+        }
 
-            if db.fetched_all_keys() {
-                return Ok(db
-                    .iter()
-                    .map(|(handle, obj)| (handle, obj.clone()))
-                    .collect());
+        let db = self.db.clone();
+        let mut login_ctx = self.login_ctx.clone();
+        *prefetch = Some(std::thread::spawn(move || {
+            if let Err(err) = fetch_all_keys(&db, &mut login_ctx, None) {
+                warn!("Failed to prefetch the keys: {}", err);
+            }
+        }));
+    }
+
+    pub fn create_object(
+        &mut self,
+        template: CkRawAttrTemplate,
+    ) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
+        // the data objects and the public keys without private key are only kept by the module
+        let object = match parse_attributes(&template)?.key_class {
+            Some(ObjectKind::Data) => Some(data_object_from_template(&template)?),
+            Some(ObjectKind::PublicKey) => {
+                Some(public_key_from_template(&template, &self.login_ctx)?)
             }
+            _ => None,
+        };
+        if let Some(mut object) = object {
+            self.check_object_access(&object)?;
+            object.owner = Some(self.handle);
+            return Ok(vec![self.db.lock()?.add_object(object)]);
         }
 
         if !self
             .login_ctx
-            .can_run_mode(super::login::UserMode::OperatorOrAdministrator)
+            .can_run_mode(super::login::UserMode::Administrator)
         {
-            return Err(Error::NotLoggedIn(
-                super::login::UserMode::OperatorOrAdministrator,
-            ));
+            return Err(Error::NotLoggedIn(super::login::UserMode::Administrator));
         }
 
-        let keys = self
-            .login_ctx
-            .try_(
-                |api_config| default_api::keys_get(api_config, None),
-                super::login::UserMode::OperatorOrAdministrator,
-            )?
-            .entity;
+        let login_ctx = self.login_ctx.clone();
 
-        let results: Result<Vec<_>, _> = if THREADS_ALLOWED.load(Ordering::Relaxed) {
-            use rayon::prelude::*;
-            keys.par_iter()
-                .map(|k| super::key::fetch_one(k, &self.db, &self.login_ctx, kind))
-                .collect()
-        } else {
-            keys.iter()
-                .map(|k| super::key::fetch_one(k, &self.db, &self.login_ctx, kind))
-                .collect()
-        };
+        // the NetHSM doesn't store the object ID of a certificate, it is added once fetched
+        let object_id = template
+            .iter()
+            .find(|attr| attr.type_() == CKA_OBJECT_ID)
+            .map(|attr| attr.val_bytes().unwrap_or_default().to_vec());
+        // nor the length of a secret key
+        let value_len = template
+            .iter()
+            .find(|attr| attr.type_() == CKA_VALUE)
+            .and_then(|attr| attr.val_bytes().map(|value| value.len() as CK_ULONG));
+        let wrap_attributes = wrap_attributes(&template);
 
-        let handles = results?.into_iter().flatten().collect();
+        let tag_attributes = self.db.lock()?.tag_attributes().clone();
+        let key_info = create_key_from_template(template, &tag_attributes, login_ctx)?;
 
-        let mut db = self.db.lock()?;
-        db.set_fetched_all_keys(true);
+        let login_ctx = self.login_ctx.clone();
+        let db = self.db.clone();
 
-        Ok(handles)
+        match key_info.1 {
+            ObjectKind::Certificate => {
+                let mut objects = fetch_certificate(&key_info.0, None, login_ctx, db)?;
+                if let Some(object_id) = object_id {
+                    self.set_module_attribute(
+                        &mut objects,
+                        ObjectKind::Certificate,
+                        CKA_OBJECT_ID,
+                        Attribute::Bytes(object_id),
+                    )?;
+                }
+                Ok(objects)
+            }
+            _ => {
+                let mut objects = fetch_key(&key_info.0, None, login_ctx, db)?;
+                if let Some(len) = value_len {
+                    self.set_module_attribute(
+                        &mut objects,
+                        ObjectKind::SecretKey,
+                        CKA_VALUE_LEN,
+                        Attribute::Ulong(len),
+                    )?;
+                }
+                self.set_wrap_attributes(&mut objects, wrap_attributes)?;
+                Ok(objects)
+            }
+        }
     }
 
-    pub fn create_object(
+    // CKA_WRAP and CKA_UNWRAP of the secret keys, the NetHSM has no metadata for them
+    fn set_wrap_attributes(
+        &self,
+        objects: &mut [(CK_OBJECT_HANDLE, Object)],
+        wrap_attributes: Vec<(CK_ATTRIBUTE_TYPE, Attribute)>,
+    ) -> Result<(), Error> {
+        for (attr_type, attr) in wrap_attributes {
+            self.set_module_attribute(objects, ObjectKind::SecretKey, attr_type, attr)?;
+        }
+        Ok(())
+    }
+
+    // sets an attribute the NetHSM doesn't store on the fetched objects of a kind
+    fn set_module_attribute(
+        &self,
+        objects: &mut [(CK_OBJECT_HANDLE, Object)],
+        kind: ObjectKind,
+        attr_type: CK_ATTRIBUTE_TYPE,
+        attr: Attribute,
+    ) -> Result<(), Error> {
+        let mut db = self.db.lock()?;
+        for (handle, object) in objects.iter_mut().filter(|(_, object)| object.kind == kind) {
+            object.set_attr(attr_type, attr.clone());
+            db.update_object(*handle, object.clone());
+        }
+        Ok(())
+    }
+
+    pub fn import_pem_key(
         &mut self,
-        template: CkRawAttrTemplate,
-    ) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
+        pem: &[u8],
+        key_id: &[u8],
+        key_type: cryptoki_sys::CK_KEY_TYPE,
+    ) -> Result<CK_OBJECT_HANDLE, Error> {
         if !self
             .login_ctx
             .can_run_mode(super::login::UserMode::Administrator)
@@ -547,24 +1648,162 @@ impl Session {
             return Err(Error::NotLoggedIn(super::login::UserMode::Administrator));
         }
 
-        let login_ctx = self.login_ctx.clone();
+        let (id, raw_id) = import_pem_key(pem, key_id, key_type, self.login_ctx.clone())?;
 
-        let key_info = create_key_from_template(template, login_ctx)?;
+        fetch_key(&id, raw_id, self.login_ctx.clone(), self.db.clone())?
+            .into_iter()
+            .find(|(_, object)| object.kind == ObjectKind::PrivateKey)
+            .map(|(handle, _)| handle)
+            .ok_or(Error::InvalidData)
+    }
 
-        let login_ctx = self.login_ctx.clone();
-        let db = self.db.clone();
+    // The NetHSM decrypts the wrapped key with the unwrapping key, the plaintext is then
+    // imported as a new key. Only RSA-OAEP can be used: the NetHSM doesn't wrap with AES and
+    // the values of its AES keys are never known to the module.
+    pub fn unwrap_key(
+        &mut self,
+        mechanism: &Mechanism,
+        unwrapping_key: CK_OBJECT_HANDLE,
+        wrapped_key: &[u8],
+        template: CkRawAttrTemplate,
+    ) -> Result<CK_OBJECT_HANDLE, Error> {
+        // the NetHSM decrypts with the operator and imports with the administrator, the session
+        // needs both: it can't be restricted to one of them by C_Login
+        for mode in [
+            super::login::UserMode::Administrator,
+            super::login::UserMode::Operator,
+        ] {
+            if !self.login_ctx.can_run_mode(mode.clone()) {
+                return Err(Error::NotLoggedIn(mode));
+            }
+        }
 
-        match key_info.1 {
-            ObjectKind::Certificate => fetch_certificate(&key_info.0, None, login_ctx, db),
-            _ => fetch_key(&key_info.0, None, login_ctx, db),
+        if !matches!(mechanism, Mechanism::RsaPkcsOaep(_)) {
+            return Err(Error::InvalidMechanismMode(
+                super::mechanism::MechMode::Decrypt,
+                mechanism.clone(),
+            ));
+        }
+
+        let key = self.get_object(unwrapping_key)?;
+        self.check_object_access(&key)?;
+        self.check_key_usage(unwrapping_key)?;
+        if key.get_attribute(CKA_UNWRAP) != Some(&Attribute::Bool(true)) {
+            return Err(Error::KeyFunctionNotPermitted);
         }
+
+        let mut decrypt_ctx = DecryptCtx::init(mechanism.clone(), &key, self.login_ctx.clone())?;
+        decrypt_ctx.update(wrapped_key);
+        let mut value = decrypt_ctx.decrypt_final()?;
+        self.count_key_usage(Some(unwrapping_key));
+
+        let tag_attributes = self.db.lock()?.tag_attributes().clone();
+        let imported =
+            import_unwrapped_key(&template, &value, &tag_attributes, self.login_ctx.clone());
+        let value_len = value.len() as CK_ULONG;
+        value.zeroize();
+        let (id, raw_id) = imported?;
+
+        let mut objects = fetch_key(&id, raw_id, self.login_ctx.clone(), self.db.clone())?;
+        self.set_module_attribute(
+            &mut objects,
+            ObjectKind::SecretKey,
+            CKA_VALUE_LEN,
+            Attribute::Ulong(value_len),
+        )?;
+        objects
+            .into_iter()
+            .find(|(_, object)| object.kind == ObjectKind::SecretKey)
+            .map(|(handle, _)| handle)
+            .ok_or(Error::InvalidData)
     }
 
-    pub fn delete_object(&mut self, handle: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    // The NetHSM has no key derivation mechanism, the base key is still checked to tell the
+    // applications why it can't be used
+    pub fn check_derive_key(
+        &self,
+        mechanism: CK_MECHANISM_TYPE,
+        base_key: CK_OBJECT_HANDLE,
+    ) -> Result<(), Error> {
+        let key = self.get_object(base_key)?;
+        self.check_object_access(&key)?;
+        if key.get_attribute(cryptoki_sys::CKA_DERIVE) != Some(&Attribute::Bool(true)) {
+            debug!("The key {} can't be used for a derivation", key.id);
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+        self.check_key_type(&key, mechanism)
+    }
+
+    // The restrictions of a key on the NetHSM, which only has tags. The tags aren't in the
+    // cached object when the slot has no tag_attributes, they are read from the NetHSM.
+    pub fn key_restrictions(&mut self, handle: CK_OBJECT_HANDLE) -> Result<Vec<String>, Error> {
+        if !self
+            .login_ctx
+            .can_run_mode(UserMode::OperatorOrAdministrator)
+        {
+            return Err(Error::NotLoggedIn(UserMode::OperatorOrAdministrator));
+        }
+        let key = self.restricted_key(handle)?;
+
+        let key_data = self.login_ctx.try_(
+            |api_config| default_api::keys_key_id_get(api_config, &key.id),
+            UserMode::OperatorOrAdministrator,
+        )?;
+        Ok(key_data.entity.restrictions.tags.unwrap_or_default())
+    }
+
+    // Replaces the tags of a key, the NetHSM adds and removes them one by one. The objects of
+    // the key are fetched again for their tag attributes.
+    pub fn set_key_restrictions(
+        &mut self,
+        handle: CK_OBJECT_HANDLE,
+        tags: &[String],
+    ) -> Result<(), Error> {
         if !self.login_ctx.can_run_mode(UserMode::Administrator) {
             return Err(Error::NotLoggedIn(UserMode::Administrator));
         }
+        let key = self.restricted_key(handle)?;
+        let current = self.key_restrictions(handle)?;
+
+        for tag in tags.iter().filter(|tag| !current.contains(tag)) {
+            self.login_ctx.try_(
+                |api_config| {
+                    default_api::keys_key_id_restrictions_tags_tag_put(api_config, tag, &key.id)
+                },
+                UserMode::Administrator,
+            )?;
+        }
+        for tag in current.iter().filter(|tag| !tags.contains(tag)) {
+            self.login_ctx.try_(
+                |api_config| {
+                    default_api::keys_key_id_restrictions_tags_tag_delete(api_config, tag, &key.id)
+                },
+                UserMode::Administrator,
+            )?;
+        }
+
+        // CKA_ID holds the raw ID when the key ID was built from it
+        let raw_id = match key.get_attribute(CKA_ID) {
+            Some(Attribute::Bytes(id)) if id != key.id.as_bytes() => Some(id.clone()),
+            _ => None,
+        };
+        fetch_key(&key.id, raw_id, self.login_ctx.clone(), self.db.clone())?;
+        Ok(())
+    }
+
+    // only the keys stored on the NetHSM have restrictions
+    fn restricted_key(&self, handle: CK_OBJECT_HANDLE) -> Result<Object, Error> {
+        let key = self.get_object(handle)?;
+        self.check_object_access(&key)?;
+
+        if !key.is_token() || key.copied_from.is_some() || key.kind == ObjectKind::Certificate {
+            debug!("The object {} is not a key of the NetHSM", key.id);
+            return Err(Error::InvalidObjectHandle(handle));
+        }
+        Ok(key)
+    }
 
+    pub fn delete_object(&mut self, handle: CK_OBJECT_HANDLE) -> Result<(), Error> {
         // get key id from the handle
 
         let key = {
@@ -576,6 +1815,17 @@ impl Session {
             }
         }?;
 
+        // a copy is only an entry of the database, the key on the NetHSM belongs to the original
+        if key.copied_from.is_some() {
+            debug!("Deleting the copy of the key {} {:?}", key.id, key.kind);
+            self.db.lock()?.remove(handle);
+            return Ok(());
+        }
+
+        if !self.login_ctx.can_run_mode(UserMode::Administrator) {
+            return Err(Error::NotLoggedIn(UserMode::Administrator));
+        }
+
         debug!("Deleting key {} {:?}", key.id, key.kind);
 
         match key.kind {
@@ -608,22 +1858,2391 @@ impl Session {
         Ok(())
     }
 
-    pub fn generate_key(
-        &self,
-        template: &CkRawAttrTemplate,
-        public_template: Option<&CkRawAttrTemplate>,
+    // The NetHSM stores a single key per id, so a copy can't be a token object: it is a session
+    // object of the slot database that refers to the same key as the original.
+    pub fn copy_object(
+        &mut self,
+        handle: CK_OBJECT_HANDLE,
+        template: Option<&CkRawAttrTemplate>,
+    ) -> Result<CK_OBJECT_HANDLE, Error> {
+        let mut copy = self.get_object(handle)?;
+        self.check_object_access(&copy)?;
+
+        if !copy.is_copyable() {
+            debug!(
+                "Tried to copy the object {} with CKA_COPYABLE false",
+                copy.id
+            );
+            return Err(Error::ActionProhibited);
+        }
+
+        // the copy is owned by the module, the template can still make it read-only
+        copy.set_attr(CKA_MODIFIABLE, Attribute::Bool(true));
+        let private = copy.is_private();
+        if let Some(template) = template {
+            if !self.login_ctx.can_run_mode(UserMode::Administrator)
+                && parse_attributes(template)?.trusted
+            {
+                debug!("Only the SO can set CKA_TRUSTED");
+                return Err(Error::AttributeReadOnly(CKA_TRUSTED));
+            }
+            copy.merge_template(template, false)?;
+        }
+
+        // the copy keeps the protection of the object, it can only become a session object
+        if copy.is_private() != private {
+            debug!("Tried to change CKA_PRIVATE on a copy of {}", copy.id);
+            return Err(Error::AttributeReadOnly(CKA_PRIVATE));
+        }
+        if copy.is_token() {
+            debug!("The NetHSM can't store a copy of the object {}", copy.id);
+            if template.is_some_and(|template| template.iter().any(|a| a.type_() == CKA_TOKEN)) {
+                return Err(Error::AttributeReadOnly(CKA_TOKEN));
+            }
+            return Err(Error::ActionProhibited);
+        }
+        copy.set_attr(CKA_UNIQUE_ID, session_unique_id());
+
+        copy.owner = Some(self.handle);
+        Ok(self.db.lock()?.add_copy(handle, copy))
+    }
+
+    // Only the session objects can be changed, the attributes of the keys stored on the NetHSM
+    // are given by the NetHSM.
+    pub fn set_attribute_value(
+        &mut self,
+        handle: CK_OBJECT_HANDLE,
+        template: &CkRawAttrTemplate,
+    ) -> Result<(), Error> {
+        let mut object = self.get_object(handle)?;
+        self.check_object_access(&object)?;
+
+        if object.is_token() && self.flags & cryptoki_sys::CKF_RW_SESSION == 0 {
+            debug!(
+                "Tried to change the token object {} in a read-only session",
+                object.id
+            );
+            return Err(Error::SessionReadOnly);
+        }
+
+        // the object ID of a certificate is only kept by the module, it can always be changed
+        let module_only = object.kind == ObjectKind::Certificate
+            && template.iter().all(|attr| attr.type_() == CKA_OBJECT_ID);
+
+        if let Some(attr) = template.iter().map(|attr| attr.type_()).find(|attr| {
+            (object.is_token() && !module_only) || matches!(*attr, CKA_TOKEN | CKA_PRIVATE)
+        }) {
+            return Err(Error::AttributeReadOnly(attr));
+        }
+
+        if !module_only
+            && matches!(
+                object.get_attribute(CKA_MODIFIABLE),
+                Some(Attribute::Bool(false))
+            )
+        {
+            debug!("Tried to change the unmodifiable object {}", object.id);
+            return Err(Error::ActionProhibited);
+        }
+
+        // the SO may change the protection of a key and mark it as trusted
+        let is_so = self.login_ctx.can_run_mode(UserMode::Administrator);
+        if !is_so && parse_attributes(template)?.trusted {
+            debug!("Only the SO can set CKA_TRUSTED");
+            return Err(Error::AttributeReadOnly(CKA_TRUSTED));
+        }
+        object.merge_template(template, is_so)?;
+
+        self.db
+            .lock()?
+            .update_object(handle, object)
+            .ok_or(Error::InvalidObjectHandle(handle))
+    }
+
+    // The NetHSM never lets a key out, the keys can't be wrapped. This checks the
+    // restrictions of the keys, for the error returned by C_WrapKey.
+    pub fn check_wrap(
+        &self,
+        wrapping_key: CK_OBJECT_HANDLE,
+        key: CK_OBJECT_HANDLE,
+    ) -> Result<(), Error> {
+        let wrapping = self.get_object(wrapping_key)?;
+        let wrapped = self.get_object(key)?;
+        self.check_object_access(&wrapping)?;
+        self.check_object_access(&wrapped)?;
+
+        if wrapping.get_attribute(CKA_WRAP) != Some(&Attribute::Bool(true)) {
+            debug!("The key {} can't wrap", wrapping.id);
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+
+        if !wrapped.can_be_wrapped_with(&wrapping) {
+            debug!(
+                "The key {} can only be wrapped with a trusted key, {} is not",
+                wrapped.id, wrapping.id
+            );
+            return Err(Error::KeyNotWrappable);
+        }
+        Ok(())
+    }
+
+    // The length C_WrapKey gives when it is called without output buffer, None when the
+    // mechanism has no length known in advance or the key sizes are not known
+    pub fn wrap_output_len(
+        &self,
+        mechanism: cryptoki_sys::CK_MECHANISM_TYPE,
+        wrapping_key: CK_OBJECT_HANDLE,
+        key: CK_OBJECT_HANDLE,
+    ) -> Result<Option<CK_ULONG>, Error> {
+        let wrapping = self.get_object(wrapping_key)?;
+        let wrapped = self.get_object(key)?;
+
+        let modulus = match wrapping.get_attribute(cryptoki_sys::CKA_MODULUS) {
+            Some(Attribute::Bytes(modulus)) if !modulus.is_empty() => Some(modulus.len()),
+            _ => None,
+        };
+        let key_len =
+            wrapped.size.or_else(
+                || match wrapped.get_attribute(cryptoki_sys::CKA_VALUE_LEN) {
+                    Some(Attribute::Ulong(len)) if *len > 0 => Some(*len as usize),
+                    _ => None,
+                },
+            );
+
+        Ok(WrapOutputLen::estimate(mechanism, modulus, key_len))
+    }
+
+    pub fn generate_key(
+        &self,
+        template: &CkRawAttrTemplate,
+        public_template: Option<&CkRawAttrTemplate>,
         mechanism: &Mechanism,
     ) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
-        if !self.login_ctx.can_run_mode(UserMode::Administrator) {
-            return Err(Error::NotLoggedIn(UserMode::Administrator));
+        // generic secrets only need random data from the NetHSM
+        let mode = match mechanism {
+            Mechanism::GenerateGeneric => UserMode::Operator,
+            _ => UserMode::Administrator,
+        };
+
+        if !self.login_ctx.can_run_mode(mode.clone()) {
+            return Err(Error::NotLoggedIn(mode));
         }
 
-        generate_key_from_template(
+        let mut objects = generate_key_from_template(
             template,
             public_template,
             mechanism,
             self.login_ctx.clone(),
             self.db.clone(),
+        )?;
+
+        // the length of an AES key was checked, the NetHSM doesn't give it back
+        if matches!(mechanism, Mechanism::GenerateAes) {
+            let value_len = template
+                .iter()
+                .find(|attr| attr.type_() == CKA_VALUE_LEN)
+                .and_then(|attr| unsafe { attr.read_value::<CK_ULONG>() });
+            if let Some(len) = value_len {
+                self.set_module_attribute(
+                    &mut objects,
+                    ObjectKind::SecretKey,
+                    CKA_VALUE_LEN,
+                    Attribute::Ulong(len),
+                )?;
+            }
+            self.set_wrap_attributes(&mut objects, wrap_attributes(template))?;
+        }
+
+        // the generic secrets are session objects
+        let mut db = self.db.lock()?;
+        for (handle, object) in objects.iter_mut().filter(|(_, object)| !object.is_token()) {
+            object.owner = Some(self.handle);
+            db.update_object(*handle, object.clone());
+        }
+        Ok(objects)
+    }
+}
+
+fn wrap_attributes(template: &CkRawAttrTemplate) -> Vec<(CK_ATTRIBUTE_TYPE, Attribute)> {
+    template
+        .iter()
+        .filter(|attr| matches!(attr.type_(), CKA_WRAP | CKA_UNWRAP))
+        .filter_map(|attr| {
+            let value = unsafe { attr.read_value::<CK_BBOOL>() }?;
+            Some((attr.type_(), Attribute::Bool(value != CK_FALSE)))
+        })
+        .collect()
+}
+
+// the keys of the database if all of them were fetched recently
+fn cached_keys(
+    db: &Arc<Mutex<Db>>,
+    kind: Option<ObjectKind>,
+) -> Result<Option<Vec<(CK_OBJECT_HANDLE, Object)>>, Error> {
+    let db = db.lock()?;
+
+    if !db.fetched_all_keys() {
+        return Ok(None);
+    }
+
+    let handles = match kind.and_then(|kind| kind.ck_class()) {
+        Some(class) => db.find_by_class(class),
+        None => db.iter().map(|(handle, _)| handle).collect(),
+    };
+    Ok(Some(
+        handles
+            .into_iter()
+            .filter_map(|handle| db.object(handle).map(|obj| (handle, obj.clone())))
+            .collect(),
+    ))
+}
+
+fn fetch_all_keys(
+    db: &Arc<Mutex<Db>>,
+    login_ctx: &mut LoginCtx,
+    kind: Option<ObjectKind>,
+) -> Result<Vec<(CK_OBJECT_HANDLE, Object)>, Error> {
+    if let Some(cached) = cached_keys(db, kind)? {
+        return Ok(cached);
+    }
+
+    // only one session of the slot fetches the keys, the others wait and use its result
+    let fetch_lock = db.lock()?.fetch_lock();
+    let _fetching = fetch_lock.lock()?;
+
+    if let Some(cached) = cached_keys(db, kind)? {
+        return Ok(cached);
+    }
+
+    if !login_ctx.can_run_mode(super::login::UserMode::OperatorOrAdministrator) {
+        return Err(Error::NotLoggedIn(
+            super::login::UserMode::OperatorOrAdministrator,
+        ));
+    }
+
+    let keys = login_ctx
+        .try_(
+            |api_config| default_api::keys_get(api_config, None),
+            super::login::UserMode::OperatorOrAdministrator,
+        )?
+        .entity;
+
+    let login_ctx = &*login_ctx;
+    let results: Result<Vec<_>, _> = if THREADS_ALLOWED.load(Ordering::Relaxed) {
+        use rayon::prelude::*;
+        keys.par_iter()
+            .map(|k| super::key::fetch_one(k, db, login_ctx, kind))
+            .collect()
+    } else {
+        keys.iter()
+            .map(|k| super::key::fetch_one(k, db, login_ctx, kind))
+            .collect()
+    };
+
+    let handles = results?.into_iter().flatten().collect();
+
+    let mut db = db.lock()?;
+    db.set_fetched_all_keys(true);
+
+    Ok(handles)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use cryptoki_sys::CKA_LABEL;
+
+    use super::*;
+
+    fn test_slot(label: &str) -> Arc<Slot> {
+        Arc::new(SlotBuilder::new().label(label).build().unwrap())
+    }
+
+    fn key_object(id: &str, private: bool) -> Object {
+        let mut object = Object::default();
+        object.id = id.to_string();
+        object.kind = ObjectKind::PrivateKey;
+        object.set_attr(
+            cryptoki_sys::CKA_PRIVATE,
+            crate::backend::db::object::Attribute::Bool(private),
+        );
+        object
+    }
+
+    #[test]
+    fn test_login_role() {
+        let (slot, _) = mock_slot(0);
+        let mut key = key_object("ed", false);
+        key.mechanisms = vec![nethsm_sdk_rs::models::KeyMechanism::EdDsaSignature];
+        let handle = slot.db.lock().unwrap().add_object(key).0;
+        let mut session = Session::new(0, slot, 0);
+
+        // without C_Login both credentials of the configuration are used
+        assert!(session.login_ctx.can_run_mode(UserMode::Operator));
+        assert!(session.login_ctx.can_run_mode(UserMode::Administrator));
+
+        session
+            .login(cryptoki_sys::CKU_USER, "password".to_string())
+            .unwrap();
+        assert!(session.sign_init(&Mechanism::EdDsa, handle).is_ok());
+        session.sign_clear();
+        assert!(matches!(
+            session.delete_object(handle),
+            Err(Error::NotLoggedIn(UserMode::Administrator))
+        ));
+        assert!(matches!(
+            session.import_pem_key(b"", b"", cryptoki_sys::CK_UNAVAILABLE_INFORMATION),
+            Err(Error::NotLoggedIn(UserMode::Administrator))
+        ));
+
+        session.logout().unwrap();
+        session
+            .login(cryptoki_sys::CKU_SO, "password".to_string())
+            .unwrap();
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, handle),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert!(session.login_ctx.can_run_mode(UserMode::Administrator));
+        assert!(session
+            .login_ctx
+            .can_run_mode(UserMode::OperatorOrAdministrator));
+
+        session.logout().unwrap();
+        assert!(session.login_ctx.can_run_mode(UserMode::Operator));
+    }
+
+    #[test]
+    fn test_verify_final_requires_login() {
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let mut key = crate::backend::db::object::from_session_secret("hmac", None, vec![1; 32]);
+        key.set_attr(cryptoki_sys::CKA_PRIVATE, Attribute::Bool(true));
+        let handle = slot.db.lock().unwrap().add_object(key).0;
+
+        let mut session = Session::new(0, Arc::new(slot), 0);
+        session
+            .verify_init(&Mechanism::Hmac(MechDigest::Sha256), handle)
+            .unwrap();
+        session.verify_update(b"data").unwrap();
+
+        session.logout().unwrap();
+        assert!(matches!(
+            session.verify_final(&[0; 32]),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+    }
+
+    #[test]
+    fn test_enum_final() {
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url("https://localhost:8443/api/v1")
+                .operator_username("operator")
+                .operator_password("password")
+                .build()
+                .unwrap(),
+        );
+        slot.db.lock().unwrap().set_fetched_all_keys(true);
+        let mut session = Session::new(0, slot, 0);
+
+        session.enum_init(None).unwrap();
+        // the previous search was not ended
+        assert!(matches!(
+            session.enum_init(None),
+            Err(Error::OperationActive)
+        ));
+
+        session.enum_final().unwrap();
+        assert!(matches!(
+            session.enum_final(),
+            Err(Error::OperationNotInitialized)
+        ));
+
+        session.enum_init(None).unwrap();
+        session.enum_final().unwrap();
+    }
+
+    #[test]
+    fn test_private_object_requires_login() {
+        // the operator password is in the configuration, so the session starts logged in
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url("https://localhost:8443/api/v1")
+                .operator_username("operator")
+                .operator_password("password")
+                .build()
+                .unwrap(),
+        );
+        let (private, public) = {
+            let mut db = slot.db.lock().unwrap();
+            db.set_fetched_all_keys(true);
+            let (private, _) = db.add_object(key_object("private", true));
+            let (public, _) = db.add_object(key_object("public", false));
+            (private, public)
+        };
+
+        let mut session = Session::new(0, slot, 0);
+        assert!(session.is_logged_in());
+
+        session.enum_init(None).unwrap();
+        let mut handles = session.enum_next_chunk(10).unwrap();
+        handles.sort();
+        assert_eq!(handles, vec![private, public]);
+        session.enum_final().unwrap();
+
+        // the key has no mechanism, getting past the login check fails on the mechanism
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, private),
+            Err(Error::InvalidMechanism(..))
+        ));
+
+        session.logout().unwrap();
+        assert!(!session.is_logged_in());
+
+        session.enum_init(None).unwrap();
+        assert_eq!(session.enum_next_chunk(10).unwrap(), vec![public]);
+        session.enum_final().unwrap();
+
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, private),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert!(matches!(
+            session.decrypt_init(&Mechanism::RsaX509, private),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, public),
+            Err(Error::InvalidMechanism(..))
+        ));
+    }
+
+    #[test]
+    fn test_session_state_roundtrip() {
+        let slot = test_slot("test");
+        let mut manager = SessionManager::new();
+        let first = manager.create_session(0, slot.clone(), cryptoki_sys::CKF_SERIAL_SESSION);
+        let second = manager.create_session(
+            0,
+            slot.clone(),
+            cryptoki_sys::CKF_SERIAL_SESSION | cryptoki_sys::CKF_RW_SESSION,
+        );
+        manager
+            .get_session(first)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .digest_init(MechDigest::Sha256)
+            .unwrap();
+
+        let state = manager.export_state(0, &slot);
+        assert!(manager
+            .get_session(first)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .digest_ctx
+            .is_none());
+
+        let path = std::env::temp_dir().join(format!(
+            "p11nethsm-session-state-{}.json",
+            std::process::id()
+        ));
+        state.save(&path).unwrap();
+        let loaded = SessionManagerState::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, state);
+        // the state is read only once
+        assert!(!path.exists());
+        assert!(SessionManagerState::load(&path).unwrap().is_none());
+
+        let mut restored = SessionManager::new();
+        assert_eq!(restored.import_state(loaded, 0, slot), 2);
+        assert_eq!(restored.next_session_handle, manager.next_session_handle);
+
+        let flags = |handle| restored.get_session(handle).unwrap().lock().unwrap().flags;
+        assert_eq!(flags(first), cryptoki_sys::CKF_SERIAL_SESSION);
+        assert_eq!(
+            flags(second),
+            cryptoki_sys::CKF_SERIAL_SESSION | cryptoki_sys::CKF_RW_SESSION
+        );
+    }
+
+    #[test]
+    fn test_session_state_other_slot() {
+        let mut manager = SessionManager::new();
+        manager.create_session(0, test_slot("first"), cryptoki_sys::CKF_SERIAL_SESSION);
+        let state = manager.export_state(0, &test_slot("first"));
+
+        let mut restored = SessionManager::new();
+        assert_eq!(restored.import_state(state, 0, test_slot("second")), 0);
+        assert!(restored.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_enum_private_filter_at_init() {
+        let (url, _) = mock_nethsm(0);
+        // no password: the session starts logged out
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .build()
+                .unwrap(),
+        );
+        let (private, public) = {
+            let mut db = slot.db.lock().unwrap();
+            db.set_fetched_all_keys(true);
+            let (private, _) = db.add_object(key_object("private", true));
+            let (public, _) = db.add_object(key_object("public", false));
+            (private, public)
+        };
+
+        let mut session = Session::new(0, slot, 0);
+        assert!(!session.is_logged_in());
+
+        session.enum_init(None).unwrap();
+        session
+            .login(cryptoki_sys::CKU_USER, "password".to_string())
+            .unwrap();
+        assert!(session.is_logged_in());
+
+        // the search was started logged out, the login doesn't reveal the private object
+        assert_eq!(session.enum_next_chunk(10).unwrap(), vec![public]);
+        session.enum_final().unwrap();
+
+        session.enum_init(None).unwrap();
+        assert_eq!(session.enum_next_chunk(1).unwrap().len(), 1);
+
+        // a search started logged in ends with the login
+        session.logout().unwrap();
+        assert!(matches!(
+            session.enum_next_chunk(10),
+            Err(Error::OperationNotInitialized)
+        ));
+
+        session
+            .login(cryptoki_sys::CKU_USER, "password".to_string())
+            .unwrap();
+        session.enum_init(None).unwrap();
+        let mut handles = session.enum_next_chunk(10).unwrap();
+        handles.sort();
+        assert_eq!(handles, vec![private, public]);
+    }
+
+    // a session object that can be changed
+    fn session_key(id: &str) -> Object {
+        let mut object = key_object(id, false);
+        object.kind = ObjectKind::SecretKey;
+        object.set_attr(CKA_MODIFIABLE, Attribute::Bool(true));
+        object.set_attr(CKA_TRUSTED, Attribute::Bool(false));
+        object
+    }
+
+    fn set_trusted(session: &mut Session, handle: CK_OBJECT_HANDLE) -> Result<(), Error> {
+        let mut value = cryptoki_sys::CK_TRUE;
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_TRUSTED,
+            pValue: &mut value as *mut u8 as cryptoki_sys::CK_VOID_PTR,
+            ulValueLen: 1,
+        }];
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        session.set_attribute_value(handle, &template)
+    }
+
+    #[test]
+    fn test_set_trusted_so_only() {
+        let user = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .build()
+            .unwrap();
+        let (handle, _) = user.db.lock().unwrap().add_object(session_key("user"));
+        let mut session = Session::new(0, Arc::new(user), 0);
+        assert!(matches!(
+            set_trusted(&mut session, handle),
+            Err(Error::AttributeReadOnly(CKA_TRUSTED))
+        ));
+
+        let so = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .administrator_username("admin")
+            .administrator_password("password")
+            .build()
+            .unwrap();
+        let (handle, _) = so.db.lock().unwrap().add_object(session_key("so"));
+        let mut session = Session::new(0, Arc::new(so), 0);
+        set_trusted(&mut session, handle).unwrap();
+        assert_eq!(
+            session
+                .get_object(handle)
+                .unwrap()
+                .get_attribute(CKA_TRUSTED),
+            Some(&Attribute::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_wrap_with_trusted() {
+        let slot = test_slot("wrap");
+        let (key, untrusted, trusted) = {
+            let mut db = slot.db.lock().unwrap();
+            let mut key = session_key("key");
+            key.set_attr(cryptoki_sys::CKA_WRAP_WITH_TRUSTED, Attribute::Bool(true));
+            let (key, _) = db.add_object(key);
+            let mut untrusted = session_key("untrusted");
+            untrusted.set_attr(CKA_WRAP, Attribute::Bool(true));
+            let (untrusted, _) = db.add_object(untrusted);
+            let mut trusted = session_key("trusted");
+            trusted.set_attr(CKA_TRUSTED, Attribute::Bool(true));
+            trusted.set_attr(CKA_WRAP, Attribute::Bool(true));
+            let (trusted, _) = db.add_object(trusted);
+            (key, untrusted, trusted)
+        };
+        let session = Session::new(0, slot, 0);
+
+        assert!(matches!(
+            session.check_wrap(untrusted, key),
+            Err(Error::KeyNotWrappable)
+        ));
+        assert!(session.check_wrap(trusted, key).is_ok());
+        // keys without CKA_WRAP_WITH_TRUSTED can be wrapped with any key
+        assert!(session.check_wrap(untrusted, trusted).is_ok());
+    }
+
+    #[test]
+    fn test_slot_state_path() {
+        assert_eq!(
+            slot_state_path(Path::new("/tmp/sessions.json"), 2),
+            PathBuf::from("/tmp/sessions.json.2")
+        );
+    }
+
+    #[test]
+    fn test_restore_sessions_state() {
+        let path = std::env::temp_dir().join(format!(
+            "p11nethsm-restore-state-{}.json",
+            std::process::id()
+        ));
+        let (url, _) = mock_nethsm(0);
+        let reachable = Arc::new(
+            SlotBuilder::new()
+                .label("reachable")
+                .url(&url)
+                .operator_username("operator")
+                .session_state_path(path.clone())
+                .build()
+                .unwrap(),
+        );
+        // nothing listens on the port 1
+        let unreachable = Arc::new(
+            SlotBuilder::new()
+                .label("unreachable")
+                .url("http://127.0.0.1:1/api/v1")
+                .operator_username("operator")
+                .session_state_path(path.clone())
+                .build()
+                .unwrap(),
+        );
+
+        let handle = 0x7e57_0000;
+        for (slot_id, slot) in [(0, &reachable), (1, &unreachable)] {
+            SessionManagerState {
+                next_session_handle: handle + 2,
+                sessions: vec![SessionState {
+                    handle: handle + slot_id,
+                    slot_id,
+                    slot_label: slot.label.clone(),
+                    flags: cryptoki_sys::CKF_SERIAL_SESSION,
+                }],
+            }
+            .save(&slot_state_path(&path, slot_id))
+            .unwrap();
+        }
+
+        let device = Device {
+            log_file: None,
+            slots: vec![reachable, unreachable],
+            enable_set_attribute_value: false,
+        };
+        restore_sessions_state(&device);
+
+        let mut manager = SESSION_MANAGER.lock().unwrap();
+        assert!(manager.get_session(handle).is_some());
+        assert!(manager.get_session(handle + 1).is_none());
+        manager.delete_session(handle);
+        drop(manager);
+        // both files are removed once read
+        assert!(!slot_state_path(&path, 0).exists());
+        assert!(!slot_state_path(&path, 1).exists());
+    }
+
+    #[test]
+    fn test_session_state_missing_file() {
+        let path = std::env::temp_dir().join("p11nethsm-session-state-missing.json");
+        assert!(SessionManagerState::load(&path).unwrap().is_none());
+    }
+
+    // the paths of the requests received are recorded
+    pub(crate) type Requests = Arc<Mutex<Vec<String>>>;
+
+    pub(crate) fn count_requests(requests: &Requests, path: &str) -> usize {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|p| p.as_str() == path)
+            .count()
+    }
+
+    // Slot on the mock NetHSM with the credentials of both users in the configuration
+    pub(crate) fn mock_slot(key_count: usize) -> (Arc<Slot>, Requests) {
+        let (url, requests) = mock_nethsm(key_count);
+        let slot = SlotBuilder::new()
+            .url(&url)
+            .operator_username("operator")
+            .operator_password("password")
+            .administrator_username("admin")
+            .administrator_password("password")
+            .build()
+            .unwrap();
+        (Arc::new(slot), requests)
+    }
+
+    // Session of SESSION_MANAGER on a slot of the mock NetHSM, for the tests of the API functions
+    pub(crate) fn mock_session(key_count: usize) -> (CK_SESSION_HANDLE, Arc<Slot>, Requests) {
+        let (slot, requests) = mock_slot(key_count);
+        let handle = crate::data::SESSION_MANAGER.lock().unwrap().create_session(
+            0,
+            slot.clone(),
+            cryptoki_sys::CKF_SERIAL_SESSION,
+        );
+        (handle, slot, requests)
+    }
+
+    // Minimal NetHSM answering the requests used to list the keys and to check the operator,
+    // over plain HTTP.
+    // Returns the url of the API and the paths of the requests received.
+    pub(crate) fn mock_nethsm(key_count: usize) -> (String, Requests) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1", listener.local_addr().unwrap());
+        let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let recorded = recorded.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    // skip the headers, the body is only read for the key generation
+                    let mut line = String::new();
+                    let mut content_length = 0;
+                    let mut proxy_authorization = String::new();
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap_or(0);
+                            }
+                            if name.eq_ignore_ascii_case("proxy-authorization") {
+                                proxy_authorization = value.trim().to_string();
+                            }
+                        }
+                        line.clear();
+                    }
+                    let mut body = vec![0; content_length];
+                    let _ = std::io::Read::read_exact(&mut reader, &mut body);
+                    let body = String::from_utf8_lossy(&body);
+
+                    let path = request_line.split(' ').nth(1).unwrap_or_default();
+                    // used as a proxy, the tunnel leads nowhere and the TLS handshake fails
+                    if request_line.starts_with("CONNECT ") {
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", path, proxy_authorization));
+                        let _ = write!(stream, "HTTP/1.1 200 Connection established\r\n\r\n");
+                        return;
+                    }
+                    recorded.lock().unwrap().push(path.to_string());
+                    if path == "/api/v1/health/alive" {
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        );
+                        return;
+                    }
+                    if let Some(user) = path.strip_prefix("/api/v1/users/") {
+                        let body = if user == "admin" {
+                            r#"{"realName":"admin","role":"Administrator"}"#
+                        } else {
+                            r#"{"realName":"operator","role":"Operator"}"#
+                        };
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        return;
+                    }
+
+                    // the generated Ed25519 keys are served by the "/ed" route
+                    if path == "/api/v1/keys/generate" {
+                        let id = if body.contains(r#""type":"Curve25519""#)
+                            && body.contains("EdDSA_Signature")
+                        {
+                            "edgenerated"
+                        } else {
+                            "generated"
+                        };
+                        let body = format!(r#"{{"id":"{}"}}"#, id);
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 201 Created\r\nLocation: /api/v1/keys/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            id,
+                            body.len(),
+                            body
+                        );
+                        return;
+                    }
+
+                    if path == "/api/v1/info" {
+                        let body = r#"{"vendor":"Nitrokey GmbH","product":"NetHSM"}"#;
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        return;
+                    }
+
+                    let (status, body) = match path.strip_prefix("/api/v1/keys") {
+                        Some("") => {
+                            // leave some time to the other sessions to start fetching
+                            std::thread::sleep(Duration::from_millis(50));
+                            let keys: Vec<String> = (0..key_count)
+                                .map(|i| format!(r#"{{"id":"key{}"}}"#, i))
+                                .collect();
+                            ("200 OK", format!("[{}]", keys.join(",")))
+                        }
+                        Some("/invalid/decrypt") | Some("/invalid/encrypt") => (
+                            "400 Bad Request",
+                            r#"{"message":"invalid data"}"#.to_string(),
+                        ),
+                        Some(key) if key.ends_with("/sign") => (
+                            "200 OK",
+                            r#"{"signature":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=="}"#.to_string(),
+                        ),
+                        Some(key) if key.ends_with("/encrypt") => (
+                            "200 OK",
+                            r#"{"encrypted":"AAAAAAAAAAAAAAAAAAAAAA==","iv":"AAAAAAAAAAAAAAAAAAAAAA=="}"#.to_string(),
+                        ),
+                        Some(key) if key.ends_with("/decrypt") => (
+                            "200 OK",
+                            r#"{"decrypted":"AAAAAAAAAAAAAAAAAAAAAA=="}"#.to_string(),
+                        ),
+                        Some("/ed1/cert") => ("200 OK", TEST_CERT.to_string()),
+                        Some(key) if key.starts_with("/oaep") && !key.ends_with("/cert") => (
+                            "200 OK",
+                            format!(
+                                r#"{{"mechanisms":["RSA_Decryption_OAEP_SHA256"],"type":"RSA","public":{{"modulus":"{}","publicExponent":"AQAB"}},"restrictions":{{}},"operations":0}}"#,
+                                // a 256 bytes modulus, all set to 0xff
+                                "/".repeat(340) + "/w=="
+                            ),
+                        ),
+                        Some(key) if key.starts_with("/ed") && !key.ends_with("/cert") => (
+                            "200 OK",
+                            r#"{"mechanisms":["EdDSA_Signature"],"type":"Curve25519","public":{"data":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="},"restrictions":{},"operations":0}"#
+                                .to_string(),
+                        ),
+                        Some("/tagged") => (
+                            "200 OK",
+                            r#"{"mechanisms":["AES_Encryption_CBC","AES_Decryption_CBC"],"type":"Generic","restrictions":{"tags":["prod","team-a"]},"operations":0}"#
+                                .to_string(),
+                        ),
+                        Some(key) if !key.ends_with("/cert") => (
+                            "200 OK",
+                            r#"{"mechanisms":["AES_Encryption_CBC","AES_Decryption_CBC"],"type":"Generic","restrictions":{},"operations":0}"#
+                                .to_string(),
+                        ),
+                        _ => ("404 Not Found", r#"{"message":"not found"}"#.to_string()),
+                    };
+
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                });
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn test_key_usage_count() {
+        let (slot, _) = mock_slot(0);
+        let (handle, invalid) = {
+            let mut db = slot.db.lock().unwrap();
+            let mut key = Object::default();
+            key.kind = ObjectKind::SecretKey;
+            key.mechanisms = vec![nethsm_sdk_rs::models::KeyMechanism::AesDecryptionCbc];
+            key.id = "aes".to_string();
+            let handle = db.add_object(key.clone()).0;
+            key.id = "invalid".to_string();
+            (handle, db.add_object(key).0)
+        };
+
+        let mut session = Session::new(0, slot.clone(), 0);
+        let mechanism = Mechanism::AesCbc(Some([0; 16]));
+        for _ in 0..2 {
+            session.decrypt_init(&mechanism, handle).unwrap();
+            assert_eq!(session.decrypt(&[0; 16]).unwrap(), vec![0; 16]);
+            session.decrypt_clear();
+        }
+        assert_eq!(session.key_usage(handle).count, 2);
+
+        // a failed operation isn't counted
+        session.decrypt_init(&mechanism, invalid).unwrap();
+        assert!(session.decrypt(&[0; 16]).is_err());
+        session.decrypt_clear();
+        assert_eq!(session.key_usage(invalid).count, 0);
+
+        // the count belongs to the session
+        assert_eq!(Session::new(0, slot, 0).key_usage(handle).count, 0);
+    }
+
+    #[test]
+    fn test_decrypt_verify_update() {
+        // HMAC-SHA256 of 32 zero bytes with the key [1; 32]
+        const MAC: [u8; 32] = [
+            197, 1, 217, 165, 117, 216, 43, 183, 117, 11, 65, 198, 56, 228, 118, 53, 36, 133, 92,
+            239, 87, 148, 27, 246, 233, 79, 184, 26, 137, 114, 119, 63,
+        ];
+
+        let (slot, _) = mock_slot(0);
+        let (aes, hmac) = {
+            let mut db = slot.db.lock().unwrap();
+            let mut key = Object::default();
+            key.id = "aes".to_string();
+            key.kind = ObjectKind::SecretKey;
+            key.mechanisms = vec![nethsm_sdk_rs::models::KeyMechanism::AesDecryptionCbc];
+            let aes = db.add_object(key).0;
+            let hmac = crate::backend::db::object::from_session_secret("hmac", None, vec![1; 32]);
+            (aes, db.add_object(hmac).0)
+        };
+        let mut session = Session::new(0, slot, 0);
+
+        session
+            .decrypt_init(&Mechanism::AesCbc(Some([0; 16])), aes)
+            .unwrap();
+        assert!(matches!(
+            session.decrypt_verify_update(&[0; 16]),
+            Err(Error::OperationNotInitialized)
+        ));
+        session
+            .verify_init(&Mechanism::Hmac(MechDigest::Sha256), hmac)
+            .unwrap();
+
+        // the mock decrypts every request to a single zero block
+        assert_eq!(session.decrypt_verify_update_len(10).unwrap(), 0);
+        assert!(session.decrypt_verify_update(&[0; 10]).unwrap().is_empty());
+        assert_eq!(session.decrypt_verify_update_len(6).unwrap(), 16);
+        assert_eq!(session.decrypt_verify_update(&[0; 6]).unwrap(), vec![0; 16]);
+        assert_eq!(
+            session.decrypt_verify_update(&[0; 16]).unwrap(),
+            vec![0; 16]
+        );
+
+        assert!(session.decrypt_final().unwrap().is_empty());
+        session.decrypt_clear();
+        session.verify_final(&MAC).unwrap();
+    }
+
+    #[test]
+    fn test_message_sign() {
+        let (slot, _) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let handle = {
+            let mut key = key_object("ed", false);
+            key.size = Some(256);
+            key.mechanisms = vec![nethsm_sdk_rs::models::KeyMechanism::EdDsaSignature];
+            session.db.lock().unwrap().add_object(key).0
+        };
+
+        assert!(matches!(
+            session.message_sign_next(MechanismParams::None, b"message"),
+            Err(Error::OperationNotInitialized)
+        ));
+
+        session
+            .message_sign_begin(&Mechanism::EdDsa, handle)
+            .unwrap();
+        assert_eq!(session.message_sign_len().unwrap(), 64);
+        for message in [&b"first"[..], b"second", b""] {
+            assert_eq!(
+                session
+                    .message_sign_next(MechanismParams::None, message)
+                    .unwrap()
+                    .len(),
+                64
+            );
+        }
+        assert_eq!(session.key_usage(handle).count, 3);
+
+        // a parameter only fails this message
+        assert!(matches!(
+            session.message_sign_next(MechanismParams::Iv([1; 16]), b"nonce"),
+            Err(Error::MechanismParamInvalid)
+        ));
+        assert!(session
+            .message_sign_next(MechanismParams::None, b"next")
+            .is_ok());
+
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, handle),
+            Err(Error::OperationActive)
+        ));
+        assert!(matches!(
+            session.message_sign_begin(&Mechanism::EdDsa, handle),
+            Err(Error::OperationActive)
+        ));
+
+        session.message_sign_end().unwrap();
+        assert!(matches!(
+            session.message_sign_end(),
+            Err(Error::OperationNotInitialized)
+        ));
+        session.sign_init(&Mechanism::EdDsa, handle).unwrap();
+    }
+
+    #[test]
+    fn test_key_usage_max() {
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .build()
+            .unwrap();
+        let mut session = Session::new(0, Arc::new(slot), 0);
+        let handle = session
+            .db
+            .lock()
+            .unwrap()
+            .add_object(key_object("key", false))
+            .0;
+
+        let max = (2 as CK_ULONG).to_ne_bytes();
+        let mut raw = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_NETHSM_MAX_USAGE_COUNT,
+            pValue: max.as_ptr() as *mut _,
+            ulValueLen: max.len() as CK_ULONG,
+        }];
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(raw.as_mut_ptr(), raw.len()) }.unwrap();
+        session.set_max_usage_count(handle, &template).unwrap();
+        assert_eq!(session.key_usage(handle).max, Some(2));
+
+        session.count_key_usage(Some(handle));
+        assert!(session.check_key_usage(handle).is_ok());
+        session.count_key_usage(Some(handle));
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, handle),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+        assert!(matches!(
+            session.decrypt_init(&Mechanism::AesCbc(None), handle),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+    }
+
+    #[test]
+    fn test_get_attributes_single_fetch() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+
+        let handles = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::SecretKey),
+                id: Some("aes".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap();
+        assert_eq!(handles.len(), 1);
+
+        // all the attributes come from the object built from the first response
+        let attributes = [
+            cryptoki_sys::CKA_CLASS,
+            cryptoki_sys::CKA_KEY_TYPE,
+            cryptoki_sys::CKA_ID,
+            cryptoki_sys::CKA_LABEL,
+            cryptoki_sys::CKA_TOKEN,
+            cryptoki_sys::CKA_PRIVATE,
+            cryptoki_sys::CKA_SENSITIVE,
+            cryptoki_sys::CKA_ENCRYPT,
+            cryptoki_sys::CKA_DECRYPT,
+        ];
+        for _ in 0..2 {
+            let object = session.get_object(handles[0]).unwrap();
+            for attribute in attributes {
+                assert!(
+                    object.get_attribute(attribute).is_some(),
+                    "missing attribute {}",
+                    attribute
+                );
+            }
+        }
+
+        assert_eq!(count_requests(&requests, "/api/v1/keys/aes"), 1);
+    }
+
+    #[test]
+    fn test_fetch_key_after_fetch_all() {
+        let (slot, _) = mock_slot(2);
+        let mut session = Session::new(0, slot.clone(), 0);
+
+        let handles = session.fetch_all_keys(None).unwrap();
+        assert_eq!(handles.len(), 2);
+        let (handle, _) = handles.iter().find(|(_, obj)| obj.id == "key0").unwrap();
+
+        // the key is fetched again, it keeps its entry
+        let fetched = fetch_key("key0", None, session.login_ctx.clone(), slot.db.clone()).unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].0, *handle);
+        assert_eq!(slot.db.lock().unwrap().iter().count(), 2);
+    }
+
+    #[test]
+    fn test_prefetch_on_login() {
+        for prefetch in [true, false] {
+            let (url, requests) = mock_nethsm(3);
+            let slot = Arc::new(
+                SlotBuilder::new()
+                    .url(&url)
+                    .operator_username("operator")
+                    .operator_password("password")
+                    .prefetch_on_login(prefetch)
+                    .build()
+                    .unwrap(),
+            );
+            let mut session = Session::new(0, slot.clone(), 0);
+            session
+                .login(cryptoki_sys::CKU_USER, "password".to_string())
+                .unwrap();
+            if let Some(handle) = slot.prefetch.lock().unwrap().take() {
+                handle.join().unwrap();
+            }
+
+            if !prefetch {
+                assert_eq!(count_requests(&requests, "/api/v1/keys"), 0);
+                continue;
+            }
+            // the keys were listed once in the background, the search uses the database
+            assert_eq!(count_requests(&requests, "/api/v1/keys"), 1);
+            assert_eq!(slot.db.lock().unwrap().iter().count(), 3);
+            assert_eq!(session.fetch_all_keys(None).unwrap().len(), 3);
+
+            // a foreground fetch of one of the keys keeps its entry
+            fetch_key("key0", None, session.login_ctx.clone(), slot.db.clone()).unwrap();
+            assert_eq!(slot.db.lock().unwrap().iter().count(), 3);
+
+            // the next login finds the keys cached
+            session.logout().unwrap();
+            session
+                .login(cryptoki_sys::CKU_USER, "password".to_string())
+                .unwrap();
+            if let Some(handle) = slot.prefetch.lock().unwrap().take() {
+                handle.join().unwrap();
+            }
+            assert_eq!(count_requests(&requests, "/api/v1/keys"), 1);
+        }
+    }
+
+    #[test]
+    fn test_finalize_joins_prefetch() {
+        let (slot, _) = mock_slot(3);
+        let device = Device {
+            log_file: None,
+            slots: vec![slot.clone()],
+            enable_set_attribute_value: false,
+        };
+        let mut manager = SessionManager::new();
+        let handle = manager.create_session(0, slot.clone(), 0);
+        manager
+            .get_session(handle)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .login(cryptoki_sys::CKU_USER, "password".to_string())
+            .unwrap();
+
+        // the database stays empty once the background fetch is done
+        manager.finalize_all(&device);
+        assert!(slot.prefetch.lock().unwrap().is_none());
+        assert_eq!(slot.db.lock().unwrap().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_fetch_all_keys_concurrent() {
+        let (slot, requests) = mock_slot(10);
+
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let slot = slot.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let mut session = Session::new(0, slot, 0);
+                    barrier.wait();
+                    let mut handles: Vec<CK_OBJECT_HANDLE> = session
+                        .fetch_all_keys(None)
+                        .unwrap()
+                        .into_iter()
+                        .map(|(handle, _)| handle)
+                        .collect();
+                    handles.sort();
+                    handles
+                })
+            })
+            .collect();
+
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        // the keys were listed once, and every session got the same handles
+        assert_eq!(count_requests(&requests, "/api/v1/keys"), 1);
+        assert_eq!(results[0].len(), 10);
+        for handles in &results {
+            assert_eq!(handles, &results[0]);
+        }
+        assert_eq!(slot.db.lock().unwrap().iter().count(), 10);
+    }
+
+    #[test]
+    fn test_key_tag_attributes() {
+        let (url, _) = mock_nethsm(0);
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .operator_password("password")
+                .tag_attributes(&["prod", "team-a", "unused"])
+                .build()
+                .unwrap(),
+        );
+        let tag_attributes = slot.db.lock().unwrap().tag_attributes().clone();
+        let prod = tag_attributes.attribute("prod").unwrap();
+        let team = tag_attributes.attribute("team-a").unwrap();
+        let unused = tag_attributes.attribute("unused").unwrap();
+        assert_eq!(prod, 0x80000200);
+        assert_eq!(team, 0x80000201);
+
+        let mut session = Session::new(0, slot, 0);
+        let find = |session: &mut Session, attr_type, value: &str| {
+            let mut id = b"tagged".to_vec();
+            let mut value = value.as_bytes().to_vec();
+            let mut template = [
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: cryptoki_sys::CKA_ID,
+                    pValue: id.as_mut_ptr() as _,
+                    ulValueLen: id.len() as _,
+                },
+                cryptoki_sys::CK_ATTRIBUTE {
+                    type_: attr_type,
+                    pValue: value.as_mut_ptr() as _,
+                    ulValueLen: value.len() as _,
+                },
+            ];
+            let template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 2) }.unwrap();
+            session.enum_init(Some(template)).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+
+        let handles = find(&mut session, prod, "prod");
+        assert_eq!(handles.len(), 1);
+        let object = session.get_object(handles[0]).unwrap();
+        assert_eq!(
+            object.get_attribute(prod),
+            Some(&Attribute::Bytes(b"prod".to_vec()))
+        );
+        assert_eq!(
+            object.get_attribute(team),
+            Some(&Attribute::Bytes(b"team-a".to_vec()))
+        );
+        assert_eq!(object.get_attribute(unused), None);
+
+        assert!(find(&mut session, unused, "unused").is_empty());
+        assert!(find(&mut session, team, "prod").is_empty());
+    }
+
+    // runs f with the template made of the attributes
+    fn with_template<R>(
+        attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)],
+        f: impl FnOnce(CkRawAttrTemplate) -> R,
+    ) -> R {
+        let mut values: Vec<Vec<u8>> = attrs.iter().map(|(_, value)| value.clone()).collect();
+        let mut raw: Vec<cryptoki_sys::CK_ATTRIBUTE> = attrs
+            .iter()
+            .zip(values.iter_mut())
+            .map(|((type_, _), value)| cryptoki_sys::CK_ATTRIBUTE {
+                type_: *type_,
+                pValue: value.as_mut_ptr() as _,
+                ulValueLen: value.len() as _,
+            })
+            .collect();
+        f(unsafe { CkRawAttrTemplate::from_raw_ptr(raw.as_mut_ptr(), raw.len()) }.unwrap())
+    }
+
+    #[test]
+    fn test_data_object_application() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+
+        let class = cryptoki_sys::CKO_DATA.to_ne_bytes().to_vec();
+        let create = |session: &mut Session, application: Option<&str>| {
+            let mut attrs = vec![
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (CKA_LABEL, b"config".to_vec()),
+                (CKA_VALUE, b"data".to_vec()),
+            ];
+            if let Some(application) = application {
+                attrs.push((
+                    cryptoki_sys::CKA_APPLICATION,
+                    application.as_bytes().to_vec(),
+                ));
+            }
+            with_template(&attrs, |template| session.create_object(template)).unwrap()[0].0
+        };
+        let find = |session: &mut Session, application: &str| {
+            let attrs = [
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (
+                    cryptoki_sys::CKA_APPLICATION,
+                    application.as_bytes().to_vec(),
+                ),
+            ];
+            with_template(&attrs, |template| session.enum_init(Some(template))).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+        let application = |session: &Session, handle| {
+            session
+                .get_object(handle)
+                .unwrap()
+                .get_attribute(cryptoki_sys::CKA_APPLICATION)
+                .cloned()
+        };
+
+        let a = create(&mut session, Some("app-a"));
+        let b = create(&mut session, Some("app-b"));
+        let none = create(&mut session, None);
+        assert_ne!(a, b);
+        assert_eq!(find(&mut session, "app-a"), vec![a]);
+        assert_eq!(find(&mut session, "app-b"), vec![b]);
+        assert_eq!(application(&session, none), Some(Attribute::Bytes(vec![])));
+
+        // data objects have no CKA_ID, it isn't compared with CKA_LABEL
+        let mut find_by = |attr_type, value: &[u8]| {
+            let attrs = [
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (attr_type, value.to_vec()),
+            ];
+            with_template(&attrs, |template| session.enum_init(Some(template))).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+        assert!(find_by(cryptoki_sys::CKA_ID, b"config").is_empty());
+        let mut labelled = find_by(CKA_LABEL, b"config");
+        labelled.sort();
+        assert_eq!(labelled, vec![a, b, none]);
+
+        // CKA_APPLICATION can be changed
+        with_template(
+            &[(cryptoki_sys::CKA_APPLICATION, b"app-c".to_vec())],
+            |template| session.set_attribute_value(b, &template),
         )
+        .unwrap();
+        assert!(find(&mut session, "app-b").is_empty());
+        assert_eq!(find(&mut session, "app-c"), vec![b]);
+
+        // the NetHSM can't keep them
+        let attrs = [
+            (cryptoki_sys::CKA_CLASS, class.clone()),
+            (cryptoki_sys::CKA_TOKEN, vec![cryptoki_sys::CK_TRUE]),
+        ];
+        assert!(matches!(
+            with_template(&attrs, |template| session.create_object(template)),
+            Err(Error::TemplateInconsistent(cryptoki_sys::CKA_TOKEN))
+        ));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_objects_of_other_sessions() {
+        let (slot, _) = mock_slot(0);
+        let mut manager = SessionManager::new();
+        let owner = manager.create_session(0, slot.clone(), 0);
+        let other = manager.create_session(0, slot.clone(), 0);
+        let session = |handle| manager.get_session(handle).unwrap();
+
+        let class = cryptoki_sys::CKO_DATA.to_ne_bytes().to_vec();
+        let attrs = [
+            (cryptoki_sys::CKA_CLASS, class.clone()),
+            (CKA_LABEL, b"owned".to_vec()),
+        ];
+        let handle = with_template(&attrs, |template| {
+            session(owner).lock().unwrap().create_object(template)
+        })
+        .unwrap()[0]
+            .0;
+        let find = |session: &mut Session| {
+            with_template(&attrs, |template| session.enum_init(Some(template))).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+        assert_eq!(find(&mut session(owner).lock().unwrap()), vec![handle]);
+        assert!(find(&mut session(other).lock().unwrap()).is_empty());
+        assert!(matches!(
+            session(other).lock().unwrap().get_object(handle),
+            Err(Error::InvalidObjectHandle(_))
+        ));
+
+        // the object is destroyed with its session
+        manager.delete_session(owner);
+        assert!(slot.db.lock().unwrap().object(handle).is_none());
+    }
+
+    #[test]
+    fn test_create_public_key() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let class = cryptoki_sys::CKO_PUBLIC_KEY.to_ne_bytes().to_vec();
+        let key_type = |key_type: cryptoki_sys::CK_KEY_TYPE| key_type.to_ne_bytes().to_vec();
+        let attribute = |session: &Session, handle, attr_type| {
+            session
+                .get_object(handle)
+                .unwrap()
+                .get_attribute(attr_type)
+                .cloned()
+        };
+
+        let modulus = vec![0xff; 256];
+        let rsa = with_template(
+            &[
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (cryptoki_sys::CKA_KEY_TYPE, key_type(cryptoki_sys::CKK_RSA)),
+                (CKA_LABEL, b"peer".to_vec()),
+                (cryptoki_sys::CKA_ID, b"peer-id".to_vec()),
+                (cryptoki_sys::CKA_MODULUS, modulus.clone()),
+                (cryptoki_sys::CKA_PUBLIC_EXPONENT, vec![0x01, 0x00, 0x01]),
+            ],
+            |template| session.create_object(template),
+        )
+        .unwrap()[0]
+            .0;
+        assert_eq!(
+            attribute(&session, rsa, cryptoki_sys::CKA_MODULUS),
+            Some(Attribute::Bytes(modulus))
+        );
+        assert_eq!(
+            attribute(&session, rsa, CKA_LABEL),
+            Some(Attribute::Bytes(b"peer".to_vec()))
+        );
+        assert_eq!(
+            attribute(&session, rsa, cryptoki_sys::CKA_TOKEN),
+            Some(Attribute::Bool(false))
+        );
+
+        // the module encrypts with it
+        session
+            .encrypt_init(&Mechanism::RsaPkcs(None), rsa)
+            .unwrap();
+        assert_eq!(session.encrypt(b"secret").unwrap().len(), 256);
+
+        // it is found by its CKA_ID and CKA_LABEL, not by the generated ID
+        let mut find = |attr_type, value: &[u8]| {
+            let attrs = [
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (cryptoki_sys::CKA_TOKEN, vec![cryptoki_sys::CK_FALSE]),
+                (attr_type, value.to_vec()),
+            ];
+            with_template(&attrs, |template| session.enum_init(Some(template))).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+        assert_eq!(find(cryptoki_sys::CKA_ID, b"peer-id"), vec![rsa]);
+        assert_eq!(find(CKA_LABEL, b"peer"), vec![rsa]);
+        assert!(find(cryptoki_sys::CKA_ID, b"peer").is_empty());
+        assert!(find(CKA_LABEL, b"peer-id").is_empty());
+
+        // CKA_EC_POINT is kept in its DER encoding
+        let mut point = vec![0x04, 0x41, 0x04];
+        point.extend_from_slice(&[0x11; 64]);
+        let ec = with_template(
+            &[
+                (cryptoki_sys::CKA_CLASS, class.clone()),
+                (cryptoki_sys::CKA_KEY_TYPE, key_type(cryptoki_sys::CKK_EC)),
+                (
+                    cryptoki_sys::CKA_EC_PARAMS,
+                    vec![0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07],
+                ),
+                (cryptoki_sys::CKA_EC_POINT, point.clone()),
+            ],
+            |template| session.create_object(template),
+        )
+        .unwrap()[0]
+            .0;
+        assert_eq!(
+            attribute(&session, ec, cryptoki_sys::CKA_EC_POINT),
+            Some(Attribute::Bytes(point))
+        );
+
+        assert!(matches!(
+            with_template(
+                &[
+                    (cryptoki_sys::CKA_CLASS, class.clone()),
+                    (cryptoki_sys::CKA_KEY_TYPE, key_type(cryptoki_sys::CKK_RSA)),
+                    (cryptoki_sys::CKA_PUBLIC_EXPONENT, vec![0x01, 0x00, 0x01]),
+                ],
+                |template| session.create_object(template),
+            ),
+            Err(Error::MissingAttribute(cryptoki_sys::CKA_MODULUS))
+        ));
+        assert!(matches!(
+            with_template(
+                &[
+                    (cryptoki_sys::CKA_CLASS, class.clone()),
+                    (cryptoki_sys::CKA_KEY_TYPE, key_type(cryptoki_sys::CKK_EC)),
+                    (cryptoki_sys::CKA_EC_PARAMS, vec![0x06, 0x01, 0x00]),
+                    (cryptoki_sys::CKA_EC_POINT, vec![0x04, 0x00]),
+                ],
+                |template| session.create_object(template),
+            ),
+            Err(Error::InvalidAttribute(cryptoki_sys::CKA_EC_PARAMS))
+        ));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sign_with_evicted_key() {
+        let (url, requests) = mock_nethsm(0);
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .operator_password("password")
+                .cache_capacity(2)
+                .build()
+                .unwrap(),
+        );
+        let mut session = Session::new(0, slot.clone(), 0);
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                session
+                    .find_key(KeyRequirements {
+                        kind: Some(ObjectKind::PrivateKey),
+                        id: Some(format!("ed{}", i)),
+                        raw_id: None,
+                        label: None,
+                        token: None,
+                    })
+                    .unwrap()[0]
+            })
+            .collect();
+        assert!(slot.db.lock().unwrap().iter().count() <= 2);
+        assert!(slot.db.lock().unwrap().evicted(handles[0]).is_some());
+
+        // the first key is fetched again and keeps its handle
+        session.sign_init(&Mechanism::EdDsa, handles[0]).unwrap();
+        assert_eq!(session.sign(&[0; 32]).unwrap().len(), 64);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed0"), 2);
+        assert_eq!(
+            session.get_object(handles[0]).unwrap().id,
+            "ed0".to_string()
+        );
+        assert!(slot.db.lock().unwrap().iter().count() <= 2);
+    }
+
+    #[test]
+    fn test_find_all_with_evicted_keys() {
+        let (url, requests) = mock_nethsm(4);
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .operator_password("password")
+                .cache_capacity(2)
+                .build()
+                .unwrap(),
+        );
+        let mut session = Session::new(0, slot.clone(), 0);
+        let find_all = |session: &mut Session| {
+            let mut handles = session
+                .find_key(KeyRequirements {
+                    kind: None,
+                    id: None,
+                    raw_id: None,
+                    label: None,
+                    token: None,
+                })
+                .unwrap();
+            handles.sort();
+            handles
+        };
+
+        let handles = find_all(&mut session);
+        assert_eq!(handles.len(), 4);
+        assert_eq!(slot.db.lock().unwrap().evicted_handles(None).len(), 2);
+
+        // the keys aren't listed again because some of them were evicted
+        assert_eq!(find_all(&mut session), handles);
+        assert_eq!(count_requests(&requests, "/api/v1/keys"), 1);
+    }
+
+    #[test]
+    fn test_evicted_key_fetch_error() {
+        let (url, _) = mock_nethsm(0);
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .operator_password("password")
+                .cache_capacity(1)
+                .build()
+                .unwrap(),
+        );
+        let session = Session::new(0, slot.clone(), 0);
+
+        let mut db = slot.db.lock().unwrap();
+        let mut key = Object::default();
+        key.id = "evicted".to_string();
+        key.kind = ObjectKind::SecretKey;
+        key.set_attr(cryptoki_sys::CKA_TOKEN, Attribute::Bool(true));
+        let (handle, _) = db.add_object(key.clone());
+        key.id = "other".to_string();
+        db.add_object(key);
+        drop(db);
+
+        // the error of the NetHSM is returned, the handle is still valid
+        let logged_out = Session {
+            login_ctx: LoginCtx::new(None, None, vec![], None),
+            ..session
+        };
+        assert!(matches!(
+            logged_out.get_object(handle),
+            Err(Error::NotLoggedIn(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_final_retry_uses_pending_output() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::PrivateKey),
+                id: Some("ed0".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+
+        session.sign_init(&Mechanism::EdDsa, handle).unwrap();
+        session.sign_update(b"message").unwrap();
+        let signature = session.sign_final().unwrap();
+        assert_eq!(session.sign_theoretical_size().unwrap(), 64);
+
+        // the signature is kept until the operation ends, the NetHSM is asked once
+        assert_eq!(session.sign_final().unwrap(), signature);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed0/sign"), 1);
+
+        session.sign_clear();
+        assert!(matches!(
+            session.sign_final(),
+            Err(Error::OperationNotInitialized)
+        ));
+    }
+
+    #[test]
+    fn test_sign_retry_uses_pending_output() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::PrivateKey),
+                id: Some("ed0".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+
+        // C_Sign called again after CKR_BUFFER_TOO_SMALL signs and counts the key once
+        session.sign_init(&Mechanism::EdDsa, handle).unwrap();
+        let signature = session.sign(b"message").unwrap();
+        assert_eq!(session.sign(b"message").unwrap(), signature);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed0/sign"), 1);
+        assert_eq!(session.key_usage[&handle].count, 1);
+        session.sign_clear();
+    }
+
+    #[test]
+    fn test_sign_always_authenticate() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot.clone(), 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::PrivateKey),
+                id: Some("ed0".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+        slot.db
+            .lock()
+            .unwrap()
+            .object_mut(handle)
+            .unwrap()
+            .set_attr(cryptoki_sys::CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(true));
+
+        // without an operation there is nothing to authenticate
+        assert!(matches!(
+            session.login(CKU_CONTEXT_SPECIFIC, "password".to_string()),
+            Err(Error::OperationNotInitialized)
+        ));
+
+        session.sign_init(&Mechanism::EdDsa, handle).unwrap();
+        assert!(matches!(
+            session.sign(b"message"),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed0/sign"), 0);
+
+        session
+            .login(CKU_CONTEXT_SPECIFIC, "password".to_string())
+            .unwrap();
+        assert!(count_requests(&requests, "/api/v1/users/operator") > 0);
+        session.sign_update(b"message").unwrap();
+        session.sign_final().unwrap();
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed0/sign"), 1);
+        assert!(!session.sign_ctx.as_ref().unwrap().context_logged_in);
+        session.sign_clear();
+
+        // the next operation needs a new context specific login
+        session.sign_init(&Mechanism::EdDsa, handle).unwrap();
+        session.sign_update(b"message").unwrap();
+        assert!(matches!(
+            session.sign_final(),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert!(session.is_logged_in());
+    }
+
+    #[test]
+    fn test_decrypt_always_authenticate() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot.clone(), 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::PrivateKey),
+                id: Some("oaep".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+        slot.db
+            .lock()
+            .unwrap()
+            .object_mut(handle)
+            .unwrap()
+            .set_attr(cryptoki_sys::CKA_ALWAYS_AUTHENTICATE, Attribute::Bool(true));
+        let oaep = Mechanism::RsaPkcsOaep(MechDigest::Sha256);
+
+        session.decrypt_init(&oaep, handle).unwrap();
+        assert!(matches!(
+            session.decrypt(&[0; 256]),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+        assert_eq!(count_requests(&requests, "/api/v1/keys/oaep/decrypt"), 0);
+        session.decrypt_clear();
+
+        session.decrypt_init(&oaep, handle).unwrap();
+        session
+            .login(CKU_CONTEXT_SPECIFIC, "password".to_string())
+            .unwrap();
+        session.decrypt_update(&[0; 256]).unwrap();
+        session.decrypt_final().unwrap();
+        assert_eq!(count_requests(&requests, "/api/v1/keys/oaep/decrypt"), 1);
+        assert!(!session.decrypt_ctx.as_ref().unwrap().context_logged_in);
+        session.decrypt_clear();
+
+        // the next operation needs a new context specific login
+        session.decrypt_init(&oaep, handle).unwrap();
+        assert!(matches!(
+            session.decrypt(&[0; 256]),
+            Err(Error::NotLoggedIn(UserMode::Operator))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_final_retry_uses_pending_output() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::SecretKey),
+                id: Some("aes".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+        let mechanism = Mechanism::AesCbc(Some([0; 16]));
+
+        session.encrypt_init(&mechanism, handle).unwrap();
+        let encrypted = session.encrypt(&[0; 16]).unwrap();
+        assert_eq!(session.encrypt(&[0; 16]).unwrap(), encrypted);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/aes/encrypt"), 1);
+        session.encrypt_clear();
+
+        // the partial block left is refused without calling the NetHSM
+        session.encrypt_init(&mechanism, handle).unwrap();
+        session.encrypt_update(&[0; 5]).unwrap();
+        assert!(matches!(
+            session.encrypt_final(),
+            Err(Error::InvalidDataLength)
+        ));
+        assert_eq!(count_requests(&requests, "/api/v1/keys/aes/encrypt"), 1);
+        session.encrypt_clear();
+
+        session.decrypt_init(&mechanism, handle).unwrap();
+        assert!(session.decrypt_update(&[0; 16]).unwrap().is_empty());
+        let decrypted = session.decrypt_final().unwrap();
+        assert_eq!(session.decrypt_theoretical_final_size().unwrap(), 16);
+        // the ciphertext was consumed by the first call, the retry doesn't need it
+        assert_eq!(session.decrypt_final().unwrap(), decrypted);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/aes/decrypt"), 1);
+        session.decrypt_clear();
+        assert!(matches!(
+            session.decrypt_final(),
+            Err(Error::OperationNotInitialized)
+        ));
+    }
+
+    #[test]
+    fn test_sign_verify_aes_cmac() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let handle = session
+            .find_key(KeyRequirements {
+                kind: Some(ObjectKind::SecretKey),
+                id: Some("aes".to_string()),
+                raw_id: None,
+                label: None,
+                token: None,
+            })
+            .unwrap()[0];
+
+        // the subkeys and the MAC are two CBC encryptions on the NetHSM
+        session.sign_init(&Mechanism::AesCmac, handle).unwrap();
+        assert_eq!(session.sign_theoretical_size().unwrap(), 16);
+        session.sign_update(b"message").unwrap();
+        let mac = session.sign_final().unwrap();
+        assert_eq!(mac.len(), 16);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/aes/encrypt"), 2);
+        session.sign_clear();
+
+        session.verify_init(&Mechanism::AesCmac, handle).unwrap();
+        session.verify_update(b"message").unwrap();
+        assert!(session.verify_final(&mac).is_ok());
+        session.verify_clear();
+
+        // the HMAC secrets of the module are neither signing nor AES-CMAC keys
+        let secret = session
+            .db
+            .lock()
+            .unwrap()
+            .add_object(crate::backend::db::object::from_session_secret(
+                "cmac",
+                None,
+                vec![0; 16],
+            ))
+            .0;
+        assert!(matches!(
+            session.sign_init(&Mechanism::AesCmac, secret),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+        assert!(matches!(
+            session.verify_init(&Mechanism::AesCmac, secret),
+            Err(Error::InvalidMechanism(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_rsa_key_sensitivity() {
+        let (slot, _) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+        let key = |session: &mut Session, kind| {
+            let handle = session
+                .find_key(KeyRequirements {
+                    kind: Some(kind),
+                    id: Some("oaep".to_string()),
+                    raw_id: None,
+                    label: None,
+                    token: None,
+                })
+                .unwrap()[0];
+            session.get_object(handle).unwrap()
+        };
+
+        let private_key = key(&mut session, ObjectKind::PrivateKey);
+        let public_key = key(&mut session, ObjectKind::PublicKey);
+        for (attr_type, private, public) in [
+            (cryptoki_sys::CKA_SENSITIVE, true, false),
+            (cryptoki_sys::CKA_ALWAYS_SENSITIVE, true, false),
+            (cryptoki_sys::CKA_EXTRACTABLE, false, true),
+            (cryptoki_sys::CKA_NEVER_EXTRACTABLE, true, false),
+        ] {
+            assert_eq!(
+                private_key.get_attribute(attr_type),
+                Some(&Attribute::Bool(private))
+            );
+            assert_eq!(
+                public_key.get_attribute(attr_type),
+                Some(&Attribute::Bool(public))
+            );
+        }
+    }
+
+    #[test]
+    fn test_sign_strict_digestinfo() {
+        let (url, _) = mock_nethsm(0);
+        let slot = Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .operator_password("password")
+                .strict_digestinfo_validation(true)
+                .build()
+                .unwrap(),
+        );
+        let handle = {
+            let mut key = Object::default();
+            key.id = "rsa".to_string();
+            key.kind = ObjectKind::PrivateKey;
+            key.size = Some(256);
+            key.mechanisms = vec![nethsm_sdk_rs::models::KeyMechanism::RsaSignaturePkcs1];
+            slot.db.lock().unwrap().add_object(key).0
+        };
+
+        let mut session = Session::new(0, slot, 0);
+        session
+            .sign_init(&Mechanism::RsaPkcs(None), handle)
+            .unwrap();
+        assert!(matches!(session.sign(&[0x42; 32]), Err(Error::InvalidData)));
+        // C_Sign ends the operation on an error
+        session.sign_clear();
+
+        session
+            .sign_init(&Mechanism::RsaPkcs(None), handle)
+            .unwrap();
+        let mut digest_info = MechDigest::Sha256.digest_info_prefix().to_vec();
+        digest_info.extend([0x42; 32]);
+        assert!(session.sign(&digest_info).is_ok());
+    }
+
+    const TEST_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBczCCARmgAwIBAgIUYIU75dS5q8lUwigpTQ597x/Ado0wCgYIKoZIzj0EAwIw
+DjEMMAoGA1UEAwwDZWQxMCAXDTI2MTAxNDA1MDEyNVoYDzIxMjYwOTIwMDUwMTI1
+WjAOMQwwCgYDVQQDDANlZDEwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATHf5BL
+75G7a9WptYj5zavixBBcvk8TrgZncfZBSz1Al1iBPjzwWnon+PeYX+4WyGDRh67/
+E+1JXvHc5GssgObMo1MwUTAdBgNVHQ4EFgQULIEkU/z2Yu2zJWHiGcR2vpr4rJYw
+HwYDVR0jBBgwFoAULIEkU/z2Yu2zJWHiGcR2vpr4rJYwDwYDVR0TAQH/BAUwAwEB
+/zAKBggqhkjOPQQDAgNIADBFAiEApOfEumSk8KJrFP9u2B1IUG0g7gaV2iPrQwho
+fxkYE9wCIBcZQzqdDZtbu1Vgzd5DE0V7qS0dFg9Uv6NdGvug3tul
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_private_key_subject() {
+        let slot = Arc::new(SlotBuilder::new().build().unwrap());
+        let add_key = |id: &str| {
+            let mut key = Object::default();
+            key.id = id.to_string();
+            key.kind = ObjectKind::PrivateKey;
+            key.set_attr(CKA_ID, Attribute::Bytes(id.as_bytes().to_vec()));
+            slot.db.lock().unwrap().add_object(key).0
+        };
+        let key = add_key("ed1");
+        let other = add_key("ed2");
+        let cert =
+            crate::backend::db::object::from_cert_data(TEST_CERT.as_bytes().to_vec(), "ed1", None)
+                .unwrap();
+        let subject = cert.get_attribute(CKA_SUBJECT).cloned().unwrap();
+        slot.db.lock().unwrap().add_object(cert);
+
+        let session = Session::new(0, slot.clone(), 0);
+        assert_eq!(session.certificate_subject(key), Some(subject.clone()));
+        assert_eq!(session.certificate_subject(other), None);
+
+        // the subject is kept on the key
+        let db = slot.db.lock().unwrap();
+        assert_eq!(
+            db.object(key).unwrap().get_attribute(CKA_SUBJECT),
+            Some(&subject)
+        );
+        assert_eq!(db.object(other).unwrap().get_attribute(CKA_SUBJECT), None);
+    }
+
+    #[test]
+    fn test_private_key_subject_fetched() {
+        let (slot, requests) = mock_slot(0);
+        let add_key = |id: &str| {
+            let mut key = Object::default();
+            key.id = id.to_string();
+            key.kind = ObjectKind::PrivateKey;
+            key.set_attr(CKA_ID, Attribute::Bytes(id.as_bytes().to_vec()));
+            key.set_attr(CKA_TOKEN, Attribute::Bool(true));
+            slot.db.lock().unwrap().add_object(key).0
+        };
+        let key = add_key("ed1");
+        let other = add_key("ed2");
+
+        let session = Session::new(0, slot.clone(), 0);
+        let subject = session.certificate_subject(key).unwrap();
+        assert_eq!(session.certificate_subject(other), None);
+
+        // the certificate is cached with the subject
+        assert_eq!(session.certificate_subject(key), Some(subject));
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed1/cert"), 1);
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed2/cert"), 1);
+    }
+
+    #[test]
+    fn test_import_pem_key_id() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot, 0);
+
+        // the key ID is the CKA_ID, the PEM has no label
+        let handle = session
+            .import_pem_key(
+                crate::backend::pkcs8::tests::ED25519_PEM.as_bytes(),
+                b"ed1",
+                cryptoki_sys::CK_UNAVAILABLE_INFORMATION,
+            )
+            .unwrap();
+        let db = session.db.lock().unwrap();
+        let key = db.object(handle).unwrap();
+        assert_eq!(key.id, "ed1");
+        assert_eq!(
+            key.get_attribute(CKA_ID),
+            Some(&Attribute::Bytes(b"ed1".to_vec()))
+        );
+        // the import and the fetch of the key
+        assert_eq!(count_requests(&requests, "/api/v1/keys/ed1"), 2);
+    }
+
+    #[test]
+    fn test_certificate_object_id() {
+        let slot = Arc::new(SlotBuilder::new().build().unwrap());
+        let add_cert = |id: &str, object_id: Option<&[u8]>| {
+            let mut cert =
+                crate::backend::db::object::from_cert_data(TEST_CERT.as_bytes().to_vec(), id, None)
+                    .unwrap();
+            if let Some(object_id) = object_id {
+                cert.set_attr(CKA_OBJECT_ID, Attribute::Bytes(object_id.to_vec()));
+            }
+            slot.db.lock().unwrap().add_object(cert).0
+        };
+        let first_id = [0x06, 0x03, 0x2a, 0x03, 0x04];
+        let second_id = [0x06, 0x03, 0x2a, 0x03, 0x05];
+        slot.db.lock().unwrap().set_fetched_all_keys(true);
+        let first = add_cert("cert1", Some(&first_id));
+        let second = add_cert("cert2", Some(&second_id));
+
+        let mut session = Session::new(0, slot.clone(), 0);
+        let mut find = |object_id: &[u8]| {
+            let mut object_id = object_id.to_vec();
+            let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+                type_: CKA_OBJECT_ID,
+                pValue: object_id.as_mut_ptr() as _,
+                ulValueLen: object_id.len() as _,
+            }];
+            let template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+            session.enum_init(Some(template)).unwrap();
+            session.enum_ctx.take().unwrap().handles
+        };
+        assert_eq!(find(&first_id), vec![first]);
+        assert_eq!(find(&second_id), vec![second]);
+        // the match is on the whole value
+        assert!(find(&first_id[..4]).is_empty());
+
+        // the certificate fetched again keeps its object ID
+        assert_eq!(add_cert("cert1", None), first);
+        assert_eq!(
+            session
+                .get_object(first)
+                .unwrap()
+                .get_attribute(CKA_OBJECT_ID),
+            Some(&Attribute::Bytes(first_id.to_vec()))
+        );
+
+        // it can be changed even if the certificate can't
+        let mut new_id = [0x06, 0x03, 0x2a, 0x03, 0x06];
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_OBJECT_ID,
+            pValue: new_id.as_mut_ptr() as _,
+            ulValueLen: new_id.len() as _,
+        }];
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        // the certificate is a token object, the session must be read-write
+        assert!(matches!(
+            session.set_attribute_value(second, &template),
+            Err(Error::SessionReadOnly)
+        ));
+        session.flags = cryptoki_sys::CKF_SERIAL_SESSION | cryptoki_sys::CKF_RW_SESSION;
+        session.set_attribute_value(second, &template).unwrap();
+        assert_eq!(
+            session
+                .get_object(second)
+                .unwrap()
+                .get_attribute(CKA_OBJECT_ID),
+            Some(&Attribute::Bytes(new_id.to_vec()))
+        );
+
+        let mut label = b"label".to_vec();
+        let mut template = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: cryptoki_sys::CKA_LABEL,
+            pValue: label.as_mut_ptr() as _,
+            ulValueLen: label.len() as _,
+        }];
+        let template =
+            unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), 1) }.unwrap();
+        assert!(matches!(
+            session.set_attribute_value(second, &template),
+            Err(Error::AttributeReadOnly(cryptoki_sys::CKA_LABEL))
+        ));
+    }
+
+    #[test]
+    fn test_rsa_pkcs_encrypt_decrypt() {
+        let (url, requests) = mock_nethsm(0);
+        for disabled in [false, true] {
+            let slot = Arc::new(
+                SlotBuilder::new()
+                    .url(&url)
+                    .operator_username("operator")
+                    .operator_password("password")
+                    .disable_rsa_pkcs_decrypt(disabled)
+                    .build()
+                    .unwrap(),
+            );
+            let mut session = Session::new(0, slot.clone(), 0);
+            let objects =
+                fetch_key("oaep", None, session.login_ctx.clone(), slot.db.clone()).unwrap();
+            let key = |kind| objects.iter().find(|(_, o)| o.kind == kind).unwrap().0;
+            let (private, public) = (key(ObjectKind::PrivateKey), key(ObjectKind::PublicKey));
+
+            // encrypted by the module with the public key, the NetHSM isn't contacted
+            let sent = requests.lock().unwrap().len();
+            session
+                .encrypt_init(&Mechanism::RsaPkcs(None), public)
+                .unwrap();
+            assert_eq!(session.encrypt_theoretical_size(5).unwrap(), 256);
+            let ciphertext = session.encrypt(b"hello").unwrap();
+            assert_eq!(ciphertext.len(), 256);
+            session.encrypt_clear();
+            assert_eq!(requests.lock().unwrap().len(), sent);
+
+            // the key of the mock only has OAEP
+            let result = session.decrypt_init(&Mechanism::RsaPkcs(None), private);
+            if disabled {
+                assert!(matches!(result, Err(Error::MechanismDisabled(_))));
+            } else {
+                assert!(matches!(result, Err(Error::InvalidMechanism(..))));
+            }
+            // OAEP isn't affected
+            session
+                .decrypt_init(&Mechanism::RsaPkcsOaep(MechDigest::Sha256), private)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_init_key_type_inconsistent() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot.clone(), 0);
+        let key = |id: &str, kind: ObjectKind| {
+            fetch_key(id, None, session.login_ctx.clone(), slot.db.clone())
+                .unwrap()
+                .into_iter()
+                .find(|(_, object)| object.kind == kind)
+                .unwrap()
+                .0
+        };
+        let rsa = key("oaep", ObjectKind::PrivateKey);
+        let ed = key("ed", ObjectKind::PrivateKey);
+        let aes = key("key0", ObjectKind::SecretKey);
+        let sent = requests.lock().unwrap().len();
+
+        // RSA key with an AES mechanism
+        assert!(matches!(
+            session.encrypt_init(&Mechanism::AesCbc(None), rsa),
+            Err(Error::KeyTypeInconsistent(
+                cryptoki_sys::CKM_AES_CBC,
+                cryptoki_sys::CKK_RSA
+            ))
+        ));
+        assert!(matches!(
+            session.decrypt_init(&Mechanism::AesCbc(None), rsa),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        // AES key with an RSA mechanism
+        assert!(matches!(
+            session.decrypt_init(&Mechanism::RsaPkcsOaep(MechDigest::Sha256), aes),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(matches!(
+            session.sign_init(&Mechanism::RsaPkcs(None), aes),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        // EC keys with an RSA mechanism or the wrong curve family
+        assert!(matches!(
+            session.sign_init(&Mechanism::RsaPkcs(None), ed),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(matches!(
+            session.sign_init(&Mechanism::Ecdsa(None), ed),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(matches!(
+            session.message_sign_begin(&Mechanism::Ecdsa(None), ed),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        // RSA key with an EC or HMAC mechanism
+        assert!(matches!(
+            session.sign_init(&Mechanism::EdDsa, rsa),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(matches!(
+            session.verify_init(&Mechanism::Hmac(MechDigest::Sha256), rsa),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(matches!(
+            session.verify_recover_init(&Mechanism::RsaPkcs(None), ed),
+            Err(Error::KeyTypeInconsistent(..))
+        ));
+        assert!(session.sign_ctx.is_none());
+        assert!(session.encrypt_ctx.is_none());
+        assert!(session.decrypt_ctx.is_none());
+        assert!(session.verify_ctx.is_none());
+
+        // the NetHSM isn't asked
+        assert_eq!(requests.lock().unwrap().len(), sent);
+
+        session.sign_init(&Mechanism::EdDsa, ed).unwrap();
+    }
+
+    #[test]
+    fn test_enum_token_filter() {
+        let (slot, requests) = mock_slot(2);
+        let secret = slot
+            .db
+            .lock()
+            .unwrap()
+            .add_object(crate::backend::db::object::from_session_secret(
+                "secret",
+                None,
+                vec![1; 16],
+            ))
+            .0;
+        let mut session = Session::new(0, slot.clone(), 0);
+        let mut find = |token: Option<bool>| {
+            let mut value = token.map(|token| token as cryptoki_sys::CK_BBOOL);
+            let mut template: Vec<_> = value
+                .iter_mut()
+                .map(|value| cryptoki_sys::CK_ATTRIBUTE {
+                    type_: CKA_TOKEN,
+                    pValue: value as *mut _ as _,
+                    ulValueLen: 1,
+                })
+                .collect();
+            let template =
+                unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) };
+            session.enum_init(template).unwrap();
+            let mut handles = session.enum_ctx.take().unwrap().handles;
+            handles.sort();
+            handles
+        };
+
+        // only the session objects, the NetHSM isn't asked
+        assert_eq!(find(Some(false)), vec![secret]);
+        assert!(requests.lock().unwrap().is_empty());
+
+        let keys = find(Some(true));
+        assert_eq!(keys.len(), 2);
+        assert!(!keys.contains(&secret));
+
+        // both when the template has no CKA_TOKEN
+        let mut all = keys.clone();
+        all.push(secret);
+        all.sort();
+        assert_eq!(find(None), all);
+
+        // the keys were all fetched, they come from the database
+        slot.db.lock().unwrap().set_fetched_all_keys(true);
+        assert_eq!(find(Some(true)), keys);
+        assert_eq!(find(None), all);
+    }
+
+    #[test]
+    fn test_binary_key_id() {
+        let (slot, requests) = mock_slot(0);
+        let mut session = Session::new(0, slot.clone(), 0);
+        let thumbprint: [u8; 20] = <sha1::Sha1 as sha1::Digest>::digest(b"certificate").into();
+
+        let mut id = thumbprint;
+        let mut raw = [cryptoki_sys::CK_ATTRIBUTE {
+            type_: CKA_ID,
+            pValue: id.as_mut_ptr() as _,
+            ulValueLen: id.len() as _,
+        }];
+        let mut template = || unsafe { CkRawAttrTemplate::from_raw_ptr(raw.as_mut_ptr(), 1) };
+        session.enum_init(template()).unwrap();
+        let handles = session.enum_ctx.take().unwrap().handles;
+        assert_eq!(handles.len(), 1);
+
+        // the NetHSM gets the hex encoded ID, the object keeps the bytes
+        let path = format!("/api/v1/keys/{}", hex::encode(thumbprint));
+        assert!(requests.lock().unwrap().contains(&path));
+        let key = session.get_object(handles[0]).unwrap();
+        assert_eq!(key.id, hex::encode(thumbprint));
+        assert_eq!(
+            key.get_attribute(CKA_ID),
+            Some(&Attribute::Bytes(thumbprint.to_vec()))
+        );
+
+        // found again in the database
+        let count = requests.lock().unwrap().len();
+        session.enum_init(template()).unwrap();
+        assert_eq!(session.enum_ctx.take().unwrap().handles, handles);
+        assert_eq!(requests.lock().unwrap().len(), count);
     }
 }
@@ -1,12 +1,16 @@
 use crate::backend::mechanism::MechDigest;
 
 use super::{
-    db::Object,
+    cmac::CmacKey,
+    db::{object::Attribute, Object},
     login::{self, LoginCtx},
-    mechanism::{MechMode, Mechanism},
+    mechanism::{is_digestinfo_prefix, MechMode, Mechanism, MechanismParams},
     Error,
 };
 use base64ct::{Base64, Encoding};
+use cryptoki_sys::{
+    CKA_ALWAYS_AUTHENTICATE, CKA_SIGN, CK_MECHANISM_TYPE, CK_OBJECT_HANDLE, CK_ULONG,
+};
 use der::Decode;
 use digest::{FixedOutput, HashMarker};
 use log::{debug, trace};
@@ -16,12 +20,24 @@ use sha2::Digest;
 #[derive(Clone, Debug)]
 pub struct SignCtx {
     pub mechanism: Mechanism,
-    pub sign_name: SignMode,
+    // None for AES-CMAC, the NetHSM has no sign mode for it
+    pub sign_name: Option<SignMode>,
+    pub cmac_key: Option<CmacKey>,
     pub key: Object,
     pub data: Vec<u8>,
     pub login_ctx: LoginCtx,
+    // C_SignUpdate or C_Sign was called, even with no data
+    pub data_fed: bool,
+    // the input of CKM_RSA_PKCS must be a DigestInfo or have the length of the modulus
+    pub strict_digestinfo: bool,
+    // signature of a C_SignFinal that returned CKR_BUFFER_TOO_SMALL, given back on the retry
+    pub pending_output: Option<Vec<u8>>,
+    // the key has CKA_ALWAYS_AUTHENTICATE, each signature needs a C_Login(CKU_CONTEXT_SPECIFIC)
+    pub requires_context_login: bool,
+    pub context_logged_in: bool,
 }
 
+#[allow(dead_code)]
 pub trait GenericDigest: HashMarker + FixedOutput {}
 
 impl SignCtx {
@@ -32,6 +48,15 @@ impl SignCtx {
             return Err(Error::NotLoggedIn(login::UserMode::Operator));
         }
 
+        if mechanism == Mechanism::AesCmac {
+            if !matches!(key.get_attribute(CKA_SIGN), Some(Attribute::Bool(true))) {
+                debug!("The key {} can't sign", key.id);
+                return Err(Error::KeyFunctionNotPermitted);
+            }
+            let cmac_key = CmacKey::from_object(&key, login_ctx.clone())?;
+            return Ok(Self::new(mechanism, key, None, Some(cmac_key), login_ctx));
+        }
+
         let sign_name = mechanism.sign_name().ok_or_else(|| {
             debug!("Tried to sign with an invalid mechanism: {:?}", mechanism);
             Error::InvalidMechanismMode(MechMode::Sign, mechanism.clone())
@@ -56,53 +81,70 @@ impl SignCtx {
             return Err(Error::InvalidMechanism((key.id, key.kind), mechanism));
         }
 
-        Ok(Self {
+        Ok(Self::new(mechanism, key, Some(sign_name), None, login_ctx))
+    }
+
+    fn new(
+        mechanism: Mechanism,
+        key: Object,
+        sign_name: Option<SignMode>,
+        cmac_key: Option<CmacKey>,
+        login_ctx: LoginCtx,
+    ) -> Self {
+        let requires_context_login = matches!(
+            key.get_attribute(CKA_ALWAYS_AUTHENTICATE),
+            Some(Attribute::Bool(true))
+        );
+
+        Self {
             mechanism,
             key,
             sign_name,
+            cmac_key,
             data: Vec::new(),
             login_ctx,
-        })
+            data_fed: false,
+            strict_digestinfo: false,
+            pending_output: None,
+            requires_context_login,
+            context_logged_in: false,
+        }
+    }
+
+    pub fn with_strict_digestinfo(mut self, strict: bool) -> Self {
+        self.strict_digestinfo = strict;
+        self
     }
+
     pub fn update(&mut self, data: &[u8]) {
         self.data.extend_from_slice(data);
+        self.data_fed = true;
     }
 
+    /// Signs the data received by `update`.
+    ///
+    /// Ending a multi-part operation without any `C_SignUpdate` is allowed for the mechanisms that
+    /// hash the data, the signature is then the one of the hash of the empty message (EdDSA also
+    /// signs the empty message). The raw mechanisms (`CKM_RSA_PKCS`, `CKM_ECDSA`, PSS without
+    /// hashing) sign a hash computed by the caller, they fail with `CKR_DATA_LEN_RANGE` if no data
+    /// was fed.
     pub fn sign_final(&self) -> Result<Vec<u8>, Error> {
-        // helper function to hash the data with the correct algorithm
-        fn hasher<D: Digest>(data: &[u8]) -> Vec<u8> {
-            let mut hasher = D::new();
-            hasher.update(data);
-            hasher.finalize().to_vec()
+        if self.requires_context_login && !self.context_logged_in {
+            debug!("The key {} needs a context specific login", self.key.id);
+            return Err(Error::NotLoggedIn(login::UserMode::Operator));
         }
 
-        let mut data = if let Some(digest) = self.mechanism.internal_digest() {
-            let hasher_fn = match digest {
-                MechDigest::Sha1 => hasher::<sha1::Sha1>,
-                MechDigest::Sha224 => hasher::<sha2::Sha224>,
-                MechDigest::Sha256 => hasher::<sha2::Sha256>,
-                MechDigest::Sha384 => hasher::<sha2::Sha384>,
-                MechDigest::Sha512 => hasher::<sha2::Sha512>,
-                // should never happen
-                _ => hasher::<sha1::Sha1>,
-            };
-            hasher_fn(&self.data)
-        } else {
-            self.data.clone()
-        };
-
-        // with ecdsa we need to send the correct size, so we truncate/pad the data to the correct size
-        if matches!(self.mechanism, Mechanism::Ecdsa(_)) {
-            let size = self.mechanism.get_input_size(self.key.size);
-            let mut out = vec![0; size];
-            let len = data.len().min(size);
-            out[(size - len)..size].copy_from_slice(&data[..len]);
-            data = out;
+        if let Some(cmac_key) = &self.cmac_key {
+            return cmac_key.mac(&self.data);
         }
 
+        let data = self.message()?;
+
         let b64_message = Base64::encode_string(data.as_slice());
 
-        let mode = self.sign_name;
+        let mode = self
+            .sign_name
+            .ok_or_else(|| Error::InvalidMechanismMode(MechMode::Sign, self.mechanism.clone()))?;
         trace!("Signing with mode: {:?}", mode);
 
         let mut login_ctx = self.login_ctx.clone();
@@ -153,7 +195,245 @@ impl SignCtx {
         Ok(output)
     }
 
-    pub fn get_theoretical_size(&self) -> usize {
-        self.mechanism.get_signature_size(self.key.size)
+    // the message sent to the NetHSM
+    fn message(&self) -> Result<Vec<u8>, Error> {
+        // helper function to hash the data with the correct algorithm
+        fn hasher<D: Digest>(data: &[u8]) -> Vec<u8> {
+            let mut hasher = D::new();
+            hasher.update(data);
+            hasher.finalize().to_vec()
+        }
+
+        let raw_input = self.mechanism.internal_digest().is_none()
+            && !matches!(self.mechanism, Mechanism::EdDsa);
+
+        if raw_input && !self.data_fed {
+            debug!("No data to sign with {:?}", self.mechanism);
+            return Err(Error::InvalidDataLength);
+        }
+
+        // TLS stacks give the DigestInfo to CKM_RSA_PKCS, anything else is most likely a mistake.
+        // The DigestInfo is signed as it is, the NetHSM only adds the PKCS#1 v1.5 padding.
+        if matches!(self.mechanism, Mechanism::RsaPkcs(None)) {
+            match is_digestinfo_prefix(&self.data) {
+                Some(hash) => debug!("Signing a DigestInfo of the hash {:#x}", hash),
+                None if self.strict_digestinfo && Some(self.data.len()) != self.key.size => {
+                    debug!(
+                        "The {} bytes to sign with CKM_RSA_PKCS are not a DigestInfo",
+                        self.data.len()
+                    );
+                    return Err(Error::InvalidData);
+                }
+                None => {}
+            }
+        }
+
+        let mut data = if let Some(digest) = self.mechanism.internal_digest() {
+            let hasher_fn = match digest {
+                MechDigest::Sha1 => hasher::<sha1::Sha1>,
+                MechDigest::Sha224 => hasher::<sha2::Sha224>,
+                MechDigest::Sha256 => hasher::<sha2::Sha256>,
+                MechDigest::Sha384 => hasher::<sha2::Sha384>,
+                MechDigest::Sha512 => hasher::<sha2::Sha512>,
+                // should never happen
+                _ => hasher::<sha1::Sha1>,
+            };
+            hasher_fn(&self.data)
+        } else {
+            self.data.clone()
+        };
+
+        // with ecdsa we need to send the correct size, so we truncate/pad the data to the correct size
+        if matches!(self.mechanism, Mechanism::Ecdsa(_)) {
+            let size = self.mechanism.get_input_size(self.key.size);
+            let mut out = vec![0; size];
+            let len = data.len().min(size);
+            out[(size - len)..size].copy_from_slice(&data[..len]);
+            data = out;
+        }
+
+        Ok(data)
+    }
+
+    // length of the signature, known without calling the NetHSM
+    pub fn output_len(&self) -> CK_ULONG {
+        self.mechanism.get_signature_size(self.key.size) as CK_ULONG
+    }
+}
+
+// Message-based signing of PKCS#11 v3.0: the key and the mechanism are checked once when
+// the context is started, then every message is signed on its own.
+#[derive(Clone, Debug)]
+pub struct MessageSignCtx {
+    pub key_handle: CK_OBJECT_HANDLE,
+    // never fed, each message is signed with a copy
+    sign_ctx: SignCtx,
+}
+
+impl MessageSignCtx {
+    pub fn init(
+        mechanism: Mechanism,
+        key: Object,
+        key_handle: CK_OBJECT_HANDLE,
+        login_ctx: LoginCtx,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            key_handle,
+            sign_ctx: SignCtx::init(mechanism, key, login_ctx)?,
+        })
+    }
+
+    pub fn with_strict_digestinfo(mut self, strict: bool) -> Self {
+        self.sign_ctx = self.sign_ctx.with_strict_digestinfo(strict);
+        self
+    }
+
+    pub fn output_len(&self) -> CK_ULONG {
+        self.sign_ctx.output_len()
+    }
+
+    pub fn mechanism_type(&self) -> CK_MECHANISM_TYPE {
+        self.sign_ctx.mechanism.ck_type()
+    }
+
+    // the mechanisms of the NetHSM take no parameter per message
+    pub fn sign(&self, parameter: MechanismParams, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if !matches!(parameter, MechanismParams::None) {
+            debug!("Tried to sign a message with a parameter");
+            return Err(Error::MechanismParamInvalid);
+        }
+
+        let mut sign_ctx = self.sign_ctx.clone();
+        sign_ctx.update(data);
+        sign_ctx.sign_final()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nethsm_sdk_rs::models::KeyType;
+
+    use super::*;
+    use crate::backend::key::key_size;
+
+    fn sign_ctx(mechanism: Mechanism) -> SignCtx {
+        SignCtx {
+            sign_name: mechanism.sign_name(),
+            cmac_key: None,
+            mechanism,
+            key: Object::default(),
+            data: Vec::new(),
+            login_ctx: LoginCtx::new(None, None, vec![], None),
+            data_fed: false,
+            strict_digestinfo: false,
+            pending_output: None,
+            requires_context_login: false,
+            context_logged_in: false,
+        }
+    }
+
+    #[test]
+    fn test_output_len() {
+        let mut ctx = sign_ctx(Mechanism::RsaPkcs(None));
+        ctx.key.size = Some(256);
+        assert_eq!(ctx.output_len(), 256);
+
+        for (key_type, len) in [
+            (KeyType::EcP256, 64),
+            (KeyType::EcP384, 96),
+            (KeyType::EcP521, 132),
+        ] {
+            let mut ctx = sign_ctx(Mechanism::Ecdsa(None));
+            ctx.key.size = key_size(&key_type);
+            assert_eq!(ctx.output_len(), len);
+        }
+
+        let mut ctx = sign_ctx(Mechanism::EdDsa);
+        ctx.key.size = key_size(&KeyType::Curve25519);
+        assert_eq!(ctx.output_len(), 64);
+    }
+
+    #[test]
+    fn test_sign_final_empty_message_hashed() {
+        let ctx = sign_ctx(Mechanism::RsaPkcs(Some(MechDigest::Sha256)));
+        assert_eq!(ctx.message().unwrap(), sha2::Sha256::digest([]).to_vec());
+
+        let ctx = sign_ctx(Mechanism::EdDsa);
+        assert!(ctx.message().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sign_final_raw_without_data() {
+        let mut ctx = sign_ctx(Mechanism::RsaPkcs(None));
+        assert!(matches!(ctx.sign_final(), Err(Error::InvalidDataLength)));
+
+        // a single-part C_Sign feeds the data, even when it is empty
+        ctx.update(&[]);
+        assert!(ctx.message().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_strict_digestinfo() {
+        let digest_info = |digest: MechDigest| {
+            let mut data = digest.digest_info_prefix().to_vec();
+            data.extend(vec![0x42; digest.output_size()]);
+            data
+        };
+
+        let mut ctx = sign_ctx(Mechanism::RsaPkcs(None)).with_strict_digestinfo(true);
+        ctx.key.size = Some(256);
+        for digest in [
+            MechDigest::Sha1,
+            MechDigest::Sha256,
+            MechDigest::Sha384,
+            MechDigest::Sha512,
+        ] {
+            let mut ctx = ctx.clone();
+            ctx.update(&digest_info(digest));
+            assert_eq!(ctx.message().unwrap(), digest_info(digest));
+        }
+
+        // raw signature of a block of the modulus length
+        let mut raw = ctx.clone();
+        raw.update(&[1; 256]);
+        assert!(raw.message().is_ok());
+
+        // a bare hash, and a SHA-256 header with a short hash
+        let mut invalid = ctx.clone();
+        invalid.update(&[0x42; 32]);
+        assert!(matches!(invalid.message(), Err(Error::InvalidData)));
+        let mut truncated = ctx.clone();
+        truncated.update(&digest_info(MechDigest::Sha256)[..40]);
+        assert!(matches!(truncated.message(), Err(Error::InvalidData)));
+
+        // the check is off by default
+        let mut lax = sign_ctx(Mechanism::RsaPkcs(None));
+        lax.update(&[0x42; 32]);
+        assert!(lax.message().is_ok());
+    }
+
+    #[test]
+    fn test_sign_digest_info_of_digest() {
+        // C_DigestInit(CKM_SHA256) + C_DigestUpdate + C_DigestFinal, then C_Sign(CKM_RSA_PKCS)
+        let mut digest = super::super::digest::DigestCtx::init(MechDigest::Sha256);
+        digest.update(b"abc");
+        let hash = digest.digest_final();
+        assert_eq!(
+            hash,
+            hex_literal::hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+
+        let mut digest_info = MechDigest::Sha256.digest_info_prefix().to_vec();
+        digest_info.extend(&hash);
+        assert_eq!(
+            is_digestinfo_prefix(&digest_info),
+            Some(cryptoki_sys::CKM_SHA256)
+        );
+
+        let mut ctx = sign_ctx(Mechanism::RsaPkcs(None)).with_strict_digestinfo(true);
+        ctx.key.size = Some(256);
+        ctx.update(&digest_info);
+        assert_eq!(ctx.message().unwrap(), digest_info);
+        assert!(matches!(ctx.sign_name, Some(SignMode::Pkcs1)));
     }
 }
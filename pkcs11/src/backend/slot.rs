@@ -1,10 +1,24 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
-use crate::{config::device::Slot, data::DEVICE};
-use log::error;
+use cryptoki_sys::{
+    CKF_LOGIN_REQUIRED, CKF_RNG, CKF_TOKEN_INITIALIZED, CKF_USER_PIN_INITIALIZED, CK_RV,
+    CK_TOKEN_INFO, CK_ULONG,
+};
+use log::{debug, error, warn};
+use nethsm_sdk_rs::apis::default_api;
 
+use crate::{
+    backend::login::{LoginCtx, UserMode},
+    config::device::Slot,
+    data::initialized_device,
+    defs::{DEFAULT_FIRMWARE_VERSION, DEFAULT_HARDWARE_VERSION},
+    utils::{padded_str, version_struct_from_str},
+};
+
+// The slots are read from the configuration in C_Initialize and don't change afterwards, the
+// slot ID is the index in the configuration.
 pub fn get_slot(slot_id: usize) -> Result<Arc<Slot>, cryptoki_sys::CK_RV> {
-    let Some(device) = DEVICE.get() else {
+    let Some(device) = initialized_device() else {
         error!("Initialization was not performed or failed");
         return Err(cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED);
     };
@@ -16,6 +30,106 @@ pub fn get_slot(slot_id: usize) -> Result<Arc<Slot>, cryptoki_sys::CK_RV> {
     Ok(slot.clone())
 }
 
+impl Slot {
+    // The token information only changes with an update of the NetHSM, it is kept for
+    // token_info_cache_ttl. When the NetHSM can't be reached, the last value is used. The
+    // cache isn't locked during the fetch, the other sessions use the last value meanwhile.
+    pub fn refresh_token_info(&self) -> Result<CK_TOKEN_INFO, CK_RV> {
+        let cached = {
+            let cache = self
+                .token_info
+                .lock()
+                .map_err(|_| cryptoki_sys::CKR_FUNCTION_FAILED)?;
+            if let (Some(info), Some(refresh)) = (cache.info, cache.last_token_info_refresh) {
+                if refresh.elapsed() < self.token_info_cache_ttl {
+                    return Ok(info);
+                }
+            }
+            cache.info
+        };
+
+        match self.fetch_token_info() {
+            Ok(info) => {
+                let mut cache = self
+                    .token_info
+                    .lock()
+                    .map_err(|_| cryptoki_sys::CKR_FUNCTION_FAILED)?;
+                cache.info = Some(info);
+                cache.last_token_info_refresh = Some(Instant::now());
+                Ok(info)
+            }
+            Err(rv) => match cached {
+                Some(info) => {
+                    warn!(
+                        "Failed to refresh the token info of the slot {}, using the cached value",
+                        self.label
+                    );
+                    Ok(info)
+                }
+                None => Err(rv),
+            },
+        }
+    }
+
+    fn fetch_token_info(&self) -> Result<CK_TOKEN_INFO, CK_RV> {
+        let mut login_ctx = LoginCtx::new(
+            None,
+            self.administrator.clone(),
+            self.instances.clone(),
+            self.retries,
+        );
+
+        // fetch info from the device
+        let info = login_ctx
+            .try_(default_api::info_get, UserMode::Guest)
+            .map_err(|e| {
+                error!("Error getting info: {:?}", e);
+                cryptoki_sys::CKR_FUNCTION_FAILED
+            })?;
+
+        let mut serial_number = "unknown".to_string();
+        let mut hardware_version = DEFAULT_HARDWARE_VERSION;
+        let mut firmware_version = DEFAULT_FIRMWARE_VERSION;
+
+        // Try to fech system info
+
+        if login_ctx.can_run_mode(UserMode::Administrator) {
+            match login_ctx.try_(default_api::system_info_get, UserMode::Administrator) {
+                Err(e) => {
+                    warn!("Error getting system info: {:?}", e);
+                }
+                Ok(system_info) => {
+                    serial_number = system_info.entity.device_id;
+                    hardware_version = version_struct_from_str(system_info.entity.hardware_version);
+                    // The PKCS11 firmware version actually corresponds to the NetHSM software version
+                    firmware_version = version_struct_from_str(system_info.entity.software_version);
+                }
+            }
+        }
+
+        let mut flags = CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED | CKF_RNG;
+
+        // if the slot has no password, set the login required flag
+        if !self.is_connected() {
+            flags |= CKF_LOGIN_REQUIRED;
+            debug!("Login required");
+        }
+
+        Ok(CK_TOKEN_INFO {
+            label: padded_str(&self.label),
+            manufacturerID: padded_str(&info.entity.vendor),
+            model: padded_str(&info.entity.product),
+            serialNumber: padded_str(&serial_number),
+            flags,
+            hardwareVersion: hardware_version,
+            firmwareVersion: firmware_version,
+            ulMinPinLen: self.min_pin_len as CK_ULONG,
+            ulMaxPinLen: self.max_pin_len as CK_ULONG,
+            ..Default::default()
+        })
+    }
+}
+
 #[cfg(test)]
 pub fn init_for_tests() {
     use std::ptr;
@@ -29,3 +143,57 @@ pub fn init_for_tests() {
         assert_eq!(C_Initialize(ptr::null_mut()), cryptoki_sys::CKR_OK);
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::session::tests::{count_requests, mock_nethsm},
+        config::device::SlotBuilder,
+    };
+
+    use super::*;
+
+    fn slot(url: &str) -> SlotBuilder {
+        SlotBuilder::new().url(url).operator_username("operator")
+    }
+
+    #[test]
+    fn test_token_info_cache() {
+        let (url, requests) = mock_nethsm(0);
+        let slot = slot(&url).build().unwrap();
+
+        // cold cache: the NetHSM is asked
+        let info = slot.refresh_token_info().unwrap();
+        assert_eq!(info.model, padded_str::<16>("NetHSM"));
+        assert_eq!(count_requests(&requests, "/api/v1/info"), 1);
+
+        // warm cache: the same value without a request
+        let cached = slot.refresh_token_info().unwrap();
+        assert_eq!(cached.manufacturerID, info.manufacturerID);
+        assert_eq!(count_requests(&requests, "/api/v1/info"), 1);
+    }
+
+    #[test]
+    fn test_token_info_stale() {
+        let (url, requests) = mock_nethsm(0);
+        let mut slot = slot(&url).token_info_cache_ttl_secs(0).build().unwrap();
+
+        let info = slot.refresh_token_info().unwrap();
+        assert_eq!(count_requests(&requests, "/api/v1/info"), 1);
+
+        // the cache has expired and the NetHSM is gone, the last value is kept
+        slot.instances[0].base_path = "http://127.0.0.1:1/api/v1".to_string();
+        let stale = slot.refresh_token_info().unwrap();
+        assert_eq!(stale.model, info.model);
+    }
+
+    #[test]
+    fn test_token_info_unreachable() {
+        let slot = slot("http://127.0.0.1:1/api/v1").build().unwrap();
+
+        assert_eq!(
+            slot.refresh_token_info().unwrap_err(),
+            cryptoki_sys::CKR_FUNCTION_FAILED
+        );
+    }
+}
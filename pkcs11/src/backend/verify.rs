@@ -0,0 +1,655 @@
+use cryptoki_sys::{
+    CKA_KEY_TYPE, CKA_MODULUS, CKA_PUBLIC_EXPONENT, CKA_VALUE, CKA_VERIFY, CKK_RSA,
+};
+use log::debug;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+use super::{
+    cmac::CmacKey,
+    db::{
+        object::{Attribute, ObjectKind},
+        Object,
+    },
+    digest::DigestCtx,
+    login::LoginCtx,
+    mechanism::{MechDigest, MechMode, Mechanism},
+    Error,
+};
+
+const HMAC_IPAD: u8 = 0x36;
+const HMAC_OPAD: u8 = 0x5c;
+
+// The NetHSM can't verify signatures, the module does it. HMACs are computed in software with
+// secret keys whose value is known to the module (generated with CKM_GENERIC_SECRET_KEY_GEN),
+// AES-CMAC is computed like for signing. RSA-PSS signatures are checked with the public key.
+#[derive(Clone, Debug)]
+pub struct VerifyCtx {
+    // a private key can only be used while the session is logged in
+    pub private: bool,
+    verifier: Verifier,
+}
+
+#[derive(Clone, Debug)]
+enum Verifier {
+    Hmac {
+        digest: MechDigest,
+        inner: DigestCtx,
+        outer_key: Vec<u8>,
+    },
+    Cmac {
+        key: CmacKey,
+        data: Vec<u8>,
+    },
+    RsaPss {
+        digest: MechDigest,
+        // None for CKM_RSA_PKCS_PSS, the data is then the hash of the message
+        hasher: Option<DigestCtx>,
+        data: Vec<u8>,
+        modulus: Vec<u8>,
+        public_exponent: Vec<u8>,
+    },
+}
+
+impl VerifyCtx {
+    pub fn init(mechanism: Mechanism, key: &Object, login_ctx: LoginCtx) -> Result<Self, Error> {
+        if !matches!(
+            mechanism,
+            Mechanism::Hmac(_) | Mechanism::AesCmac | Mechanism::RsaPkcsPss(_, _)
+        ) {
+            debug!("Tried to verify with an invalid mechanism: {:?}", mechanism);
+            return Err(Error::InvalidMechanismMode(MechMode::Verify, mechanism));
+        }
+
+        if !matches!(key.get_attribute(CKA_VERIFY), Some(Attribute::Bool(true))) {
+            debug!("The key {} can't verify", key.id);
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+
+        let digest = match mechanism {
+            Mechanism::Hmac(digest) => digest,
+            Mechanism::RsaPkcsPss(digest, pre_hash) => {
+                let (modulus, public_exponent) = rsa_public_key(key, &mechanism)?;
+                return Ok(Self {
+                    private: key.is_private(),
+                    verifier: Verifier::RsaPss {
+                        digest,
+                        hasher: pre_hash.then(|| DigestCtx::init(digest)),
+                        data: Vec::new(),
+                        modulus,
+                        public_exponent,
+                    },
+                });
+            }
+            _ => {
+                return Ok(Self {
+                    private: key.is_private(),
+                    verifier: Verifier::Cmac {
+                        key: CmacKey::from_object(key, login_ctx)?,
+                        data: Vec::new(),
+                    },
+                })
+            }
+        };
+
+        let value = match (key.kind, key.get_attribute(CKA_VALUE)) {
+            (ObjectKind::SecretKey, Some(Attribute::Bytes(value))) if !value.is_empty() => value,
+            _ => {
+                debug!("The value of the key {} is not available", key.id);
+                return Err(Error::InvalidMechanism(
+                    (key.id.clone(), key.kind),
+                    mechanism,
+                ));
+            }
+        };
+
+        // RFC 2104: keys longer than a block are hashed, shorter ones are padded with zeros
+        let mut block = if value.len() > digest.block_size() {
+            let mut hasher = DigestCtx::init(digest);
+            hasher.update(value);
+            hasher.digest_final()
+        } else {
+            value.clone()
+        };
+        block.resize(digest.block_size(), 0);
+
+        let mut inner = DigestCtx::init(digest);
+        inner.update(&block.iter().map(|b| b ^ HMAC_IPAD).collect::<Vec<u8>>());
+
+        Ok(Self {
+            private: key.is_private(),
+            verifier: Verifier::Hmac {
+                digest,
+                inner,
+                outer_key: block.iter().map(|b| b ^ HMAC_OPAD).collect(),
+            },
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.verifier {
+            Verifier::Hmac { inner, .. } => inner.update(data),
+            Verifier::RsaPss {
+                hasher: Some(hasher),
+                ..
+            } => hasher.update(data),
+            Verifier::Cmac { data: buffer, .. } | Verifier::RsaPss { data: buffer, .. } => {
+                buffer.extend_from_slice(data)
+            }
+        }
+    }
+
+    fn mac(&self) -> Result<Vec<u8>, Error> {
+        match &self.verifier {
+            Verifier::Hmac {
+                digest,
+                inner,
+                outer_key,
+            } => {
+                let mut outer = DigestCtx::init(*digest);
+                outer.update(outer_key);
+                outer.update(&inner.digest_final());
+                Ok(outer.digest_final())
+            }
+            Verifier::Cmac { key, data } => key.mac(data),
+            // a signature, not a MAC
+            Verifier::RsaPss { digest, hasher, .. } => Err(Error::InvalidMechanismMode(
+                MechMode::Verify,
+                Mechanism::RsaPkcsPss(*digest, hasher.is_some()),
+            )),
+        }
+    }
+
+    // The result is only known once all the data has been fed and the whole MAC compared.
+    pub fn verify_final(&self, signature: &[u8]) -> Result<(), Error> {
+        if let Verifier::RsaPss {
+            digest,
+            hasher,
+            data,
+            modulus,
+            public_exponent,
+        } = &self.verifier
+        {
+            let hash = match hasher {
+                Some(hasher) => hasher.digest_final(),
+                None if data.len() == digest.output_size() => data.clone(),
+                None => return Err(Error::InvalidDataLength),
+            };
+            return verify_pss(*digest, &hash, modulus, public_exponent, signature);
+        }
+
+        let mac = self.mac()?;
+
+        // the length of the MAC is public, it can be checked right away
+        if signature.len() != mac.len() {
+            return Err(Error::InvalidSignatureLength);
+        }
+
+        // HMAC and CMAC, the time taken doesn't depend on the first byte that differs
+        if !bool::from(mac.ct_eq(signature)) {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+// the padded key is a copy of the secret
+impl Drop for VerifyCtx {
+    fn drop(&mut self) {
+        if let Verifier::Hmac { outer_key, .. } = &mut self.verifier {
+            outer_key.zeroize();
+        }
+    }
+}
+
+// C_VerifyRecover with CKM_RSA_PKCS, the NetHSM can't do it either: the public key operation
+// and the removal of the PKCS#1 v1.5 padding are done by the module.
+#[derive(Clone, Debug)]
+pub struct VerifyRecoverCtx {
+    pub private: bool,
+    modulus: Vec<u8>,
+    public_exponent: Vec<u8>,
+}
+
+impl VerifyRecoverCtx {
+    pub fn init(mechanism: Mechanism, key: &Object) -> Result<Self, Error> {
+        if !matches!(mechanism, Mechanism::RsaPkcs(None)) {
+            debug!(
+                "Tried to verify with recovery with an invalid mechanism: {:?}",
+                mechanism
+            );
+            return Err(Error::InvalidMechanismMode(MechMode::Verify, mechanism));
+        }
+
+        let (modulus, public_exponent) = rsa_public_key(key, &mechanism)?;
+
+        Ok(Self {
+            private: key.is_private(),
+            modulus,
+            public_exponent,
+        })
+    }
+
+    // the signed data, the padding is EM = 0x00 || 0x01 || PS || 0x00 || T with at least
+    // 8 bytes of 0xff in PS (RFC 8017 section 9.2)
+    pub fn recover(&self, signature: &[u8]) -> Result<Vec<u8>, Error> {
+        if signature.len() != self.modulus.len() {
+            return Err(Error::InvalidSignatureLength);
+        }
+
+        let encoded = super::rsa::public_op(&self.modulus, &self.public_exponent, signature)
+            .ok_or(Error::InvalidSignature)?;
+        // the modulus can have leading zeros
+        let encoded = &encoded[encoded.len().saturating_sub(signature.len())..];
+
+        let padding_end = match encoded {
+            [0x00, 0x01, rest @ ..] => rest
+                .iter()
+                .position(|b| *b != 0xff)
+                .filter(|pos| *pos >= 8 && rest[*pos] == 0x00)
+                .ok_or(Error::InvalidSignature)?,
+            _ => return Err(Error::InvalidSignature),
+        };
+
+        Ok(encoded[padding_end + 3..].to_vec())
+    }
+}
+
+fn rsa_public_key(key: &Object, mechanism: &Mechanism) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    match (
+        key.get_attribute(CKA_KEY_TYPE),
+        key.get_attribute(CKA_MODULUS),
+        key.get_attribute(CKA_PUBLIC_EXPONENT),
+    ) {
+        (
+            Some(Attribute::Ulong(CKK_RSA)),
+            Some(Attribute::Bytes(modulus)),
+            Some(Attribute::Bytes(public_exponent)),
+        ) => Ok((modulus.clone(), public_exponent.clone())),
+        _ => {
+            debug!("The key {} is not an RSA key", key.id);
+            Err(Error::InvalidMechanism(
+                (key.id.clone(), key.kind),
+                mechanism.clone(),
+            ))
+        }
+    }
+}
+
+// EMSA-PSS-VERIFY of RFC 8017 section 9.1.2, with MGF1 of the same hash and a salt as long as
+// the hash: the only parameters accepted by the mechanism
+fn verify_pss(
+    digest: MechDigest,
+    hash: &[u8],
+    modulus: &[u8],
+    public_exponent: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    let modulus = &modulus[modulus.iter().position(|b| *b != 0).unwrap_or(0)..];
+    if signature.len() != modulus.len() {
+        return Err(Error::InvalidSignatureLength);
+    }
+    let encoded = super::rsa::public_op(modulus, public_exponent, signature)
+        .ok_or(Error::InvalidSignature)?;
+
+    // emBits is modBits - 1, the encoded message loses its first byte when modBits is 8k + 1
+    let em_bits = modulus.len() * 8 - modulus[0].leading_zeros() as usize - 1;
+    let em = &encoded[encoded.len() - em_bits.div_ceil(8)..];
+    if encoded[..encoded.len() - em.len()].iter().any(|b| *b != 0) {
+        return Err(Error::InvalidSignature);
+    }
+
+    let h_len = digest.output_size();
+    let s_len = h_len;
+    if em.len() < h_len + s_len + 2 || em[em.len() - 1] != 0xbc {
+        return Err(Error::InvalidSignature);
+    }
+    let (masked_db, h) = em[..em.len() - 1].split_at(em.len() - h_len - 1);
+    let top_mask = 0xffu8 >> (8 * em.len() - em_bits);
+    if masked_db[0] & !top_mask != 0 {
+        return Err(Error::InvalidSignature);
+    }
+
+    let mut db: Vec<u8> = masked_db
+        .iter()
+        .zip(mgf1(digest, h, masked_db.len()))
+        .map(|(b, m)| b ^ m)
+        .collect();
+    db[0] &= top_mask;
+    let (padding, salt) = db.split_at(db.len() - s_len);
+    match padding.split_last() {
+        Some((0x01, zeros)) if zeros.iter().all(|b| *b == 0) => {}
+        _ => return Err(Error::InvalidSignature),
+    }
+
+    let mut hasher = DigestCtx::init(digest);
+    hasher.update(&[0; 8]);
+    hasher.update(hash);
+    hasher.update(salt);
+    if hasher.digest_final() != h {
+        return Err(Error::InvalidSignature);
+    }
+    Ok(())
+}
+
+// the mask generation function of RFC 8017 appendix B.2.1
+fn mgf1(digest: MechDigest, seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + digest.output_size());
+    let mut counter: u32 = 0;
+    while mask.len() < len {
+        let mut hasher = DigestCtx::init(digest);
+        hasher.update(seed);
+        hasher.update(&counter.to_be_bytes());
+        mask.extend_from_slice(&hasher.digest_final());
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::db::object::from_session_secret;
+
+    use super::*;
+
+    // RFC 4231 test case 1
+    const KEY: [u8; 20] = [0x0b; 20];
+    const DATA: &[u8] = b"Hi There";
+    const SHA256_MAC: [u8; 32] = [
+        0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b, 0xf1,
+        0x2b, 0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c, 0x2e, 0x32,
+        0xcf, 0xf7,
+    ];
+
+    fn login_ctx() -> LoginCtx {
+        LoginCtx::new(None, None, vec![], None)
+    }
+
+    fn hmac_ctx(key: &[u8]) -> VerifyCtx {
+        let key = from_session_secret("hmac", None, key.to_vec());
+        VerifyCtx::init(Mechanism::Hmac(MechDigest::Sha256), &key, login_ctx()).unwrap()
+    }
+
+    #[test]
+    fn test_verify_hmac() {
+        let mut ctx = hmac_ctx(&KEY);
+        ctx.update(DATA);
+        assert!(ctx.verify_final(&SHA256_MAC).is_ok());
+    }
+
+    #[test]
+    fn test_verify_hmac_streaming() {
+        // a 1 MB document fed in 4 KB chunks gives the MAC of the whole document
+        let document: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let mut whole = hmac_ctx(&KEY);
+        whole.update(&document);
+
+        let mut chunked = hmac_ctx(&KEY);
+        for chunk in document.chunks(4096) {
+            chunked.update(chunk);
+        }
+
+        let mac = whole.mac().unwrap();
+        assert_eq!(chunked.mac().unwrap(), mac);
+        assert!(chunked.verify_final(&mac).is_ok());
+    }
+
+    #[test]
+    fn test_verify_hmac_long_key() {
+        // RFC 4231 test case 6, the key is longer than a block
+        let key = [0xaa; 131];
+        let mut ctx = hmac_ctx(&key);
+        ctx.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        let mac = [
+            0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f, 0x0d, 0x8a, 0x26, 0xaa, 0xcb, 0xf5,
+            0xb7, 0x7f, 0x8e, 0x0b, 0xc6, 0x21, 0x37, 0x28, 0xc5, 0x14, 0x05, 0x46, 0x04, 0x0f,
+            0x0e, 0xe3, 0x7f, 0x54,
+        ];
+        assert!(ctx.verify_final(&mac).is_ok());
+    }
+
+    #[test]
+    fn test_verify_hmac_bit_flipped() {
+        let mut ctx = hmac_ctx(&KEY);
+        ctx.update(DATA);
+
+        for i in [0, SHA256_MAC.len() / 2, SHA256_MAC.len() - 1] {
+            let mut mac = SHA256_MAC;
+            mac[i] ^= 1;
+            assert!(matches!(
+                ctx.verify_final(&mac),
+                Err(Error::InvalidSignature)
+            ));
+        }
+
+        assert!(matches!(
+            ctx.verify_final(&SHA256_MAC[..31]),
+            Err(Error::InvalidSignatureLength)
+        ));
+    }
+
+    #[test]
+    fn test_verify_hmac_invalid_key() {
+        // keys stored on the NetHSM have no readable value
+        let mut key = Object::default();
+        key.kind = ObjectKind::SecretKey;
+        key.set_attr(CKA_VERIFY, Attribute::Bool(true));
+        assert!(matches!(
+            VerifyCtx::init(Mechanism::Hmac(MechDigest::Sha256), &key, login_ctx()),
+            Err(Error::InvalidMechanism(_, _))
+        ));
+
+        // the key must allow verification
+        key.set_attr(CKA_VERIFY, Attribute::Bool(false));
+        key.set_attr(CKA_VALUE, Attribute::Bytes(KEY.to_vec()));
+        assert!(matches!(
+            VerifyCtx::init(Mechanism::Hmac(MechDigest::Sha256), &key, login_ctx()),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+
+        let key = from_session_secret("hmac", None, KEY.to_vec());
+        assert!(matches!(
+            VerifyCtx::init(Mechanism::EdDsa, &key, login_ctx()),
+            Err(Error::InvalidMechanismMode(MechMode::Verify, _))
+        ));
+    }
+
+    #[test]
+    fn test_verify_aes_cmac() {
+        // RFC 4493 example 2
+        let mut key = Object::default();
+        key.kind = ObjectKind::SecretKey;
+        key.set_attr(CKA_KEY_TYPE, Attribute::Ulong(cryptoki_sys::CKK_AES));
+        key.set_attr(
+            CKA_VALUE,
+            Attribute::Bytes(hex_literal::hex!("2b7e151628aed2a6abf7158809cf4f3c").to_vec()),
+        );
+        key.set_attr(CKA_VERIFY, Attribute::Bool(true));
+        let mac = hex_literal::hex!("070a16b46b4d4144f79bdd9dd04a287c");
+        let mut ctx = VerifyCtx::init(Mechanism::AesCmac, &key, login_ctx()).unwrap();
+        ctx.update(&hex_literal::hex!("6bc1bee22e409f96"));
+        ctx.update(&hex_literal::hex!("e93d7e117393172a"));
+        assert!(ctx.verify_final(&mac).is_ok());
+
+        let mut flipped = mac;
+        flipped[15] ^= 1;
+        assert!(matches!(
+            ctx.verify_final(&flipped),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(
+            ctx.verify_final(&mac[..8]),
+            Err(Error::InvalidSignatureLength)
+        ));
+
+        // the key must allow verification
+        key.set_attr(CKA_VERIFY, Attribute::Bool(false));
+        assert!(matches!(
+            VerifyCtx::init(Mechanism::AesCmac, &key, login_ctx()),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+
+        // the HMAC secrets aren't AES keys, even with the length of one
+        let key = from_session_secret("hmac", None, vec![0; 16]);
+        assert!(matches!(
+            VerifyCtx::init(Mechanism::AesCmac, &key, login_ctx()),
+            Err(Error::InvalidMechanism(_, _))
+        ));
+    }
+
+    // RSA-1024 key and PKCS#1 v1.5 signature of "hello PKCS#1", made with OpenSSL
+    const RSA_MODULUS: [u8; 128] = hex_literal::hex!(
+        "eb58dd1872efca047db74865f2b7dab46cffeb6ae02da93b919979bcada4ea87"
+        "77aeae2a8c5e2378c4e8064d379c1b63f1b0308c64a1567043b640f0df6ba3b9"
+        "c1c82755cd45569d085ac8481a360fd71d094d325a94cc1fbb67f8e244f11a57"
+        "73a2c7b4f647380ac52a6ed9136a3aa6a629563c4c1d46518fe2f01ef7ad4d7d"
+    );
+    const RSA_SIGNATURE: [u8; 128] = hex_literal::hex!(
+        "7ae9785e3c2a52fe290cc8651e5aaf9745d95e65eaff33cfe1650fc9143975ce"
+        "d2c4b965a1d3ed78a49d39b5ea00a1a742132b0688813caeaf8781336af9c11e"
+        "49b44c2395be08856433dccaccb74897ad4d892804d906b97b5e6104f06d0564"
+        "648bd4d9ebdc2574c81c9079252edab8baee5705f35553e7a51a71f98527b693"
+    );
+
+    fn rsa_key() -> Object {
+        let mut key = Object::default();
+        key.kind = ObjectKind::PublicKey;
+        key.set_attr(CKA_KEY_TYPE, Attribute::Ulong(CKK_RSA));
+        key.set_attr(CKA_MODULUS, Attribute::Bytes(RSA_MODULUS.to_vec()));
+        key.set_attr(
+            CKA_PUBLIC_EXPONENT,
+            Attribute::Bytes(vec![0x01, 0x00, 0x01]),
+        );
+        key
+    }
+
+    #[test]
+    fn test_verify_recover() {
+        let ctx = VerifyRecoverCtx::init(Mechanism::RsaPkcs(None), &rsa_key()).unwrap();
+        assert_eq!(ctx.recover(&RSA_SIGNATURE).unwrap(), b"hello PKCS#1");
+
+        let mut signature = RSA_SIGNATURE;
+        signature[10] ^= 1;
+        assert!(matches!(
+            ctx.recover(&signature),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(
+            ctx.recover(&RSA_SIGNATURE[1..]),
+            Err(Error::InvalidSignatureLength)
+        ));
+        // bigger than the modulus
+        assert!(matches!(
+            ctx.recover(&[0xff; 128]),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    // RSA-1024 key and RSA-PSS signature of "hello PSS" with SHA-256, MGF1-SHA-256 and a salt of
+    // 32 bytes, made with OpenSSL
+    const PSS_MODULUS: [u8; 128] = hex_literal::hex!(
+        "b74757bca3fc9b21a07c7412c7f05d38e205ff38e614b82a8bafe63c36a878d9"
+        "cca7bedd989d667bfc80ddae9320dc68546b9aadee01554dbd6398f3bfc03160"
+        "7bd2e289b611683ba6788ff00eccb81f05a0d309382d078374d1e9af06df98f9"
+        "df8a80f951affd9713b439cb319e0f3f8fe4f15169f9b00233715a5a8c677f0b"
+    );
+    const PSS_SIGNATURE: [u8; 128] = hex_literal::hex!(
+        "87902b70f96ae975a3cc4f106a15ce37b947002559d822021efc03123c163d73"
+        "d81644ed6660a9f94cb5742b7dd571c127251cd942098e7d2f0418a1006eae72"
+        "dbda2dcac165bfb703052d8f41559aa85e75f3a3148d2dc941a061f26fa79d17"
+        "759112c149338a4e3a191d90c7c068def522e80dc3d93c0f7ab4472b02d85dab"
+    );
+
+    fn pss_ctx(mechanism: Mechanism) -> Result<VerifyCtx, Error> {
+        let mut key = rsa_key();
+        key.set_attr(CKA_MODULUS, Attribute::Bytes(PSS_MODULUS.to_vec()));
+        key.set_attr(CKA_VERIFY, Attribute::Bool(true));
+        VerifyCtx::init(mechanism, &key, login_ctx())
+    }
+
+    #[test]
+    fn test_verify_pss() {
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha256, true)).unwrap();
+        ctx.update(b"hello ");
+        ctx.update(b"PSS");
+        assert!(ctx.verify_final(&PSS_SIGNATURE).is_ok());
+
+        // CKM_RSA_PKCS_PSS is given the hash of the message
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha256, false)).unwrap();
+        ctx.update(&hex_literal::hex!(
+            "024b090b554de883c50a3171fdfdf9be1de63dadafca0c97d132efe21b78ed05"
+        ));
+        assert!(ctx.verify_final(&PSS_SIGNATURE).is_ok());
+
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha256, false)).unwrap();
+        ctx.update(b"hello PSS");
+        assert!(matches!(
+            ctx.verify_final(&PSS_SIGNATURE),
+            Err(Error::InvalidDataLength)
+        ));
+    }
+
+    #[test]
+    fn test_verify_pss_invalid() {
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha256, true)).unwrap();
+        ctx.update(b"hello PSS!");
+        assert!(matches!(
+            ctx.verify_final(&PSS_SIGNATURE),
+            Err(Error::InvalidSignature)
+        ));
+
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha256, true)).unwrap();
+        ctx.update(b"hello PSS");
+        let mut signature = PSS_SIGNATURE;
+        signature[64] ^= 1;
+        assert!(matches!(
+            ctx.verify_final(&signature),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(
+            ctx.verify_final(&PSS_SIGNATURE[1..]),
+            Err(Error::InvalidSignatureLength)
+        ));
+
+        // another hash gives another encoding
+        let mut ctx = pss_ctx(Mechanism::RsaPkcsPss(MechDigest::Sha384, true)).unwrap();
+        ctx.update(b"hello PSS");
+        assert!(matches!(
+            ctx.verify_final(&PSS_SIGNATURE),
+            Err(Error::InvalidSignature)
+        ));
+
+        // the key must allow verification
+        assert!(matches!(
+            VerifyCtx::init(
+                Mechanism::RsaPkcsPss(MechDigest::Sha256, true),
+                &rsa_key(),
+                login_ctx()
+            ),
+            Err(Error::KeyFunctionNotPermitted)
+        ));
+    }
+
+    #[test]
+    fn test_verify_recover_invalid_mechanism() {
+        for mechanism in [
+            Mechanism::EdDsa,
+            Mechanism::Ecdsa(None),
+            Mechanism::RsaPkcs(Some(MechDigest::Sha256)),
+        ] {
+            assert!(matches!(
+                VerifyRecoverCtx::init(mechanism, &rsa_key()),
+                Err(Error::InvalidMechanismMode(MechMode::Verify, _))
+            ));
+        }
+
+        let secret = from_session_secret("hmac", None, KEY.to_vec());
+        assert!(matches!(
+            VerifyRecoverCtx::init(Mechanism::RsaPkcs(None), &secret),
+            Err(Error::InvalidMechanism(_, _))
+        ));
+    }
+}
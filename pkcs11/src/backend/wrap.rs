@@ -0,0 +1,64 @@
+use cryptoki_sys::{CKM_AES_KEY_WRAP, CKM_RSA_PKCS_OAEP, CK_MECHANISM_TYPE, CK_ULONG};
+
+// RFC 3394: the wrapped key has an extra 64-bit block for the integrity check
+const AES_KEY_WRAP_OVERHEAD: usize = 8;
+
+// Length of the wrapped key, for the C_WrapKey calls that ask for the size of the output.
+// The lengths are in bytes.
+pub struct WrapOutputLen;
+
+impl WrapOutputLen {
+    // None when the mechanism doesn't give a length known before wrapping
+    pub fn estimate(
+        mechanism: CK_MECHANISM_TYPE,
+        wrapping_key_modulus: Option<usize>,
+        target_key_len: Option<usize>,
+    ) -> Option<CK_ULONG> {
+        match mechanism {
+            CKM_RSA_PKCS_OAEP => wrapping_key_modulus.map(|len| len as CK_ULONG),
+            // AES key wrap without padding only takes keys made of 64-bit blocks
+            CKM_AES_KEY_WRAP => target_key_len
+                .filter(|len| *len >= 16 && len % 8 == 0)
+                .map(|len| (len + AES_KEY_WRAP_OVERHEAD) as CK_ULONG),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cryptoki_sys::CKM_AES_CBC;
+
+    use super::*;
+
+    #[test]
+    fn test_estimate_aes_key_wrap() {
+        for (len, wrapped) in [(16, 24), (24, 32), (32, 40)] {
+            assert_eq!(
+                WrapOutputLen::estimate(CKM_AES_KEY_WRAP, None, Some(len)),
+                Some(wrapped)
+            );
+        }
+        assert_eq!(
+            WrapOutputLen::estimate(CKM_AES_KEY_WRAP, None, Some(20)),
+            None
+        );
+        assert_eq!(WrapOutputLen::estimate(CKM_AES_KEY_WRAP, None, None), None);
+    }
+
+    #[test]
+    fn test_estimate_rsa_oaep() {
+        assert_eq!(
+            WrapOutputLen::estimate(CKM_RSA_PKCS_OAEP, Some(256), Some(32)),
+            Some(256)
+        );
+        assert_eq!(
+            WrapOutputLen::estimate(CKM_RSA_PKCS_OAEP, None, Some(32)),
+            None
+        );
+        assert_eq!(
+            WrapOutputLen::estimate(CKM_AES_CBC, Some(256), Some(32)),
+            None
+        );
+    }
+}
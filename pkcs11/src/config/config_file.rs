@@ -1,4 +1,4 @@
-use std::{io::Read, mem, net::SocketAddr, path::PathBuf};
+use std::{error::Error, fmt, io::Read, mem, net::SocketAddr, path::PathBuf};
 
 use merge::Merge;
 use serde::{Deserialize, Serialize};
@@ -6,17 +6,80 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug)]
 pub enum ConfigError {
     Io(std::io::Error),
-    Yaml(serde_yaml::Error),
     NoConfigFile,
+    MissingField {
+        field: &'static str,
+    },
+    InvalidUrl {
+        field: &'static str,
+        value: String,
+    },
+    FileNotFound {
+        path: PathBuf,
+    },
+    ParseError {
+        field: &'static str,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    SchemaVersionUnsupported {
+        found: u32,
+        max: u32,
+    },
 }
 
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read the configuration: {err}"),
+            ConfigError::NoConfigFile => write!(f, "no configuration file found"),
+            ConfigError::MissingField { field } => write!(f, "missing value for `{field}`"),
+            ConfigError::InvalidUrl { field, value } => {
+                write!(f, "invalid URL for `{field}`: {value:?}")
+            }
+            ConfigError::FileNotFound { path } => {
+                write!(f, "configuration file {} not found", path.display())
+            }
+            ConfigError::ParseError { field, source } => {
+                write!(f, "failed to parse `{field}`: {source}")
+            }
+            ConfigError::SchemaVersionUnsupported { found, max } => write!(
+                f,
+                "configuration version {found} is not supported, the maximum is {max}"
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(err) => Some(err),
+            ConfigError::ParseError { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+// the most recent version of the configuration format
+pub const CONFIG_VERSION: u32 = 1;
+
 const CONFIG_FILE_NAME: &str = "p11nethsm.conf";
 const ENV_VAR_CONFIG_FILE: &str = "P11NETHSM_CONFIG_FILE";
 
+// the file given in the environment must exist
+fn read_config_file(file_path: PathBuf) -> Result<(Vec<u8>, PathBuf), ConfigError> {
+    let file = std::fs::read(&file_path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => ConfigError::FileNotFound {
+            path: file_path.clone(),
+        },
+        _ => ConfigError::Io(err),
+    })?;
+    Ok((file, file_path))
+}
+
 pub fn config_files() -> Result<Vec<(Vec<u8>, PathBuf)>, ConfigError> {
     if let Ok(file_path) = std::env::var(ENV_VAR_CONFIG_FILE) {
-        let file = std::fs::read(&file_path).map_err(ConfigError::Io)?;
-        return Ok(vec![(file, file_path.into())]);
+        return Ok(vec![read_config_file(file_path.into())?]);
     }
 
     let mut config_folders = vec![
@@ -48,7 +111,17 @@ pub fn merge_configurations<'a>(
 
     let mut no_config = true;
     for file in configs {
-        let parsed = serde_yaml::from_slice(file).map_err(ConfigError::Yaml)?;
+        let parsed: P11Config =
+            serde_yaml::from_slice(file).map_err(|err| ConfigError::ParseError {
+                field: "configuration",
+                source: Box::new(err),
+            })?;
+        if let Some(version) = parsed.version.filter(|v| *v > CONFIG_VERSION) {
+            return Err(ConfigError::SchemaVersionUnsupported {
+                found: version,
+                max: CONFIG_VERSION,
+            });
+        }
         no_config = false;
         config.merge(parsed);
     }
@@ -57,9 +130,38 @@ pub fn merge_configurations<'a>(
         return Err(ConfigError::NoConfigFile);
     }
 
+    validate_config(&config)?;
     Ok(config)
 }
 
+// checks of the values that serde can't express
+fn validate_config(config: &P11Config) -> Result<(), ConfigError> {
+    for slot in &config.slots {
+        if slot.label.is_empty() {
+            return Err(ConfigError::MissingField { field: "label" });
+        }
+        for instance in &slot.instances {
+            if instance.url.is_empty() {
+                return Err(ConfigError::MissingField { field: "url" });
+            }
+            let valid = ["http://", "https://"].iter().any(|scheme| {
+                instance
+                    .url
+                    .strip_prefix(scheme)
+                    .map(|rest| !rest.is_empty() && !rest.starts_with('/'))
+                    .unwrap_or(false)
+            });
+            if !valid {
+                return Err(ConfigError::InvalidUrl {
+                    field: "url",
+                    value: instance.url.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub fn read_configuration() -> Result<P11Config, ConfigError> {
     let configs = config_files()?;
@@ -69,10 +171,15 @@ pub fn read_configuration() -> Result<P11Config, ConfigError> {
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum LogLevel {
+    #[serde(alias = "trace")]
     Trace,
+    #[serde(alias = "debug")]
     Debug,
+    #[serde(alias = "info")]
     Info,
+    #[serde(alias = "warn")]
     Warn,
+    #[serde(alias = "error")]
     Error,
 }
 
@@ -98,6 +205,8 @@ pub struct SyslogUdp {
 // representation of the config file to parse
 #[derive(Debug, Clone, Serialize, Deserialize, Merge, Default, PartialEq)]
 pub struct P11Config {
+    #[serde(default)]
+    pub version: Option<u32>,
     #[merge(strategy = merge::bool::overwrite_false)]
     #[serde(default)]
     pub enable_set_attribute_value: bool,
@@ -191,6 +300,44 @@ pub struct SlotConfig {
     pub retries: Option<RetryConfig>,
     #[serde(default)]
     pub timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub session_state_path: Option<PathBuf>,
+    #[serde(default)]
+    pub fail_on_connect_error: bool,
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub token_info_cache_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    #[serde(default)]
+    pub log_nethsm_api_requests: bool,
+    #[serde(default)]
+    pub tag_attributes: Vec<String>,
+    #[serde(default)]
+    pub vendor_attr_base: Option<u32>,
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+    #[serde(default)]
+    pub https_proxy: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    #[serde(default)]
+    pub proxy_ignore_env: bool,
+    #[serde(default)]
+    pub cache_capacity: Option<usize>,
+    #[serde(default)]
+    pub strict_digestinfo_validation: bool,
+    #[serde(default)]
+    pub min_pin_len: Option<u32>,
+    #[serde(default)]
+    pub max_pin_len: Option<u32>,
+    #[serde(default)]
+    pub disable_sha1: bool,
+    #[serde(default)]
+    pub disable_rsa_pkcs_decrypt: bool,
+    #[serde(default)]
+    pub prefetch_on_login: Option<bool>,
 }
 
 // An user
@@ -288,6 +435,59 @@ slots:
         ));
     }
 
+    #[test]
+    fn test_config_file_not_found() {
+        let path = std::env::temp_dir().join("p11nethsm-missing.conf");
+        let err = read_config_file(path.clone()).unwrap_err();
+        assert!(matches!(&err, ConfigError::FileNotFound { path: p } if *p == path));
+        assert!(err.to_string().contains("p11nethsm-missing.conf"));
+    }
+
+    fn config_error(config: &str) -> ConfigError {
+        merge_configurations([config.as_bytes()]).unwrap_err()
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let err = config_error("slots: [");
+        assert!(matches!(
+            err,
+            ConfigError::ParseError {
+                field: "configuration",
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("failed to parse `configuration`"));
+
+        let err = config_error("version: 2\nslots: []");
+        assert!(matches!(
+            err,
+            ConfigError::SchemaVersionUnsupported { found: 2, max: 1 }
+        ));
+        assert!(err.to_string().contains("version 2"));
+
+        let err = config_error("slots:\n  - label: \"\"\n    instances: []");
+        assert!(matches!(err, ConfigError::MissingField { field: "label" }));
+        assert!(err.to_string().contains("`label`"));
+
+        let err = config_error("slots:\n  - label: test\n    instances:\n      - url: \"\"");
+        assert!(matches!(err, ConfigError::MissingField { field: "url" }));
+        assert!(err.to_string().contains("`url`"));
+
+        let err =
+            config_error("slots:\n  - label: test\n    instances:\n      - url: localhost:8443");
+        assert!(
+            matches!(&err, ConfigError::InvalidUrl { field: "url", value } if value == "localhost:8443")
+        );
+        assert!(err.to_string().contains("localhost:8443"));
+
+        let config = "version: 1\nslots: []";
+        assert_eq!(
+            merge_configurations([config.as_bytes()]).unwrap().version,
+            Some(1)
+        );
+    }
+
     #[test]
     fn test_deserialize_password_env() {
         let config = r#"
@@ -333,11 +533,25 @@ password: ""
         assert_eq!(config.password, None);
     }
 
+    #[test]
+    fn test_deserialize_slot_log_level() {
+        let config = r#"
+label: test
+instances: []
+log_level: debug
+log_nethsm_api_requests: true
+"#;
+        let config: SlotConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.log_level, Some(LogLevel::Debug));
+        assert!(config.log_nethsm_api_requests);
+    }
+
     #[test]
     fn test_deserialize_full_example_config() {
         let config = include_str!("../../../p11nethsm.example.conf");
         assert_eq!(
             P11Config {
+                version: None,
                 enable_set_attribute_value: false,
                 syslog_socket: Some("/var/nethsm/log".into()),
                 syslog_facility: Some("user".into()),
@@ -375,6 +589,25 @@ password: ""
                         delay_seconds: 1
                     }),
                     timeout_seconds: Some(10),
+                    session_state_path: None,
+                    fail_on_connect_error: false,
+                    connect_timeout_ms: None,
+                    token_info_cache_ttl_secs: None,
+                    log_level: None,
+                    log_nethsm_api_requests: false,
+                    tag_attributes: vec![],
+                    vendor_attr_base: None,
+                    http_proxy: None,
+                    https_proxy: None,
+                    no_proxy: None,
+                    proxy_ignore_env: false,
+                    cache_capacity: None,
+                    strict_digestinfo_validation: false,
+                    min_pin_len: None,
+                    max_pin_len: None,
+                    disable_sha1: false,
+                    disable_rsa_pkcs_decrypt: false,
+                    prefetch_on_login: None,
                 }]
             },
             serde_yaml::from_str(config).unwrap()
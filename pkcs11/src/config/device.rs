@@ -1,22 +1,40 @@
 use std::{
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use nethsm_sdk_rs::apis::configuration::Configuration;
+use cryptoki_sys::{CK_MECHANISM_TYPE, CK_TOKEN_INFO};
 
-use crate::backend::db::Db;
+use log::{error, warn};
+use nethsm_sdk_rs::apis::{configuration::Configuration, default_api};
 
-use super::config_file::{RetryConfig, UserConfig};
+use crate::{
+    backend::{
+        auth::{Credentials, SharedCredentials},
+        db::Db,
+    },
+    defs::MECHANISM_LIST,
+};
+
+use super::config_file::{RetryConfig, SlotConfig, UserConfig};
+#[cfg(test)]
+use super::{
+    config_file::InstanceConfig,
+    initialization::{slot_db, validate_slot, InitializationError, DEFAULT_USER_AGENT},
+};
 
 // stores the global configuration of the module
 #[derive(Debug, Clone)]
 pub struct Device {
+    #[allow(dead_code)]
     pub log_file: Option<PathBuf>,
     pub slots: Vec<Arc<Slot>>,
     pub enable_set_attribute_value: bool,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct ClusterInstance {
     pub api_config: nethsm_sdk_rs::apis::configuration::Configuration,
@@ -26,14 +44,367 @@ pub struct ClusterInstance {
 pub struct Slot {
     pub label: String,
     pub retries: Option<RetryConfig>,
+    #[allow(dead_code)]
     pub description: Option<String>,
     pub instances: Vec<Configuration>,
-    pub operator: Option<UserConfig>,
-    pub administrator: Option<UserConfig>,
+    // shared with the sessions of the slot, C_SetPIN rotates the password for all of them
+    pub operator: Option<SharedCredentials>,
+    pub administrator: Option<SharedCredentials>,
     pub db: Arc<Mutex<Db>>,
+    pub session_state_path: Option<PathBuf>,
+    pub fail_on_connect_error: bool,
+    pub mechanisms: OnceLock<Vec<CK_MECHANISM_TYPE>>,
+    pub token_info_cache_ttl: Duration,
+    pub token_info: Arc<Mutex<TokenInfoCache>>,
+    // CKM_RSA_PKCS only signs a DigestInfo or a block of the modulus length
+    pub strict_digestinfo_validation: bool,
+    // C_Login refuses the PINs outside of this range without asking the NetHSM
+    pub min_pin_len: u32,
+    pub max_pin_len: u32,
+    // C_DigestInit refuses CKM_SHA_1
+    pub disable_sha1: bool,
+    // C_DecryptInit refuses CKM_RSA_PKCS
+    pub disable_rsa_pkcs_decrypt: bool,
+    // C_Login starts fetching the keys in the background
+    pub prefetch_on_login: bool,
+    // the background fetch started by the last login of a session of the slot
+    pub prefetch: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+// the token information is only fetched again from the NetHSM once it is older than the TTL
+#[derive(Debug, Default)]
+pub struct TokenInfoCache {
+    pub info: Option<CK_TOKEN_INFO>,
+    pub last_token_info_refresh: Option<Instant>,
+}
+
+pub const DEFAULT_TOKEN_INFO_CACHE_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MIN_PIN_LEN: u32 = 0;
+pub const DEFAULT_MAX_PIN_LEN: u32 = 255;
+
+pub fn token_info_cache_ttl(slot: &SlotConfig) -> Duration {
+    slot.token_info_cache_ttl_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_INFO_CACHE_TTL)
+}
+
+const DEFAULT_SLOT_URL: &str = "https://localhost:8443/api/v1";
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            label: "NetHSM".to_string(),
+            retries: None,
+            description: None,
+            instances: vec![Configuration {
+                base_path: DEFAULT_SLOT_URL.to_string(),
+                ..Default::default()
+            }],
+            operator: None,
+            administrator: None,
+            db: Arc::new(Mutex::new(Db::new())),
+            session_state_path: None,
+            fail_on_connect_error: false,
+            mechanisms: OnceLock::new(),
+            token_info_cache_ttl: DEFAULT_TOKEN_INFO_CACHE_TTL,
+            token_info: Default::default(),
+            strict_digestinfo_validation: false,
+            min_pin_len: DEFAULT_MIN_PIN_LEN,
+            max_pin_len: DEFAULT_MAX_PIN_LEN,
+            disable_sha1: false,
+            disable_rsa_pkcs_decrypt: false,
+            prefetch_on_login: true,
+            prefetch: Default::default(),
+        }
+    }
+}
+
+// Builds a slot without going through the configuration file, the instances use the default HTTP client
+// unless their configuration is given with api_config
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct SlotBuilder {
+    config: SlotConfig,
+    api_configs: Vec<Configuration>,
+}
+
+#[cfg(test)]
+impl SlotBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: SlotConfig {
+                label: "test".to_string(),
+                operator: None,
+                administrator: None,
+                description: None,
+                instances: vec![],
+                retries: None,
+                timeout_seconds: None,
+                session_state_path: None,
+                fail_on_connect_error: false,
+                connect_timeout_ms: None,
+                token_info_cache_ttl_secs: None,
+                log_level: None,
+                log_nethsm_api_requests: false,
+                tag_attributes: vec![],
+                vendor_attr_base: None,
+                http_proxy: None,
+                https_proxy: None,
+                no_proxy: None,
+                proxy_ignore_env: false,
+                cache_capacity: None,
+                strict_digestinfo_validation: false,
+                min_pin_len: None,
+                max_pin_len: None,
+                disable_sha1: false,
+                disable_rsa_pkcs_decrypt: false,
+                prefetch_on_login: None,
+            },
+            api_configs: vec![],
+        }
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.config.label = label.to_string();
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.config.description = Some(description.to_string());
+        self
+    }
+
+    // adds an instance to the slot
+    pub fn url(mut self, url: &str) -> Self {
+        self.config.instances.push(InstanceConfig {
+            url: url.to_string(),
+            danger_insecure_cert: false,
+            sha256_fingerprints: vec![],
+            max_idle_connections: None,
+        });
+        self
+    }
+
+    // adds an instance with a configuration built by the caller, it is used as it is
+    pub fn api_config(mut self, api_config: Configuration) -> Self {
+        self.api_configs.push(api_config);
+        self
+    }
+
+    pub fn operator_username(mut self, username: &str) -> Self {
+        user_entry(&mut self.config.operator).username = username.to_string();
+        self
+    }
+
+    pub fn operator_password(mut self, password: &str) -> Self {
+        user_entry(&mut self.config.operator).password = Some(password.to_string());
+        self
+    }
+
+    pub fn administrator_username(mut self, username: &str) -> Self {
+        user_entry(&mut self.config.administrator).username = username.to_string();
+        self
+    }
+
+    pub fn administrator_password(mut self, password: &str) -> Self {
+        user_entry(&mut self.config.administrator).password = Some(password.to_string());
+        self
+    }
+
+    pub fn retries(mut self, retries: RetryConfig) -> Self {
+        self.config.retries = Some(retries);
+        self
+    }
+
+    pub fn session_state_path(mut self, path: PathBuf) -> Self {
+        self.config.session_state_path = Some(path);
+        self
+    }
+
+    pub fn strict_digestinfo_validation(mut self, strict: bool) -> Self {
+        self.config.strict_digestinfo_validation = strict;
+        self
+    }
+
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.cache_capacity = Some(capacity);
+        self
+    }
+
+    pub fn tag_attributes(mut self, tags: &[&str]) -> Self {
+        self.config.tag_attributes = tags.iter().map(|tag| tag.to_string()).collect();
+        self
+    }
+
+    pub fn token_info_cache_ttl_secs(mut self, ttl: u64) -> Self {
+        self.config.token_info_cache_ttl_secs = Some(ttl);
+        self
+    }
+
+    pub fn pin_len(mut self, min: u32, max: u32) -> Self {
+        self.config.min_pin_len = Some(min);
+        self.config.max_pin_len = Some(max);
+        self
+    }
+
+    pub fn disable_sha1(mut self, disable: bool) -> Self {
+        self.config.disable_sha1 = disable;
+        self
+    }
+
+    pub fn disable_rsa_pkcs_decrypt(mut self, disable: bool) -> Self {
+        self.config.disable_rsa_pkcs_decrypt = disable;
+        self
+    }
+
+    pub fn prefetch_on_login(mut self, prefetch: bool) -> Self {
+        self.config.prefetch_on_login = Some(prefetch);
+        self
+    }
+
+    pub fn fail_on_connect_error(mut self, fail_on_connect_error: bool) -> Self {
+        self.config.fail_on_connect_error = fail_on_connect_error;
+        self
+    }
+
+    pub fn build(self) -> Result<Slot, InitializationError> {
+        validate_slot(&self.config)?;
+        let token_info_cache_ttl = token_info_cache_ttl(&self.config);
+        let db = slot_db(&self.config, 0);
+
+        let default_user = self
+            .config
+            .operator
+            .as_ref()
+            .or(self.config.administrator.as_ref());
+
+        let instances = self
+            .config
+            .instances
+            .iter()
+            .map(|instance| Configuration {
+                base_path: instance.url.clone(),
+                basic_auth: default_user.map(|user| (user.username.clone(), user.password.clone())),
+                user_agent: Some(DEFAULT_USER_AGENT.to_string()),
+                ..Default::default()
+            })
+            .chain(self.api_configs)
+            .collect();
+
+        Ok(Slot {
+            label: self.config.label,
+            retries: self.config.retries,
+            description: self.config.description,
+            instances,
+            operator: shared_credentials(&self.config.operator),
+            administrator: shared_credentials(&self.config.administrator),
+            db: Arc::new(Mutex::new(db)),
+            session_state_path: self.config.session_state_path,
+            fail_on_connect_error: self.config.fail_on_connect_error,
+            mechanisms: OnceLock::new(),
+            token_info_cache_ttl,
+            token_info: Default::default(),
+            strict_digestinfo_validation: self.config.strict_digestinfo_validation,
+            min_pin_len: self.config.min_pin_len.unwrap_or(DEFAULT_MIN_PIN_LEN),
+            max_pin_len: self.config.max_pin_len.unwrap_or(DEFAULT_MAX_PIN_LEN),
+            disable_sha1: self.config.disable_sha1,
+            disable_rsa_pkcs_decrypt: self.config.disable_rsa_pkcs_decrypt,
+            prefetch_on_login: self.config.prefetch_on_login.unwrap_or(true),
+            prefetch: Default::default(),
+        })
+    }
+}
+
+pub fn shared_credentials(user: &Option<UserConfig>) -> Option<SharedCredentials> {
+    user.as_ref()
+        .map(|user| Credentials::from_config(user).shared())
+}
+
+#[cfg(test)]
+fn user_entry(user: &mut Option<UserConfig>) -> &mut UserConfig {
+    user.get_or_insert_with(|| UserConfig {
+        username: String::new(),
+        password: None,
+    })
+}
+
+impl Device {
+    // check that the slots can reach their NetHSM, only the slots with fail_on_connect_error make it fail
+    pub fn check_connections(&self) -> Result<(), String> {
+        for slot in self.slots.iter() {
+            if let Err(err) = slot.test_connection() {
+                if slot.fail_on_connect_error {
+                    error!("{}", err);
+                    return Err(err);
+                }
+                warn!(
+                    "{}, the connection will be retried on the first operation",
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Slot {
+    // A slot on a single instance whose configuration, HTTP client included, is built by the
+    // caller. Its credentials are the ones of the operator.
+    #[cfg(test)]
+    pub fn with_api_config(api_config: Configuration) -> Self {
+        let operator = api_config.basic_auth.as_ref().map(|(username, password)| {
+            Credentials::new(
+                username.clone(),
+                password.clone().unwrap_or_default().into(),
+            )
+            .shared()
+        });
+
+        Self {
+            instances: vec![api_config],
+            operator,
+            ..Default::default()
+        }
+    }
+
+    // the configuration of the first instance, None for a slot without instance
+    #[cfg(test)]
+    pub fn api_config(&self) -> Option<&Configuration> {
+        self.instances.first()
+    }
+
+    // check that at least one instance of the slot is alive
+    pub fn test_connection(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        for instance in self.instances.iter() {
+            match default_api::health_alive_get(instance) {
+                Ok(_) => return Ok(()),
+                Err(err) => errors.push(format!(
+                    "{}: {}",
+                    instance.base_path,
+                    crate::backend::Error::from(err)
+                )),
+            }
+        }
+
+        Err(format!(
+            "Slot {} failed to connect to the NetHSM ({})",
+            self.label,
+            errors.join(", ")
+        ))
+    }
+
+    // all the NetHSM models support the same mechanisms, the list is computed once per slot
+    pub fn mechanism_list(&self) -> &[CK_MECHANISM_TYPE] {
+        self.mechanisms.get_or_init(|| {
+            MECHANISM_LIST
+                .iter()
+                .map(|mechanism| mechanism.ck_type())
+                .collect()
+        })
+    }
+
     // the user is connected if the basic auth is filled with an username and a password, otherwise the user will have to login
     pub fn is_connected(&self) -> bool {
         self.instances
@@ -52,3 +423,187 @@ impl Slot {
             .unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{auth::read_credentials, session::tests::mock_nethsm};
+
+    use super::*;
+
+    fn slot_with_url(url: String, fail_on_connect_error: bool) -> Arc<Slot> {
+        Arc::new(
+            SlotBuilder::new()
+                .url(&url)
+                .operator_username("operator")
+                .fail_on_connect_error(fail_on_connect_error)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn mock_server() -> String {
+        mock_nethsm(0).0
+    }
+
+    // nothing listens on the port 1
+    const CLOSED_URL: &str = "http://127.0.0.1:1/api/v1";
+
+    #[test]
+    fn test_connection_up() {
+        let slot = slot_with_url(mock_server(), true);
+        assert!(slot.test_connection().is_ok());
+
+        let device = Device {
+            log_file: None,
+            slots: vec![slot_with_url(mock_server(), true)],
+            enable_set_attribute_value: false,
+        };
+        assert!(device.check_connections().is_ok());
+    }
+
+    #[test]
+    fn test_connection_down() {
+        let err = slot_with_url(CLOSED_URL.to_string(), false)
+            .test_connection()
+            .unwrap_err();
+        assert!(err.contains(CLOSED_URL));
+
+        let lazy = Device {
+            log_file: None,
+            slots: vec![slot_with_url(CLOSED_URL.to_string(), false)],
+            enable_set_attribute_value: false,
+        };
+        assert!(lazy.check_connections().is_ok());
+
+        let strict = Device {
+            log_file: None,
+            slots: vec![slot_with_url(CLOSED_URL.to_string(), true)],
+            enable_set_attribute_value: false,
+        };
+        assert!(strict.check_connections().is_err());
+    }
+
+    #[test]
+    fn test_slot_builder() {
+        let slot = SlotBuilder::new()
+            .label("builder")
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("p4ss")
+            .administrator_username("admin")
+            .administrator_password("admin_p4ss")
+            .description("built in a test")
+            .retries(RetryConfig {
+                count: 2,
+                delay_seconds: 0,
+            })
+            .session_state_path("/tmp/sessions.json".into())
+            .build()
+            .unwrap();
+
+        assert_eq!(slot.label, "builder");
+        assert_eq!(slot.instances.len(), 1);
+        assert_eq!(slot.instances[0].base_path, "https://localhost:8443/api/v1");
+        assert_eq!(
+            slot.instances[0].basic_auth,
+            Some(("operator".to_string(), Some("p4ss".to_string())))
+        );
+        assert_eq!(
+            read_credentials(slot.administrator.as_ref().unwrap())
+                .password
+                .as_str(),
+            "admin_p4ss"
+        );
+        assert_eq!(slot.description, Some("built in a test".to_string()));
+        assert_eq!(slot.retries.unwrap().count, 2);
+        assert_eq!(slot.session_state_path, Some("/tmp/sessions.json".into()));
+        assert!(slot.is_connected());
+    }
+
+    #[test]
+    fn test_slot_builder_no_user() {
+        let err = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_password("p4ss")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, InitializationError::NoUser(label) if label == "test"));
+
+        // without instance there is nothing to connect to
+        assert!(SlotBuilder::new().build().is_ok());
+    }
+
+    #[test]
+    fn test_slot_with_api_config() {
+        let api_config = Configuration {
+            base_path: mock_server(),
+            user_agent: Some("custom-agent".to_string()),
+            basic_auth: Some(("operator".to_string(), Some("password".to_string()))),
+            ..Default::default()
+        };
+        let slot = Slot::with_api_config(api_config.clone());
+        assert_eq!(slot.instances.len(), 1);
+        let config = slot.api_config().unwrap();
+        assert_eq!(config.base_path, api_config.base_path);
+        assert_eq!(config.user_agent, Some("custom-agent".to_string()));
+        assert_eq!(
+            read_credentials(slot.operator.as_ref().unwrap()).username,
+            "operator"
+        );
+        assert!(slot.is_connected());
+        assert!(slot.test_connection().is_ok());
+
+        // the configuration can be reused for another slot
+        let copy = Slot::with_api_config(slot.api_config().unwrap().clone());
+        assert_eq!(copy.instances[0].base_path, api_config.base_path);
+
+        assert!(SlotBuilder::new().build().unwrap().api_config().is_none());
+    }
+
+    #[test]
+    fn test_slot_builder_api_config() {
+        let (url, requests) = mock_nethsm(0);
+        let api_config = Configuration {
+            base_path: url.clone(),
+            user_agent: Some("custom-agent".to_string()),
+            basic_auth: Some(("operator".to_string(), Some("password".to_string()))),
+            ..Default::default()
+        };
+        let slot = SlotBuilder::new()
+            .url("https://localhost:8443/api/v1")
+            .operator_username("operator")
+            .operator_password("password")
+            .api_config(api_config)
+            .build()
+            .unwrap();
+
+        // the configuration given is kept as it is, after the instances built from urls
+        assert_eq!(slot.instances.len(), 2);
+        assert_eq!(slot.instances[0].base_path, "https://localhost:8443/api/v1");
+        assert_eq!(
+            slot.instances[0].user_agent,
+            Some(DEFAULT_USER_AGENT.to_string())
+        );
+        assert_eq!(slot.instances[1].base_path, url);
+        assert_eq!(
+            slot.instances[1].user_agent,
+            Some("custom-agent".to_string())
+        );
+
+        // the requests of the slot go through the configuration given
+        let keys = default_api::keys_get(&slot.instances[1], None).unwrap();
+        assert!(keys.entity.is_empty());
+        assert_eq!(
+            crate::backend::session::tests::count_requests(&requests, "/api/v1/keys"),
+            1
+        );
+    }
+
+    #[test]
+    fn test_slot_default() {
+        let slot = Slot::default();
+        assert_eq!(slot.instances.len(), 1);
+        assert_eq!(slot.instances[0].base_path, DEFAULT_SLOT_URL);
+        assert!(!slot.is_connected());
+    }
+}
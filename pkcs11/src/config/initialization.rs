@@ -1,26 +1,43 @@
 use std::{
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
     thread::available_parallelism,
     time::Duration,
 };
 
 use super::{
     config_file::{config_files, ConfigError, SlotConfig},
-    device::{Device, Slot},
+    device::{
+        shared_credentials, token_info_cache_ttl, Device, Slot, DEFAULT_MAX_PIN_LEN,
+        DEFAULT_MIN_PIN_LEN,
+    },
 };
+use crate::backend::{
+    db::{Db, TagAttributes, DEFAULT_CACHE_CAPACITY},
+    session::MAX_SLOTS,
+};
+use cryptoki_sys::{CK_ATTRIBUTE_TYPE, CK_SLOT_ID};
 use log::{debug, error, info, trace};
 use nethsm_sdk_rs::ureq;
 use rustls::client::ServerCertVerifier;
 use sha2::Digest;
 
-const DEFAULT_USER_AGENT: &str = "pkcs11-rs/0.1.0";
+pub const DEFAULT_USER_AGENT: &str = "pkcs11-rs/0.1.0";
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum InitializationError {
     Config(crate::config::config_file::ConfigError),
     NoCerts,
     NoUser(String),
+    // the tag attributes of the slot overlap the other vendor attributes of the module
+    VendorAttrOverlap(String),
+    // the proxy of the slot is not a valid URL
+    InvalidProxy(String),
+    // min_pin_len is greater than max_pin_len
+    PinLenRange(String),
+    // the object handles have room for MAX_SLOTS slots
+    TooManySlots(usize),
 }
 
 pub fn initialize_with_configs(
@@ -42,10 +59,13 @@ pub fn initialize_with_configs(
     let (config, _) = config_res?;
 
     info!("Loaded configuration with {} slots", config.slots.len());
+    if config.slots.len() > MAX_SLOTS {
+        return Err(InitializationError::TooManySlots(config.slots.len()));
+    }
     // initialize the clients
     let mut slots = vec![];
-    for slot in config.slots.iter() {
-        slots.push(Arc::new(slot_from_config(slot)?));
+    for (slot_id, slot) in config.slots.iter().enumerate() {
+        slots.push(Arc::new(slot_from_config(slot, slot_id as CK_SLOT_ID)?));
     }
     Ok(Device {
         slots,
@@ -104,7 +124,47 @@ impl ServerCertVerifier for FingerprintVerifier {
     }
 }
 
-fn slot_from_config(slot: &SlotConfig) -> Result<Slot, InitializationError> {
+pub fn tag_attributes(slot: &SlotConfig) -> TagAttributes {
+    TagAttributes::new(
+        slot.tag_attributes.clone(),
+        slot.vendor_attr_base.map(CK_ATTRIBUTE_TYPE::from),
+    )
+}
+
+pub fn slot_db(slot: &SlotConfig, slot_id: CK_SLOT_ID) -> Db {
+    Db::for_slot(slot_id)
+        .with_tag_attributes(tag_attributes(slot))
+        .with_cache_capacity(slot.cache_capacity.unwrap_or(DEFAULT_CACHE_CAPACITY))
+}
+
+// checks of a slot configuration that don't need to connect to the NetHSM
+pub fn validate_slot(slot: &SlotConfig) -> Result<(), InitializationError> {
+    let has_user = [slot.operator.as_ref(), slot.administrator.as_ref()]
+        .into_iter()
+        .flatten()
+        .any(|user| !user.username.is_empty());
+
+    // a slot without instance can't connect to anything, it doesn't need a user
+    if !slot.instances.is_empty() && !has_user {
+        return Err(InitializationError::NoUser(slot.label.clone()));
+    }
+
+    if tag_attributes(slot).overlaps_usage_count() {
+        return Err(InitializationError::VendorAttrOverlap(slot.label.clone()));
+    }
+
+    if slot.min_pin_len.unwrap_or(DEFAULT_MIN_PIN_LEN)
+        > slot.max_pin_len.unwrap_or(DEFAULT_MAX_PIN_LEN)
+    {
+        return Err(InitializationError::PinLenRange(slot.label.clone()));
+    }
+
+    Ok(())
+}
+
+fn slot_from_config(slot: &SlotConfig, slot_id: CK_SLOT_ID) -> Result<Slot, InitializationError> {
+    validate_slot(slot)?;
+
     let mut instances = vec![];
 
     let default_user = slot
@@ -179,6 +239,16 @@ fn slot_from_config(slot: &SlotConfig) -> Result<Slot, InitializationError> {
                 .timeout_connect(Duration::from_secs(10));
         }
 
+        if let Some(t) = slot.connect_timeout_ms {
+            builder = builder.timeout_connect(Duration::from_millis(t));
+        }
+
+        builder = configure_proxy(builder, slot, &instance.url)?;
+
+        if slot.log_nethsm_api_requests {
+            builder = builder.middleware(log_api_request);
+        }
+
         let agent = builder.build();
 
         let api_config = nethsm_sdk_rs::apis::configuration::Configuration {
@@ -195,13 +265,115 @@ fn slot_from_config(slot: &SlotConfig) -> Result<Slot, InitializationError> {
         description: slot.description.clone(),
         label: slot.label.clone(),
         instances,
-        administrator: slot.administrator.clone(),
-        operator: slot.operator.clone(),
+        administrator: shared_credentials(&slot.administrator),
+        operator: shared_credentials(&slot.operator),
         retries: slot.retries,
-        db: Arc::new(Mutex::new(crate::backend::db::Db::new())),
+        db: Arc::new(Mutex::new(slot_db(slot, slot_id))),
+        session_state_path: slot.session_state_path.clone(),
+        fail_on_connect_error: slot.fail_on_connect_error,
+        mechanisms: OnceLock::new(),
+        token_info_cache_ttl: token_info_cache_ttl(slot),
+        token_info: Default::default(),
+        strict_digestinfo_validation: slot.strict_digestinfo_validation,
+        min_pin_len: slot.min_pin_len.unwrap_or(DEFAULT_MIN_PIN_LEN),
+        max_pin_len: slot.max_pin_len.unwrap_or(DEFAULT_MAX_PIN_LEN),
+        disable_sha1: slot.disable_sha1,
+        disable_rsa_pkcs_decrypt: slot.disable_rsa_pkcs_decrypt,
+        prefetch_on_login: slot.prefetch_on_login.unwrap_or(true),
+        prefetch: Default::default(),
     })
 }
 
+// The proxy of an instance comes from http_proxy or https_proxy, depending on the scheme of its
+// URL, or from the HTTP_PROXY and HTTPS_PROXY variables unless proxy_ignore_env is set.
+// The hosts matching no_proxy (or NO_PROXY) are reached directly.
+fn configure_proxy(
+    builder: ureq::AgentBuilder,
+    slot: &SlotConfig,
+    url: &str,
+) -> Result<ureq::AgentBuilder, InitializationError> {
+    let env = |name: &str| {
+        if slot.proxy_ignore_env {
+            return None;
+        }
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+
+    // the environment is read here, ureq would not apply no_proxy
+    let builder = builder.try_proxy_from_env(false);
+
+    let no_proxy = slot.no_proxy.clone().or_else(|| env("NO_PROXY"));
+    if no_proxy.is_some_and(|no_proxy| proxy_bypassed(&no_proxy, url_host(url))) {
+        debug!("Connecting to {} without proxy", url);
+        return Ok(builder);
+    }
+
+    let proxy = if url.starts_with("https://") {
+        slot.https_proxy.clone().or_else(|| env("HTTPS_PROXY"))
+    } else {
+        slot.http_proxy.clone().or_else(|| env("HTTP_PROXY"))
+    };
+    let Some(proxy) = proxy else {
+        return Ok(builder);
+    };
+
+    // the proxy URL can hold credentials, it is not logged
+    let proxy = ureq::Proxy::new(proxy).map_err(|err| {
+        error!("Invalid proxy for the slot {}: {}", slot.label, err);
+        InitializationError::InvalidProxy(slot.label.clone())
+    })?;
+    Ok(builder.proxy(proxy))
+}
+
+// the host of an instance URL, without the scheme, the port and the path
+fn url_host(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split('/').next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if let Some(ipv6) = host.strip_prefix('[') {
+        return ipv6.split(']').next().unwrap_or_default();
+    }
+    host.split(':').next().unwrap_or_default()
+}
+
+// no_proxy is a comma separated list of hosts or domains, `*` matches all the hosts
+fn proxy_bypassed(no_proxy: &str, host: &str) -> bool {
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            let domain = entry.trim_start_matches('.');
+            entry == "*"
+                || host.eq_ignore_ascii_case(domain)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+        })
+}
+
+// logs the URL and the HTTP status of every request sent to the NetHSM
+fn log_api_request(
+    request: ureq::Request,
+    next: ureq::MiddlewareNext,
+) -> Result<ureq::Response, ureq::Error> {
+    let method = request.method().to_string();
+    let url = request.url().to_string();
+
+    let result = next.handle(request);
+    match &result {
+        Ok(response) => debug!("{} {}: {}", method, url, response.status()),
+        Err(ureq::Error::Status(status, _)) => debug!("{} {}: {}", method, url, status),
+        Err(err) => debug!("{} {} failed: {}", method, url, err),
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -264,4 +436,110 @@ bad_yml
         let configs_bad_yml = vec![(config_bad_yml_content.into(), config_path.into())];
         assert!(initialize_with_configs(Ok(configs_bad_yml)).is_err());
     }
+
+    #[test]
+    fn test_too_many_slots() {
+        let slot = r#"
+  - label: slot
+    operator:
+      username: "operator"
+    instances:
+      - url: "https://localhost:8443/api/v1"
+"#;
+        let config = format!("slots:{}", slot.repeat(MAX_SLOTS + 1));
+        let configs = vec![(config.into_bytes(), "/path/to/config.conf".into())];
+        assert!(matches!(
+            initialize_with_configs(Ok(configs)),
+            Err(InitializationError::TooManySlots(count)) if count == MAX_SLOTS + 1
+        ));
+    }
+
+    #[test]
+    fn test_proxy_bypassed() {
+        assert_eq!(
+            url_host("https://nethsm.example.com:8443/api/v1"),
+            "nethsm.example.com"
+        );
+        assert_eq!(url_host("http://[::1]:8443/api/v1"), "::1");
+        assert_eq!(url_host("https://user@10.0.0.1/api/v1"), "10.0.0.1");
+
+        assert!(proxy_bypassed(
+            "localhost, .example.com",
+            "nethsm.example.com"
+        ));
+        assert!(proxy_bypassed("example.com", "example.com"));
+        assert!(proxy_bypassed("*", "nethsm.example.com"));
+        assert!(!proxy_bypassed("example.com", "nethsm.example.org"));
+        assert!(!proxy_bypassed("ample.com", "example.com"));
+        assert!(!proxy_bypassed("", "example.com"));
+    }
+
+    #[test]
+    fn test_proxy_connect() {
+        let (url, requests) = crate::backend::session::tests::mock_nethsm(0);
+        let address = url
+            .trim_start_matches("http://")
+            .trim_end_matches("/api/v1");
+        let proxy = format!("http://user:secret@{}", address);
+
+        let config = format!(
+            r#"
+label: proxied
+operator:
+  username: operator
+  password: password
+instances:
+  - url: "https://nethsm.example.com:8443/api/v1"
+    danger_insecure_cert: true
+https_proxy: "{proxy}"
+proxy_ignore_env: true
+"#
+        );
+        let config: SlotConfig = serde_yaml::from_str(&config).unwrap();
+        let slot = slot_from_config(&config, 0).unwrap();
+
+        assert!(nethsm_sdk_rs::apis::default_api::health_alive_get(&slot.instances[0]).is_err());
+
+        // the tunnel is opened with the credentials user:secret
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["nethsm.example.com:8443 basic dXNlcjpzZWNyZXQ="]
+        );
+    }
+
+    #[test]
+    fn test_invalid_proxy() {
+        let config = r#"
+label: proxied
+operator:
+  username: operator
+instances:
+  - url: "https://nethsm.example.com:8443/api/v1"
+    danger_insecure_cert: true
+https_proxy: "ftp://proxy:8080"
+"#;
+        let config: SlotConfig = serde_yaml::from_str(config).unwrap();
+        assert!(matches!(
+            slot_from_config(&config, 0),
+            Err(InitializationError::InvalidProxy(label)) if label == "proxied"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_pin_len_range() {
+        let config = r#"
+label: pins
+operator:
+  username: operator
+instances:
+  - url: "https://nethsm.example.com:8443/api/v1"
+min_pin_len: 10
+max_pin_len: 8
+"#;
+        let config: SlotConfig = serde_yaml::from_str(config).unwrap();
+        assert!(matches!(
+            validate_slot(&config),
+            Err(InitializationError::PinLenRange(label)) if label == "pins"
+        ));
+    }
 }
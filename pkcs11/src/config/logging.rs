@@ -40,6 +40,27 @@ impl log::Log for MultiLog {
     }
 }
 
+// the most detailed of the levels set globally and for the slots
+fn configured_level(config: &P11Config) -> Option<LevelFilter> {
+    config
+        .slots
+        .iter()
+        .filter_map(|slot| slot.log_level)
+        .chain(config.log_level)
+        .map(LevelFilter::from)
+        .max()
+}
+
+// the level of the configuration is the default, the filter of env (RUST_LOG) still overrides it
+fn env_logger_builder(level: Option<LevelFilter>, env: env_logger::Env) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    builder.parse_env(env);
+    builder
+}
+
 // output to stdout, a file or syslog
 pub fn configure_logger(config: &Result<(P11Config, Vec<PathBuf>), InitializationError>) {
     let Ok((config, file_paths)) = config else {
@@ -55,12 +76,15 @@ pub fn configure_logger(config: &Result<(P11Config, Vec<PathBuf>), Initializatio
         let unix_logger = syslog::unix(formatter).map(BasicLogger::new).ok();
         let env_logger = env_logger::Builder::from_default_env().build();
 
-        log::set_boxed_logger(Box::new(MultiLog {
+        // the logger of the application is kept
+        if log::set_boxed_logger(Box::new(MultiLog {
             syslog_logger: unix_logger,
             env_logger: Some(env_logger),
         }))
-        .ok();
-        log::set_max_level(log::LevelFilter::Info);
+        .is_ok()
+        {
+            log::set_max_level(log::LevelFilter::Info);
+        }
         return;
     };
 
@@ -162,8 +186,10 @@ pub fn configure_logger(config: &Result<(P11Config, Vec<PathBuf>), Initializatio
         }
     }
 
+    let level = configured_level(config);
+
     if use_file {
-        let mut builder = env_logger::Builder::from_default_env();
+        let mut builder = env_logger_builder(level, env_logger::Env::default());
 
         let path = &config.log_file.as_deref().unwrap_or("-".as_ref());
 
@@ -194,24 +220,24 @@ pub fn configure_logger(config: &Result<(P11Config, Vec<PathBuf>), Initializatio
     }
 
     // RUST_LOG must override the default filter
-    match (env_logger.as_ref(), config.log_level.as_ref()) {
-        (Some(logger), Some(config_filter)) => {
-            log::set_max_level(logger.filter().min(LevelFilter::from(*config_filter)));
-        }
-        (Some(logger), _) if logger.filter() > LevelFilter::Error => {
-            log::set_max_level(logger.filter());
-        }
-        (None, Some(level)) => {
-            log::set_max_level((*level).into());
-        }
-        _ => {}
-    }
+    let max_level = match (env_logger.as_ref(), level) {
+        (Some(logger), Some(config_filter)) => Some(logger.filter().min(config_filter)),
+        (Some(logger), _) if logger.filter() > LevelFilter::Error => Some(logger.filter()),
+        (None, Some(level)) => Some(level),
+        _ => None,
+    };
 
-    log::set_boxed_logger(Box::new(MultiLog {
+    // the logger of the application is kept, with its level
+    if log::set_boxed_logger(Box::new(MultiLog {
         syslog_logger,
         env_logger,
     }))
-    .ok();
+    .is_ok()
+    {
+        if let Some(level) = max_level {
+            log::set_max_level(level);
+        }
+    }
 
     for m in info_messages {
         info!("{m}");
@@ -220,3 +246,73 @@ pub fn configure_logger(config: &Result<(P11Config, Vec<PathBuf>), Initializatio
         warn!("{m}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::config::config_file::{LogLevel, SlotConfig};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn slot(log_level: Option<LogLevel>) -> SlotConfig {
+        serde_yaml::from_str::<SlotConfig>("label: test\ninstances: []")
+            .map(|slot| SlotConfig { log_level, ..slot })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_configured_level() {
+        let mut config = P11Config::default();
+        assert_eq!(configured_level(&config), None);
+
+        config.log_level = Some(LogLevel::Warn);
+        config.slots = vec![slot(None), slot(Some(LogLevel::Debug))];
+        assert_eq!(configured_level(&config), Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn test_env_logger_level() {
+        // the tests run in parallel, RUST_LOG isn't changed, a variable that is never set is read
+        let output = Output::default();
+        let env = env_logger::Env::new().filter("NETHSM_PKCS11_TEST_UNSET_LOG");
+        let logger = env_logger_builder(Some(LevelFilter::Debug), env)
+            .target(env_logger::Target::Pipe(Box::new(output.clone())))
+            .build();
+        assert_eq!(logger.filter(), LevelFilter::Debug);
+
+        for (level, message) in [
+            (log::Level::Debug, "debug message"),
+            (log::Level::Trace, "trace message"),
+        ] {
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("debug message"));
+        assert!(!output.contains("trace message"));
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::HashMap;
-use std::sync::{atomic::AtomicBool, Mutex, Once, OnceLock, RwLock};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Once, OnceLock, RwLock,
+};
 
-use crate::backend::events::EventsManager;
+use crate::backend::{events::EventsManager, locking::AppMutex};
 
 use crate::{api, backend::session::SessionManager, config::device::Device};
 use cryptoki_sys::{CK_FUNCTION_LIST, CK_SLOT_ID, CK_VERSION};
@@ -15,6 +18,17 @@ pub const DEVICE_VERSION: CK_VERSION = CK_VERSION {
 /// A separate DEVICE_INIT is required because `OnceLock::get_or_try_insert` is unstable
 pub static DEVICE_INIT: Once = Once::new();
 pub static DEVICE: OnceLock<Device> = OnceLock::new();
+// DEVICE can't be emptied, C_Finalize only clears this flag
+pub static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// the device, if C_Initialize was called and C_Finalize wasn't called since
+pub fn initialized_device() -> Option<&'static Device> {
+    if INITIALIZED.load(Ordering::SeqCst) {
+        DEVICE.get()
+    } else {
+        None
+    }
+}
 
 lazy_static! {
     pub static ref SESSION_MANAGER : Mutex<SessionManager> =  Mutex::new(SessionManager::new());
@@ -30,6 +44,9 @@ lazy_static! {
 // Storage of events
 pub static EVENTS_MANAGER: RwLock<EventsManager> = RwLock::new(EventsManager::new());
 
+// Mutex of the application, when it gave its own locking functions to C_Initialize
+pub static APP_MUTEX: RwLock<Option<Arc<AppMutex>>> = RwLock::new(None);
+
 // If the calling application allows threads to be used
 pub static THREADS_ALLOWED: AtomicBool = AtomicBool::new(true);
 
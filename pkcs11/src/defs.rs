@@ -1,51 +1,19 @@
-use cryptoki_sys::{CK_VERSION, CRYPTOKI_VERSION_MAJOR};
+use cryptoki_sys::CK_VERSION;
 
 use crate::backend::mechanism::Mechanism;
 
-pub const CRYPTOKI_VERSION: cryptoki_sys::CK_VERSION = cryptoki_sys::CK_VERSION {
-    major: CRYPTOKI_VERSION_MAJOR,
-    minor: cryptoki_sys::CRYPTOKI_VERSION_MINOR,
+// the module implements PKCS#11 2.40
+pub const CRYPTOKI_VERSION: CK_VERSION = CK_VERSION {
+    major: 2,
+    minor: 40,
 };
 
-#[allow(clippy::result_unit_err)]
-const fn parse_u8(s: &str) -> Result<u8, ()> {
-    let mut idx = 0;
-    let mut acc = 0u8;
-    while idx < s.len() {
-        let Some(temp1) = acc.checked_mul(10u8) else {
-            return Err(());
-        };
-        let Some(digit) = s.as_bytes()[idx].checked_sub(b'0') else {
-            return Err(());
-        };
-        if digit >= 10 {
-            return Err(());
-        }
-        let Some(temp2) = temp1.checked_add(digit) else {
-            return Err(());
-        };
-        acc = temp2;
-        idx += 1;
-    }
-    Ok(acc)
-}
-
-pub const LIB_VERSION_MINOR: u8 = {
-    match parse_u8(env!("CARGO_PKG_VERSION_MINOR")) {
-        Ok(v) => v,
-        Err(()) => panic!("Failed to parse minor version"),
-    }
-};
-pub const LIB_VERSION_MAJOR: u8 = {
-    match parse_u8(env!("CARGO_PKG_VERSION_MAJOR")) {
-        Ok(v) => v,
-        Err(()) => panic!("Failed to parse major version"),
-    }
-};
+// LIBRARY_VERSION_MAJOR and LIBRARY_VERSION_MINOR, parsed from the crate version by build.rs
+include!(concat!(env!("OUT_DIR"), "/version.rs"));
 
 pub const LIB_VERSION: CK_VERSION = CK_VERSION {
-    major: LIB_VERSION_MAJOR,
-    minor: LIB_VERSION_MINOR,
+    major: LIBRARY_VERSION_MAJOR,
+    minor: LIBRARY_VERSION_MINOR,
 };
 pub const LIB_DESCRIPTION: &str = {
     let v = "Nitrokey NetHsm PKCS#11 library";
@@ -58,7 +26,7 @@ pub const LIB_MANUFACTURER: &str = "Nitrokey";
 pub const DEFAULT_FIRMWARE_VERSION: CK_VERSION = CK_VERSION { major: 0, minor: 1 };
 pub const DEFAULT_HARDWARE_VERSION: CK_VERSION = CK_VERSION { major: 0, minor: 1 };
 
-pub const MECHANISM_LIST: [Mechanism; 27] = [
+pub const MECHANISM_LIST: [Mechanism; 34] = [
     Mechanism::AesCbc(None),
     Mechanism::RsaX509,
     Mechanism::RsaPkcs(None),
@@ -81,6 +49,13 @@ pub const MECHANISM_LIST: [Mechanism; 27] = [
     Mechanism::Ecdsa(Some(crate::backend::mechanism::MechDigest::Sha256)),
     Mechanism::Ecdsa(Some(crate::backend::mechanism::MechDigest::Sha384)),
     Mechanism::Ecdsa(Some(crate::backend::mechanism::MechDigest::Sha512)),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Md5),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Sha1),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Sha224),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Sha256),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Sha384),
+    Mechanism::Hmac(crate::backend::mechanism::MechDigest::Sha512),
+    Mechanism::AesCmac,
     Mechanism::GenerateAes,
     Mechanism::GenerateRsa,
     Mechanism::GenerateEc,
@@ -95,11 +70,11 @@ mod tests {
     #[test]
     fn version_parsing() {
         assert_eq!(
-            LIB_VERSION_MAJOR,
+            LIBRARY_VERSION_MAJOR,
             env!("CARGO_PKG_VERSION_MAJOR").parse::<u8>().unwrap()
         );
         assert_eq!(
-            LIB_VERSION_MINOR,
+            LIBRARY_VERSION_MINOR,
             env!("CARGO_PKG_VERSION_MINOR").parse::<u8>().unwrap()
         );
     }
@@ -0,0 +1,76 @@
+// Entry points of the fuzz targets in fuzz/, they turn the fuzz data into the arguments of the backend
+
+use std::sync::Arc;
+
+use cryptoki_sys::{
+    CKA_CLASS, CKA_ID, CKA_KEY_TYPE, CKA_LABEL, CKF_SERIAL_SESSION, CK_ATTRIBUTE,
+    CK_ATTRIBUTE_TYPE, CK_ULONG,
+};
+
+use crate::{
+    backend::{db::attr::CkRawAttrTemplate, session::Session},
+    config::device::Slot,
+};
+
+// the attributes read by the search, any other selector is used as the type itself
+const ATTRIBUTE_TYPES: [CK_ATTRIBUTE_TYPE; 4] = [CKA_ID, CKA_LABEL, CKA_CLASS, CKA_KEY_TYPE];
+
+// Each attribute is a type selector, a value kind, a length and the value. The values point into
+// the fuzz data, they are only read by the search.
+fn template(mut data: &[u8]) -> Vec<CK_ATTRIBUTE> {
+    let mut template = vec![];
+    while let [selector, kind, len, rest @ ..] = data {
+        let (value, next) = rest.split_at((*len as usize).min(rest.len()));
+        data = next;
+
+        let type_ = ATTRIBUTE_TYPES
+            .get(*selector as usize)
+            .copied()
+            .unwrap_or(*selector as CK_ATTRIBUTE_TYPE);
+        let (value_ptr, value_len) = match kind % 4 {
+            0 => (value.as_ptr() as _, value.len() as CK_ULONG),
+            1 => (value.as_ptr() as _, 0),
+            2 => (std::ptr::null_mut(), 0),
+            // a null pointer with a length, the module must not read it
+            _ => (std::ptr::null_mut(), value.len() as CK_ULONG),
+        };
+        template.push(CK_ATTRIBUTE {
+            type_,
+            pValue: value_ptr,
+            ulValueLen: value_len,
+        });
+    }
+    template
+}
+
+// C_FindObjectsInit on a slot without instance, the objects are only searched in its database
+pub fn find_objects_init(data: &[u8]) {
+    let slot = Slot {
+        instances: vec![],
+        ..Default::default()
+    };
+    let mut session = Session::new(0, Arc::new(slot), CKF_SERIAL_SESSION);
+
+    let mut template = template(data);
+    let template =
+        unsafe { CkRawAttrTemplate::from_raw_ptr(template.as_mut_ptr(), template.len()) };
+    let _ = session.enum_init(template);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_objects_init_inputs() {
+        // valid UTF-8, invalid UTF-8, empty value, null pointers
+        find_objects_init(&[0, 0, 3, b'a', b'b', b'c']);
+        find_objects_init(&[0, 0, 2, 0xff, 0xfe]);
+        find_objects_init(&[1, 1, 0]);
+        find_objects_init(&[1, 2, 0, 0, 3, 4, 1, 2, 3, 4]);
+        // a class value that isn't aligned, a length longer than the data
+        find_objects_init(&[2, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]);
+        find_objects_init(&[0xff, 0, 200, 1]);
+        find_objects_init(&[]);
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::result_large_err)]
+
 mod api;
 
 mod data;
@@ -8,6 +10,9 @@ mod backend;
 mod config;
 mod defs;
 
+#[cfg(any(feature = "fuzz", test))]
+pub mod fuzz;
+
 #[cfg(panic = "abort")]
 mod unwind_stubs;
 
@@ -33,7 +33,14 @@ pub fn version_struct_from_str(version_str: String) -> cryptoki_sys::CK_VERSION
 #[macro_export]
 macro_rules! lock_session {
     ($hSession:expr, $session:ident) => {
-        let $session =
+        if !$crate::data::INITIALIZED.load(std::sync::atomic::Ordering::SeqCst) {
+            return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
+        }
+        let _app_lock = match $crate::backend::locking::lock_app_mutex() {
+            Ok(guard) => guard,
+            Err(rv) => return rv,
+        };
+        let session_ref =
             match $crate::lock_mutex!($crate::data::SESSION_MANAGER).get_session($hSession) {
                 Some(session) => session,
                 None => {
@@ -41,14 +48,26 @@ macro_rules! lock_session {
                     return cryptoki_sys::CKR_SESSION_HANDLE_INVALID;
                 }
             };
-        let mut $session = $crate::lock_mutex!($session);
+        // dropped after the session is unlocked, it notifies the removal of the device
+        let _removal_check = $crate::backend::session::DeviceRemovalCheck(session_ref.clone());
+        let mut $session = $crate::lock_mutex!(session_ref);
+        if $session.device_removed() {
+            return cryptoki_sys::CKR_DEVICE_REMOVED;
+        }
     };
 }
 
 #[macro_export]
 macro_rules! read_session {
     ($hSession:expr, $session:ident) => {
-        let $session =
+        if !$crate::data::INITIALIZED.load(std::sync::atomic::Ordering::SeqCst) {
+            return cryptoki_sys::CKR_CRYPTOKI_NOT_INITIALIZED;
+        }
+        let _app_lock = match $crate::backend::locking::lock_app_mutex() {
+            Ok(guard) => guard,
+            Err(rv) => return rv,
+        };
+        let session_ref =
             match $crate::lock_mutex!($crate::data::SESSION_MANAGER).get_session($hSession) {
                 Some(session) => session,
                 None => {
@@ -56,7 +75,12 @@ macro_rules! read_session {
                     return cryptoki_sys::CKR_SESSION_HANDLE_INVALID;
                 }
             };
-        let $session = $crate::lock_mutex!($session);
+        // dropped after the session is unlocked, it notifies the removal of the device
+        let _removal_check = $crate::backend::session::DeviceRemovalCheck(session_ref.clone());
+        let $session = $crate::lock_mutex!(session_ref);
+        if $session.device_removed() {
+            return cryptoki_sys::CKR_DEVICE_REMOVED;
+        }
     };
 }
 
@@ -0,0 +1,29 @@
+use std::path::PathBuf;
+
+use cryptoki_sys::{CKR_OK, CK_FUNCTION_LIST, CK_FUNCTION_LIST_PTR_PTR, CK_RV};
+
+// The integration tests are in target/<profile>/deps, next to the library.
+// Cargo doesn't build the cdylib for them, `cargo build` has to be run first.
+pub fn library_path() -> PathBuf {
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(libloading::library_filename("nethsm_pkcs11"))
+}
+
+// loads the compiled module, the library must be kept alive while the functions are used
+pub fn function_list() -> (libloading::Library, &'static CK_FUNCTION_LIST) {
+    let library = unsafe { libloading::Library::new(library_path()) }.unwrap();
+    let mut list = std::ptr::null_mut();
+    {
+        let get_function_list: libloading::Symbol<
+            unsafe extern "C" fn(CK_FUNCTION_LIST_PTR_PTR) -> CK_RV,
+        > = unsafe { library.get(b"C_GetFunctionList") }.unwrap();
+        assert_eq!(unsafe { get_function_list(&mut list) }, CKR_OK);
+    }
+    assert!(!list.is_null());
+
+    (library, unsafe { &*list })
+}
@@ -0,0 +1,84 @@
+// C_Finalize changes the global state of the module, it is tested in its own process
+
+mod common;
+
+use cryptoki_sys::{
+    CKF_SERIAL_SESSION, CKR_CRYPTOKI_NOT_INITIALIZED, CKR_OK, CKR_SESSION_HANDLE_INVALID,
+    CK_SESSION_INFO, CK_ULONG,
+};
+
+#[test]
+fn test_calls_after_finalize() {
+    // nothing listens on the instance, the module doesn't need the NetHSM here
+    let config = std::env::temp_dir().join("p11nethsm-finalize-test.conf");
+    std::fs::write(
+        &config,
+        r#"
+slots:
+  - label: test
+    operator:
+      username: operator
+      password: password
+    instances:
+      - url: "http://127.0.0.1:1/api/v1"
+"#,
+    )
+    .unwrap();
+    std::env::set_var("P11NETHSM_CONFIG_FILE", &config);
+    let (_library, list) = common::function_list();
+
+    unsafe {
+        assert_eq!(list.C_Initialize.unwrap()(std::ptr::null_mut()), CKR_OK);
+
+        let mut session = 0;
+        assert_eq!(
+            list.C_OpenSession.unwrap()(
+                0,
+                CKF_SERIAL_SESSION,
+                std::ptr::null_mut(),
+                None,
+                &mut session
+            ),
+            CKR_OK
+        );
+
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+
+        let mut data = [0u8; 32];
+        let mut signature = [0u8; 64];
+        let mut signature_len = signature.len() as CK_ULONG;
+        assert_eq!(
+            list.C_Sign.unwrap()(
+                session,
+                data.as_mut_ptr(),
+                data.len() as CK_ULONG,
+                signature.as_mut_ptr(),
+                &mut signature_len
+            ),
+            CKR_CRYPTOKI_NOT_INITIALIZED
+        );
+        assert_eq!(
+            list.C_Finalize.unwrap()(std::ptr::null_mut()),
+            CKR_CRYPTOKI_NOT_INITIALIZED
+        );
+        assert_eq!(
+            list.C_OpenSession.unwrap()(
+                0,
+                CKF_SERIAL_SESSION,
+                std::ptr::null_mut(),
+                None,
+                &mut session
+            ),
+            CKR_CRYPTOKI_NOT_INITIALIZED
+        );
+
+        // the sessions don't survive a new initialization
+        assert_eq!(list.C_Initialize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        let mut info: CK_SESSION_INFO = std::mem::zeroed();
+        assert_eq!(
+            list.C_GetSessionInfo.unwrap()(session, &mut info),
+            CKR_SESSION_HANDLE_INVALID
+        );
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+    }
+}
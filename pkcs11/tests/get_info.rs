@@ -0,0 +1,52 @@
+// C_GetInfo depends on the global state of the module, it is tested in its own process
+
+mod common;
+
+use cryptoki_sys::{CKR_CRYPTOKI_NOT_INITIALIZED, CKR_OK, CK_INFO};
+
+#[test]
+fn test_get_info_before_initialize() {
+    // nothing listens on the instance, the module doesn't need the NetHSM here
+    let config = std::env::temp_dir().join("p11nethsm-get-info-test.conf");
+    std::fs::write(
+        &config,
+        r#"
+slots:
+  - label: test
+    operator:
+      username: operator
+      password: password
+    instances:
+      - url: "http://127.0.0.1:1/api/v1"
+"#,
+    )
+    .unwrap();
+    std::env::set_var("P11NETHSM_CONFIG_FILE", &config);
+    let (_library, list) = common::function_list();
+
+    unsafe {
+        let mut info: CK_INFO = std::mem::zeroed();
+        assert_eq!(
+            list.C_GetInfo.unwrap()(&mut info),
+            CKR_CRYPTOKI_NOT_INITIALIZED
+        );
+        let mut fn_list = std::ptr::null_mut();
+        assert_eq!(list.C_GetFunctionList.unwrap()(&mut fn_list), CKR_OK);
+        assert!(!fn_list.is_null());
+
+        assert_eq!(list.C_Initialize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        assert_eq!(list.C_GetInfo.unwrap()(&mut info), CKR_OK);
+        assert_eq!(
+            (info.cryptokiVersion.major, info.cryptokiVersion.minor),
+            (2, 40)
+        );
+        assert_eq!(list.C_GetFunctionList.unwrap()(&mut fn_list), CKR_OK);
+
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        assert_eq!(
+            list.C_GetInfo.unwrap()(&mut info),
+            CKR_CRYPTOKI_NOT_INITIALIZED
+        );
+        assert_eq!(list.C_GetFunctionList.unwrap()(&mut fn_list), CKR_OK);
+    }
+}
@@ -0,0 +1,111 @@
+// C_Initialize changes the global state of the module, it is tested in its own process
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cryptoki_sys::{
+    CKF_SERIAL_SESSION, CKR_OK, CK_C_INITIALIZE_ARGS, CK_RV, CK_SESSION_INFO, CK_VOID_PTR,
+    CK_VOID_PTR_PTR,
+};
+
+static LOCKED: AtomicUsize = AtomicUsize::new(0);
+static UNLOCKED: AtomicUsize = AtomicUsize::new(0);
+static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" fn create(mutex: CK_VOID_PTR_PTR) -> CK_RV {
+    *mutex = 1 as CK_VOID_PTR;
+    CKR_OK
+}
+
+unsafe extern "C" fn destroy(_: CK_VOID_PTR) -> CK_RV {
+    DESTROYED.fetch_add(1, Ordering::SeqCst);
+    CKR_OK
+}
+
+unsafe extern "C" fn lock(_: CK_VOID_PTR) -> CK_RV {
+    LOCKED.fetch_add(1, Ordering::SeqCst);
+    CKR_OK
+}
+
+unsafe extern "C" fn unlock(_: CK_VOID_PTR) -> CK_RV {
+    UNLOCKED.fetch_add(1, Ordering::SeqCst);
+    CKR_OK
+}
+
+// opens a session and reads its information, returns the result of C_GetSessionInfo
+unsafe fn use_session(list: &cryptoki_sys::CK_FUNCTION_LIST) -> CK_RV {
+    let mut session = 0;
+    assert_eq!(
+        list.C_OpenSession.unwrap()(
+            0,
+            CKF_SERIAL_SESSION,
+            std::ptr::null_mut(),
+            None,
+            &mut session
+        ),
+        CKR_OK
+    );
+    let mut info: CK_SESSION_INFO = std::mem::zeroed();
+    list.C_GetSessionInfo.unwrap()(session, &mut info)
+}
+
+#[test]
+fn test_initialize_locking() {
+    // nothing listens on the instance, the module doesn't need the NetHSM here
+    let config = std::env::temp_dir().join("p11nethsm-initialize-test.conf");
+    std::fs::write(
+        &config,
+        r#"
+slots:
+  - label: test
+    operator:
+      username: operator
+      password: password
+    instances:
+      - url: "http://127.0.0.1:1/api/v1"
+"#,
+    )
+    .unwrap();
+    std::env::set_var("P11NETHSM_CONFIG_FILE", &config);
+    let (_library, list) = common::function_list();
+
+    unsafe {
+        // no arguments: the module uses the locking of the OS
+        assert_eq!(list.C_Initialize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        assert_eq!(use_session(list), CKR_OK);
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+
+        // the locking of the OS is allowed, the functions of the application are not used
+        let mut args = CK_C_INITIALIZE_ARGS {
+            CreateMutex: Some(create),
+            DestroyMutex: Some(destroy),
+            LockMutex: Some(lock),
+            UnlockMutex: Some(unlock),
+            flags: cryptoki_sys::CKF_OS_LOCKING_OK,
+            pReserved: std::ptr::null_mut(),
+        };
+        assert_eq!(
+            list.C_Initialize.unwrap()(&mut args as *mut _ as CK_VOID_PTR),
+            CKR_OK
+        );
+        assert_eq!(use_session(list), CKR_OK);
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        assert_eq!(LOCKED.load(Ordering::SeqCst), 0);
+
+        // only the functions of the application can be used
+        args.flags = 0;
+        assert_eq!(
+            list.C_Initialize.unwrap()(&mut args as *mut _ as CK_VOID_PTR),
+            CKR_OK
+        );
+        assert_eq!(use_session(list), CKR_OK);
+        assert!(LOCKED.load(Ordering::SeqCst) > 0);
+        assert_eq!(
+            LOCKED.load(Ordering::SeqCst),
+            UNLOCKED.load(Ordering::SeqCst)
+        );
+        assert_eq!(list.C_Finalize.unwrap()(std::ptr::null_mut()), CKR_OK);
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 1);
+    }
+}